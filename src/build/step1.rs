@@ -1,22 +1,285 @@
-use std::string::FromUtf8Error;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::rc::Rc;
 
+use crate::build::step2::SourceFile;
+
+/// ソースの文字エンコーディング
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    ShiftJis,
+    EucJp,
+}
+
+impl Encoding {
+    /// "utf-8"や"sjis"などのラベルからエンコーディングを得る。
+    pub fn for_label(label: &str) -> Option<Encoding> {
+        let encoding = encoding_rs::Encoding::for_label(label.as_bytes())?;
+        [
+            Encoding::Utf8,
+            Encoding::Utf16Le,
+            Encoding::Utf16Be,
+            Encoding::ShiftJis,
+            Encoding::EucJp,
+        ]
+        .into_iter()
+        .find(|candidate| candidate.to_encoding_rs() == encoding)
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.to_encoding_rs().name()
+    }
+
+    /// 文字をこのエンコーディングで表した時のバイト数
+    fn char_length(self, c: char) -> u8 {
+        match self {
+            Encoding::Utf8 => c.len_utf8() as u8,
+            Encoding::Utf16Le | Encoding::Utf16Be => c.len_utf16() as u8 * 2,
+            // 0x80と半角カナは1バイト
+            Encoding::ShiftJis if c <= '\u{80}' || ('\u{ff61}'..='\u{ff9f}').contains(&c) => 1,
+            Encoding::ShiftJis => 2,
+            Encoding::EucJp if c.is_ascii() => 1,
+            // 半角カナは0x8Eを付けて2バイト
+            Encoding::EucJp if ('\u{ff61}'..='\u{ff9f}').contains(&c) => 2,
+            // JIS X 0208で表せなければJIS X 0212の文字で、0x8Fを付けて3バイト
+            Encoding::EucJp => {
+                let (_, _, had_errors) = encoding_rs::EUC_JP.encode(c.encode_utf8(&mut [0; 4]));
+                if had_errors {
+                    3
+                } else {
+                    2
+                }
+            }
+        }
+    }
+
+    fn to_encoding_rs(self) -> &'static encoding_rs::Encoding {
+        match self {
+            Encoding::Utf8 => encoding_rs::UTF_8,
+            Encoding::Utf16Le => encoding_rs::UTF_16LE,
+            Encoding::Utf16Be => encoding_rs::UTF_16BE,
+            Encoding::ShiftJis => encoding_rs::SHIFT_JIS,
+            Encoding::EucJp => encoding_rs::EUC_JP,
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// デコードできなかったバイト列。
+/// デコードした文字列では置換文字(U+FFFD)になる。
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodeError {
+    /// バイト列の先頭のバイトオフセット
+    pub byte_offset: usize,
+    /// 置換文字の位置
+    pub position: Position,
+}
+
+/// デコードしたソース
+pub struct DecodedSource {
+    /// BOMがあれば先頭に'\u{feff}'を残した文字列
+    pub text: String,
+    pub encoding: Encoding,
+}
+
+/// バイナリを文字列にデコードする。
+/// BOMがあればBOMのエンコーディングを、なければ指定されたエンコーディングを使う。
+/// どちらもなければUTF-8とする。
+pub fn decode(binary: &[u8], encoding: Option<Encoding>) -> DecodedSource {
+    let (encoding, bom_length) = sniff_encoding(binary, encoding);
+
+    let mut text = String::new();
+    if bom_length > 0 {
+        text.push('\u{feff}');
+    }
+
+    let mut decoder = SourceDecoder::new(encoding, bom_length);
+    decoder.decode(&binary[bom_length..], true, &mut text, &mut vec![]);
+
+    DecodedSource { text, encoding }
+}
+
+/// BOMを調べてエンコーディングとBOMのバイト数を返す。
+fn sniff_encoding(binary: &[u8], encoding: Option<Encoding>) -> (Encoding, usize) {
+    match encoding_rs::Encoding::for_bom(binary) {
+        Some((bom_encoding, bom_length)) => {
+            let encoding = if bom_encoding == encoding_rs::UTF_8 {
+                Encoding::Utf8
+            } else if bom_encoding == encoding_rs::UTF_16LE {
+                Encoding::Utf16Le
+            } else {
+                Encoding::Utf16Be
+            };
+            (encoding, bom_length)
+        }
+        None => (encoding.unwrap_or(Encoding::Utf8), 0),
+    }
+}
+
+/// バイト列を少しずつデコードする。
+struct SourceDecoder {
+    decoder: encoding_rs::Decoder,
+    /// デコードに渡したバイト数。BOMも含む
+    byte_count: usize,
+    /// デコードした文字数。BOMは含まない
+    char_count: usize,
+}
+
+impl SourceDecoder {
+    fn new(encoding: Encoding, bom_length: usize) -> SourceDecoder {
+        SourceDecoder {
+            decoder: encoding.to_encoding_rs().new_decoder_without_bom_handling(),
+            byte_count: bom_length,
+            char_count: 0,
+        }
+    }
+
+    /// バイト列をデコードしてtextに追加する。lastならバイト列の終わり。
+    /// デコードできないバイト列は置換文字(U+FFFD)にして、
+    /// その文字の位置とバイト列のバイトオフセットとバイト数をerrorsに追加する。
+    fn decode(
+        &mut self,
+        bytes: &[u8],
+        last: bool,
+        text: &mut String,
+        errors: &mut Vec<(usize, usize, u8)>,
+    ) {
+        let mut offset = 0;
+        loop {
+            let src = &bytes[offset..];
+            // 置換文字を入れる分も確保する
+            let needed = self
+                .decoder
+                .max_utf8_buffer_length_without_replacement(src.len())
+                .unwrap_or(src.len() * 3)
+                + 3;
+            text.reserve(needed);
+
+            let length = text.len();
+            let (result, read) = self
+                .decoder
+                .decode_to_string_without_replacement(src, text, last);
+            self.char_count += text[length..].chars().count();
+            offset += read;
+            self.byte_count += read;
+
+            match result {
+                encoding_rs::DecoderResult::InputEmpty => break,
+                encoding_rs::DecoderResult::OutputFull => {}
+                encoding_rs::DecoderResult::Malformed(bad_length, extra_length) => {
+                    let byte_offset = self.byte_count - bad_length as usize - extra_length as usize;
+                    errors.push((self.char_count, byte_offset, bad_length));
+                    text.push('\u{fffd}');
+                    self.char_count += 1;
+                }
+            }
+        }
+    }
+}
+
+/// 一度に読み込むバイト数
+const CHUNK_SIZE: usize = 8192;
+
+/// 生きているMarkの文字の位置。Markはほとんど作った順と逆に捨てられる
+type LiveMarks = Rc<RefCell<Vec<usize>>>;
+
+/// ソースを少しずつ読み込みながら文字を返す。
+/// 読み戻せるように、生きているMarkのうち最も古い位置からの文字を残しておく。
+/// ただしcutした位置より前のMarkには読み戻さないので、その文字は残さない。
 pub struct CharStream {
-    chars: Vec<char>,
+    reader: Box<dyn Read>,
+    decoder: SourceDecoder,
+    /// ソースを最後まで読み込んだか
+    finished: bool,
+    /// デコードした文字。先頭はbuffer_start番目の文字
+    buffer: VecDeque<char>,
+    buffer_start: usize,
+    /// バッファにある置換文字の、文字の位置とデコードできなかったバイト数。
+    /// ほかの文字のバイト数はエンコーディングから求める
+    bad_lengths: VecDeque<(usize, u8)>,
     pointer: usize,
+    /// これより前には読み戻さない文字の位置
+    cut: usize,
     position: Position,
+    encoding: Encoding,
+    marks: LiveMarks,
+    /// まだ読み込んでいない置換文字の、文字の位置とバイトオフセットとバイト数
+    pending_decode_errors: VecDeque<(usize, usize, u8)>,
+    decode_errors: Vec<DecodeError>,
+    read_error: Option<io::Error>,
 }
 
 impl CharStream {
-    pub fn new(binary: Vec<u8>) -> Result<CharStream, FromUtf8Error> {
-        let string = String::from_utf8(binary)?;
-        let mut chars = string.chars().peekable();
-        chars.next_if_eq(&'\u{feff}');
+    /// UTF-8かBOMのエンコーディングでデコードする。
+    pub fn new(binary: Vec<u8>) -> CharStream {
+        CharStream::with_encoding(binary, None)
+    }
 
-        Ok(CharStream {
-            chars: chars.collect(),
+    /// BOMがなければ指定されたエンコーディングでデコードする。
+    pub fn with_encoding(binary: Vec<u8>, encoding: Option<Encoding>) -> CharStream {
+        CharStream::from_reader(io::Cursor::new(binary), encoding)
+    }
+
+    /// 読み込みながらデコードする。BOMがなければ指定されたエンコーディングを使う。
+    /// デコードできないバイト列は置換文字にして、読み込んだ時にdecode_errorsに残す。
+    /// 読み込みに失敗したらソースの終わりとして、read_errorに残す。
+    pub fn from_reader<R: Read + 'static>(reader: R, encoding: Option<Encoding>) -> CharStream {
+        let mut reader: Box<dyn Read> = Box::new(reader);
+
+        // BOMを調べるために先頭の3バイトを読み込む
+        let mut head = vec![];
+        let mut read_error = None;
+        if let Err(e) = (&mut reader).take(3).read_to_end(&mut head) {
+            read_error = Some(e);
+        }
+        let (encoding, bom_length) = sniff_encoding(&head, encoding);
+
+        let mut char_stream = CharStream {
+            reader,
+            decoder: SourceDecoder::new(encoding, bom_length),
+            finished: read_error.is_some(),
+            buffer: VecDeque::new(),
+            buffer_start: 0,
+            bad_lengths: VecDeque::new(),
             pointer: 0,
-            position: Position::new(1, 1),
-        })
+            cut: 0,
+            position: Position {
+                byte_offset: Some(bom_length),
+                ..Position::new(1, 1)
+            },
+            encoding,
+            marks: LiveMarks::default(),
+            pending_decode_errors: VecDeque::new(),
+            decode_errors: vec![],
+            read_error,
+        };
+        char_stream.push_bytes(&head[bom_length..], char_stream.finished);
+
+        char_stream
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// これまでに読み込んだ文字のうちデコードできなかったもの
+    pub fn decode_errors(&self) -> &[DecodeError] {
+        &self.decode_errors
+    }
+
+    pub fn read_error(&self) -> Option<&io::Error> {
+        self.read_error.as_ref()
     }
 
     pub fn get_position(&self) -> Position {
@@ -24,11 +287,12 @@ impl CharStream {
     }
 
     pub fn read(&mut self) -> (Option<char>, Position) {
-        if self.pointer >= self.chars.len() {
-            return (None, self.position.clone());
-        }
-
-        let mut c = self.chars[self.pointer];
+        let index = self.pointer;
+        let (mut c, length) = match self.get(index) {
+            Some(c) => c,
+            None => return (None, self.position.clone()),
+        };
+        let mut length = length as usize;
 
         self.pointer += 1;
 
@@ -36,81 +300,332 @@ impl CharStream {
             // 改行を'\n'に統一する
             c = '\n';
             // 次が\nならその次まで読み込み位置を進める
-            if self.chars.get(self.pointer) == Some(&'\n') {
+            if let Some(('\n', next_length)) = self.get(self.pointer) {
                 self.pointer += 1;
+                length += next_length as usize;
             }
         }
 
         let position = if c == '\n' {
-            self.position.next_line()
+            self.position.next_line(length)
         } else {
-            self.position.next_char()
+            self.position.next_char(c, length)
         };
 
+        // 初めて読み込んだ置換文字ならデコードできなかった位置を残す
+        while let Some(&(error_index, byte_offset, _)) = self.pending_decode_errors.front() {
+            if error_index > index {
+                break;
+            }
+            self.pending_decode_errors.pop_front();
+            if error_index == index {
+                self.decode_errors.push(DecodeError {
+                    byte_offset,
+                    position: position.clone(),
+                });
+            }
+        }
+
         (Some(c), position)
     }
 
     pub fn mark(&self) -> Mark {
-        Mark::new(self.pointer, self.position.clone())
+        self.marks.borrow_mut().push(self.pointer);
+        Mark {
+            pointer: self.pointer,
+            position: self.position.clone(),
+            marks: Rc::clone(&self.marks),
+        }
     }
 
+    /// Markの位置に読み戻す。cutした位置より前のMarkなら文字が捨てられているのでパニックする。
     pub fn reset(&mut self, mark: Mark) {
+        assert!(mark.pointer >= self.cut, "The mark has been cut.");
         self.pointer = mark.pointer;
-        self.position = mark.position;
+        self.position = mark.position.clone();
+    }
+
+    /// 読み込み位置より前のMarkには読み戻さないことにする。
+    /// それより前の文字は生きているMarkがあっても捨てられる。
+    pub fn cut(&mut self) {
+        self.cut = self.pointer;
+    }
+
+    /// 読み戻せるように残している文字数
+    #[cfg(test)]
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// index番目の文字とバイト数。必要ならソースを読み込む。
+    fn get(&mut self, index: usize) -> Option<(char, u8)> {
+        if index >= self.buffer_start + self.buffer.len() {
+            self.fill(index);
+        }
+
+        let c = *self.buffer.get(index - self.buffer_start)?;
+        let length = match self.bad_lengths.binary_search_by_key(&index, |&(i, _)| i) {
+            Ok(i) => self.bad_lengths[i].1,
+            Err(_) => self.encoding.char_length(c),
+        };
+        Some((c, length))
+    }
+
+    /// index番目の文字まで、ソースの終わりでなければ読み込む。
+    fn fill(&mut self, index: usize) {
+        while index >= self.buffer_start + self.buffer.len() {
+            if self.finished {
+                return;
+            }
+            self.discard();
+
+            let mut bytes = [0; CHUNK_SIZE];
+            let length = match self.reader.read(&mut bytes) {
+                Ok(length) => length,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.read_error = Some(e);
+                    0
+                }
+            };
+            self.finished = length == 0;
+            self.push_bytes(&bytes[..length], self.finished);
+        }
+    }
+
+    /// バイト列をデコードして文字を追加する。
+    /// 置換文字のバイト数はデコードできなかったバイト列のバイト数にする。
+    fn push_bytes(&mut self, bytes: &[u8], last: bool) {
+        let mut text = String::new();
+        let mut errors = vec![];
+        self.decoder.decode(bytes, last, &mut text, &mut errors);
+
+        self.buffer.extend(text.chars());
+        self.bad_lengths.extend(
+            errors
+                .iter()
+                .map(|&(index, _, bad_length)| (index, bad_length)),
+        );
+        self.pending_decode_errors.extend(errors);
+    }
+
+    /// 読み込み位置と、cutした位置以降の生きているMarkより前の文字を捨てる。
+    fn discard(&mut self) {
+        let oldest = match self
+            .marks
+            .borrow()
+            .iter()
+            .filter(|&&pointer| pointer >= self.cut)
+            .min()
+        {
+            Some(&pointer) => pointer.min(self.pointer),
+            None => self.pointer,
+        };
+        let count = oldest
+            .saturating_sub(self.buffer_start)
+            .min(self.buffer.len());
+        self.buffer.drain(..count);
+        self.buffer_start += count;
+        while let Some(&(index, _)) = self.bad_lengths.front() {
+            if index >= self.buffer_start {
+                break;
+            }
+            self.bad_lengths.pop_front();
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// 読み込み位置。生きている間は、その位置からの文字を読み戻せるように残す。
 pub struct Mark {
     pointer: usize,
     position: Position,
+    marks: LiveMarks,
+}
+
+impl Drop for Mark {
+    fn drop(&mut self) {
+        let mut marks = self.marks.borrow_mut();
+        if let Some(i) = marks.iter().rposition(|&pointer| pointer == self.pointer) {
+            marks.swap_remove(i);
+        }
+    }
+}
+
+impl fmt::Debug for Mark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mark")
+            .field("pointer", &self.pointer)
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl PartialEq for Mark {
+    fn eq(&self, other: &Mark) -> bool {
+        self.pointer == other.pointer && self.position == other.position
+    }
 }
 
-impl Mark {
-    fn new(pointer: usize, position: Position) -> Mark {
-        Mark { pointer, position }
+/// 桁の数え方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnUnit {
+    /// 文字数
+    Char,
+    /// UTF-16のコードユニット数。LSPの既定の数え方
+    Utf16,
+    /// UTF-8のバイト数
+    Utf8,
+}
+
+impl ColumnUnit {
+    fn length(self, c: char) -> u64 {
+        match self {
+            ColumnUnit::Char => 1,
+            ColumnUnit::Utf16 => c.len_utf16() as u64,
+            ColumnUnit::Utf8 => c.len_utf8() as u64,
+        }
     }
 }
 
+/// ソース上の位置。行と桁は1から数える。
 #[derive(Clone, Debug, PartialEq)]
 pub struct Position {
     pub line_number: u64,
+    /// 文字数で数えた桁
     pub column_number: u64,
+    /// 元のファイルでのバイトオフセット。BOMも含めて数え、\r\nは2文字分になる。
+    /// ソースを読み込まずに作った位置ならNone
+    pub byte_offset: Option<usize>,
+    /// UTF-16のコードユニット数で数えた桁
+    pub utf16_column_number: u64,
+    /// UTF-8のバイト数で数えた桁
+    pub utf8_column_number: u64,
 }
 
 impl Position {
+    /// 行頭から桁までASCIIだけの位置。バイトオフセットはわからないのでNoneにする。
     pub fn new(line_number: u64, column_number: u64) -> Position {
         Position {
             line_number,
             column_number,
+            byte_offset: None,
+            utf16_column_number: column_number,
+            utf8_column_number: column_number,
+        }
+    }
+
+    /// 行頭から桁までASCIIだけの、バイトオフセットがわかっている位置。
+    pub fn with_byte_offset(line_number: u64, column_number: u64, byte_offset: usize) -> Position {
+        Position {
+            byte_offset: Some(byte_offset),
+            ..Position::new(line_number, column_number)
         }
     }
 
-    fn next_char(&mut self) -> Position {
+    /// 指定した数え方の桁
+    pub fn column(&self, unit: ColumnUnit) -> u64 {
+        match unit {
+            ColumnUnit::Char => self.column_number,
+            ColumnUnit::Utf16 => self.utf16_column_number,
+            ColumnUnit::Utf8 => self.utf8_column_number,
+        }
+    }
+
+    /// lengthはソースでの文字のバイト数
+    fn next_char(&mut self, c: char, length: usize) -> Position {
         let result = self.clone();
         self.column_number += 1;
+        self.utf16_column_number += ColumnUnit::Utf16.length(c);
+        self.utf8_column_number += ColumnUnit::Utf8.length(c);
+        self.advance_byte_offset(length);
         result
     }
 
-    fn next_line(&mut self) -> Position {
+    /// lengthはソースでの改行のバイト数
+    fn next_line(&mut self, length: usize) -> Position {
         let result = self.clone();
         self.line_number += 1;
         self.column_number = 1;
+        self.utf16_column_number = 1;
+        self.utf8_column_number = 1;
+        self.advance_byte_offset(length);
         result
     }
+
+    fn advance_byte_offset(&mut self, length: usize) {
+        if let Some(byte_offset) = &mut self.byte_offset {
+            *byte_offset += length;
+        }
+    }
+}
+
+/// 行の桁を別の数え方の桁に変換する。lineは改行を含まない行の文字列。
+/// 桁が文字の途中を指すか、行末の次より後ならNone。
+pub fn convert_column(line: &str, column: u64, from: ColumnUnit, to: ColumnUnit) -> Option<u64> {
+    let mut from_column = 1;
+    let mut to_column = 1;
+    for c in line.chars() {
+        if from_column >= column {
+            break;
+        }
+        from_column += from.length(c);
+        to_column += to.length(c);
+    }
+    (from_column == column).then_some(to_column)
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line_number, self.column_number)
+    }
+}
+
+/// ソース上の範囲。
+/// endは範囲の最後の文字の次の位置。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+    /// インクルードしたファイルやテンプレートから持ち込んだ範囲なら、書かれていたファイル。
+    /// ドキュメントのファイルの範囲ならNone
+    pub file: Option<Rc<SourceFile>>,
+}
+
+impl Span {
+    pub fn new(start: Position, end: Position) -> Span {
+        Span {
+            start,
+            end,
+            file: None,
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::error::Error;
+    use std::io;
+    use std::io::Read;
 
+    use crate::build::step1::convert_column;
     use crate::build::step1::CharStream;
+    use crate::build::step1::ColumnUnit;
+    use crate::build::step1::DecodeError;
+    use crate::build::step1::Encoding;
     use crate::build::step1::Mark;
     use crate::build::step1::Position;
+    use crate::build::step1::Span;
+    use crate::build::step1::CHUNK_SIZE;
 
     #[test]
     fn test_read() -> Result<(), Box<dyn Error>> {
-        let mut r = CharStream::new(b"abc\r\nxyz\n123".to_vec())?;
+        let mut r = CharStream::new(b"abc\r\nxyz\n123".to_vec());
 
         let mut s = String::new();
         let mut p = vec![];
@@ -132,48 +647,376 @@ mod test {
     #[test]
     fn test_read_with_bom() -> Result<(), Box<dyn Error>> {
         let binary = Vec::from(&b"\xEF\xBB\xBFabc"[..]);
-        let mut r = CharStream::new(binary)?;
+        let mut r = CharStream::new(binary);
 
-        assert_eq!((Some('a'), Position::new(1, 1)), r.read());
-        assert_eq!((Some('b'), Position::new(1, 2)), r.read());
-        assert_eq!((Some('c'), Position::new(1, 3)), r.read());
-        assert_eq!((None, Position::new(1, 4)), r.read());
+        assert_eq!((Some('a'), Position::with_byte_offset(1, 1, 3)), r.read());
+        assert_eq!((Some('b'), Position::with_byte_offset(1, 2, 4)), r.read());
+        assert_eq!((Some('c'), Position::with_byte_offset(1, 3, 5)), r.read());
+        assert_eq!((None, Position::with_byte_offset(1, 4, 6)), r.read());
 
         Ok(())
     }
 
+    /// UTF-16はBOMで判定する
+    #[test]
+    fn test_read_utf16() {
+        let mut r = CharStream::new(b"\xFF\xFEa\x00\x42\x30".to_vec());
+        assert_eq!(r.encoding(), Encoding::Utf16Le);
+        assert_eq!(read_all(&mut r), "aあ");
+
+        let mut r = CharStream::new(b"\xFE\xFF\x00a\x30\x42".to_vec());
+        assert_eq!(r.encoding(), Encoding::Utf16Be);
+        assert_eq!(read_all(&mut r), "aあ");
+    }
+
+    /// BOMがなければ指定されたエンコーディングでデコードする
+    #[test]
+    fn test_read_with_encoding() {
+        let mut r = CharStream::with_encoding(b"a\x82\xA0".to_vec(), Some(Encoding::ShiftJis));
+        assert_eq!(r.encoding(), Encoding::ShiftJis);
+        assert_eq!(read_all(&mut r), "aあ");
+
+        let mut r = CharStream::with_encoding(b"a\xA4\xA2".to_vec(), Some(Encoding::EucJp));
+        assert_eq!(read_all(&mut r), "aあ");
+
+        // BOMを優先する
+        let r = CharStream::with_encoding(b"\xEF\xBB\xBFa".to_vec(), Some(Encoding::EucJp));
+        assert_eq!(r.encoding(), Encoding::Utf8);
+    }
+
+    /// デコードできないバイト列は置換文字にして、位置を残す
+    #[test]
+    fn test_decode_error() {
+        let mut r = CharStream::new(b"ab\r\n\xFFc\xE3\x81".to_vec());
+        let mark = r.mark();
+
+        assert_eq!(read_all(&mut r), "ab\n\u{fffd}c\u{fffd}");
+        assert_eq!(
+            r.decode_errors(),
+            &[
+                DecodeError {
+                    byte_offset: 4,
+                    position: Position::with_byte_offset(2, 1, 4),
+                },
+                DecodeError {
+                    byte_offset: 6,
+                    // 置換文字はUTF-8で3バイト
+                    position: Position {
+                        utf8_column_number: 5,
+                        ..Position::with_byte_offset(2, 3, 6)
+                    },
+                },
+            ]
+        );
+
+        // 読み戻しても重ねて残さない
+        r.reset(mark);
+        read_all(&mut r);
+        assert_eq!(r.decode_errors().len(), 2);
+    }
+
+    #[test]
+    fn test_encoding_for_label() {
+        assert_eq!(Encoding::for_label("sjis"), Some(Encoding::ShiftJis));
+        assert_eq!(Encoding::for_label("EUC-JP"), Some(Encoding::EucJp));
+        assert_eq!(Encoding::for_label("utf-16"), Some(Encoding::Utf16Le));
+        assert_eq!(Encoding::for_label("latin1"), None);
+        assert_eq!(Encoding::ShiftJis.to_string(), "Shift_JIS");
+    }
+
     #[test]
     fn test_mark_reset() -> Result<(), Box<dyn Error>> {
-        let mut r = CharStream::new(b"abc\r\nxyz".to_vec())?;
+        let mut r = CharStream::new(b"abc\r\nxyz".to_vec());
 
-        assert_eq!(Mark::new(0, Position::new(1, 1)), r.mark());
-        assert_eq!((Some('a'), Position::new(1, 1)), r.read());
-        assert_eq!(Mark::new(1, Position::new(1, 2)), r.mark());
+        assert_eq!(
+            (0, Position::with_byte_offset(1, 1, 0)),
+            pointer_and_position(&r.mark())
+        );
+        assert_eq!((Some('a'), Position::with_byte_offset(1, 1, 0)), r.read());
+        assert_eq!(
+            (1, Position::with_byte_offset(1, 2, 1)),
+            pointer_and_position(&r.mark())
+        );
 
         let mark = r.mark();
 
-        assert_eq!((Some('b'), Position::new(1, 2)), r.read());
-        assert_eq!((Some('c'), Position::new(1, 3)), r.read());
-        assert_eq!((Some('\n'), Position::new(1, 4)), r.read());
-        assert_eq!(Mark::new(5, Position::new(2, 1)), r.mark());
+        assert_eq!((Some('b'), Position::with_byte_offset(1, 2, 1)), r.read());
+        assert_eq!((Some('c'), Position::with_byte_offset(1, 3, 2)), r.read());
+        assert_eq!((Some('\n'), Position::with_byte_offset(1, 4, 3)), r.read());
+        assert_eq!(
+            (5, Position::with_byte_offset(2, 1, 5)),
+            pointer_and_position(&r.mark())
+        );
 
         r.reset(mark);
-        assert_eq!((Some('b'), Position::new(1, 2)), r.read());
+        assert_eq!((Some('b'), Position::with_byte_offset(1, 2, 1)), r.read());
 
         Ok(())
     }
 
     #[test]
     fn test_eof() -> Result<(), Box<dyn Error>> {
-        let mut r = CharStream::new(b"a".to_vec())?;
+        let mut r = CharStream::new(b"a".to_vec());
 
-        assert_eq!((Some('a'), Position::new(1, 1)), r.read());
-        assert_eq!((None, Position::new(1, 2)), r.read());
-        assert_eq!((None, Position::new(1, 2)), r.read());
+        assert_eq!((Some('a'), Position::with_byte_offset(1, 1, 0)), r.read());
+        assert_eq!((None, Position::with_byte_offset(1, 2, 1)), r.read());
+        assert_eq!((None, Position::with_byte_offset(1, 2, 1)), r.read());
 
         Ok(())
     }
 
+    /// バイトオフセットとUTF-16とUTF-8の桁も比較する
+    #[test]
+    fn test_position_eq() {
+        assert_eq!(Position::new(1, 2), Position::new(1, 2));
+        assert_ne!(Position::new(1, 2), Position::with_byte_offset(1, 2, 1));
+        assert_ne!(
+            Position::with_byte_offset(1, 2, 1),
+            Position {
+                utf8_column_number: 4,
+                ..Position::with_byte_offset(1, 2, 1)
+            }
+        );
+    }
+
+    #[test]
+    fn test_span_display() {
+        let span = Span::new(Position::new(1, 2), Position::new(3, 4));
+        assert_eq!(&span.to_string(), "1:2-3:4");
+    }
+
+    fn read_all(r: &mut CharStream) -> String {
+        let mut s = String::new();
+        while let (Some(c), _) = r.read() {
+            s.push(c);
+        }
+        s
+    }
+
+    fn pointer_and_position(mark: &Mark) -> (usize, Position) {
+        (mark.pointer, mark.position.clone())
+    }
+
+    /// 1バイトずつ返すソース
+    struct ByteReader(Vec<u8>, usize);
+
+    impl Read for ByteReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.get(self.1) {
+                Some(&b) if !buf.is_empty() => {
+                    buf[0] = b;
+                    self.1 += 1;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    /// 途中で失敗するソース
+    struct FailingReader(bool);
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 {
+                return Err(io::Error::other("broken"));
+            }
+            self.0 = true;
+            buf[..2].copy_from_slice(b"ab");
+            Ok(2)
+        }
+    }
+
+    /// 文字の途中で区切られても読み込みながらデコードする
+    #[test]
+    fn test_from_reader() {
+        let binary = b"\xEF\xBB\xBFa\xE3\x81\x82\r\n\xFFb".to_vec();
+        let mut r = CharStream::from_reader(ByteReader(binary, 0), None);
+
+        assert_eq!(r.encoding(), Encoding::Utf8);
+        assert_eq!(read_all(&mut r), "aあ\n\u{fffd}b");
+        assert_eq!(
+            r.decode_errors(),
+            &[DecodeError {
+                byte_offset: 9,
+                position: Position::with_byte_offset(2, 1, 9),
+            }]
+        );
+        assert!(r.read_error().is_none());
+
+        let mut r = CharStream::from_reader(ByteReader(b"\xFE\xFF\x00a\x30\x42".to_vec(), 0), None);
+        assert_eq!(r.encoding(), Encoding::Utf16Be);
+        assert_eq!(read_all(&mut r), "aあ");
+    }
+
+    /// 生きているMarkより前の文字だけを捨てる
+    #[test]
+    fn test_discard() {
+        let binary = "a".repeat(CHUNK_SIZE * 3).into_bytes();
+
+        let mut r = CharStream::new(binary.clone());
+        read_all(&mut r);
+        assert!(r.buffer.len() <= CHUNK_SIZE);
+
+        let mut r = CharStream::new(binary);
+        r.read();
+        let mark = r.mark();
+        read_all(&mut r);
+        assert_eq!(r.buffer_start, 1);
+
+        r.reset(mark);
+        assert_eq!(r.get_position(), Position::with_byte_offset(1, 2, 1));
+        assert_eq!(read_all(&mut r).len(), CHUNK_SIZE * 3 - 1);
+        assert!(r.marks.borrow().is_empty());
+
+        // cutした位置より前のMarkの文字は捨てる
+        let mut r = CharStream::new("a".repeat(CHUNK_SIZE * 3).into_bytes());
+        let _mark = r.mark();
+        r.read();
+        r.cut();
+        read_all(&mut r);
+        assert!(r.buffer_start > 1);
+    }
+
+    /// cutした位置より前のMarkには読み戻せない
+    #[test]
+    #[should_panic(expected = "The mark has been cut.")]
+    fn test_reset_cut_mark() {
+        let mut r = CharStream::new("abc".as_bytes().to_vec());
+        let mark = r.mark();
+        r.read();
+        r.cut();
+        r.reset(mark);
+    }
+
+    /// 読み込みに失敗したらそこで終わる
+    #[test]
+    fn test_read_error() {
+        let mut r = CharStream::from_reader(FailingReader(false), None);
+
+        assert_eq!(read_all(&mut r), "ab");
+        assert_eq!(r.read_error().unwrap().to_string(), "broken");
+    }
+
+    /// 読み込んだ文字ごとのバイトオフセットとUTF-16とUTF-8の桁
+    fn read_offsets(r: &mut CharStream) -> Vec<(char, usize, u64, u64)> {
+        let mut offsets = vec![];
+        while let (Some(c), position) = r.read() {
+            offsets.push((
+                c,
+                position.byte_offset.unwrap(),
+                position.utf16_column_number,
+                position.utf8_column_number,
+            ));
+        }
+        let position = r.get_position();
+        offsets.push((
+            '$',
+            position.byte_offset.unwrap(),
+            position.utf16_column_number,
+            position.utf8_column_number,
+        ));
+        offsets
+    }
+
+    /// BOMと\r\nも元のファイルのバイト数で数える
+    #[test]
+    fn test_byte_offset() {
+        let mut r = CharStream::new("\u{feff}aあ😀\r\nb".as_bytes().to_vec());
+        assert_eq!(
+            read_offsets(&mut r),
+            vec![
+                ('a', 3, 1, 1),
+                ('あ', 4, 2, 2),
+                ('😀', 7, 3, 5),
+                ('\n', 11, 5, 9),
+                ('b', 13, 1, 1),
+                ('$', 14, 2, 2),
+            ]
+        );
+
+        let mut r = CharStream::new(b"\xFF\xFEa\x00\x3D\xD8\x00\xDE\r\x00\n\x00b\x00".to_vec());
+        assert_eq!(
+            read_offsets(&mut r),
+            vec![
+                ('a', 2, 1, 1),
+                ('😀', 4, 2, 2),
+                ('\n', 8, 4, 6),
+                ('b', 12, 1, 1),
+                ('$', 14, 2, 2),
+            ]
+        );
+    }
+
+    /// 置換文字はデコードできなかったバイト列のバイト数で数える
+    #[test]
+    fn test_byte_offset_with_encoding() {
+        let mut r =
+            CharStream::with_encoding(b"\x82\xA0\xB1\x81 a".to_vec(), Some(Encoding::ShiftJis));
+        assert_eq!(
+            read_offsets(&mut r),
+            vec![
+                ('あ', 0, 1, 1),
+                ('ｱ', 2, 2, 4),
+                ('\u{fffd}', 3, 3, 7),
+                (' ', 4, 4, 10),
+                ('a', 5, 5, 11),
+                ('$', 6, 6, 12),
+            ]
+        );
+
+        let mut r = CharStream::with_encoding(
+            b"\xA4\xA2\x8E\xB1\x8F\xB0\xA1a".to_vec(),
+            Some(Encoding::EucJp),
+        );
+        assert_eq!(
+            read_offsets(&mut r),
+            vec![
+                ('あ', 0, 1, 1),
+                ('ｱ', 2, 2, 4),
+                ('丂', 4, 3, 7),
+                ('a', 7, 4, 10),
+                ('$', 8, 5, 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_convert_column() {
+        let line = "aあ😀b";
+
+        assert_eq!(
+            convert_column(line, 3, ColumnUnit::Char, ColumnUnit::Utf16),
+            Some(3)
+        );
+        assert_eq!(
+            convert_column(line, 4, ColumnUnit::Char, ColumnUnit::Utf16),
+            Some(5)
+        );
+        assert_eq!(
+            convert_column(line, 5, ColumnUnit::Utf16, ColumnUnit::Utf8),
+            Some(9)
+        );
+        assert_eq!(
+            convert_column(line, 10, ColumnUnit::Utf8, ColumnUnit::Char),
+            Some(5)
+        );
+        assert_eq!(
+            convert_column(line, 1, ColumnUnit::Utf8, ColumnUnit::Utf16),
+            Some(1)
+        );
+
+        // 文字の途中や行末の次より後は変換できない
+        assert_eq!(
+            convert_column(line, 4, ColumnUnit::Utf16, ColumnUnit::Char),
+            None
+        );
+        assert_eq!(
+            convert_column(line, 6, ColumnUnit::Char, ColumnUnit::Utf16),
+            None
+        );
+    }
+
     fn format_positions(positions: &Vec<Position>) -> String {
         let mut s = String::new();
         for position in positions {
@@ -1,10 +1,77 @@
 use crate::build::step1::CharStream;
 use crate::build::step1::Mark as Step1Mark;
 use crate::build::step1::Position;
+use crate::build::step1::Span;
+use std::fmt;
 use std::path::PathBuf;
+use std::rc::Rc;
 
+/// 既定のインデントの幅
 pub const INDENT_SIZE: u64 = 4;
 
+/// インデントに書かれたタブの扱い
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TabPolicy {
+    /// 指定した桁数ごとのタブ位置まで空白にする
+    Expand(u64),
+    /// 次のインデントの深さまで進める
+    Indent,
+    /// タブとそれ以降は文字として読み込む
+    #[default]
+    Text,
+    /// エラーにする。タブとそれ以降は文字として読み込む
+    Reject,
+}
+
+/// インデントの書き方
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IndentStyle {
+    /// 1段のインデントの桁数
+    pub width: u64,
+    pub tab_policy: TabPolicy,
+    /// 幅の倍数でないインデントと、2段以上深くなるインデントを警告する
+    pub strict: bool,
+}
+
+impl IndentStyle {
+    pub const fn new() -> IndentStyle {
+        IndentStyle {
+            width: INDENT_SIZE,
+            tab_policy: TabPolicy::Text,
+            strict: false,
+        }
+    }
+
+    /// インデントの文字を読み込んだ後の桁数。インデントに使えない文字ならNone。
+    pub fn advance(&self, columns: u64, c: char) -> Option<u64> {
+        match (c, self.tab_policy) {
+            (' ', _) => Some(columns + 1),
+            ('\t', TabPolicy::Expand(tab_width)) => {
+                let tab_width = tab_width.max(1);
+                Some((columns / tab_width + 1) * tab_width)
+            }
+            ('\t', TabPolicy::Indent) => {
+                let width = self.width.max(1);
+                Some((columns / width + 1) * width)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Default for IndentStyle {
+    fn default() -> IndentStyle {
+        IndentStyle::new()
+    }
+}
+
+/// インデントについての診断
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndentDiagnostic {
+    pub position: Position,
+    pub message: String,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Unit {
     Char(char),
@@ -18,27 +85,139 @@ pub enum Unit {
 pub struct FilePosition {
     pub filepath: PathBuf,
     pub position: Option<Position>,
+    /// インクルードしたファイルなら、インクルードしたタグの位置
+    pub included_from: Option<Box<FilePosition>>,
+}
+
+impl FilePosition {
+    /// インクルードしたタグの位置。内側のインクルードから順に辿る。
+    pub fn include_chain(&self) -> impl Iterator<Item = &FilePosition> {
+        std::iter::successors(self.included_from.as_deref(), |file_position| {
+            file_position.included_from.as_deref()
+        })
+    }
+
+    /// 一番外側のインクルードのさらに外側に、インクルードしたタグの位置を繋げる。
+    pub fn push_included_from(&mut self, file_position: FilePosition) {
+        let mut link = &mut self.included_from;
+        while let Some(outer) = link {
+            link = &mut outer.included_from;
+        }
+        *link = Some(Box::new(file_position));
+    }
+}
+
+/// 別のファイルから持ち込んだ範囲が書かれていたファイル
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceFile {
+    pub filepath: PathBuf,
+    /// 持ち込んだ位置。インクルードしたタグか、テンプレートを呼び出したタグの位置
+    pub included_from: FilePosition,
+}
+
+impl SourceFile {
+    /// 範囲の始まりのファイル上の位置。
+    pub fn file_position(&self, span: &Span) -> FilePosition {
+        FilePosition {
+            filepath: self.filepath.clone(),
+            position: Some(span.start.clone()),
+            included_from: Some(Box::new(self.included_from.clone())),
+        }
+    }
+}
+
+/// filepathのファイルをincluded_fromの位置に持ち込んだ範囲にする。
+/// 返した関数で範囲を書き換える。
+/// さらに別のファイルから持ち込んだ範囲は、持ち込んだ位置の外側にincluded_fromを繋げる。
+pub fn include_span(filepath: PathBuf, included_from: FilePosition) -> impl FnMut(&mut Span) {
+    let file = Rc::new(SourceFile {
+        filepath,
+        included_from: included_from.clone(),
+    });
+    // 同じファイルの範囲は同じものを指すようにする
+    let mut nested: Vec<(Rc<SourceFile>, Rc<SourceFile>)> = vec![];
+
+    move |span| {
+        span.file = Some(match &span.file {
+            None => file.clone(),
+            Some(inner) => match nested.iter().find(|(old, _)| Rc::ptr_eq(old, inner)) {
+                Some((_, new)) => new.clone(),
+                None => {
+                    let mut new = SourceFile::clone(inner);
+                    new.included_from.push_included_from(included_from.clone());
+                    let new = Rc::new(new);
+                    nested.push((inner.clone(), new.clone()));
+                    new
+                }
+            },
+        });
+    }
+}
+
+impl fmt::Display for FilePosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.position {
+            Some(position) => write!(f, "{}:{}", self.filepath.display(), position),
+            None => write!(f, "{}", self.filepath.display()),
+        }
+    }
 }
 
 pub struct UnitStream {
     filepath: PathBuf,
     char_stream: CharStream,
     status: Status,
+    indent_style: IndentStyle,
+    /// 読み戻しても消えないように状態とは別に持つ
+    indent_errors: Vec<IndentDiagnostic>,
+    indent_warnings: Vec<IndentDiagnostic>,
 }
 
 impl UnitStream {
     pub fn new(filepath: PathBuf, char_stream: CharStream) -> Self {
+        UnitStream::with_indent_style(filepath, char_stream, IndentStyle::new())
+    }
+
+    pub fn with_indent_style(
+        filepath: PathBuf,
+        char_stream: CharStream,
+        indent_style: IndentStyle,
+    ) -> Self {
+        // 読み込み前の位置もバイトオフセットを持つようにソースの先頭の位置にする
+        let position = char_stream.get_position();
         UnitStream {
             filepath,
             char_stream,
-            status: Status::new(),
+            status: Status {
+                end_position: position.clone(),
+                indent_span: Span::new(position.clone(), position),
+                ..Status::new()
+            },
+            indent_style,
+            indent_errors: vec![],
+            indent_warnings: vec![],
         }
     }
 
+    /// インデントのエラー。ソースの順に並ぶ。
+    pub fn indent_errors(&self) -> &[IndentDiagnostic] {
+        &self.indent_errors
+    }
+
+    pub fn char_stream(&self) -> &CharStream {
+        &self.char_stream
+    }
+
+    /// 厳密なインデントの検査の警告。ソースの順に並ぶ。
+    pub fn indent_warnings(&self) -> &[IndentDiagnostic] {
+        &self.indent_warnings
+    }
+
     pub fn file_position(&mut self) -> FilePosition {
         FilePosition {
             filepath: self.filepath.clone(),
             position: self.position(),
+            included_from: None,
         }
     }
 
@@ -49,39 +228,109 @@ impl UnitStream {
         position
     }
 
+    /// 次に読み込むユニットの位置。
+    pub fn next_position(&mut self) -> Position {
+        match self.position() {
+            Some(position) => position,
+            None => self.char_stream.get_position(),
+        }
+    }
+
+    /// 最後に読み込んだ文字か改行の次の位置。
+    pub fn end_position(&self) -> Position {
+        self.status.end_position.clone()
+    }
+
+    /// 次に読み込むユニットがブロックの開始か終了なら、その深さを決めたインデントの範囲。
+    /// ファイルの終わりなら、ファイルの終わりの位置だけの範囲。
+    pub fn next_indent_span(&mut self) -> Option<Span> {
+        let mark = self.mark();
+        let (unit, position) = self.read();
+        let span = match unit {
+            Unit::BlockBeginning | Unit::BlockEnd => Some(self.status.indent_span.clone()),
+            Unit::Eof => position.map(|position| Span::new(position.clone(), position)),
+            _ => None,
+        };
+        self.reset(mark);
+        span
+    }
+
+    /// ユニットとその位置を読み込む。
+    /// ブロックの開始と終了の位置は、深さを決めたインデントの終わりの位置。
     pub fn read(&mut self) -> (Unit, Option<Position>) {
+        let result = self.read_unit();
+
+        // 文字か改行なら読み込んだ範囲の終わりを更新する
+        if let Unit::Char(_) | Unit::NewLine = result.0 {
+            self.status.end_position = self.char_stream.get_position();
+        }
+
+        result
+    }
+
+    fn read_unit(&mut self) -> (Unit, Option<Position>) {
         match self.status.reading_mode {
             ReadingMode::HeadOfLine => {
                 if self.status.indent_check_mode {
+                    let line_start = self.char_stream.get_position();
                     match self.scan_indent_depth() {
                         Some(indent_depth) => {
+                            self.status.indent_span =
+                                Span::new(line_start, self.char_stream.get_position());
+                            let base_depth = self.status.block_depth.max(1);
+                            if self.indent_style.strict && indent_depth > base_depth + 1 {
+                                let levels = indent_depth - base_depth;
+                                add_indent_diagnostic(
+                                    &mut self.indent_warnings,
+                                    self.char_stream.get_position(),
+                                    format!(
+                                        "The indentation is {} levels deeper than the enclosing block. It is read as {} nested blocks.",
+                                        levels, levels
+                                    ),
+                                );
+                            }
                             self.status.indent_depth = indent_depth;
                             self.status.reading_mode = ReadingMode::UpdatingBlockDepth;
-                            self.read()
+                            self.read_unit()
                         }
+                        // 空白しかない行のインデント深度は次の空白でない行で決まる。
+                        // ブロックの終了と最初のブロックの開始は空行より先に出力する
                         None => {
-                            self.status.reading_mode = ReadingMode::ReadingText;
-                            self.read()
+                            let (indent_depth, indent_span) = self.scan_next_indent_depth();
+                            self.status.indent_span = indent_span;
+                            if self.status.block_depth == 0 {
+                                self.status.indent_depth = indent_depth.min(1);
+                                self.status.reading_mode = ReadingMode::UpdatingBlockDepth;
+                            } else if indent_depth < self.status.block_depth {
+                                self.status.indent_depth = indent_depth;
+                                self.status.reading_mode = ReadingMode::UpdatingBlockDepth;
+                            } else {
+                                self.status.reading_mode = ReadingMode::ReadingText;
+                            }
+                            self.read_unit()
                         }
                     }
                 } else {
                     self.status.reading_mode = ReadingMode::ReadingText;
-                    self.read()
+                    self.read_unit()
                 }
             }
             ReadingMode::UpdatingBlockDepth => {
                 if self.status.indent_depth > self.status.block_depth {
                     self.status.block_depth += 1;
-                    (Unit::BlockBeginning, None)
+                    (
+                        Unit::BlockBeginning,
+                        Some(self.status.indent_span.end.clone()),
+                    )
                 } else if self.status.indent_depth < self.status.block_depth {
                     self.status.block_depth -= 1;
-                    (Unit::BlockEnd, None)
+                    (Unit::BlockEnd, Some(self.status.indent_span.end.clone()))
                 } else if self.status.block_depth == 0 {
                     self.status.reading_mode = ReadingMode::Eof;
-                    self.read()
+                    self.read_unit()
                 } else {
                     self.status.reading_mode = ReadingMode::ReadingText;
-                    self.read()
+                    self.read_unit()
                 }
             }
             ReadingMode::ReadingText => {
@@ -100,7 +349,7 @@ impl UnitStream {
                                     }
                                     (None, _) => {
                                         // 再実行でEOFが読み込まれるのでEOFモードになる
-                                        return self.read();
+                                        return self.read_unit();
                                     }
                                     (Some(_), _) => {
                                         self.char_stream.reset(mark);
@@ -118,11 +367,13 @@ impl UnitStream {
                 } else {
                     if self.status.indent_check_mode {
                         self.status.indent_depth = 0;
+                        let position = self.char_stream.get_position();
+                        self.status.indent_span = Span::new(position.clone(), position);
                         self.status.reading_mode = ReadingMode::UpdatingBlockDepth;
                     } else {
                         self.status.reading_mode = ReadingMode::Eof;
                     }
-                    self.read()
+                    self.read_unit()
                 }
             }
             ReadingMode::Eof => (Unit::Eof, Some(self.char_stream.get_position())),
@@ -148,6 +399,11 @@ impl UnitStream {
         self.status = mark.status;
     }
 
+    /// 読み込み位置より前のMarkには読み戻さないことにして、そこまでの文字を捨てられるようにする。
+    pub fn cut(&mut self) {
+        self.char_stream.cut();
+    }
+
     pub fn get_indent_check_mode(&self) -> bool {
         self.status.indent_check_mode
     }
@@ -156,35 +412,198 @@ impl UnitStream {
         self.status.indent_check_mode = indent_check_mode;
     }
 
-    fn scan_indent_depth(&mut self) -> Option<u64> {
-        let mut result = 1;
+    /// 行頭から、ブロックの内容になる行をインデントを除いてそのまま読み込む。
+    ///
+    /// 今のブロックより1段深いインデントまでを取り除き、それより深いインデント、タブ、
+    /// 行末の空白、空行を残す。インデントが浅い空白だけの行は、その行にある分だけを取り除く。インデントが浅い空白でない行の前で終わり、
+    /// 最後に続く空行は内容に含めない。内容がなければ何も読み込まずにNoneを返す。
+    pub fn read_verbatim_block(&mut self) -> Option<(String, Span)> {
+        if self.status.reading_mode != ReadingMode::HeadOfLine || !self.status.indent_check_mode {
+            return None;
+        }
 
-        'l: loop {
-            let indent_mark = self.char_stream.mark();
-            for _ in 0..INDENT_SIZE {
+        let prefix = self.status.block_depth.max(1) * self.indent_style.width.max(1);
+        let mut text = String::new();
+        let mut start = None;
+        let mut end = None;
+        // 内容の行が続くかどうかまだわからない空行
+        let mut blank_lines = String::new();
+        let mut blank_lines_mark = None;
+
+        let line_mark = loop {
+            let line_mark = self.char_stream.mark();
+            let line_position = self.char_stream.get_position();
+
+            let mut columns = 0;
+            while columns < prefix {
                 let mark = self.char_stream.mark();
-                let (c, _) = self.char_stream.read();
-                match c {
-                    Some(' ') => {}
-                    // 空白しかない行ならインデント深度なし
-                    Some('\n') => {
+                match self.char_stream.read() {
+                    (Some(c), _) => match self.indent_style.advance(columns, c) {
+                        Some(next_columns) if next_columns <= prefix => columns = next_columns,
+                        _ => {
+                            self.char_stream.reset(mark);
+                            break;
+                        }
+                    },
+                    (None, _) => {
                         self.char_stream.reset(mark);
-                        return None;
+                        break;
                     }
-                    None => {
-                        self.char_stream.reset(mark);
-                        return Some(0);
+                }
+            }
+
+            let mark = self.char_stream.mark();
+            let (c, position) = self.char_stream.read();
+            self.char_stream.reset(mark);
+
+            let blank = match c {
+                None => break line_mark,
+                Some('\n') => true,
+                Some(_) if columns < prefix => {
+                    if !self.is_blank_rest_of_line() {
+                        break line_mark;
                     }
-                    _ => {
-                        self.char_stream.reset(indent_mark);
-                        break 'l;
+                    true
+                }
+                Some(_) => false,
+            };
+
+            if blank {
+                if blank_lines_mark.is_none() {
+                    blank_lines_mark = Some(line_mark);
+                }
+                start.get_or_insert(line_position);
+                // 取り除いたインデントより後ろの空白は残す
+                while let (Some(c), _) = self.char_stream.read() {
+                    blank_lines.push(c);
+                    if c == '\n' {
+                        break;
                     }
                 }
+                continue;
+            }
+
+            start.get_or_insert(position);
+            text.push_str(&blank_lines);
+            blank_lines.clear();
+            blank_lines_mark = None;
+            while let (Some(c), _) = self.char_stream.read() {
+                text.push(c);
+                if c == '\n' {
+                    break;
+                }
+            }
+            end = Some(self.char_stream.get_position());
+        };
+
+        // 最後に続く空行と、インデントが浅い行はブロックの外なので読み戻す
+        self.char_stream
+            .reset(blank_lines_mark.unwrap_or(line_mark));
+
+        let (start, end) = (start?, end?);
+        self.status.end_position = end.clone();
+        Some((text, Span::new(start, end)))
+    }
+
+    /// 読み込み位置から行末まで空白しかないか。
+    fn is_blank_rest_of_line(&mut self) -> bool {
+        let mark = self.char_stream.mark();
+        let result = loop {
+            match self.char_stream.read() {
+                (Some(' ' | '\t'), _) => {}
+                (Some('\n') | None, _) => break true,
+                _ => break false,
+            }
+        };
+        self.char_stream.reset(mark);
+        result
+    }
+
+    fn scan_indent_depth(&mut self) -> Option<u64> {
+        let width = self.indent_style.width.max(1);
+        let mut columns = 0;
+        // インデントの深さの区切りまでの位置。区切りを超えた空白は文字として読み込む
+        let mut indent_mark = self.char_stream.mark();
+        let mut indent_columns = 0;
+
+        loop {
+            let mark = self.char_stream.mark();
+            let (c, position) = self.char_stream.read();
+            match c {
+                // 空白しかない行ならインデント深度なし
+                Some('\n') => {
+                    self.char_stream.reset(mark);
+                    return None;
+                }
+                None => {
+                    self.char_stream.reset(mark);
+                    return Some(0);
+                }
+                Some(c) => match self.indent_style.advance(columns, c) {
+                    Some(next_columns) => {
+                        columns = next_columns;
+                        if columns % width == 0 {
+                            indent_mark = self.char_stream.mark();
+                            indent_columns = columns;
+                        }
+                    }
+                    None => {
+                        if c == '\t' && self.indent_style.tab_policy == TabPolicy::Reject {
+                            add_indent_diagnostic(
+                                &mut self.indent_errors,
+                                position,
+                                "A tab cannot be used for indentation.".to_owned(),
+                            );
+                        }
+                        self.char_stream.reset(indent_mark);
+                        break;
+                    }
+                },
             }
-            result += 1;
         }
 
-        Some(result)
+        if self.indent_style.strict && columns % width != 0 {
+            add_indent_diagnostic(
+                &mut self.indent_warnings,
+                self.char_stream.get_position(),
+                format!(
+                    "The indentation of {} is not a multiple of {}. It is read as {} of indentation followed by {} of text.",
+                    count(columns, "column"),
+                    count(width, "column"),
+                    count(indent_columns / width, "level"),
+                    count(columns - indent_columns, "column"),
+                ),
+            );
+        }
+
+        Some(indent_columns / width + 1)
+    }
+
+    /// 次の空白でない行のインデント深度と、そのインデントの範囲。
+    /// ファイルの終わりまでなければ0とファイルの終わりの位置だけの範囲。
+    fn scan_next_indent_depth(&mut self) -> (u64, Span) {
+        let mark = self.char_stream.mark();
+
+        let result = 'l: loop {
+            // 行末まで読み飛ばす
+            loop {
+                match self.char_stream.read() {
+                    (Some('\n'), _) => break,
+                    (None, position) => break 'l (0, Span::new(position.clone(), position)),
+                    _ => {}
+                }
+            }
+            let line_start = self.char_stream.get_position();
+            if let Some(indent_depth) = self.scan_indent_depth() {
+                break (
+                    indent_depth,
+                    Span::new(line_start, self.char_stream.get_position()),
+                );
+            }
+        };
+
+        self.char_stream.reset(mark);
+        result
     }
 
     #[cfg(test)]
@@ -193,12 +612,49 @@ impl UnitStream {
     }
 }
 
+/// 数と単位。2以上なら単位を複数形にする。
+fn count(n: u64, unit: &str) -> String {
+    if n == 1 {
+        format!("{} {}", n, unit)
+    } else {
+        format!("{} {}s", n, unit)
+    }
+}
+
+/// 診断を位置の順に追加する。同じ位置の診断は追加した順に並ぶ。
+/// 先読みで同じ行を何度も読み込むことがあるので、同じ位置の同じ診断は追加しない。
+fn add_indent_diagnostic(
+    diagnostics: &mut Vec<IndentDiagnostic>,
+    position: Position,
+    message: String,
+) {
+    let key = |position: &Position| (position.line_number, position.column_number);
+    let start =
+        diagnostics.partition_point(|diagnostic| key(&diagnostic.position) < key(&position));
+    let end = diagnostics.partition_point(|diagnostic| key(&diagnostic.position) <= key(&position));
+    if !diagnostics[start..end]
+        .iter()
+        .any(|diagnostic| diagnostic.message == message)
+    {
+        diagnostics.insert(end, IndentDiagnostic { position, message });
+    }
+}
+
 #[derive(Clone)]
 pub struct Status {
     reading_mode: ReadingMode,
     block_depth: u64,
     indent_depth: u64,
     indent_check_mode: bool,
+    end_position: Position,
+    /// インデントの深さを決めたインデントの範囲。ブロックの開始と終了の範囲になる
+    indent_span: Span,
+}
+
+impl Default for Status {
+    fn default() -> Status {
+        Status::new()
+    }
 }
 
 impl Status {
@@ -208,6 +664,8 @@ impl Status {
             block_depth: 0,
             indent_depth: 0,
             indent_check_mode: true,
+            end_position: Position::new(1, 1),
+            indent_span: Span::new(Position::new(1, 1), Position::new(1, 1)),
         }
     }
 }
@@ -229,8 +687,12 @@ pub struct Mark {
 mod test {
     use crate::build::step1::CharStream;
     use crate::build::step1::Position;
+    use crate::build::step1::Span;
     use crate::build::step2::test_utils;
     use crate::build::step2::test_utils::unit_stream;
+    use crate::build::step2::IndentDiagnostic;
+    use crate::build::step2::IndentStyle;
+    use crate::build::step2::TabPolicy;
     use crate::build::step2::Unit;
     use crate::build::step2::UnitStream;
     use std::error::Error;
@@ -241,7 +703,7 @@ mod test {
     fn test_read_unit() -> Result<(), Box<dyn Error>> {
         let filepath = PathBuf::from("resources/test/source_unit_reader/source_1.oreno");
         let data = fs::read(&filepath).unwrap();
-        let mut us = UnitStream::new(filepath, CharStream::new(data)?);
+        let mut us = UnitStream::new(filepath, CharStream::new(data));
         let mut units = vec![];
         loop {
             let (unit, _) = us.read();
@@ -263,24 +725,374 @@ mod test {
     #[test]
     fn test_mark_reset() -> Result<(), Box<dyn Error>> {
         let mut us = unit_stream("abc\n    xyz\n123")?;
-        assert_eq!(&us.read().1, &None); // block beginning
-        assert_eq!(&us.read().1, &Some(Position::new(1, 1)));
-        assert_eq!(&us.read().1, &Some(Position::new(1, 2)));
-        assert_eq!(&us.read().1, &Some(Position::new(1, 3)));
-        assert_eq!(&us.read().1, &Some(Position::new(1, 4)));
-        assert_eq!(&us.read().1, &None); // block beginning
-        assert_eq!(&us.read().1, &Some(Position::new(2, 5)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(1, 1, 0))); // block beginning
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(1, 1, 0)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(1, 2, 1)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(1, 3, 2)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(1, 4, 3)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(2, 5, 8))); // block beginning
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(2, 5, 8)));
         let mark = us.mark();
-        assert_eq!(&us.read().1, &Some(Position::new(2, 6)));
-        assert_eq!(&us.read().1, &Some(Position::new(2, 7)));
-        assert_eq!(&us.read().1, &Some(Position::new(2, 8)));
-        assert_eq!(&us.read().1, &None); // block end
-        assert_eq!(&us.read().1, &Some(Position::new(3, 1)));
-        assert_eq!(&us.read().1, &Some(Position::new(3, 2)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(2, 6, 9)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(2, 7, 10)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(2, 8, 11)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(3, 1, 12))); // block end
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(3, 1, 12)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(3, 2, 13)));
         us.reset(mark);
-        assert_eq!(&us.read().1, &Some(Position::new(2, 6)));
-        assert_eq!(&us.read().1, &Some(Position::new(2, 7)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(2, 6, 9)));
+        assert_eq!(&us.read().1, &Some(Position::with_byte_offset(2, 7, 10)));
+
+        Ok(())
+    }
 
+    /// ブロックの終了は空行より先に読み込まれる
+    #[test]
+    fn test_blank_line_before_dedent() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("a\n    b\n        c\n\n  \nd\n")?;
+
+        // 空行の後の行を読み飛ばさず、浅くなった段だけブロックを終える
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nChar:b\nNewLine\nBegin\nChar:c\nNewLine\nEnd\nEnd\nNewLine\nNewLine\nChar:d\nNewLine\nEnd\nEof\n"
+        );
+
+        Ok(())
+    }
+
+    /// 最初のブロックの開始は先頭の空行より先に読み込まれる
+    #[test]
+    fn test_leading_blank_line() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("\n\na\n")?;
+
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nNewLine\nNewLine\nChar:a\nNewLine\nEnd\nEof\n"
+        );
+
+        // 空行しかなければブロックは始まらない
+        let mut us = unit_stream("\n  \n")?;
+        assert_eq!(read_tokens(&mut us), "Eof\n");
+
+        Ok(())
+    }
+
+    fn read_tokens(us: &mut UnitStream) -> String {
+        let mut units = vec![];
+        loop {
+            let (unit, _) = us.read();
+            let eof = unit == Unit::Eof;
+            units.push(unit);
+            if eof {
+                break;
+            }
+        }
+        test_utils::to_tokens(&units)
+    }
+
+    fn unit_stream_with_indent_style(data: &str, indent_style: IndentStyle) -> UnitStream {
+        UnitStream::with_indent_style(
+            PathBuf::from("a/b.c"),
+            CharStream::new(data.as_bytes().to_vec()),
+            indent_style,
+        )
+    }
+
+    /// インデントの幅を変えられる
+    #[test]
+    fn test_indent_width() {
+        let mut us = unit_stream_with_indent_style(
+            "a\n  b\n     c",
+            IndentStyle {
+                width: 2,
+                ..IndentStyle::new()
+            },
+        );
+
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nChar:b\nNewLine\nBegin\nChar: c\nEnd\nEnd\nEnd\nEof\n"
+        );
+    }
+
+    /// 幅が0なら1として読み込む
+    #[test]
+    fn test_indent_width_zero() {
+        let mut us = unit_stream_with_indent_style(
+            "a\n\tb\n c",
+            IndentStyle {
+                width: 0,
+                tab_policy: TabPolicy::Indent,
+                ..IndentStyle::new()
+            },
+        );
+
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nChar:b\nNewLine\nChar:c\nEnd\nEnd\nEof\n"
+        );
+    }
+
+    /// タブは方針に従って読み込む
+    #[test]
+    fn test_tab_policy() {
+        // タブ位置まで空白にする
+        let mut us = unit_stream_with_indent_style(
+            "a\n\tb\n  \tc",
+            IndentStyle {
+                width: 4,
+                tab_policy: TabPolicy::Expand(8),
+                ..IndentStyle::new()
+            },
+        );
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nBegin\nChar:b\nNewLine\nChar:c\nEnd\nEnd\nEnd\nEof\n"
+        );
+        assert!(us.indent_errors().is_empty());
+
+        // 次のインデントの深さまで進める
+        let mut us = unit_stream_with_indent_style(
+            "a\n\tb\n  \tc",
+            IndentStyle {
+                width: 4,
+                tab_policy: TabPolicy::Indent,
+                ..IndentStyle::new()
+            },
+        );
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nChar:b\nNewLine\nChar:c\nEnd\nEnd\nEof\n"
+        );
+
+        // 既定ではタブは文字として読み込む
+        let mut us = unit_stream("a\n    \tb\n\tc").unwrap();
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nChar:\tb\nNewLine\nEnd\nChar:\tc\nEnd\nEof\n"
+        );
+        assert!(us.indent_errors().is_empty());
+
+        // エラーにして、タブは文字として読み込む
+        let mut us = unit_stream_with_indent_style(
+            "a\n    \tb\n\tc",
+            IndentStyle {
+                tab_policy: TabPolicy::Reject,
+                ..IndentStyle::new()
+            },
+        );
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nChar:\tb\nNewLine\nEnd\nChar:\tc\nEnd\nEof\n"
+        );
+        assert_eq!(
+            us.indent_errors(),
+            &[
+                IndentDiagnostic {
+                    position: Position::with_byte_offset(2, 5, 6),
+                    message: "A tab cannot be used for indentation.".to_owned(),
+                },
+                IndentDiagnostic {
+                    position: Position::with_byte_offset(3, 1, 9),
+                    message: "A tab cannot be used for indentation.".to_owned(),
+                },
+            ]
+        );
+    }
+
+    /// 厳密な検査ではインデントのずれと飛躍を警告する
+    #[test]
+    fn test_strict_indent() {
+        let source = "a\n      b\n    c\n            d\n";
+        let strict = IndentStyle {
+            strict: true,
+            ..IndentStyle::new()
+        };
+
+        let mut us = unit_stream_with_indent_style(source, strict);
+        assert_eq!(
+            read_tokens(&mut us),
+            "Begin\nChar:a\nNewLine\nBegin\nChar:  b\nNewLine\nChar:c\nNewLine\nBegin\nBegin\nChar:d\nNewLine\nEnd\nEnd\nEnd\nEnd\nEof\n"
+        );
+        assert_eq!(
+            us.indent_warnings(),
+            &[
+                IndentDiagnostic {
+                    position: Position::with_byte_offset(2, 5, 6),
+                    message: "The indentation of 6 columns is not a multiple of 4 columns. It is read as 1 level of indentation followed by 2 columns of text.".to_owned(),
+                },
+                IndentDiagnostic {
+                    position: Position::with_byte_offset(4, 13, 28),
+                    message: "The indentation is 2 levels deeper than the enclosing block. It is read as 2 nested blocks.".to_owned(),
+                },
+            ]
+        );
+
+        // 厳密でなければ警告しない
+        let mut us = unit_stream_with_indent_style(source, IndentStyle::new());
+        read_tokens(&mut us);
+        assert!(us.indent_warnings().is_empty());
+    }
+
+    /// ずれていて2段以上深くなるインデントは両方を警告する
+    #[test]
+    fn test_strict_indent_misaligned_jump() {
+        let strict = IndentStyle {
+            strict: true,
+            ..IndentStyle::new()
+        };
+
+        let mut us = unit_stream_with_indent_style(":a\n          b\n", strict);
+        read_tokens(&mut us);
+        assert_eq!(
+            us.indent_warnings(),
+            &[
+                IndentDiagnostic {
+                    position: Position::with_byte_offset(2, 9, 11),
+                    message: "The indentation of 10 columns is not a multiple of 4 columns. It is read as 2 levels of indentation followed by 2 columns of text.".to_owned(),
+                },
+                IndentDiagnostic {
+                    position: Position::with_byte_offset(2, 9, 11),
+                    message: "The indentation is 2 levels deeper than the enclosing block. It is read as 2 nested blocks.".to_owned(),
+                },
+            ]
+        );
+    }
+
+    /// ブロックの開始と終了は深さを決めたインデントの終わりの位置になる
+    #[test]
+    fn test_block_position() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("\n    a\n\n        b\n\nc\n    d")?;
+        let mut positions = vec![];
+        loop {
+            let (unit, position) = us.read();
+            if let Unit::BlockBeginning | Unit::BlockEnd | Unit::Eof = unit {
+                positions.push((unit.clone(), position));
+            }
+            if unit == Unit::Eof {
+                break;
+            }
+        }
+
+        assert_eq!(
+            positions,
+            vec![
+                // 空行より先のブロックの開始は次の空白でない行の位置
+                (
+                    Unit::BlockBeginning,
+                    Some(Position::with_byte_offset(2, 5, 5))
+                ),
+                (
+                    Unit::BlockBeginning,
+                    Some(Position::with_byte_offset(2, 5, 5))
+                ),
+                (
+                    Unit::BlockBeginning,
+                    Some(Position::with_byte_offset(4, 9, 16))
+                ),
+                (Unit::BlockEnd, Some(Position::with_byte_offset(6, 1, 19))),
+                (Unit::BlockEnd, Some(Position::with_byte_offset(6, 1, 19))),
+                (
+                    Unit::BlockBeginning,
+                    Some(Position::with_byte_offset(7, 5, 25))
+                ),
+                // ファイルの終わりでのブロックの終了
+                (Unit::BlockEnd, Some(Position::with_byte_offset(7, 6, 26))),
+                (Unit::BlockEnd, Some(Position::with_byte_offset(7, 6, 26))),
+                (Unit::Eof, Some(Position::with_byte_offset(7, 6, 26))),
+            ]
+        );
+
+        Ok(())
+    }
+
+    /// ブロックの開始と終了は深さを決めたインデントの範囲を持つ
+    #[test]
+    fn test_next_indent_span() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("a\n        b\n\nc")?;
+        let mut spans = vec![];
+        loop {
+            let span = us.next_indent_span();
+            let (unit, _) = us.read();
+            if let Unit::BlockBeginning | Unit::BlockEnd | Unit::Eof = unit {
+                spans.push((unit.clone(), span));
+            } else {
+                assert_eq!(span, None);
+            }
+            if unit == Unit::Eof {
+                break;
+            }
+        }
+
+        let span = |l1, c1, o1, l2, c2, o2| {
+            Some(Span::new(
+                Position::with_byte_offset(l1, c1, o1),
+                Position::with_byte_offset(l2, c2, o2),
+            ))
+        };
+        assert_eq!(
+            spans,
+            vec![
+                (Unit::BlockBeginning, span(1, 1, 0, 1, 1, 0)),
+                (Unit::BlockBeginning, span(2, 1, 2, 2, 9, 10)),
+                (Unit::BlockBeginning, span(2, 1, 2, 2, 9, 10)),
+                // 空行より先のブロックの終了は次の空白でない行のインデント
+                (Unit::BlockEnd, span(4, 1, 13, 4, 1, 13)),
+                (Unit::BlockEnd, span(4, 1, 13, 4, 1, 13)),
+                // ファイルの終わりでのブロックの終了
+                (Unit::BlockEnd, span(4, 2, 14, 4, 2, 14)),
+                (Unit::Eof, span(4, 2, 14, 4, 2, 14)),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_next_position() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(
+            "abc  
+    xyz",
+        )?;
+        // ブロック開始は深さを決めたインデントの終わりの位置で、インデントがなければ行頭
+        assert_eq!(us.next_position(), Position::with_byte_offset(1, 1, 0));
+        us.read();
+        assert_eq!(us.next_position(), Position::with_byte_offset(1, 1, 0));
+        us.read();
+        assert_eq!(us.next_position(), Position::with_byte_offset(1, 2, 1));
+        us.read();
+        us.read();
+        us.read();
+        // インデントのある行のブロック開始は最初の文字と同じ位置
+        assert_eq!(us.next_position(), Position::with_byte_offset(2, 5, 10));
+        assert_eq!(us.read().0, Unit::BlockBeginning);
+        assert_eq!(us.next_position(), Position::with_byte_offset(2, 5, 10));
+        Ok(())
+    }
+
+    #[test]
+    fn test_end_position() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(
+            "abc  
+    xyz",
+        )?;
+        us.read();
+        assert_eq!(us.end_position(), Position::with_byte_offset(1, 1, 0));
+        us.read();
+        assert_eq!(us.end_position(), Position::with_byte_offset(1, 2, 1));
+        let mark = us.mark();
+        us.read();
+        us.read();
+        // 行末の空白は改行に含まれる
+        assert_eq!(us.read().0, Unit::NewLine);
+        assert_eq!(us.end_position(), Position::with_byte_offset(2, 1, 6));
+        // ブロック開始では変わらない
+        assert_eq!(us.read().0, Unit::BlockBeginning);
+        assert_eq!(us.end_position(), Position::with_byte_offset(2, 1, 6));
+        assert_eq!(us.read().0, Unit::Char('x'));
+        assert_eq!(us.end_position(), Position::with_byte_offset(2, 6, 11));
+        us.reset(mark);
+        assert_eq!(us.end_position(), Position::with_byte_offset(1, 2, 1));
         Ok(())
     }
 }
@@ -295,7 +1107,7 @@ pub mod test_utils {
     use crate::build::step1::CharStream;
 
     pub fn unit_stream(data: &str) -> Result<UnitStream, Box<dyn Error>> {
-        let char_stream = CharStream::new(data.as_bytes().to_vec())?;
+        let char_stream = CharStream::new(data.as_bytes().to_vec());
         let unit_stream = UnitStream::new(PathBuf::from("a/b.c"), char_stream);
         Ok(unit_stream)
     }
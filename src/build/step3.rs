@@ -1,42 +1,252 @@
-mod attribute;
-mod block;
-mod block_tag;
-mod block_tag_header;
-mod inline_tag;
-mod paragraph;
-mod symbol;
-mod tag;
+pub mod attribute;
+pub mod block;
+pub mod block_tag;
+pub mod block_tag_header;
+pub mod comment;
+pub mod inline_tag;
+pub mod paragraph;
+pub mod symbol;
+pub mod tag;
+pub mod visit;
 
+use std::error::Error;
+use std::fmt;
+
+use crate::build::step1::Encoding;
+use crate::build::step1::Span;
 use crate::build::step2::FilePosition;
+use crate::build::step2::IndentStyle;
+use crate::build::step2::TabPolicy;
+use crate::build::step2::Unit;
 use crate::build::step2::UnitStream;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::comment::Comment;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::paragraph::Paragraph;
+use crate::build::step3::symbol::NameSyntax;
+use crate::build::step3::tag::Abbreviations;
+use crate::schema::Schema;
+use crate::schema::STANDARD_SCHEMA;
 
 pub trait ContentModel {
     #[cfg(test)]
     fn to_json(&self) -> String;
 }
 
-pub trait BlockContent: ContentModel {}
-pub type BlockContents = Vec<Box<dyn BlockContent>>;
+/// ブロックの要素。
+#[derive(Clone, Debug, PartialEq)]
+pub enum BlockNode {
+    Paragraph(Paragraph),
+    BlockTag(Box<BlockTag>),
+    Block(Block),
+    BlankLine(Span),
+    /// パースエラーから回復した時に読み飛ばした範囲
+    Error(Span),
+}
+
+impl BlockNode {
+    pub fn span(&self) -> &Span {
+        match self {
+            BlockNode::Paragraph(paragraph) => paragraph.span(),
+            BlockNode::BlockTag(block_tag) => block_tag.span(),
+            BlockNode::Block(block) => block.span(),
+            BlockNode::BlankLine(span) => span,
+            BlockNode::Error(span) => span,
+        }
+    }
+
+    pub fn as_paragraph(&self) -> Option<&Paragraph> {
+        match self {
+            BlockNode::Paragraph(paragraph) => Some(paragraph),
+            _ => None,
+        }
+    }
+
+    pub fn as_block_tag(&self) -> Option<&BlockTag> {
+        match self {
+            BlockNode::BlockTag(block_tag) => Some(block_tag),
+            _ => None,
+        }
+    }
+
+    pub fn as_block(&self) -> Option<&Block> {
+        match self {
+            BlockNode::Block(block) => Some(block),
+            _ => None,
+        }
+    }
+
+    pub fn is_blank_line(&self) -> bool {
+        matches!(self, BlockNode::BlankLine(_))
+    }
+}
+
+impl ContentModel for BlockNode {
+    #[cfg(test)]
+    fn to_json(&self) -> String {
+        match self {
+            BlockNode::Paragraph(paragraph) => paragraph.to_json(),
+            BlockNode::BlockTag(block_tag) => block_tag.to_json(),
+            BlockNode::Block(block) => block.to_json(),
+            BlockNode::BlankLine(_) => "\"<bl>\"".to_owned(),
+            BlockNode::Error(_) => "\"<err>\"".to_owned(),
+        }
+    }
+}
 
-pub trait InlineContent: ContentModel + core::fmt::Debug {}
-pub type InlineContents = Vec<Box<dyn InlineContent>>;
+pub type BlockContents = Vec<BlockNode>;
+
+/// 段落、ブロックタグヘッダー、インラインタグの内容の要素。
+#[derive(Clone, Debug, PartialEq)]
+pub enum InlineNode {
+    Text(Text),
+    InlineTag(InlineTag),
+}
+
+impl InlineNode {
+    pub fn span(&self) -> &Span {
+        match self {
+            InlineNode::Text(text) => text.span(),
+            InlineNode::InlineTag(inline_tag) => inline_tag.span(),
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&Text> {
+        match self {
+            InlineNode::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_inline_tag(&self) -> Option<&InlineTag> {
+        match self {
+            InlineNode::InlineTag(inline_tag) => Some(inline_tag),
+            _ => None,
+        }
+    }
+}
+
+impl ContentModel for InlineNode {
+    #[cfg(test)]
+    fn to_json(&self) -> String {
+        match self {
+            InlineNode::Text(text) => text.to_json(),
+            InlineNode::InlineTag(inline_tag) => inline_tag.to_json(),
+        }
+    }
+}
+
+pub type InlineContents = Vec<InlineNode>;
+
+/// テキスト
+#[derive(Clone, Debug, PartialEq)]
+pub struct Text {
+    value: String,
+    span: Span,
+}
+
+impl Text {
+    pub fn new(value: String, span: Span) -> Text {
+        Text { value, span }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn value_mut(&mut self) -> &mut String {
+        &mut self.value
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+}
+
+impl ContentModel for Text {
+    #[cfg(test)]
+    fn to_json(&self) -> String {
+        self.value.to_json()
+    }
+}
 
 impl ContentModel for String {
     #[cfg(test)]
     fn to_json(&self) -> String {
-        format!("\"{}\"", self.replace("\n", "\\n").replace("\"", "\\\""))
+        serde_json::Value::String(self.clone()).to_string()
     }
 }
 
-impl InlineContent for String {}
+/// 読み込んだ文字を溜めてテキストにする。
+struct TextBuffer {
+    value: String,
+    span: Option<Span>,
+}
+
+impl TextBuffer {
+    fn new() -> TextBuffer {
+        TextBuffer {
+            value: String::new(),
+            span: None,
+        }
+    }
+
+    /// 次のユニットを読み込んで、その文字を追加する。
+    fn read(&mut self, c: char, unit_stream: &mut UnitStream) {
+        let start = unit_stream.next_position();
+        unit_stream.read();
+        let end = unit_stream.end_position();
+
+        self.value.push(c);
+        match &mut self.span {
+            Some(span) => span.end = end,
+            None => self.span = Some(Span::new(start, end)),
+        }
+    }
+
+    /// バックスラッシュを読み込んで、次の文字をそのままの文字として追加する。
+    /// 次が文字でなければバックスラッシュを追加する。
+    fn read_escape(&mut self, unit_stream: &mut UnitStream) {
+        let start = unit_stream.next_position();
+        unit_stream.read();
+
+        match unit_stream.peek() {
+            Unit::Char(c) => {
+                unit_stream.read();
+                self.value.push(c);
+            }
+            _ => self.value.push('\\'),
+        }
+
+        let end = unit_stream.end_position();
+        match &mut self.span {
+            Some(span) => span.end = end,
+            None => self.span = Some(Span::new(start, end)),
+        }
+    }
+
+    /// 溜めた文字があればテキストにして取り出す。
+    fn take(&mut self) -> Option<Text> {
+        let span = self.span.take()?;
+        let value = std::mem::take(&mut self.value);
+        Some(Text::new(value, span))
+    }
+}
 
 pub type ParseResult<S> = Result<Option<S>, ParseError>;
 
 #[derive(Debug, PartialEq)]
 pub struct ParseError {
     pub file_position: FilePosition,
-    pub parser_name: Option<String>,
-    pub message: String,
+    pub parser_name: Option<Box<str>>,
+    pub message: Box<str>,
+    /// エラーの原因になった範囲。ブロックの開始や終了についてのエラーならインデントの範囲
+    pub span: Option<Box<Span>>,
 }
 
 impl ParseError {
@@ -47,33 +257,244 @@ impl ParseError {
     ) -> ParseError {
         ParseError {
             file_position,
-            parser_name,
-            message,
+            parser_name: parser_name.map(String::into_boxed_str),
+            message: message.into_boxed_str(),
+            span: None,
+        }
+    }
+
+    /// 次に読み込むブロックの開始か終了についてのエラー。
+    /// 位置はその深さを決めたインデントの始まりにして、インデントの範囲を持たせる。
+    pub fn block_structure(
+        unit_stream: &mut UnitStream,
+        parser_name: Option<String>,
+        message: String,
+    ) -> ParseError {
+        let mut error = ParseError::new(unit_stream.file_position(), parser_name, message);
+        if let Some(span) = unit_stream.next_indent_span() {
+            error.file_position.position = Some(span.start.clone());
+            error.span = Some(Box::new(span));
         }
+        error
     }
 
     pub fn parser_name(&self) -> Option<String> {
-        self.parser_name.clone()
+        self.parser_name.as_deref().map(str::to_owned)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.file_position, self.message)?;
+        for (i, file_position) in self.file_position.include_chain().enumerate() {
+            let separator = if i == 0 { " (" } else { ", " };
+            write!(f, "{}included from {}", separator, file_position)?;
+        }
+        if self.file_position.included_from.is_some() {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+/// パースのオプション
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParseOptions {
+    /// 繰り返しを許す属性。タグ名と属性名の組。
+    repeated_attributes: Vec<(String, String)>,
+    /// BOMがない時のエンコーディング
+    encoding: Option<Encoding>,
+    indent_style: IndentStyle,
+    name_syntax: NameSyntax,
+    abbreviations: Abbreviations,
+    /// タグの定義。Noneなら標準の定義
+    schema: Option<Schema>,
+    /// includeタグのファイルを読み込んで置き換えるか
+    resolve_includes: bool,
+    /// パースエラーから回復してパースを続けるか
+    recover_errors: bool,
+}
+
+/// オプションが指定されなかった時のオプション
+static DEFAULT_PARSE_OPTIONS: ParseOptions = ParseOptions::new();
+
+impl ParseOptions {
+    pub const fn new() -> ParseOptions {
+        ParseOptions {
+            repeated_attributes: Vec::new(),
+            encoding: None,
+            indent_style: IndentStyle::new(),
+            name_syntax: NameSyntax::Unicode,
+            abbreviations: Abbreviations::new(),
+            schema: None,
+            resolve_includes: false,
+            recover_errors: false,
+        }
+    }
+
+    /// BOMがない時のエンコーディングを指定する。
+    /// 指定しなければUTF-8とする。
+    pub fn encoding(mut self, encoding: Encoding) -> ParseOptions {
+        self.encoding = Some(encoding);
+        self
+    }
+
+    pub fn get_encoding(&self) -> Option<Encoding> {
+        self.encoding
+    }
+
+    /// 1段のインデントの桁数を指定する。0なら1として扱う。
+    pub fn indent_width(mut self, width: u64) -> ParseOptions {
+        self.indent_style.width = width;
+        self
+    }
+
+    /// インデントに書かれたタブの扱いを指定する。
+    pub fn tab_policy(mut self, tab_policy: TabPolicy) -> ParseOptions {
+        self.indent_style.tab_policy = tab_policy;
+        self
+    }
+
+    /// 幅の倍数でないインデントと、2段以上深くなるインデントを警告する。
+    pub fn strict_indent(mut self, strict: bool) -> ParseOptions {
+        self.indent_style.strict = strict;
+        self
+    }
+
+    pub fn get_indent_style(&self) -> IndentStyle {
+        self.indent_style
+    }
+
+    /// タグ名と属性名に使える文字を指定する。
+    /// 指定しなければUnicodeの識別子の文字とハイフンとする。
+    pub fn name_syntax(mut self, name_syntax: NameSyntax) -> ParseOptions {
+        self.name_syntax = name_syntax;
+        self
+    }
+
+    pub fn get_name_syntax(&self) -> NameSyntax {
+        self.name_syntax
+    }
+
+    /// 省略記法の記号とタグ名の表を指定する。
+    /// 指定しなければ標準の表とする。
+    pub fn abbreviations(mut self, abbreviations: Abbreviations) -> ParseOptions {
+        self.abbreviations = abbreviations;
+        self
+    }
+
+    pub fn get_abbreviations(&self) -> &Abbreviations {
+        &self.abbreviations
+    }
+
+    /// タグの定義を指定する。内容をパースしないタグは定義に従う。
+    /// 指定しなければ標準の定義とする。
+    pub fn schema(mut self, schema: Schema) -> ParseOptions {
+        self.schema = Some(schema);
+        self
+    }
+
+    pub fn get_schema(&self) -> &Schema {
+        self.schema.as_ref().unwrap_or(&STANDARD_SCHEMA)
+    }
+
+    /// includeタグで指定したファイルをパースして、タグの位置に内容を入れる。
+    /// ファイルのパスはインクルードするファイルからの相対パスにする。
+    pub fn resolve_includes(mut self, resolve_includes: bool) -> ParseOptions {
+        self.resolve_includes = resolve_includes;
+        self
+    }
+
+    pub fn get_resolve_includes(&self) -> bool {
+        self.resolve_includes
+    }
+
+    /// パースエラーがあってもパースを続ける。
+    /// エラーを記録して読み飛ばした範囲をエラーの要素にし、次の行かブロックの境界から読み直す。
+    /// 指定しなければ最初のエラーでパースを中止する。
+    pub fn recover_errors(mut self, recover_errors: bool) -> ParseOptions {
+        self.recover_errors = recover_errors;
+        self
+    }
+
+    pub fn get_recover_errors(&self) -> bool {
+        self.recover_errors
+    }
+
+    /// タグに同じ名前の属性を繰り返し書けるようにする。
+    /// 許さなければ最初の属性だけを残して警告する。
+    pub fn allow_repeated_attribute<T: Into<String>, A: Into<String>>(
+        mut self,
+        tag_name: T,
+        attribute_name: A,
+    ) -> ParseOptions {
+        self.repeated_attributes
+            .push((tag_name.into(), attribute_name.into()));
+        self
+    }
+
+    pub fn allows_repeated_attribute(&self, tag_name: &str, attribute_name: &str) -> bool {
+        self.repeated_attributes
+            .iter()
+            .any(|(tag, attribute)| tag == tag_name && attribute == attribute_name)
     }
 }
 
 pub struct ParseContext<'a> {
     pub warnings: &'a mut Vec<ParseError>,
+    options: &'a ParseOptions,
+    /// コメントを残す先。Noneならコメントは捨てる
+    comments: Option<&'a mut Vec<Comment>>,
+    /// 回復したエラーを残す先。Noneならエラーでパースを中止する
+    errors: Option<&'a mut Vec<ParseError>>,
     save_warnings: bool,
     parser_name: Option<String>,
     parse_tags: bool,
+    /// 属性をパースしているタグの名前
+    tag_name: Option<String>,
 }
 
 impl<'a> ParseContext<'a> {
     pub fn new(warnings: &'a mut Vec<ParseError>) -> ParseContext<'a> {
+        ParseContext::with_options(warnings, &DEFAULT_PARSE_OPTIONS)
+    }
+
+    pub fn with_options(
+        warnings: &'a mut Vec<ParseError>,
+        options: &'a ParseOptions,
+    ) -> ParseContext<'a> {
         ParseContext {
             warnings,
+            options,
+            comments: None,
+            errors: None,
             save_warnings: true,
             parser_name: None,
             parse_tags: true,
+            tag_name: None,
         }
     }
 
+    /// パースしたコメントを残すコンテキスト
+    pub fn with_comments(
+        warnings: &'a mut Vec<ParseError>,
+        options: &'a ParseOptions,
+        comments: &'a mut Vec<Comment>,
+    ) -> ParseContext<'a> {
+        ParseContext {
+            comments: Some(comments),
+            ..ParseContext::with_options(warnings, options)
+        }
+    }
+
+    /// パースエラーから回復するコンテキストにする。
+    pub fn recover_errors(mut self, errors: &'a mut Vec<ParseError>) -> ParseContext<'a> {
+        self.errors = Some(errors);
+        self
+    }
+
     pub fn parser_name(&self) -> Option<String> {
         self.parser_name.clone()
     }
@@ -82,6 +503,20 @@ impl<'a> ParseContext<'a> {
         self.parse_tags
     }
 
+    pub fn options(&self) -> &ParseOptions {
+        self.options
+    }
+
+    /// パースしているタグで属性の繰り返しが許されているか。
+    pub fn allows_repeated_attribute(&self, attribute_name: &str) -> bool {
+        match &self.tag_name {
+            Some(tag_name) => self
+                .options
+                .allows_repeated_attribute(tag_name, attribute_name),
+            None => false,
+        }
+    }
+
     pub fn warn(&mut self, file_position: FilePosition, message: String) {
         if self.save_warnings {
             self.warnings
@@ -89,30 +524,94 @@ impl<'a> ParseContext<'a> {
         }
     }
 
-    pub fn change_warn_mode(&mut self, save_warnings: bool) -> ParseContext {
+    pub fn add_comment(&mut self, comment: Comment) {
+        if let Some(comments) = &mut self.comments {
+            comments.push(comment);
+        }
+    }
+
+    /// エラーから回復するならエラーを記録する。回復しないならエラーを返す。
+    pub fn recover(&mut self, error: ParseError) -> Result<(), ParseError> {
+        match &mut self.errors {
+            Some(errors) => {
+                errors.push(error);
+                Ok(())
+            }
+            None => Err(error),
+        }
+    }
+
+    fn error_count(&self) -> usize {
+        self.errors.as_ref().map_or(0, |errors| errors.len())
+    }
+
+    fn comment_count(&self) -> usize {
+        self.comments.as_ref().map_or(0, |comments| comments.len())
+    }
+
+    /// 読み込み位置を戻した時に、戻した範囲のコメントを取り除く。
+    fn truncate_comments(&mut self, count: usize) {
+        if let Some(comments) = &mut self.comments {
+            comments.truncate(count);
+        }
+    }
+
+    /// 読み込み位置を戻した時に、戻した範囲で回復したエラーを取り除く。
+    fn truncate_errors(&mut self, count: usize) {
+        if let Some(errors) = &mut self.errors {
+            errors.truncate(count);
+        }
+    }
+
+    pub fn change_warn_mode(&mut self, save_warnings: bool) -> ParseContext<'_> {
         ParseContext {
             warnings: self.warnings,
+            options: self.options,
+            comments: self.comments.as_deref_mut(),
+            errors: self.errors.as_deref_mut(),
             save_warnings,
             parser_name: self.parser_name.clone(),
             parse_tags: self.parse_tags,
+            tag_name: self.tag_name.clone(),
         }
     }
 
-    pub fn change_parser_name(&mut self, parser_name: Option<String>) -> ParseContext {
+    pub fn change_parser_name(&mut self, parser_name: Option<String>) -> ParseContext<'_> {
         ParseContext {
             warnings: self.warnings,
+            options: self.options,
+            comments: self.comments.as_deref_mut(),
+            errors: self.errors.as_deref_mut(),
             save_warnings: self.save_warnings,
-            parser_name: parser_name,
+            parser_name,
             parse_tags: self.parse_tags,
+            tag_name: self.tag_name.clone(),
         }
     }
 
-    pub fn change_parse_mode(&mut self, parse_tags: bool) -> ParseContext {
+    pub fn change_parse_mode(&mut self, parse_tags: bool) -> ParseContext<'_> {
         ParseContext {
             warnings: self.warnings,
+            options: self.options,
+            comments: self.comments.as_deref_mut(),
+            errors: self.errors.as_deref_mut(),
             save_warnings: self.save_warnings,
             parser_name: self.parser_name.clone(),
             parse_tags,
+            tag_name: self.tag_name.clone(),
+        }
+    }
+
+    pub fn change_tag_name(&mut self, tag_name: Option<String>) -> ParseContext<'_> {
+        ParseContext {
+            warnings: self.warnings,
+            options: self.options,
+            comments: self.comments.as_deref_mut(),
+            errors: self.errors.as_deref_mut(),
+            save_warnings: self.save_warnings,
+            parser_name: self.parser_name.clone(),
+            parse_tags: self.parse_tags,
+            tag_name,
         }
     }
 }
@@ -128,6 +627,8 @@ fn call_parser<S>(
 ) -> ParseResult<S> {
     let mark = unit_stream.mark();
     let indent_check_mode = unit_stream.get_indent_check_mode();
+    let comment_count = context.comment_count();
+    let error_count = context.error_count();
 
     let result = parse_func(unit_stream, context);
 
@@ -138,11 +639,146 @@ fn call_parser<S>(
     // ビルドエラーだと実行されないがビルドを中止するので必要ない
     if let Ok(None) = result {
         unit_stream.reset(mark);
+        context.truncate_comments(comment_count);
+        context.truncate_errors(error_count);
     }
 
     result
 }
 
+#[cfg(test)]
+mod test_block_node {
+    use super::block::parse_block;
+    use super::BlockNode;
+    use super::InlineNode;
+    use super::ParseContext;
+    use super::Text;
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
+    use crate::build::step2::test_utils::unit_stream;
+    use indoc::indoc;
+    use std::error::Error;
+
+    /// パースしたツリーをパターンマッチで参照できる
+    #[test]
+    fn test_match() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(indoc! {"
+            abc:b{xyz}
+
+            :tag[a=1] header
+                contents
+        "})?;
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let block = parse_block(&mut us, &mut context).unwrap().unwrap();
+
+        let contents = block.contents();
+        assert_eq!(contents.len(), 3);
+
+        let paragraph = contents[0].as_paragraph().unwrap();
+        assert_eq!(
+            paragraph.contents()[0].as_text().map(|t| t.value()),
+            Some("abc")
+        );
+        match &paragraph.contents()[1] {
+            InlineNode::InlineTag(inline_tag) => {
+                assert_eq!(inline_tag.name().name(), "b");
+                assert_eq!(
+                    inline_tag.contents()[0].as_text().map(|t| t.value()),
+                    Some("xyz")
+                );
+            }
+            _ => panic!(),
+        }
+
+        assert!(contents[1].is_blank_line());
+        assert!(contents[1].as_paragraph().is_none());
+
+        match &contents[2] {
+            BlockNode::BlockTag(block_tag) => {
+                assert_eq!(block_tag.name().name(), "tag");
+                assert_eq!(block_tag.attributes().get("a").unwrap().value(), "1");
+                let header = block_tag.header().unwrap();
+                assert_eq!(
+                    header.contents()[0].as_text().map(|t| t.value()),
+                    Some("header")
+                );
+                let block = block_tag.contents().unwrap();
+                assert!(block.contents()[0].as_paragraph().is_some());
+            }
+            _ => panic!(),
+        }
+
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
+    /// 全ての要素と属性がソース上の範囲を持つ
+    #[test]
+    fn test_span() -> Result<(), Box<dyn Error>> {
+        let source = indoc! {"
+            abc:b{xyz}
+
+            :tag[a=1] header
+                contents
+        "};
+        let mut us = unit_stream(source)?;
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let block = parse_block(&mut us, &mut context).unwrap().unwrap();
+
+        // ASCIIだけのソースなので、バイトオフセットは行と桁から決まる
+        let position = |line: u64, column: u64| {
+            let line_start: usize = source
+                .split_inclusive('\n')
+                .take(line as usize - 1)
+                .map(str::len)
+                .sum();
+            Position::with_byte_offset(line, column, line_start + column as usize - 1)
+        };
+        let span = |l1, c1, l2, c2| Span::new(position(l1, c1), position(l2, c2));
+
+        assert_eq!(block.span(), &span(1, 1, 5, 1));
+
+        let contents = block.contents();
+        let paragraph = contents[0].as_paragraph().unwrap();
+        assert_eq!(paragraph.span(), &span(1, 1, 2, 1));
+        assert_eq!(paragraph.contents()[0].span(), &span(1, 1, 1, 4));
+        let inline_tag = paragraph.contents()[1].as_inline_tag().unwrap();
+        assert_eq!(inline_tag.span(), &span(1, 4, 1, 11));
+        assert_eq!(inline_tag.name().span(), &span(1, 4, 1, 6));
+        assert_eq!(inline_tag.contents()[0].span(), &span(1, 7, 1, 10));
+        assert_eq!(paragraph.contents()[2].span(), &span(1, 11, 2, 1));
+
+        assert_eq!(contents[1].span(), &span(2, 1, 3, 1));
+
+        let block_tag = contents[2].as_block_tag().unwrap();
+        assert_eq!(block_tag.span(), &span(3, 1, 5, 1));
+        let attribute = block_tag.attributes().get("a").unwrap();
+        assert_eq!(attribute.name_span(), &span(3, 6, 3, 7));
+        assert_eq!(attribute.value().span(), &span(3, 8, 3, 9));
+        assert_eq!(attribute.span(), span(3, 6, 3, 9));
+        assert_eq!(block_tag.header().unwrap().span(), &span(3, 11, 3, 17));
+        let block = block_tag.contents().unwrap();
+        assert_eq!(block.span(), &span(4, 5, 5, 1));
+
+        Ok(())
+    }
+
+    /// 種類が違えばNone
+    #[test]
+    fn test_mismatched_accessors() {
+        let span = Span::new(Position::new(1, 1), Position::new(1, 4));
+        let node = InlineNode::Text(Text::new("abc".to_owned(), span.clone()));
+        assert!(node.as_inline_tag().is_none());
+
+        let node = BlockNode::BlankLine(span);
+        assert!(node.as_block().is_none());
+        assert!(node.as_block_tag().is_none());
+    }
+}
+
 #[cfg(test)]
 mod test_parse_error {
     use std::path::PathBuf;
@@ -158,6 +794,7 @@ mod test_parse_error {
             FilePosition {
                 filepath: PathBuf::from("a/b.c"),
                 position: Some(Position::new(10, 21)),
+                included_from: None,
             },
             Some("some".to_owned()),
             "!error!".to_owned(),
@@ -168,8 +805,8 @@ mod test_parse_error {
             &subject.file_position.position,
             &Some(Position::new(10, 21))
         );
-        assert_eq!(&subject.parser_name, &Some("some".to_owned()));
-        assert_eq!(&subject.message, "!error!");
+        assert_eq!(subject.parser_name(), Some("some".to_owned()));
+        assert_eq!(&*subject.message, "!error!");
     }
 
     #[test]
@@ -178,6 +815,7 @@ mod test_parse_error {
             FilePosition {
                 filepath: PathBuf::from("a/b.c"),
                 position: None,
+                included_from: None,
             },
             Some("some".to_owned()),
             "!error!".to_owned(),
@@ -185,8 +823,23 @@ mod test_parse_error {
 
         assert_eq!(&subject.file_position.filepath, &PathBuf::from("a/b.c"));
         assert_eq!(&subject.file_position.position, &None);
-        assert_eq!(&subject.parser_name, &Some("some".to_owned()));
-        assert_eq!(&subject.message, "!error!");
+        assert_eq!(subject.parser_name(), Some("some".to_owned()));
+        assert_eq!(&*subject.message, "!error!");
+    }
+
+    #[test]
+    fn test_display() {
+        let subject = ParseError::new(
+            FilePosition {
+                filepath: PathBuf::from("a/b.c"),
+                position: Some(Position::new(10, 21)),
+                included_from: None,
+            },
+            None,
+            "!error!".to_owned(),
+        );
+
+        assert_eq!(&subject.to_string(), "a/b.c:10:21: !error!");
     }
 }
 
@@ -213,6 +866,7 @@ mod test_parse_context {
             FilePosition {
                 filepath: PathBuf::from("a/b.c"),
                 position: Some(Position::new(10, 21)),
+                included_from: None,
             },
             "!error!".to_owned(),
         );
@@ -221,7 +875,7 @@ mod test_parse_context {
         let error = &subject.warnings[0];
         assert_eq!(&error.file_position.filepath, &PathBuf::from("a/b.c"));
         assert_eq!(&error.file_position.position, &Some(Position::new(10, 21)));
-        assert_eq!(&error.message, "!error!");
+        assert_eq!(&*error.message, "!error!");
     }
 }
 
@@ -252,7 +906,7 @@ mod test_try_parse {
         assert_eq!(&result.unwrap().unwrap(), "xxx");
         assert_eq!(
             &unit_stream.file_position().position,
-            &Some(Position::new(3, 1))
+            &Some(Position::with_byte_offset(3, 1, 8))
         );
 
         Ok(())
@@ -273,7 +927,7 @@ mod test_try_parse {
         assert!(result.unwrap().is_none());
         assert_eq!(
             &unit_stream.file_position().position,
-            &Some(Position::new(2, 1))
+            &Some(Position::with_byte_offset(2, 1, 4))
         );
 
         Ok(())
@@ -313,10 +967,7 @@ mod test_try_parse {
         Ok(None)
     }
 
-    fn parse_error(
-        unit_stream: &mut UnitStream,
-        context: &mut ParseContext,
-    ) -> ParseResult<String> {
+    fn parse_error(unit_stream: &mut UnitStream, _: &mut ParseContext) -> ParseResult<String> {
         for _ in 0..4 {
             unit_stream.read();
         }
@@ -1,75 +1,274 @@
-#[cfg(test)]
-use std::cmp::Ordering;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-
+use crate::build::step1::Span;
 use crate::build::step2::Unit;
 use crate::build::step2::UnitStream;
 use crate::build::step3::call_parser;
+use crate::build::step3::comment::parse_comment;
 use crate::build::step3::symbol;
 use crate::build::step3::ContentModel;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseError;
 use crate::build::step3::ParseResult;
 
-pub type Attributes = HashMap<String, String>;
+/// 属性値
+/// 範囲は引用符があれば引用符も含む。
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttributeValue {
+    value: String,
+    span: Span,
+}
 
-impl ContentModel for Attributes {
+impl AttributeValue {
+    pub fn new(value: String, span: Span) -> AttributeValue {
+        AttributeValue { value, span }
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn value_mut(&mut self) -> &mut String {
+        &mut self.value
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+}
+
+impl PartialEq<str> for AttributeValue {
+    fn eq(&self, other: &str) -> bool {
+        self.value == other
+    }
+}
+
+impl PartialEq<&str> for AttributeValue {
+    fn eq(&self, other: &&str) -> bool {
+        self.value == *other
+    }
+}
+
+impl ContentModel for AttributeValue {
     #[cfg(test)]
     fn to_json(&self) -> String {
-        if self.is_empty() {
-            return "null".to_owned();
+        self.value.to_json()
+    }
+}
+
+/// 名前付きの属性
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attribute {
+    name: String,
+    name_span: Span,
+    value: AttributeValue,
+}
+
+impl Attribute {
+    pub fn new(name: String, name_span: Span, value: AttributeValue) -> Attribute {
+        Attribute {
+            name,
+            name_span,
+            value,
         }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn name_span(&self) -> &Span {
+        &self.name_span
+    }
 
-        let mut s = String::new();
+    pub fn name_span_mut(&mut self) -> &mut Span {
+        &mut self.name_span
+    }
 
-        s.push('{');
+    pub fn value(&self) -> &AttributeValue {
+        &self.value
+    }
 
-        let mut first = true;
+    pub fn value_mut(&mut self) -> &mut AttributeValue {
+        &mut self.value
+    }
 
-        for attr_name in sort_keys(&self) {
-            let v = self.get(attr_name).unwrap();
+    /// 属性名の始まりから属性値の終わりまでの範囲
+    pub fn span(&self) -> Span {
+        let mut span = Span::new(self.name_span.start.clone(), self.value.span.end.clone());
+        span.file = self.name_span.file.clone();
+        span
+    }
+}
 
-            if !first {
-                s.push(',');
-            }
-            first = false;
+impl ContentModel for Attribute {
+    #[cfg(test)]
+    fn to_json(&self) -> String {
+        self.value.to_json()
+    }
+}
+
+/// 属性
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeItem {
+    Named(Attribute),
+    Nameless(AttributeValue),
+}
 
-            let value = v.to_json();
-            let value = value.as_str();
-            s.push_str(&format!("\"{}\":{}", &attr_name, value));
+impl AttributeItem {
+    /// 名前付きの属性なら属性名の始まりから、属性値の終わりまでの範囲
+    pub fn span(&self) -> Span {
+        match self {
+            AttributeItem::Named(attribute) => attribute.span(),
+            AttributeItem::Nameless(value) => value.span().clone(),
         }
+    }
 
-        s.push('}');
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            AttributeItem::Named(attribute) => Some(attribute.name()),
+            AttributeItem::Nameless(_) => None,
+        }
+    }
 
-        s
+    pub fn value(&self) -> &AttributeValue {
+        match self {
+            AttributeItem::Named(attribute) => attribute.value(),
+            AttributeItem::Nameless(value) => value,
+        }
     }
 }
 
-pub type NamelessAttributeValues = Vec<String>;
+/// タグの属性。名前付きの属性と無名属性を書かれた順に持つ。
+/// 同じ名前の属性が繰り返されることもある。
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Attributes {
+    items: Vec<AttributeItem>,
+}
+
+impl Attributes {
+    pub fn new() -> Attributes {
+        Attributes::default()
+    }
+
+    /// 書かれた順の属性
+    pub fn items(&self) -> &[AttributeItem] {
+        &self.items
+    }
+
+    pub fn items_mut(&mut self) -> &mut Vec<AttributeItem> {
+        &mut self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn push(&mut self, item: AttributeItem) {
+        self.items.push(item);
+    }
+
+    /// 名前付きの属性を書かれた順に返す。
+    pub fn named(&self) -> impl Iterator<Item = &Attribute> {
+        self.items.iter().filter_map(|item| match item {
+            AttributeItem::Named(attribute) => Some(attribute),
+            AttributeItem::Nameless(_) => None,
+        })
+    }
+
+    /// 無名属性の値を書かれた順に返す。
+    pub fn nameless(&self) -> impl Iterator<Item = &AttributeValue> {
+        self.items.iter().filter_map(|item| match item {
+            AttributeItem::Named(_) => None,
+            AttributeItem::Nameless(value) => Some(value),
+        })
+    }
 
-impl ContentModel for NamelessAttributeValues {
+    pub fn nameless_mut(&mut self) -> impl Iterator<Item = &mut AttributeValue> {
+        self.items.iter_mut().filter_map(|item| match item {
+            AttributeItem::Named(_) => None,
+            AttributeItem::Nameless(value) => Some(value),
+        })
+    }
+
+    /// 名前が一致する最初の属性
+    pub fn get(&self, name: &str) -> Option<&Attribute> {
+        self.named().find(|attribute| attribute.name() == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Attribute> {
+        self.items.iter_mut().find_map(|item| match item {
+            AttributeItem::Named(attribute) if attribute.name() == name => Some(attribute),
+            _ => None,
+        })
+    }
+
+    /// 名前が一致する全ての属性
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Attribute> {
+        self.named()
+            .filter(move |attribute| attribute.name() == name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+}
+
+impl ContentModel for Attributes {
+    /// 名前付きの属性だけを属性名をキーにしたオブジェクトにする。
+    /// 繰り返された属性の値は配列にする。
     #[cfg(test)]
     fn to_json(&self) -> String {
-        let mut result = "[".to_owned();
+        let mut names: Vec<&str> = vec![];
+        for attribute in self.named() {
+            if !names.contains(&attribute.name()) {
+                names.push(attribute.name());
+            }
+        }
+
+        if names.is_empty() {
+            return "null".to_owned();
+        }
 
-        let values = self
+        let entries = names
             .iter()
-            .map(|x| format!("\"{}\"", x.as_str()))
+            .map(|name| {
+                let values = self
+                    .get_all(name)
+                    .map(|attribute| attribute.to_json())
+                    .collect::<Vec<String>>();
+                let value = if values.len() == 1 {
+                    values[0].clone()
+                } else {
+                    format!("[{}]", values.join(","))
+                };
+                format!("{}:{}", name.to_string().to_json(), value)
+            })
             .collect::<Vec<String>>()
             .join(",");
-        result.push_str(values.as_str());
-
-        result.push(']');
 
-        result
+        format!("{{{}}}", entries)
     }
 }
 
+/// 無名属性だけを配列にする。
+#[cfg(test)]
+pub fn nameless_to_json(attributes: &Attributes) -> String {
+    let values = attributes
+        .nameless()
+        .map(|value| value.to_json())
+        .collect::<Vec<String>>()
+        .join(",");
+
+    format!("[{}]", values)
+}
+
 pub fn parse_attributes(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
-) -> ParseResult<(Attributes, NamelessAttributeValues)> {
+) -> ParseResult<Attributes> {
     // 開始が"["でなければ不適合
     if unit_stream.peek() != Unit::Char('[') {
         return Ok(None);
@@ -79,8 +278,7 @@ pub fn parse_attributes(
     // 属性の[]の中ではインデントの増減をブロック開始/終了と見なさない
     unit_stream.set_indent_check_mode(false);
 
-    let mut attributes = HashMap::new();
-    let mut nameless_attribute_values = vec![];
+    let mut attributes = Attributes::new();
 
     loop {
         match unit_stream.peek() {
@@ -92,20 +290,27 @@ pub fn parse_attributes(
                 ' ' => {
                     unit_stream.read();
                 }
+                ':' if call_parser(parse_comment, unit_stream, context)?.is_some() => {}
                 _ => match call_parser(parse_attribute, unit_stream, context)? {
                     Some((attribute_name, attribute_value)) => {
-                        if let Some(attribute_name) = attribute_name {
-                            match attributes.entry(attribute_name) {
-                                Entry::Occupied(_) => context.warn(
+                        if let Some((attribute_name, name_span)) = attribute_name {
+                            // 繰り返しを許されていない属性は最初のものだけにする
+                            if attributes.contains(&attribute_name)
+                                && !context.allows_repeated_attribute(&attribute_name)
+                            {
+                                context.warn(
                                     unit_stream.file_position(),
                                     "The attributes are duplicated.".to_owned(),
-                                ),
-                                Entry::Vacant(entry) => {
-                                    entry.insert(attribute_value);
-                                }
+                                );
+                            } else {
+                                attributes.push(AttributeItem::Named(Attribute::new(
+                                    attribute_name,
+                                    name_span,
+                                    attribute_value,
+                                )));
                             }
                         } else {
-                            nameless_attribute_values.push(attribute_value);
+                            attributes.push(AttributeItem::Nameless(attribute_value));
                         }
                     }
                     None => {
@@ -117,8 +322,8 @@ pub fn parse_attributes(
                 unit_stream.read();
             }
             Unit::BlockBeginning | Unit::BlockEnd => {
-                return Err(ParseError::new(
-                    unit_stream.file_position(),
+                return Err(ParseError::block_structure(
+                    unit_stream,
                     context.parser_name(),
                     "Block beginning or end occurred while indent check mode is off.".to_owned(),
                 ));
@@ -130,13 +335,13 @@ pub fn parse_attributes(
         }
     }
 
-    Ok(Some((attributes, nameless_attribute_values)))
+    Ok(Some(attributes))
 }
 
 fn parse_attribute(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
-) -> ParseResult<(Option<String>, String)> {
+) -> ParseResult<(Option<(String, Span)>, AttributeValue)> {
     // 無名属性をパースする
     // 普通の属性が書かれていた時に"="がないことの警告を無効にする
     let mut no_warning = context.change_warn_mode(false);
@@ -152,6 +357,7 @@ fn parse_attribute(
     }
 
     // 属性名をパースする
+    let name_start = unit_stream.next_position();
     let attribute_name = match call_parser(symbol::parse_symbol, unit_stream, context)? {
         Some(attribute_name) => attribute_name,
         None => {
@@ -163,6 +369,11 @@ fn parse_attribute(
         }
     };
 
+    let attribute_name = (
+        attribute_name,
+        Span::new(name_start, unit_stream.end_position()),
+    );
+
     // 次が"="でなければ不適合
     if unit_stream.peek() != Unit::Char('=') {
         return Ok(None);
@@ -170,9 +381,9 @@ fn parse_attribute(
     unit_stream.read();
 
     // 引用符付き属性値がパースできればパース成功
-    match call_parser(parse_quoted_attribute_value, unit_stream, context)? {
-        Some(attribute_value) => return Ok(Some((Some(attribute_name), attribute_value))),
-        None => {}
+    if let Some(attribute_value) = call_parser(parse_quoted_attribute_value, unit_stream, context)?
+    {
+        return Ok(Some((Some(attribute_name), attribute_value)));
     }
 
     // 単純属性値がパースできればパース成功
@@ -186,7 +397,9 @@ fn parse_attribute(
 fn parse_quoted_attribute_value(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
-) -> ParseResult<String> {
+) -> ParseResult<AttributeValue> {
+    let start = unit_stream.next_position();
+
     // 開始が引用符でなければ不適合
     if unit_stream.peek() != Unit::Char('"') {
         return Ok(None);
@@ -226,8 +439,8 @@ fn parse_quoted_attribute_value(
                 }
             }
             Unit::BlockBeginning | Unit::BlockEnd => {
-                return Err(ParseError::new(
-                    unit_stream.file_position(),
+                return Err(ParseError::block_structure(
+                    unit_stream,
                     context.parser_name(),
                     "Unexpected block beginning or end.".to_owned(),
                 ));
@@ -235,14 +448,16 @@ fn parse_quoted_attribute_value(
         }
     }
 
-    Ok(Some(attribute_value))
+    let span = Span::new(start, unit_stream.end_position());
+    Ok(Some(AttributeValue::new(attribute_value, span)))
 }
 
 /// 引用符なしの属性値をパースする。
 fn parse_simple_attribute_value(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
-) -> ParseResult<String> {
+) -> ParseResult<AttributeValue> {
+    let start = unit_stream.next_position();
     let mut attribute_value = String::new();
 
     loop {
@@ -267,8 +482,8 @@ fn parse_simple_attribute_value(
             },
             Unit::NewLine | Unit::Eof => break,
             Unit::BlockBeginning | Unit::BlockEnd => {
-                return Err(ParseError::new(
-                    unit_stream.file_position(),
+                return Err(ParseError::block_structure(
+                    unit_stream,
                     context.parser_name(),
                     "Unexpected block beginning or end.".to_owned(),
                 ));
@@ -276,23 +491,20 @@ fn parse_simple_attribute_value(
         }
     }
 
-    Ok(Some(attribute_value))
-}
-
-#[cfg(test)]
-pub fn sort_keys(attributes: &Attributes) -> Vec<&String> {
-    let mut keys = attributes.keys().collect::<Vec<&String>>();
-    keys.sort_by(|a, b| a.partial_cmp(b).unwrap());
-
-    keys
+    let span = Span::new(start, unit_stream.end_position());
+    Ok(Some(AttributeValue::new(attribute_value, span)))
 }
 
 #[cfg(test)]
 mod test_parse_attributes {
+    use super::nameless_to_json;
     use super::parse_attributes;
     use crate::build::step1::Position;
+    use crate::build::step2::test_utils::unit_stream;
     use crate::build::step3::test_utils::assert_model;
     use crate::build::step3::test_utils::test_parser;
+    use crate::build::step3::ParseContext;
+    use crate::build::step3::ParseOptions;
 
     /// 正常ケース
     /// 名前付きの属性をパースできる
@@ -303,10 +515,10 @@ mod test_parse_attributes {
     #[test]
     fn test_normal() {
         let (r, p, w) = test_parser(parse_attributes, "[a=xxx b=\"y y\"\"y\"\n    zzz] c=123");
-        let (attributes, values) = r.unwrap().unwrap();
+        let attributes = r.unwrap().unwrap();
         assert_model(&attributes, r#"{"a":"xxx", "b":"y y\"y"}"#);
-        assert_model(&values, r#"["zzz"]"#);
-        assert_eq!(p, Position::new(2, 9));
+        assert_eq!(nameless_to_json(&attributes), r#"["zzz"]"#);
+        assert_eq!(p, Position::with_byte_offset(2, 9, 26));
         assert_eq!(w.len(), 0);
     }
 
@@ -314,12 +526,58 @@ mod test_parse_attributes {
     #[test]
     fn test_duplicated_key() {
         let (r, p, w) = test_parser(parse_attributes, "[a=xxx a=yyy]");
-        let (attributes, values) = r.unwrap().unwrap();
+        let attributes = r.unwrap().unwrap();
         assert_model(&attributes, r#"{"a":"xxx"}"#);
-        assert_eq!(values.len(), 0);
-        assert_eq!(p, Position::new(1, 14));
+        assert_eq!(attributes.nameless().count(), 0);
+        assert_eq!(p, Position::with_byte_offset(1, 14, 13));
         assert_eq!(w.len(), 1);
-        assert_eq!(&w[0].message, "The attributes are duplicated.");
+        assert_eq!(&*w[0].message, "The attributes are duplicated.");
+    }
+
+    /// 繰り返しを許された属性は全て残す
+    #[test]
+    fn test_repeated_key() {
+        let mut us = unit_stream("[class=a x class=b id=c id=d]").unwrap();
+        let options = ParseOptions::new().allow_repeated_attribute("tag", "class");
+        let mut warnings = vec![];
+        let mut context = ParseContext::with_options(&mut warnings, &options);
+        let mut context = context.change_tag_name(Some("tag".to_owned()));
+        us.read();
+        let attributes = parse_attributes(&mut us, &mut context).unwrap().unwrap();
+
+        assert_model(&attributes, r#"{"class":["a","b"],"id":"c"}"#);
+        assert_eq!(
+            attributes
+                .items()
+                .iter()
+                .map(|item| (item.name(), item.value().value()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Some("class"), "a"),
+                (None, "x"),
+                (Some("class"), "b"),
+                (Some("id"), "c")
+            ]
+        );
+        assert_eq!(
+            attributes
+                .get_all("class")
+                .map(|attribute| attribute.value().value())
+                .collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(&*warnings[0].message, "The attributes are duplicated.");
+    }
+
+    /// バックスラッシュや引用符を含む属性値
+    #[test]
+    fn test_special_chars() {
+        let (r, _, w) = test_parser(parse_attributes, r#"[a\b "x""y"]"#);
+        let attributes = r.unwrap().unwrap();
+        assert_eq!(attributes.nameless().next().unwrap().value(), "a\\b");
+        assert_eq!(nameless_to_json(&attributes), r#"["a\\b","x\"y"]"#);
+        assert_eq!(w.len(), 0);
     }
 
     /// 開始が"["でなければ不適合
@@ -334,10 +592,10 @@ mod test_parse_attributes {
     #[test]
     fn test_missing_comma() {
         let (r, p, w) = test_parser(parse_attributes, r#"[a="x"b=y]"#);
-        let (attributes, values) = r.unwrap().unwrap();
+        let attributes = r.unwrap().unwrap();
         assert_model(&attributes, r#"{"a":"x","b":"y"}"#);
-        assert_eq!(values.len(), 0);
-        assert_eq!(p, Position::new(1, 11));
+        assert_eq!(attributes.nameless().count(), 0);
+        assert_eq!(p, Position::with_byte_offset(1, 11, 10));
         assert_eq!(w.len(), 0);
     }
 
@@ -347,7 +605,7 @@ mod test_parse_attributes {
         let (r, _, w) = test_parser(parse_attributes, "[?=a]");
         assert!(r.unwrap().is_none());
         assert_eq!(w.len(), 1);
-        assert_eq!(&w[0].message, "There is no attribute name.");
+        assert_eq!(&*w[0].message, "There is no attribute name.");
     }
 
     /// EOFが出現したら不適合
@@ -356,7 +614,7 @@ mod test_parse_attributes {
         let (r, _, w) = test_parser(parse_attributes, "[a=x b=y");
         assert!(r.unwrap().is_none());
         assert_eq!(w.len(), 1);
-        assert_eq!(&w[0].message, "']' is required.");
+        assert_eq!(&*w[0].message, "']' is required.");
     }
 }
 
@@ -364,6 +622,7 @@ mod test_parse_attributes {
 mod test_parse_attribute {
     use super::parse_attribute;
     use crate::build::step1::Position;
+    use crate::build::step1::Span;
     use crate::build::step3::test_utils::test_parser;
 
     /// 引用符なし属性
@@ -371,20 +630,37 @@ mod test_parse_attribute {
     fn test_simple_value() {
         let (r, p, w) = test_parser(parse_attribute, r#"!i!a=xxx "#);
         let (k, v) = r.unwrap().unwrap();
-        assert_eq!(&k, &Some("a".to_owned()));
+        assert_eq!(k.map(|(name, _)| name), Some("a".to_owned()));
         assert_eq!(&v, "xxx");
-        assert_eq!(p, Position::new(1, 9));
+        assert_eq!(p, Position::with_byte_offset(1, 9, 8));
         assert_eq!(w.len(), 0);
     }
 
     /// 引用符付き属性
+    /// 属性値の範囲は引用符を含む
     #[test]
     fn test_quoted_value() {
         let (r, p, w) = test_parser(parse_attribute, r#"!i!a="xxx"]"#);
         let (k, v) = r.unwrap().unwrap();
-        assert_eq!(&k, &Some("a".to_owned()));
+        assert_eq!(
+            k,
+            Some((
+                "a".to_owned(),
+                Span::new(
+                    Position::with_byte_offset(1, 4, 3),
+                    Position::with_byte_offset(1, 5, 4)
+                )
+            ))
+        );
         assert_eq!(&v, "xxx");
-        assert_eq!(p, Position::new(1, 11));
+        assert_eq!(
+            v.span(),
+            &Span::new(
+                Position::with_byte_offset(1, 6, 5),
+                Position::with_byte_offset(1, 11, 10)
+            )
+        );
+        assert_eq!(p, Position::with_byte_offset(1, 11, 10));
         assert_eq!(w.len(), 0);
     }
 
@@ -395,7 +671,7 @@ mod test_parse_attribute {
         let (k, v) = r.unwrap().unwrap();
         assert_eq!(&k, &None);
         assert_eq!(&v, "xxx");
-        assert_eq!(p, Position::new(1, 7));
+        assert_eq!(p, Position::with_byte_offset(1, 7, 6));
         assert_eq!(w.len(), 0);
     }
 
@@ -406,7 +682,7 @@ mod test_parse_attribute {
         let (k, v) = r.unwrap().unwrap();
         assert_eq!(&k, &None);
         assert_eq!(&v, "xxx");
-        assert_eq!(p, Position::new(1, 9));
+        assert_eq!(p, Position::with_byte_offset(1, 9, 8));
         assert_eq!(w.len(), 0);
     }
 
@@ -416,7 +692,7 @@ mod test_parse_attribute {
         let (r, _, w) = test_parser(parse_attribute, "!i!$=xxx ");
         assert!(r.unwrap().is_none());
         assert_eq!(w.len(), 1);
-        assert_eq!(w[0].message, "There is no attribute name.");
+        assert_eq!(&*w[0].message, "There is no attribute name.");
     }
 
     /// 属性名の後が"="でなければ不適合
@@ -433,7 +709,7 @@ mod test_parse_attribute {
         let (r, _, w) = test_parser(parse_attribute, "!i!=xxx ");
         assert!(r.unwrap().is_none());
         assert_eq!(w.len(), 1);
-        assert_eq!(w[0].message, "There is no attribute name.");
+        assert_eq!(&*w[0].message, "There is no attribute name.");
     }
 
     /// 値が空
@@ -441,9 +717,9 @@ mod test_parse_attribute {
     fn test_empty_value() {
         let (r, p, w) = test_parser(parse_attribute, "!i!a= ");
         let (k, v) = r.unwrap().unwrap();
-        assert_eq!(&k, &Some("a".to_owned()));
-        assert_eq!(&v, &"".to_owned());
-        assert_eq!(p, Position::new(1, 6));
+        assert_eq!(k.map(|(name, _)| name), Some("a".to_owned()));
+        assert_eq!(&v, "");
+        assert_eq!(p, Position::with_byte_offset(1, 6, 5));
         assert_eq!(w.len(), 0);
     }
 }
@@ -462,7 +738,7 @@ mod test_parse_quoted_attribute_value {
         let (r, p, w) = test_parser(parse_quoted_attribute_value, r#"!i!"xxx" x"#);
         let v = r.unwrap().unwrap();
         assert_eq!(&v, "xxx");
-        assert_eq!(p, Position::new(1, 9));
+        assert_eq!(p, Position::with_byte_offset(1, 9, 8));
         assert_eq!(w.len(), 0);
     }
 
@@ -489,9 +765,12 @@ mod test_parse_quoted_attribute_value {
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
         let result = parse_quoted_attribute_value(&mut us, &mut context).unwrap();
-        assert_eq!(&result, &Some("xx\"zz".to_owned()));
+        assert_eq!(result.as_ref().map(|v| v.value()), Some("xx\"zz"));
         assert_eq!(warnings.len(), 0);
-        assert_eq!(us.file_position().position, Some(Position::new(1, 9)));
+        assert_eq!(
+            us.file_position().position,
+            Some(Position::with_byte_offset(1, 9, 8))
+        );
         Ok(())
     }
 
@@ -504,9 +783,12 @@ mod test_parse_quoted_attribute_value {
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
         let result = parse_quoted_attribute_value(&mut us, &mut context).unwrap();
-        assert_eq!(&result, &Some("xx\"\"zz".to_owned()));
+        assert_eq!(result.as_ref().map(|v| v.value()), Some("xx\"\"zz"));
         assert_eq!(warnings.len(), 0);
-        assert_eq!(us.file_position().position, Some(Position::new(1, 11)));
+        assert_eq!(
+            us.file_position().position,
+            Some(Position::with_byte_offset(1, 11, 10))
+        );
         Ok(())
     }
 
@@ -519,9 +801,12 @@ mod test_parse_quoted_attribute_value {
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
         let result = parse_quoted_attribute_value(&mut us, &mut context).unwrap();
-        assert_eq!(&result, &Some("xx\"".to_owned()));
+        assert_eq!(result.as_ref().map(|v| v.value()), Some("xx\""));
         assert_eq!(warnings.len(), 0);
-        assert_eq!(us.file_position().position, Some(Position::new(1, 7)));
+        assert_eq!(
+            us.file_position().position,
+            Some(Position::with_byte_offset(1, 7, 6))
+        );
         Ok(())
     }
 
@@ -537,7 +822,7 @@ mod test_parse_quoted_attribute_value {
         assert_eq!(&result, &None);
         assert_eq!(warnings.len(), 1);
         assert_eq!(
-            &warnings[0].message,
+            &*warnings[0].message,
             "A quoted attribute value is not closed."
         );
         Ok(())
@@ -555,7 +840,7 @@ mod test_parse_quoted_attribute_value {
         assert_eq!(&result, &None);
         assert_eq!(warnings.len(), 1);
         assert_eq!(
-            &warnings[0].message,
+            &*warnings[0].message,
             "A quoted attribute value is not closed."
         );
         Ok(())
@@ -579,9 +864,12 @@ mod test_parse_simple_attribute_value {
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
         let result = parse_simple_attribute_value(&mut us, &mut context).unwrap();
-        assert_eq!(&result, &Some("xxx".to_owned()));
+        assert_eq!(result.as_ref().map(|v| v.value()), Some("xxx"));
         assert_eq!(warnings.len(), 0);
-        assert_eq!(us.file_position().position, Some(Position::new(1, 4)));
+        assert_eq!(
+            us.file_position().position,
+            Some(Position::with_byte_offset(1, 4, 3))
+        );
         Ok(())
     }
 
@@ -594,9 +882,12 @@ mod test_parse_simple_attribute_value {
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
         let result = parse_simple_attribute_value(&mut us, &mut context).unwrap();
-        assert_eq!(&result, &Some("xxx".to_owned()));
+        assert_eq!(result.as_ref().map(|v| v.value()), Some("xxx"));
         assert_eq!(warnings.len(), 0);
-        assert_eq!(us.file_position().position, Some(Position::new(1, 4)));
+        assert_eq!(
+            us.file_position().position,
+            Some(Position::with_byte_offset(1, 4, 3))
+        );
         Ok(())
     }
 
@@ -609,9 +900,12 @@ mod test_parse_simple_attribute_value {
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
         let result = parse_simple_attribute_value(&mut us, &mut context).unwrap();
-        assert_eq!(&result, &Some("xxx".to_owned()));
+        assert_eq!(result.as_ref().map(|v| v.value()), Some("xxx"));
         assert_eq!(warnings.len(), 0);
-        assert_eq!(us.file_position().position, Some(Position::new(1, 4)));
+        assert_eq!(
+            us.file_position().position,
+            Some(Position::with_byte_offset(1, 4, 3))
+        );
         Ok(())
     }
 
@@ -624,9 +918,12 @@ mod test_parse_simple_attribute_value {
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
         let result = parse_simple_attribute_value(&mut us, &mut context).unwrap();
-        assert_eq!(&result, &Some("xxx".to_owned()));
+        assert_eq!(result.as_ref().map(|v| v.value()), Some("xxx"));
         assert_eq!(warnings.len(), 0);
-        assert_eq!(us.file_position().position, Some(Position::new(1, 4)));
+        assert_eq!(
+            us.file_position().position,
+            Some(Position::with_byte_offset(1, 4, 3))
+        );
         Ok(())
     }
 
@@ -642,7 +939,7 @@ mod test_parse_simple_attribute_value {
         assert_eq!(&result, &None);
         assert_eq!(warnings.len(), 1);
         assert_eq!(
-            &warnings[0].message,
+            &*warnings[0].message,
             "Quotes cannot be written in the middle of an attribute value."
         );
         Ok(())
@@ -660,7 +957,7 @@ mod test_parse_simple_attribute_value {
         assert_eq!(&result, &None);
         assert_eq!(warnings.len(), 1);
         assert_eq!(
-            &warnings[0].message,
+            &*warnings[0].message,
             "Equal Signs cannot be written in the middle of an attribute value."
         );
         Ok(())
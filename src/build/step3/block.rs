@@ -1,23 +1,43 @@
+use crate::build::step1::Span;
 use crate::build::step2::Mark;
 use crate::build::step2::Unit;
 use crate::build::step2::UnitStream;
 use crate::build::step3::block_tag::parse_block_tag;
 use crate::build::step3::call_parser;
+use crate::build::step3::comment::parse_block_comment;
 use crate::build::step3::paragraph::parse_paragraph;
-use crate::build::step3::BlockContent;
 use crate::build::step3::BlockContents;
+use crate::build::step3::BlockNode;
 use crate::build::step3::ContentModel;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseError;
 use crate::build::step3::ParseResult;
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Block {
     contents: BlockContents,
+    span: Span,
 }
 
 impl Block {
-    pub fn new(contents: BlockContents) -> Block {
-        Block { contents }
+    pub fn new(contents: BlockContents, span: Span) -> Block {
+        Block { contents, span }
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+
+    pub fn contents(&self) -> &BlockContents {
+        &self.contents
+    }
+
+    pub fn contents_mut(&mut self) -> &mut BlockContents {
+        &mut self.contents
     }
 }
 
@@ -40,21 +60,6 @@ impl ContentModel for Block {
     }
 }
 
-impl BlockContent for Block {}
-
-pub enum BlankLine {
-    INSTANCE,
-}
-
-impl ContentModel for BlankLine {
-    #[cfg(test)]
-    fn to_json(&self) -> String {
-        "\"<bl>\"".to_owned()
-    }
-}
-
-impl BlockContent for BlankLine {}
-
 pub fn parse_block(unit_stream: &mut UnitStream, context: &mut ParseContext) -> ParseResult<Block> {
     // 開始位置がブロック開始でなければ不適合
     if unit_stream.peek() != Unit::BlockBeginning {
@@ -62,61 +67,69 @@ pub fn parse_block(unit_stream: &mut UnitStream, context: &mut ParseContext) ->
     }
     unit_stream.read();
 
+    let start = unit_stream.next_position();
     let mut contents: BlockContents = vec![];
+    // コメントだけのブロックも内容が空のブロックにする
+    let mut has_comments = false;
 
     let mut blank_lines_beginning: Option<Mark> = None;
-    let mut blank_line_count = 0;
+    let mut blank_line_spans: Vec<Span> = vec![];
 
     loop {
         if blank_lines_beginning.is_some() {
             match unit_stream.peek() {
                 Unit::Char(_) | Unit::BlockBeginning => {
-                    for _ in 0..blank_line_count {
-                        contents.push(Box::new(BlankLine::INSTANCE));
+                    for span in blank_line_spans.drain(..) {
+                        contents.push(BlockNode::BlankLine(span));
                     }
                     blank_lines_beginning = None;
-                    blank_line_count = 0;
                 }
                 _ => {}
             }
         }
 
         match unit_stream.peek() {
-            Unit::Char(c) => {
-                if c == ':' && context.is_parse_tags() {
-                    if let Some(block_tag) = call_parser(parse_block_tag, unit_stream, context)? {
-                        contents.push(Box::new(block_tag));
-                        continue;
-                    }
+            Unit::Char(_) => {
+                let mark = unit_stream.mark();
+                match parse_line_node(unit_stream, context) {
+                    Ok(Some(node)) => contents.push(node),
+                    Ok(None) => has_comments = true,
+                    Err(error) => contents.push(recover(unit_stream, context, mark, error)?),
                 }
-
-                // 開始位置に文字がある以上は段落のパースは成功する
-                let paragraph = call_parser(parse_paragraph, unit_stream, context)?.unwrap();
-                contents.push(Box::new(paragraph));
+                // 内容があるブロックはもう不適合にならず、外側も読み戻さないので、
+                // ここまでの文字を捨てられるようにする
+                unit_stream.cut();
             }
             Unit::NewLine => {
                 if blank_lines_beginning.is_none() {
                     blank_lines_beginning = Some(unit_stream.mark());
                 }
-                blank_line_count += 1;
 
+                let start = unit_stream.next_position();
                 unit_stream.read();
+                blank_line_spans.push(Span::new(start, unit_stream.end_position()));
             }
             Unit::BlockBeginning => {
-                // ブロック開始があった以上はその後に文字があるので空ではあり得ない
-                let block = call_parser(parse_block, unit_stream, context)?.unwrap();
-                contents.push(Box::new(block));
+                let mark = unit_stream.mark();
+                match call_parser(parse_block, unit_stream, context) {
+                    // ブロック開始があった以上はその後に文字があるので空ではあり得ない
+                    Ok(block) => contents.push(BlockNode::Block(block.unwrap())),
+                    Err(error) => contents.push(recover(unit_stream, context, mark, error)?),
+                }
+                unit_stream.cut();
             }
             Unit::BlockEnd => {
                 unit_stream.read();
                 break;
             }
             Unit::Eof => {
-                return Err(ParseError::new(
-                    unit_stream.file_position(),
+                // 回復するならブロックの終わりと見なす
+                context.recover(ParseError::block_structure(
+                    unit_stream,
                     context.parser_name(),
                     "Although there is a block beginning, there is no block end.".to_owned(),
-                ));
+                ))?;
+                break;
             }
         }
     }
@@ -125,12 +138,108 @@ pub fn parse_block(unit_stream: &mut UnitStream, context: &mut ParseContext) ->
         if let Some(mark) = blank_lines_beginning {
             unit_stream.reset(mark);
         }
-        Ok(Some(Block { contents }))
+        // 範囲は最初の内容の始まりから最後の内容の終わりまで
+        let span = Span::new(
+            contents[0].span().start.clone(),
+            contents[contents.len() - 1].span().end.clone(),
+        );
+        Ok(Some(Block { contents, span }))
+    } else if has_comments {
+        if let Some(mark) = blank_lines_beginning {
+            unit_stream.reset(mark);
+        }
+        Ok(Some(Block {
+            contents,
+            span: Span::new(start.clone(), start),
+        }))
     } else {
         Ok(None)
     }
 }
 
+/// 文字から始まる要素をパースする。コメントならNone。
+fn parse_line_node(
+    unit_stream: &mut UnitStream,
+    context: &mut ParseContext,
+) -> ParseResult<BlockNode> {
+    if unit_stream.peek() == Unit::Char(':') && context.is_parse_tags() {
+        if call_parser(parse_block_comment, unit_stream, context)?.is_some() {
+            return Ok(None);
+        }
+        if let Some(block_tag) = call_parser(parse_block_tag, unit_stream, context)? {
+            return Ok(Some(BlockNode::BlockTag(Box::new(block_tag))));
+        }
+    }
+
+    // 開始位置に文字がある以上は段落のパースは成功する
+    let paragraph = call_parser(parse_paragraph, unit_stream, context)?.unwrap();
+    Ok(Some(BlockNode::Paragraph(paragraph)))
+}
+
+/// 要素のパースのエラーから回復する。回復しないならエラーを返す。
+///
+/// 要素の開始位置からエラーの位置まで読み飛ばし、その後の行の終わりかブロックの境界で止める。
+/// 途中のブロックは終わりまで読み飛ばす。読み飛ばした範囲をエラーの要素にする。
+fn recover(
+    unit_stream: &mut UnitStream,
+    context: &mut ParseContext,
+    mark: Mark,
+    error: ParseError,
+) -> Result<BlockNode, ParseError> {
+    let error_position = error.file_position.position.clone();
+    context.recover(error)?;
+
+    unit_stream.reset(mark);
+    let start = unit_stream.next_position();
+    let mut end = start.clone();
+    let mut depth = 0;
+    // 同じ位置でパースし直さないように、少なくとも1つは読み飛ばす
+    let mut first = true;
+
+    loop {
+        let position = unit_stream.next_position();
+        let after_error = error_position.as_ref().is_none_or(|error_position| {
+            (position.line_number, position.column_number)
+                >= (error_position.line_number, error_position.column_number)
+        });
+
+        match unit_stream.peek() {
+            Unit::Char(_) => {}
+            Unit::NewLine => {
+                if depth == 0 && after_error {
+                    unit_stream.read();
+                    end = unit_stream.end_position();
+                    break;
+                }
+            }
+            Unit::BlockBeginning => {
+                if depth == 0 && after_error && !first {
+                    break;
+                }
+                depth += 1;
+            }
+            Unit::BlockEnd => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+                if depth == 0 && after_error {
+                    unit_stream.read();
+                    break;
+                }
+            }
+            Unit::Eof => break,
+        }
+
+        if let (Unit::Char(_) | Unit::NewLine, _) = unit_stream.read() {
+            end = unit_stream.end_position();
+        }
+        first = false;
+    }
+
+    Ok(BlockNode::Error(Span::new(start, end)))
+}
+
 #[cfg(test)]
 mod test_parse_block {
     use super::parse_block;
@@ -213,9 +322,23 @@ mod test_parse_block {
 
         assert_eq!(warnings.len(), 2);
         assert_eq!(warnings[0].parser_name(), Some("block tag".to_owned()));
-        assert_eq!(warnings[0].message, "There is an illegal character. ';'");
+        assert_eq!(&*warnings[0].message, "There is an illegal character. ';'");
         assert_eq!(warnings[1].parser_name(), Some("inline tag".to_owned()));
-        assert_eq!(warnings[1].message, "There is an illegal character. ';'");
+        assert_eq!(&*warnings[1].message, "There is an illegal character. ';'");
+
+        Ok(())
+    }
+
+    /// エスケープしたコロンから始まる段落はブロックタグではない
+    #[test]
+    fn test_escaped_block_tag() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("\\:tag[a=1]\n")?;
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let block = parse_block(&mut us, &mut context).unwrap().unwrap();
+
+        assert_model(&block, r#"{"b":[{"p":[":tag[a=1]\n"]}]}"#);
+        assert!(warnings.is_empty());
 
         Ok(())
     }
@@ -234,4 +357,173 @@ mod test_parse_block {
 
         Ok(())
     }
+
+    /// 大きなブロックタグの中でも、読み込んだ文字をすべて残しはしない
+    #[test]
+    fn test_buffer_size() -> Result<(), Box<dyn Error>> {
+        let source = format!(
+            ":section\n{}",
+            "    :sub header\n        some contents of the sub section\n".repeat(2000)
+        );
+        let mut us = unit_stream(&source)?;
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let block = parse_block(&mut us, &mut context).unwrap().unwrap();
+
+        assert_eq!(block.contents().len(), 1);
+        assert!(us.char_stream().buffer_len() < source.len() / 4);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_recover {
+    use super::recover;
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
+    use crate::build::step2::test_utils::unit_stream;
+    use crate::build::step2::Unit;
+    use crate::build::step2::UnitStream;
+    use crate::build::step3::test_utils::assert_model;
+    use crate::build::step3::BlockNode;
+    use crate::build::step3::ParseContext;
+    use crate::build::step3::ParseError;
+    use crate::build::step3::ParseOptions;
+    use crate::document::parse_document_with_options;
+    use std::error::Error;
+    use std::path::PathBuf;
+
+    /// 最初のブロック開始を読み捨てて、指定した位置のエラーから回復する
+    fn recover_at(
+        us: &mut UnitStream,
+        line_number: u64,
+        column_number: u64,
+    ) -> (BlockNode, Vec<ParseError>) {
+        us.read();
+        let mark = us.mark();
+        let mut file_position = us.file_position();
+        file_position.position = Some(Position::new(line_number, column_number));
+        let error = ParseError::new(file_position, None, "error".to_owned());
+
+        let mut warnings = vec![];
+        let mut errors = vec![];
+        let mut context = ParseContext::new(&mut warnings).recover_errors(&mut errors);
+        let node = recover(us, &mut context, mark, error).unwrap();
+        assert!(warnings.is_empty());
+
+        (node, errors)
+    }
+
+    fn span(start: (u64, u64, usize), end: (u64, u64, usize)) -> Span {
+        Span::new(
+            Position::with_byte_offset(start.0, start.1, start.2),
+            Position::with_byte_offset(end.0, end.1, end.2),
+        )
+    }
+
+    /// エラーの後の行の終わりまで読み飛ばす
+    #[test]
+    fn test_line() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("abc\ndef\nghi\n")?;
+        let (node, errors) = recover_at(&mut us, 2, 2);
+
+        assert_eq!(node, BlockNode::Error(span((1, 1, 0), (3, 1, 8))));
+        assert_eq!(errors.len(), 1);
+        assert_eq!(us.peek(), Unit::Char('g'));
+
+        Ok(())
+    }
+
+    /// ブロックの開始の前で止める
+    #[test]
+    fn test_block_beginning() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("abc\n    def\n")?;
+        let (node, _) = recover_at(&mut us, 2, 5);
+
+        assert_eq!(node, BlockNode::Error(span((1, 1, 0), (2, 1, 4))));
+        assert_eq!(us.peek(), Unit::BlockBeginning);
+
+        Ok(())
+    }
+
+    /// エラーのあるブロックは終わりまで読み飛ばす
+    #[test]
+    fn test_nested_block() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("abc\n    def\n    ghi\njkl\n")?;
+        let (node, _) = recover_at(&mut us, 2, 6);
+
+        assert_eq!(node, BlockNode::Error(span((1, 1, 0), (4, 1, 20))));
+        assert_eq!(us.peek(), Unit::Char('j'));
+
+        Ok(())
+    }
+
+    /// ドキュメントのパースで、置き換えられないincludeタグのエラーをすべて集めて続きをパースする
+    #[test]
+    fn test_document() {
+        let document = parse_document_with_options(
+            PathBuf::from("resources/test/include/recover_book.oreno"),
+            &ParseOptions::new()
+                .resolve_includes(true)
+                .recover_errors(true),
+        )
+        .unwrap();
+
+        let errors = document.errors();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].file_position.filepath,
+            PathBuf::from("resources/test/include/absent.oreno")
+        );
+        assert_eq!(
+            errors[1].to_string(),
+            "resources/test/include/cycle_b.oreno:3:1: \
+             The file is included recursively. (\"cycle_a.oreno\") \
+             (included from resources/test/include/cycle_a.oreno:3:1, \
+             included from resources/test/include/recover_book.oreno:7:5)"
+        );
+        // includeタグの範囲をエラーの要素にして、次の要素からパースを続ける
+        assert_model(
+            document.block(),
+            r#"{"b":[
+                {"p":["Before\n"]},
+                "<bl>",
+                "<err>",
+                {"p":["Between\n"]},
+                "<bl>",
+                {"bt":"section","c":{"b":[
+                    "<err>",
+                    "<bl>",
+                    {"p":["Inside\n"]}
+                ]}},
+                "<bl>",
+                {"p":["After\n"]}
+            ]}"#,
+        );
+        let root = document.block().contents();
+        assert_eq!(root[2], BlockNode::Error(span((3, 1, 8), (3, 23, 30))));
+        let section = match &root[5] {
+            BlockNode::BlockTag(section) => section.contents().unwrap().contents(),
+            _ => unreachable!(),
+        };
+        assert_eq!(section[0], BlockNode::Error(span((7, 5, 53), (7, 28, 76))));
+    }
+
+    /// 回復しないならエラーを返す
+    #[test]
+    fn test_no_recovery() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("abc\n")?;
+        us.read();
+        let mark = us.mark();
+        let error = ParseError::new(us.file_position(), None, "error".to_owned());
+
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let result = recover(&mut us, &mut context, mark, error);
+
+        assert_eq!(&*result.unwrap_err().message, "error");
+
+        Ok(())
+    }
 }
@@ -1,26 +1,90 @@
+use crate::build::step1::Span;
 use crate::build::step2::Unit;
 use crate::build::step2::UnitStream;
+#[cfg(test)]
+use crate::build::step3::attribute::nameless_to_json;
 use crate::build::step3::attribute::Attributes;
-use crate::build::step3::attribute::NamelessAttributeValues;
 use crate::build::step3::block::parse_block;
 use crate::build::step3::block::Block;
 use crate::build::step3::block_tag_header::parse_block_tag_header;
 use crate::build::step3::block_tag_header::BlockTagHeader;
 use crate::build::step3::call_parser;
+use crate::build::step3::paragraph::Paragraph;
 use crate::build::step3::tag::parse_tag_and_attributes;
 use crate::build::step3::tag::TagName;
-use crate::build::step3::BlockContent;
+use crate::build::step3::BlockNode;
 use crate::build::step3::ContentModel;
+use crate::build::step3::InlineNode;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseError;
 use crate::build::step3::ParseResult;
+use crate::build::step3::Text;
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct BlockTag {
     name: TagName,
     attributes: Attributes,
-    nameless_attribute_values: NamelessAttributeValues,
     header: Option<BlockTagHeader>,
     contents: Option<Block>,
+    span: Span,
+}
+
+impl BlockTag {
+    pub fn new(
+        name: TagName,
+        attributes: Attributes,
+        header: Option<BlockTagHeader>,
+        contents: Option<Block>,
+        span: Span,
+    ) -> BlockTag {
+        BlockTag {
+            name,
+            attributes,
+            header,
+            contents,
+            span,
+        }
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+
+    pub fn name(&self) -> &TagName {
+        &self.name
+    }
+
+    pub fn name_mut(&mut self) -> &mut TagName {
+        &mut self.name
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    pub fn attributes_mut(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+
+    pub fn header(&self) -> Option<&BlockTagHeader> {
+        self.header.as_ref()
+    }
+
+    pub fn header_mut(&mut self) -> &mut Option<BlockTagHeader> {
+        &mut self.header
+    }
+
+    pub fn contents(&self) -> Option<&Block> {
+        self.contents.as_ref()
+    }
+
+    pub fn contents_mut(&mut self) -> &mut Option<Block> {
+        &mut self.contents
+    }
 }
 
 impl ContentModel for BlockTag {
@@ -28,13 +92,12 @@ impl ContentModel for BlockTag {
     fn to_json(&self) -> String {
         let mut result = format!("{{\"bt\":{}", &self.name.to_json());
 
-        if !self.attributes.is_empty() {
+        if self.attributes.named().next().is_some() {
             result.push_str(format!(",\"a\":{}", &self.attributes.to_json()).as_str());
         }
 
-        if !self.nameless_attribute_values.is_empty() {
-            result
-                .push_str(format!(",\"v\":{}", &self.nameless_attribute_values.to_json()).as_str());
+        if self.attributes.nameless().next().is_some() {
+            result.push_str(format!(",\"v\":{}", nameless_to_json(&self.attributes)).as_str());
         }
 
         if let Some(header) = &self.header {
@@ -45,14 +108,12 @@ impl ContentModel for BlockTag {
             result.push_str(format!(",\"c\":{}", &contents.to_json()).as_str());
         }
 
-        result.push_str("}");
+        result.push('}');
 
         result
     }
 }
 
-impl BlockContent for BlockTag {}
-
 pub fn parse_block_tag(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
@@ -60,25 +121,35 @@ pub fn parse_block_tag(
     let mut context = context.change_parser_name(Some("block tag".to_owned()));
     let context = &mut context;
 
-    let (tag_name, attributes, nameless_attribute_values) =
-        match call_parser(parse_tag_and_attributes, unit_stream, context)? {
-            Some(x) => x,
-            None => return Ok(None),
-        };
+    let start = unit_stream.next_position();
 
-    let parse_tags = tag_name.name() != "code-block" && tag_name.name() != "raw-html";
+    let (tag_name, attributes) = match call_parser(parse_tag_and_attributes, unit_stream, context)?
+    {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+
+    let parse_tags = !context
+        .options()
+        .get_schema()
+        .is_raw_block_tag(tag_name.name());
 
     match unit_stream.peek() {
         Unit::Char(' ') | Unit::NewLine | Unit::BlockEnd | Unit::Eof => {}
         Unit::Char(c) => {
             if c == ':' && parse_tags && !tag_name.abbreviation() {
                 if let Some(block_tag) = call_parser(parse_block_tag, unit_stream, context)? {
+                    let span = Span::new(start, block_tag.span().end.clone());
+                    let block_span = block_tag.span().clone();
                     return Ok(Some(BlockTag {
                         name: tag_name,
                         attributes,
-                        nameless_attribute_values,
                         header: None,
-                        contents: Some(Block::new(vec![Box::new(block_tag)])),
+                        contents: Some(Block::new(
+                            vec![BlockNode::BlockTag(Box::new(block_tag))],
+                            block_span,
+                        )),
+                        span,
                     }));
                 }
             }
@@ -90,8 +161,8 @@ pub fn parse_block_tag(
             return Ok(None);
         }
         Unit::BlockBeginning => {
-            return Err(ParseError::new(
-                unit_stream.file_position(),
+            return Err(ParseError::block_structure(
+                unit_stream,
                 context.parser_name(),
                 "Unexpected block beginning or end.".to_owned(),
             ));
@@ -105,29 +176,45 @@ pub fn parse_block_tag(
         None
     };
 
+    // 内容がなければタグかヘッダーの終わりまでが範囲
+    let mut end = unit_stream.end_position();
+
     let contents = if unit_stream.peek() == Unit::NewLine {
         unit_stream.read();
-        call_parser(
-            parse_block,
-            unit_stream,
-            &mut context.change_parse_mode(parse_tags),
-        )?
+        if parse_tags {
+            call_parser(parse_block, unit_stream, context)?
+        } else {
+            // 内容をパースしないタグは、内容を空白も含めてそのまま1つのテキストにする
+            unit_stream.read_verbatim_block().map(|(text, span)| {
+                let paragraph = Paragraph::new(
+                    vec![InlineNode::Text(Text::new(text, span.clone()))],
+                    span.clone(),
+                );
+                Block::new(vec![BlockNode::Paragraph(paragraph)], span)
+            })
+        }
     } else {
         None
     };
 
+    if let Some(contents) = &contents {
+        end = contents.span().end.clone();
+    }
+
     Ok(Some(BlockTag {
         name: tag_name,
         attributes,
-        nameless_attribute_values,
         header,
         contents,
+        span: Span::new(start, end),
     }))
 }
 
 #[cfg(test)]
 mod test_parse_block_tag {
     use super::parse_block_tag;
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
     use crate::build::step2::test_utils::unit_stream;
     use crate::build::step2::Unit;
     use crate::build::step3::test_utils::assert_model;
@@ -223,7 +310,7 @@ mod test_parse_block_tag {
         assert!(tag.is_none());
 
         assert_eq!(warnings.len(), 1);
-        assert_eq!(&warnings[0].message, "There is an illegal character. '*'");
+        assert_eq!(&*warnings[0].message, "There is an illegal character. '*'");
 
         Ok(())
     }
@@ -257,6 +344,78 @@ mod test_parse_block_tag {
         Ok(())
     }
 
+    /// rawタグの内容はインデント、タブ、行末の空白、空行も含めてそのまま1つのテキストにする
+    #[test]
+    fn test_raw_verbatim() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(concat!(
+            ":code-block[make]\n",
+            "    all:  \n",
+            "    \tcc -o a a.c\n",
+            "\n",
+            "      \n",
+            "        nested\n",
+            "  \n",
+            "    end\n",
+            "\n",
+            "next\n",
+        ))?;
+        us.read();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let tag = parse_block_tag(&mut us, &mut context).unwrap().unwrap();
+
+        assert_model(
+            &tag,
+            r#"{
+                "bt":"code-block",
+                "v":["make"],
+                "c":{"b":[{"p":["all:  \n\tcc -o a a.c\n\n  \n    nested\n\nend\n"]}]}
+            }"#,
+        );
+        let contents = tag.contents.unwrap();
+        assert_eq!(
+            contents.span(),
+            &Span::new(
+                Position::with_byte_offset(2, 5, 22),
+                Position::with_byte_offset(9, 1, 80)
+            )
+        );
+
+        // 最後の空行はタグの後に残る
+        assert_eq!(us.read().0, Unit::NewLine);
+        assert_eq!(us.read().0, Unit::Char('n'));
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
+    /// インデントが浅い空白だけの行は、その行にある分のインデントだけを取り除く
+    #[test]
+    fn test_raw_verbatim_shallow_blank_line() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(concat!(
+            ":code-block\n",
+            "    a\n",
+            "  \t \n",
+            " \n",
+            "    b\n",
+        ))?;
+        us.read();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let tag = parse_block_tag(&mut us, &mut context).unwrap().unwrap();
+
+        assert_model(
+            &tag,
+            r#"{
+                "bt":"code-block",
+                "c":{"b":[{"p":["a\n\t \n\nb\n"]}]}
+            }"#,
+        );
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
     /// 内容なし
     /// 属性の後でEOF
     #[test]
@@ -367,7 +526,7 @@ mod test_parse_block_tag {
         assert!(tag.is_none());
 
         assert_eq!(warnings.len(), 1);
-        assert_eq!(&warnings[0].message, "There is an illegal character. '$'");
+        assert_eq!(&*warnings[0].message, "There is an illegal character. '$'");
 
         Ok(())
     }
@@ -421,7 +580,7 @@ mod test_parse_block_tag {
         assert!(tag.is_none());
 
         assert_eq!(warnings.len(), 1);
-        assert_eq!(&warnings[0].message, "There is an illegal character. ':'");
+        assert_eq!(&*warnings[0].message, "There is an illegal character. ':'");
 
         Ok(())
     }
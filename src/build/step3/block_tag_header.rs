@@ -1,16 +1,43 @@
+use crate::build::step1::Span;
 use crate::build::step2::Unit;
 use crate::build::step2::UnitStream;
 use crate::build::step3::call_parser;
+use crate::build::step3::comment::parse_comment;
 use crate::build::step3::inline_tag::parse_inline_tag;
 use crate::build::step3::ContentModel;
 use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseError;
 use crate::build::step3::ParseResult;
+use crate::build::step3::TextBuffer;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct BlockTagHeader {
     contents: InlineContents,
+    span: Span,
+}
+
+impl BlockTagHeader {
+    pub fn new(contents: InlineContents, span: Span) -> BlockTagHeader {
+        BlockTagHeader { contents, span }
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+
+    pub fn contents(&self) -> &InlineContents {
+        &self.contents
+    }
+
+    pub fn contents_mut(&mut self) -> &mut InlineContents {
+        &mut self.contents
+    }
 }
 
 impl ContentModel for BlockTagHeader {
@@ -37,35 +64,37 @@ pub fn parse_block_tag_header(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
 ) -> ParseResult<BlockTagHeader> {
+    let start = unit_stream.next_position();
     let mut contents: InlineContents = vec![];
-    let mut text = String::new();
+    let mut text = TextBuffer::new();
 
     loop {
         match unit_stream.peek() {
             Unit::Char(c) => match c {
+                '\\' => text.read_escape(unit_stream),
                 ':' => {
+                    if call_parser(parse_comment, unit_stream, context)?.is_some() {
+                        continue;
+                    }
                     if let Some(inline_tag) = call_parser(parse_inline_tag, unit_stream, context)? {
-                        if !text.is_empty() {
-                            contents.push(Box::new(text));
-                            text = String::new();
+                        if let Some(text) = text.take() {
+                            contents.push(InlineNode::Text(text));
                         }
-                        contents.push(Box::new(inline_tag));
+                        contents.push(InlineNode::InlineTag(inline_tag));
                     } else {
-                        text.push(c);
-                        unit_stream.read();
+                        text.read(c, unit_stream);
                     }
                 }
                 _ => {
-                    text.push(c);
-                    unit_stream.read();
+                    text.read(c, unit_stream);
                 }
             },
             Unit::NewLine | Unit::BlockEnd | Unit::Eof => {
                 break;
             }
             Unit::BlockBeginning => {
-                return Err(ParseError::new(
-                    unit_stream.file_position(),
+                return Err(ParseError::block_structure(
+                    unit_stream,
                     context.parser_name(),
                     "Unexpected block beginning.".to_owned(),
                 ));
@@ -73,12 +102,13 @@ pub fn parse_block_tag_header(
         }
     }
 
-    if !text.is_empty() {
-        contents.push(Box::new(text));
+    if let Some(text) = text.take() {
+        contents.push(InlineNode::Text(text));
     }
 
     if !contents.is_empty() {
-        Ok(Some(BlockTagHeader { contents }))
+        let span = Span::new(start, unit_stream.end_position());
+        Ok(Some(BlockTagHeader { contents, span }))
     } else {
         Ok(None)
     }
@@ -87,6 +117,8 @@ pub fn parse_block_tag_header(
 #[cfg(test)]
 mod test_parse_block_tag_header {
     use super::parse_block_tag_header;
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
     use crate::build::step2::test_utils::unit_stream;
     use crate::build::step3::test_utils::assert_model;
     use crate::build::step3::ContentModel;
@@ -122,6 +154,23 @@ mod test_parse_block_tag_header {
         Ok(())
     }
 
+    /// バックスラッシュの次の文字はそのままの文字にする
+    #[test]
+    fn test_escape() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(r"a\:b{c}\\")?;
+        us.read();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let header = parse_block_tag_header(&mut us, &mut context)
+            .unwrap()
+            .unwrap();
+
+        assert_model(&header, r#"["a:b{c}\\"]"#);
+        assert_eq!(warnings.len(), 0);
+
+        Ok(())
+    }
+
     #[test]
     fn test_ends_with_block_end() -> Result<(), Box<dyn Error>> {
         let mut us = unit_stream("abc")?;
@@ -148,7 +197,19 @@ mod test_parse_block_tag_header {
         let mut context = ParseContext::new(&mut warnings);
         let header = parse_block_tag_header(&mut us, &mut context).unwrap_err();
 
-        assert_eq!(&header.message, "Unexpected block beginning.");
+        assert_eq!(&*header.message, "Unexpected block beginning.");
+        // ブロックの開始を生んだインデントを指す
+        assert_eq!(
+            header.file_position.position,
+            Some(Position::with_byte_offset(1, 1, 0))
+        );
+        assert_eq!(
+            header.span.as_deref(),
+            Some(&Span::new(
+                Position::with_byte_offset(1, 1, 0),
+                Position::with_byte_offset(1, 5, 4)
+            ))
+        );
 
         assert_eq!(warnings.len(), 0);
 
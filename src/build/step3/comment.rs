@@ -0,0 +1,186 @@
+use crate::build::step1::Span;
+use crate::build::step2::Unit;
+use crate::build::step2::UnitStream;
+use crate::build::step3::ParseContext;
+use crate::build::step3::ParseResult;
+
+/// コメントの種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommentKind {
+    /// 段落、ヘッダー、インラインタグの内容、属性の中の行末までのコメント
+    Line,
+    /// ブロックの要素の位置に書いたコメント。次の行からインデントの深い行も含む。
+    Block,
+}
+
+/// コメント。
+/// ツリーには含めず、ドキュメントに出現順に残す。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Comment {
+    kind: CommentKind,
+    text: String,
+    span: Span,
+}
+
+impl Comment {
+    pub fn new(kind: CommentKind, text: String, span: Span) -> Comment {
+        Comment { kind, text, span }
+    }
+
+    pub fn kind(&self) -> CommentKind {
+        self.kind
+    }
+
+    /// ":;"の後ろから行末までのテキスト。
+    /// ブロックのコメントなら続けて改行と、1段のインデントを除いた内容の行。
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// ":;"からコメントの終わりまでの範囲
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+}
+
+/// 行末までのコメントをパースして、パースできたらコンテキストに追加する。
+/// 改行は消費しない。
+///
+/// 開始位置は":;"の想定。
+pub fn parse_comment(unit_stream: &mut UnitStream, context: &mut ParseContext) -> ParseResult<()> {
+    let start = unit_stream.next_position();
+    let text = match read_comment_line(unit_stream) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+
+    let span = Span::new(start, unit_stream.end_position());
+    context.add_comment(Comment::new(CommentKind::Line, text, span));
+    Ok(Some(()))
+}
+
+/// ブロックの要素の位置に書いたコメントをパースして、パースできたらコンテキストに追加する。
+/// 次の行からインデントの深い行はコメントの内容にする。
+pub fn parse_block_comment(
+    unit_stream: &mut UnitStream,
+    context: &mut ParseContext,
+) -> ParseResult<()> {
+    let start = unit_stream.next_position();
+    let mut text = match read_comment_line(unit_stream) {
+        Some(text) => text,
+        None => return Ok(None),
+    };
+    let mut end = unit_stream.end_position();
+
+    if unit_stream.peek() == Unit::NewLine {
+        unit_stream.read();
+        // 内容はタグをパースしないタグと同じようにそのまま読み込む
+        if let Some((contents, span)) = unit_stream.read_verbatim_block() {
+            text.push('\n');
+            text.push_str(contents.strip_suffix('\n').unwrap_or(&contents));
+            end = span.end;
+        }
+    }
+
+    context.add_comment(Comment::new(
+        CommentKind::Block,
+        text,
+        Span::new(start, end),
+    ));
+    Ok(Some(()))
+}
+
+/// ":;"から行末までを読み込んで、":;"の後ろのテキストを返す。
+fn read_comment_line(unit_stream: &mut UnitStream) -> Option<String> {
+    if unit_stream.read().0 != Unit::Char(':') || unit_stream.read().0 != Unit::Char(';') {
+        return None;
+    }
+
+    let mut text = String::new();
+    while let Unit::Char(c) = unit_stream.peek() {
+        text.push(c);
+        unit_stream.read();
+    }
+    Some(text)
+}
+
+#[cfg(test)]
+mod test_parse_comment {
+    use super::parse_block_comment;
+    use super::parse_comment;
+    use super::Comment;
+    use super::CommentKind;
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
+    use crate::build::step2::test_utils::unit_stream;
+    use crate::build::step2::Unit;
+    use crate::build::step3::ParseContext;
+    use crate::build::step3::ParseOptions;
+    use indoc::indoc;
+    use std::error::Error;
+
+    #[test]
+    fn test_line() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(":; TODO\nabc")?;
+        us.read();
+        let mut warnings = vec![];
+        let mut comments = vec![];
+        let options = ParseOptions::new();
+        let mut context = ParseContext::with_comments(&mut warnings, &options, &mut comments);
+        assert_eq!(parse_comment(&mut us, &mut context)?, Some(()));
+        assert_eq!(us.peek(), Unit::NewLine);
+        assert_eq!(
+            comments,
+            vec![Comment::new(
+                CommentKind::Line,
+                " TODO".to_owned(),
+                Span::new(
+                    Position::with_byte_offset(1, 1, 0),
+                    Position::with_byte_offset(1, 8, 7)
+                )
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mismatched() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(":a")?;
+        us.read();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        assert_eq!(parse_comment(&mut us, &mut context)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(indoc! {"
+            :; note
+                a
+                    b
+
+                c
+
+            d
+        "})?;
+        us.read();
+        let mut warnings = vec![];
+        let mut comments = vec![];
+        let options = ParseOptions::new();
+        let mut context = ParseContext::with_comments(&mut warnings, &options, &mut comments);
+        assert_eq!(parse_block_comment(&mut us, &mut context)?, Some(()));
+        assert_eq!(
+            comments,
+            vec![Comment::new(
+                CommentKind::Block,
+                " note\na\n    b\n\nc".to_owned(),
+                Span::new(
+                    Position::with_byte_offset(1, 1, 0),
+                    Position::with_byte_offset(6, 1, 31)
+                )
+            )]
+        );
+        Ok(())
+    }
+}
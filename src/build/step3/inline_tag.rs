@@ -1,23 +1,75 @@
+use crate::build::step1::Span;
 use crate::build::step2::Unit;
 use crate::build::step2::UnitStream;
+#[cfg(test)]
+use crate::build::step3::attribute::nameless_to_json;
 use crate::build::step3::attribute::Attributes;
-use crate::build::step3::attribute::NamelessAttributeValues;
 use crate::build::step3::call_parser;
+use crate::build::step3::comment::parse_comment;
 use crate::build::step3::tag::parse_tag_and_attributes;
 use crate::build::step3::tag::TagName;
 use crate::build::step3::ContentModel;
-use crate::build::step3::InlineContent;
 use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseError;
 use crate::build::step3::ParseResult;
+use crate::build::step3::TextBuffer;
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct InlineTag {
     name: TagName,
     attributes: Attributes,
-    nameless_attribute_values: NamelessAttributeValues,
     contents: InlineContents,
+    span: Span,
+}
+
+impl InlineTag {
+    pub fn new(
+        name: TagName,
+        attributes: Attributes,
+        contents: InlineContents,
+        span: Span,
+    ) -> InlineTag {
+        InlineTag {
+            name,
+            attributes,
+            contents,
+            span,
+        }
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+
+    pub fn name(&self) -> &TagName {
+        &self.name
+    }
+
+    pub fn name_mut(&mut self) -> &mut TagName {
+        &mut self.name
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    pub fn attributes_mut(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+
+    pub fn contents(&self) -> &InlineContents {
+        &self.contents
+    }
+
+    pub fn contents_mut(&mut self) -> &mut InlineContents {
+        &mut self.contents
+    }
 }
 
 impl ContentModel for InlineTag {
@@ -25,13 +77,12 @@ impl ContentModel for InlineTag {
     fn to_json(&self) -> String {
         let mut result = format!("{{\"it\":{}", &self.name.to_json());
 
-        if !self.attributes.is_empty() {
+        if self.attributes.named().next().is_some() {
             result.push_str(format!(",\"a\":{}", &self.attributes.to_json()).as_str());
         }
 
-        if !self.nameless_attribute_values.is_empty() {
-            result
-                .push_str(format!(",\"v\":{}", &self.nameless_attribute_values.to_json()).as_str());
+        if self.attributes.nameless().next().is_some() {
+            result.push_str(format!(",\"v\":{}", nameless_to_json(&self.attributes)).as_str());
         }
 
         if !self.contents.is_empty() {
@@ -44,14 +95,12 @@ impl ContentModel for InlineTag {
             result.push_str(format!(",\"c\":[{}]", contents.as_str()).as_str());
         };
 
-        result.push_str("}");
+        result.push('}');
 
         result
     }
 }
 
-impl InlineContent for InlineTag {}
-
 pub fn parse_inline_tag(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
@@ -59,25 +108,25 @@ pub fn parse_inline_tag(
     let mut context = context.change_parser_name(Some("inline tag".to_owned()));
     let context = &mut context;
 
-    let (tag_name, attributes, nameless_attribute_values) =
-        match parse_tag_and_attributes(unit_stream, context)? {
-            Some(x) => x,
-            None => return Ok(None),
-        };
+    let start = unit_stream.next_position();
 
-    let parse_tags = match tag_name.name() {
-        "code" | "raw-html" => false,
-        _ => true,
+    let (tag_name, attributes) = match parse_tag_and_attributes(unit_stream, context)? {
+        Some(x) => x,
+        None => return Ok(None),
     };
 
+    let parse_tags = !context
+        .options()
+        .get_schema()
+        .is_raw_inline_tag(tag_name.name());
+
     if parse_tags && !tag_name.abbreviation() {
         if let Some(nested_tag) = call_parser(parse_inline_tag, unit_stream, context)? {
-            let contents: Vec<Box<dyn InlineContent>> = vec![Box::new(nested_tag)];
             return Ok(Some(InlineTag {
                 name: tag_name,
                 attributes,
-                nameless_attribute_values,
-                contents,
+                contents: vec![InlineNode::InlineTag(nested_tag)],
+                span: Span::new(start, unit_stream.end_position()),
             }));
         }
     }
@@ -109,8 +158,8 @@ pub fn parse_inline_tag(
             return Ok(None);
         }
         Unit::BlockBeginning => {
-            return Err(ParseError::new(
-                unit_stream.file_position(),
+            return Err(ParseError::block_structure(
+                unit_stream,
                 context.parser_name(),
                 "Unexpected block beginning.".to_owned(),
             ));
@@ -120,8 +169,8 @@ pub fn parse_inline_tag(
     Ok(Some(InlineTag {
         name: tag_name,
         attributes,
-        nameless_attribute_values,
         contents,
+        span: Span::new(start, unit_stream.end_position()),
     }))
 }
 
@@ -141,60 +190,59 @@ fn parse_inline_tag_contents(
     unit_stream.set_indent_check_mode(false);
 
     let mut contents: InlineContents = vec![];
-    let mut text = String::new();
+    let mut text = TextBuffer::new();
 
     loop {
         match unit_stream.peek() {
             Unit::Char(c) => match c {
+                // エスケープした括弧は数えない
+                '\\' if context.is_parse_tags() => text.read_escape(unit_stream),
                 ':' if context.is_parse_tags() => {
+                    if call_parser(parse_comment, unit_stream, context)?.is_some() {
+                        continue;
+                    }
                     match call_parser(parse_inline_tag, unit_stream, context)? {
                         Some(inline_tag) => {
-                            if !text.is_empty() {
-                                contents.push(Box::new(text));
-                                text = String::new();
+                            if let Some(text) = text.take() {
+                                contents.push(InlineNode::Text(text));
                             }
-                            contents.push(Box::new(inline_tag));
+                            contents.push(InlineNode::InlineTag(inline_tag));
                         }
                         None => {
-                            text.push(c);
-                            unit_stream.read();
+                            text.read(c, unit_stream);
                         }
                     }
                 }
                 '{' => {
                     bracket_depth += 1;
-                    text.push('{');
-                    unit_stream.read();
+                    text.read('{', unit_stream);
                 }
                 '}' => {
                     bracket_depth -= 1;
                     if bracket_depth == 0 {
-                        if !text.is_empty() {
-                            contents.push(Box::new(text));
+                        if let Some(text) = text.take() {
+                            contents.push(InlineNode::Text(text));
                         }
                         unit_stream.read();
                         break;
                     } else {
-                        text.push('}');
-                        unit_stream.read();
+                        text.read('}', unit_stream);
                     }
                 }
                 _ => {
-                    text.push(c);
-                    unit_stream.read();
+                    text.read(c, unit_stream);
                 }
             },
             Unit::NewLine => {
-                text.push('\n');
-                unit_stream.read();
+                text.read('\n', unit_stream);
             }
             Unit::Eof => {
                 context.warn(unit_stream.file_position(), "} is required.".to_owned());
                 return Ok(None);
             }
             Unit::BlockBeginning | Unit::BlockEnd => {
-                return Err(ParseError::new(
-                    unit_stream.file_position(),
+                return Err(ParseError::block_structure(
+                    unit_stream,
                     context.parser_name(),
                     "Block beginning or end occurred while indent check mode is off.".to_owned(),
                 ));
@@ -221,7 +269,7 @@ mod test_parse_inline_tag {
         let tag = r.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"tag"}"#);
 
-        assert_eq!(p, Position::new(1, 5));
+        assert_eq!(p, Position::with_byte_offset(1, 5, 4));
 
         assert!(w.is_empty());
     }
@@ -234,7 +282,7 @@ mod test_parse_inline_tag {
         let tag = r.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"tag"}"#);
 
-        assert_eq!(p, Position::new(1, 5));
+        assert_eq!(p, Position::with_byte_offset(1, 5, 4));
 
         assert!(w.is_empty());
     }
@@ -247,7 +295,7 @@ mod test_parse_inline_tag {
         let tag = r.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"tag"}"#);
 
-        assert_eq!(p, Position::new(1, 5));
+        assert_eq!(p, Position::with_byte_offset(1, 5, 4));
 
         assert!(w.is_empty());
     }
@@ -260,7 +308,7 @@ mod test_parse_inline_tag {
         let tag = r.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"tag", "a":{"a":"x","b":"yy"}, "v":["123"]}"#);
 
-        assert_eq!(p, Position::new(1, 21));
+        assert_eq!(p, Position::with_byte_offset(1, 21, 20));
 
         assert!(w.is_empty());
     }
@@ -273,7 +321,7 @@ mod test_parse_inline_tag {
         let tag = r.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"tag", "a":{"a":"x","b":"yy"}, "v":["123"]}"#);
 
-        assert_eq!(p, Position::new(1, 20));
+        assert_eq!(p, Position::with_byte_offset(1, 20, 19));
 
         assert!(w.is_empty());
     }
@@ -286,7 +334,7 @@ mod test_parse_inline_tag {
         let tag = r.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"tag", "a":{"a":"x","b":"yy"}, "v":["123"]}"#);
 
-        assert_eq!(p, Position::new(1, 23));
+        assert_eq!(p, Position::with_byte_offset(1, 23, 22));
 
         assert!(w.is_empty());
     }
@@ -306,7 +354,7 @@ mod test_parse_inline_tag {
             ]}"#,
         );
 
-        assert_eq!(p, Position::new(1, 21));
+        assert_eq!(p, Position::with_byte_offset(1, 21, 20));
 
         assert!(w.is_empty());
     }
@@ -326,7 +374,7 @@ mod test_parse_inline_tag {
             ]}"#,
         );
 
-        assert_eq!(p, Position::new(1, 21));
+        assert_eq!(p, Position::with_byte_offset(1, 21, 20));
 
         assert!(w.is_empty());
     }
@@ -346,7 +394,7 @@ mod test_parse_inline_tag {
             ]}"#,
         );
 
-        assert_eq!(p, Position::new(1, 21));
+        assert_eq!(p, Position::with_byte_offset(1, 21, 20));
 
         assert!(w.is_empty());
     }
@@ -372,7 +420,7 @@ mod test_parse_inline_tag {
             }"#,
         );
 
-        assert_eq!(position, Position::new(1, 37));
+        assert_eq!(position, Position::with_byte_offset(1, 37, 36));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -398,7 +446,7 @@ mod test_parse_inline_tag {
             }"#,
         );
 
-        assert_eq!(position, Position::new(1, 36));
+        assert_eq!(position, Position::with_byte_offset(1, 36, 35));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -424,7 +472,7 @@ mod test_parse_inline_tag {
             }"#,
         );
 
-        assert_eq!(position, Position::new(1, 37));
+        assert_eq!(position, Position::with_byte_offset(1, 37, 36));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -437,7 +485,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":""}"#);
 
-        assert_eq!(position, Position::new(1, 2));
+        assert_eq!(position, Position::with_byte_offset(1, 2, 1));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -450,7 +498,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":""}"#);
 
-        assert_eq!(position, Position::new(1, 2));
+        assert_eq!(position, Position::with_byte_offset(1, 2, 1));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -463,7 +511,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":""}"#);
 
-        assert_eq!(position, Position::new(1, 2));
+        assert_eq!(position, Position::with_byte_offset(1, 2, 1));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -476,7 +524,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "a":{"b":"c"}, "v":["aa"]}"#);
 
-        assert_eq!(position, Position::new(1, 10));
+        assert_eq!(position, Position::with_byte_offset(1, 10, 9));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -489,7 +537,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "a":{"b":"c"}, "v":["aa"]}"#);
 
-        assert_eq!(position, Position::new(1, 10));
+        assert_eq!(position, Position::with_byte_offset(1, 10, 9));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -502,7 +550,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "a":{"b":"c"}, "v":["aa"]}"#);
 
-        assert_eq!(position, Position::new(1, 10));
+        assert_eq!(position, Position::with_byte_offset(1, 10, 9));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -515,7 +563,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "c":["xxx"]}"#);
 
-        assert_eq!(position, Position::new(1, 7));
+        assert_eq!(position, Position::with_byte_offset(1, 7, 6));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -528,7 +576,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "c":["xxx"]}"#);
 
-        assert_eq!(position, Position::new(1, 7));
+        assert_eq!(position, Position::with_byte_offset(1, 7, 6));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -541,7 +589,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "c":["xxx"]}"#);
 
-        assert_eq!(position, Position::new(1, 7));
+        assert_eq!(position, Position::with_byte_offset(1, 7, 6));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -554,7 +602,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "a":{"b":"c"}, "v":["aa"], "c":["xxx"]}"#);
 
-        assert_eq!(position, Position::new(1, 15));
+        assert_eq!(position, Position::with_byte_offset(1, 15, 14));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -567,7 +615,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "a":{"b":"c"}, "v":["aa"], "c":["xxx"]}"#);
 
-        assert_eq!(position, Position::new(1, 15));
+        assert_eq!(position, Position::with_byte_offset(1, 15, 14));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -580,7 +628,7 @@ mod test_parse_inline_tag {
         let tag = result.unwrap().unwrap();
         assert_model(&tag, r#"{"it":"", "a":{"b":"c"}, "v":["aa"], "c":["xxx"]}"#);
 
-        assert_eq!(position, Position::new(1, 15));
+        assert_eq!(position, Position::with_byte_offset(1, 15, 14));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -603,7 +651,7 @@ mod test_parse_inline_tag {
         assert!(result.unwrap().is_none());
 
         assert_eq!(warnings.len(), 1);
-        assert_eq!(&warnings[0].message, "There is an illegal character. '$'");
+        assert_eq!(&*warnings[0].message, "There is an illegal character. '$'");
     }
 
     /// タグ名の後に不正な文字
@@ -614,7 +662,7 @@ mod test_parse_inline_tag {
         assert!(result.unwrap().is_none());
 
         assert_eq!(warnings.len(), 1);
-        assert_eq!(&warnings[0].message, "There is an illegal character. ';'");
+        assert_eq!(&*warnings[0].message, "There is an illegal character. ';'");
     }
 
     /// rawタグなら内容のタグをパースしない
@@ -634,7 +682,7 @@ mod test_parse_inline_tag {
             }"#,
         );
 
-        assert_eq!(position, Position::new(1, 38));
+        assert_eq!(position, Position::with_byte_offset(1, 38, 37));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -654,7 +702,7 @@ mod test_parse_inline_tag {
             }"#,
         );
 
-        assert_eq!(position, Position::new(1, 10));
+        assert_eq!(position, Position::with_byte_offset(1, 10, 9));
 
         assert_eq!(warnings.len(), 0);
     }
@@ -675,13 +723,32 @@ mod test_parse_inline_tag {
             }"#,
         );
 
-        assert_eq!(position, Position::new(1, 20));
+        assert_eq!(position, Position::with_byte_offset(1, 20, 19));
 
         assert_eq!(warnings.len(), 0);
 
         Ok(())
     }
 
+    /// タグの直後のバックスラッシュはエスケープではなくrawタグの省略記法
+    #[test]
+    fn test_raw_abbreviation() {
+        let (result, position, warnings) = test_parser(parse_inline_tag, r":i\{a\:b}");
+
+        let tag = result.unwrap().unwrap();
+        assert_model(
+            &tag,
+            r#"{
+                "it":"i",
+                "c":[{"it":"raw","c":["a:b"]}]
+            }"#,
+        );
+
+        assert_eq!(position, Position::with_byte_offset(1, 10, 9));
+
+        assert_eq!(warnings.len(), 0);
+    }
+
     /// ネスト
     /// 親がrawタグならネストを許可しない
     #[test]
@@ -691,7 +758,7 @@ mod test_parse_inline_tag {
         assert!(result.unwrap().is_none());
 
         assert_eq!(warnings.len(), 1);
-        assert_eq!(&warnings[0].message, "There is an illegal character. ':'");
+        assert_eq!(&*warnings[0].message, "There is an illegal character. ':'");
 
         Ok(())
     }
@@ -703,6 +770,7 @@ mod test_parse_inline_tag_contents {
     use crate::build::step1::Position;
     use crate::build::step3::test_utils::assert_model;
     use crate::build::step3::test_utils::test_parser;
+    use crate::build::step3::ContentModel;
     use std::error::Error;
 
     #[test]
@@ -712,9 +780,9 @@ mod test_parse_inline_tag_contents {
 
         let result = result.unwrap().unwrap();
         assert_eq!(result.len(), 3);
-        assert_model(result[0].as_ref(), r#""abc""#);
+        assert_model(&result[0], r#""abc""#);
         assert_model(
-            result[1].as_ref(),
+            &result[1],
             r#"{
                 "it":"tag",
                 "c":["xxx"]
@@ -722,7 +790,7 @@ mod test_parse_inline_tag_contents {
         );
         assert_eq!(result[2].to_json(), r#""zzz""#.to_owned());
 
-        assert_eq!(position, Position::new(1, 18));
+        assert_eq!(position, Position::with_byte_offset(1, 18, 17));
 
         assert_eq!(warnings.len(), 0);
 
@@ -736,9 +804,41 @@ mod test_parse_inline_tag_contents {
 
         let result = result.unwrap().unwrap();
         assert_eq!(result.len(), 1);
-        assert_model(result[0].as_ref(), r#""abc:tag{xxx}zzz""#);
+        assert_model(&result[0], r#""abc:tag{xxx}zzz""#);
+
+        assert_eq!(position, Position::with_byte_offset(1, 21, 20));
+
+        assert_eq!(warnings.len(), 0);
+
+        Ok(())
+    }
+
+    /// エスケープした括弧は数えない
+    #[test]
+    fn test_escape() -> Result<(), Box<dyn Error>> {
+        let (result, position, warnings) =
+            test_parser(parse_inline_tag_contents, r"{a\}b\{c\\\:d}??");
+
+        let result = result.unwrap().unwrap();
+        assert_eq!(result.len(), 1);
+        assert_model(&result[0], r#""a}b{c\\:d""#);
+
+        assert_eq!(position, Position::with_byte_offset(1, 15, 14));
+
+        assert_eq!(warnings.len(), 0);
+
+        Ok(())
+    }
+
+    /// rawタグの中ではエスケープしない
+    #[test]
+    fn test_raw_escape() -> Result<(), Box<dyn Error>> {
+        let (result, position, warnings) = test_parser(parse_inline_tag_contents, r"!r!{a\:b\}??");
+
+        let result = result.unwrap().unwrap();
+        assert_model(&result[0], r#""a\\:b\\""#);
 
-        assert_eq!(position, Position::new(1, 21));
+        assert_eq!(position, Position::with_byte_offset(1, 11, 10));
 
         assert_eq!(warnings.len(), 0);
 
@@ -763,9 +863,9 @@ mod test_parse_inline_tag_contents {
 
         let result = result.unwrap().unwrap();
         assert_eq!(result.len(), 1);
-        assert_model(result[0].as_ref(), r#""abc{{xxx}zzz}""#);
+        assert_model(&result[0], r#""abc{{xxx}zzz}""#);
 
-        assert_eq!(position, Position::new(1, 16));
+        assert_eq!(position, Position::with_byte_offset(1, 16, 15));
 
         assert_eq!(warnings.len(), 0);
 
@@ -778,9 +878,9 @@ mod test_parse_inline_tag_contents {
 
         let result = result.unwrap().unwrap();
         assert_eq!(result.len(), 1);
-        assert_model(result[0].as_ref(), r#""abc\nxxx""#);
+        assert_model(&result[0], r#""abc\nxxx""#);
 
-        assert_eq!(position, Position::new(2, 5));
+        assert_eq!(position, Position::with_byte_offset(2, 5, 9));
 
         assert_eq!(warnings.len(), 0);
 
@@ -794,7 +894,7 @@ mod test_parse_inline_tag_contents {
         assert!(result.unwrap().is_none());
 
         assert_eq!(warnings.len(), 1);
-        assert_eq!(warnings[0].message, "} is required.");
+        assert_eq!(&*warnings[0].message, "} is required.");
 
         Ok(())
     }
@@ -806,7 +906,7 @@ mod test_parse_inline_tag_contents {
         let result = result.unwrap().unwrap();
         assert_eq!(result.len(), 0);
 
-        assert_eq!(position, Position::new(1, 3));
+        assert_eq!(position, Position::with_byte_offset(1, 3, 2));
 
         assert_eq!(warnings.len(), 0);
 
@@ -1,20 +1,41 @@
+use crate::build::step1::Span;
 use crate::build::step2::{Unit, UnitStream};
 use crate::build::step3::call_parser;
+use crate::build::step3::comment::parse_comment;
 use crate::build::step3::inline_tag::parse_inline_tag;
-use crate::build::step3::BlockContent;
 use crate::build::step3::ContentModel;
 use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseError;
 use crate::build::step3::ParseResult;
+use crate::build::step3::TextBuffer;
 
+#[derive(Clone, Debug, PartialEq)]
 pub struct Paragraph {
     contents: InlineContents,
+    span: Span,
 }
 
 impl Paragraph {
-    pub fn new(contents: InlineContents) -> Paragraph {
-        Paragraph { contents }
+    pub fn new(contents: InlineContents, span: Span) -> Paragraph {
+        Paragraph { contents, span }
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
+
+    pub fn contents(&self) -> &InlineContents {
+        &self.contents
+    }
+
+    pub fn contents_mut(&mut self) -> &mut InlineContents {
+        &mut self.contents
     }
 }
 
@@ -36,8 +57,6 @@ impl ContentModel for Paragraph {
     }
 }
 
-impl BlockContent for Paragraph {}
-
 pub fn parse_paragraph(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
@@ -47,31 +66,62 @@ pub fn parse_paragraph(
         _ => {}
     }
 
+    let start = unit_stream.next_position();
     let mut contents: InlineContents = vec![];
-    let mut text = String::new();
+    let mut text = TextBuffer::new();
+    // 行頭から読み込んでいるか
+    let mut head_of_line = true;
 
     loop {
         match unit_stream.peek() {
             Unit::Char(c) => {
+                if c == '\\' && context.is_parse_tags() {
+                    text.read_escape(unit_stream);
+                    head_of_line = false;
+                    continue;
+                }
+
                 if c == ':' && context.is_parse_tags() {
+                    let mark = unit_stream.mark();
+                    let comment_count = context.comment_count();
+                    if call_parser(parse_comment, unit_stream, context)?.is_some() {
+                        if head_of_line {
+                            // コメントだけの行は改行も含めて取り除く
+                            if unit_stream.peek() == Unit::NewLine {
+                                unit_stream.read();
+                            }
+                            // 段落の最後ならブロックのコメントとして読み込み直す
+                            if let Unit::NewLine
+                            | Unit::BlockBeginning
+                            | Unit::BlockEnd
+                            | Unit::Eof = unit_stream.peek()
+                            {
+                                unit_stream.reset(mark);
+                                context.truncate_comments(comment_count);
+                                break;
+                            }
+                        }
+                        continue;
+                    }
+
                     if let Some(inline_tag) = call_parser(parse_inline_tag, unit_stream, context)? {
-                        if !text.is_empty() {
-                            contents.push(Box::new(text));
-                            text = String::new();
+                        if let Some(text) = text.take() {
+                            contents.push(InlineNode::Text(text));
                         }
 
-                        contents.push(Box::new(inline_tag));
+                        contents.push(InlineNode::InlineTag(inline_tag));
+                        head_of_line = false;
 
                         continue;
                     }
                 }
 
-                text.push(c);
-                unit_stream.read();
+                text.read(c, unit_stream);
+                head_of_line = false;
             }
             Unit::NewLine => {
-                text.push('\n');
-                unit_stream.read();
+                text.read('\n', unit_stream);
+                head_of_line = true;
 
                 match unit_stream.peek() {
                     Unit::NewLine | Unit::BlockBeginning | Unit::BlockEnd => break,
@@ -80,8 +130,8 @@ pub fn parse_paragraph(
             }
             Unit::Eof | Unit::BlockEnd => break,
             Unit::BlockBeginning => {
-                return Err(ParseError::new(
-                    unit_stream.file_position(),
+                return Err(ParseError::block_structure(
+                    unit_stream,
                     context.parser_name(),
                     "Unexpected block beginning.".to_owned(),
                 ));
@@ -89,12 +139,13 @@ pub fn parse_paragraph(
         }
     }
 
-    if !text.is_empty() {
-        contents.push(Box::new(text));
+    if let Some(text) = text.take() {
+        contents.push(InlineNode::Text(text));
     }
 
     if !contents.is_empty() {
-        Ok(Some(Paragraph { contents }))
+        let span = Span::new(start, unit_stream.end_position());
+        Ok(Some(Paragraph { contents, span }))
     } else {
         Ok(None)
     }
@@ -103,6 +154,8 @@ pub fn parse_paragraph(
 #[cfg(test)]
 mod test_parse_paragraph {
     use super::parse_paragraph;
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
     use crate::build::step2::test_utils::unit_stream;
     use crate::build::step2::Unit;
     use crate::build::step3::test_utils::assert_model;
@@ -135,6 +188,33 @@ mod test_parse_paragraph {
         Ok(())
     }
 
+    /// バックスラッシュの次の文字はそのままの文字にする
+    #[test]
+    fn test_escape() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream(r"a\:b{c} \\ \*x:i{\}}\")?;
+        us.read();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let paragraph = parse_paragraph(&mut us, &mut context).unwrap().unwrap();
+
+        // 最後のバックスラッシュは次に文字がないのでそのまま
+        assert_model(
+            &paragraph,
+            r#"{"p":["a:b{c} \\ *x",{"it":"i","c":["}"]},"\\"]}"#,
+        );
+        assert_eq!(
+            paragraph.span(),
+            &Span::new(
+                Position::with_byte_offset(1, 1, 0),
+                Position::with_byte_offset(1, 22, 21)
+            )
+        );
+
+        assert!(warnings.is_empty());
+
+        Ok(())
+    }
+
     #[test]
     fn test_starts_with_wrap() -> Result<(), Box<dyn Error>> {
         let mut us = unit_stream("\nabc:t{xyz}0\n123")?;
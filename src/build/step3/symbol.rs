@@ -3,6 +3,53 @@ use crate::build::step2::UnitStream;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseResult;
 
+/// タグ名と属性名に使える文字
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NameSyntax {
+    /// 英数字とハイフン
+    Ascii,
+    /// Unicodeの識別子の文字(XID_Start、XID_Continue)とハイフン。
+    /// 省略記法の記号と重なる"_"は使えない。
+    #[default]
+    Unicode,
+    /// Unicodeの識別子の文字とハイフンに加えて、名前空間を区切る"."と"_"。
+    /// 区切りは先頭には書けない。
+    Namespaced,
+}
+
+impl NameSyntax {
+    /// 名前に使える文字か判定する。firstは先頭の文字かどうか。
+    /// どの書式でも英数字とハイフンはどこにでも書ける。
+    pub fn is_name_char(self, c: char, first: bool) -> bool {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            return true;
+        }
+
+        match self {
+            NameSyntax::Ascii => false,
+            NameSyntax::Unicode => {
+                c != '_'
+                    && (unicode_ident::is_xid_start(c)
+                        || !first && unicode_ident::is_xid_continue(c))
+            }
+            NameSyntax::Namespaced => {
+                if first {
+                    unicode_ident::is_xid_start(c)
+                } else {
+                    c == '.' || unicode_ident::is_xid_continue(c)
+                }
+            }
+        }
+    }
+
+    /// 名前に使えない文字がなければtrueを返す。
+    pub fn is_valid_name(self, name: &str) -> bool {
+        name.chars()
+            .enumerate()
+            .all(|(i, c)| self.is_name_char(c, i == 0))
+    }
+}
+
 /// シンボルをパースする。
 /// シンボルはタグ名、属性名。
 pub fn parse_symbol(
@@ -10,21 +57,17 @@ pub fn parse_symbol(
     context: &mut ParseContext,
 ) -> ParseResult<String> {
     let mut symbol = String::new();
+    let syntax = context.options().get_name_syntax();
 
-    // 英数字とハイフンが続く限りバッファに追加していく。
+    // 名前に使える文字が続く限りバッファに追加していく。
     // 他の文字、改行、EOFが出現したらその直前までをシンボルにする。
     // ブロック開始/終了は出現しない。
-    loop {
-        match unit_stream.peek() {
-            Unit::Char(c) => {
-                if c.is_ascii_alphanumeric() || c == '-' {
-                    symbol.push(c);
-                    unit_stream.read();
-                } else {
-                    break;
-                }
-            }
-            _ => break,
+    while let Unit::Char(c) = unit_stream.peek() {
+        if syntax.is_name_char(c, symbol.is_empty()) {
+            symbol.push(c);
+            unit_stream.read();
+        } else {
+            break;
         }
     }
 
@@ -41,8 +84,10 @@ mod test_parse_symbol {
     use std::error::Error;
 
     use super::parse_symbol;
+    use super::NameSyntax;
     use crate::build::step2::test_utils::unit_stream;
     use crate::build::step3::ParseContext;
+    use crate::build::step3::ParseOptions;
 
     #[test]
     fn test_end_by_char() -> Result<(), Box<dyn Error>> {
@@ -98,4 +143,49 @@ mod test_parse_symbol {
         assert_eq!(&symbol, &None);
         Ok(())
     }
+
+    #[test]
+    fn test_unicode() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("注意事項2_a.b")?;
+        us.read();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        let symbol = parse_symbol(&mut us, &mut context).unwrap().unwrap();
+        assert_eq!(&symbol, "注意事項2");
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespaced() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("x.注意_a{b}")?;
+        us.read();
+        let mut warnings = vec![];
+        let options = ParseOptions::new().name_syntax(NameSyntax::Namespaced);
+        let mut context = ParseContext::with_options(&mut warnings, &options);
+        let symbol = parse_symbol(&mut us, &mut context).unwrap().unwrap();
+        assert_eq!(&symbol, "x.注意_a");
+        Ok(())
+    }
+
+    #[test]
+    fn test_ascii() -> Result<(), Box<dyn Error>> {
+        let mut us = unit_stream("note注意")?;
+        us.read();
+        let mut warnings = vec![];
+        let options = ParseOptions::new().name_syntax(NameSyntax::Ascii);
+        let mut context = ParseContext::with_options(&mut warnings, &options);
+        let symbol = parse_symbol(&mut us, &mut context).unwrap().unwrap();
+        assert_eq!(&symbol, "note");
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_valid_name() {
+        assert!(NameSyntax::Unicode.is_valid_name("著者"));
+        assert!(NameSyntax::Unicode.is_valid_name("1-a"));
+        assert!(!NameSyntax::Unicode.is_valid_name("a_b"));
+        assert!(NameSyntax::Namespaced.is_valid_name("a_b.c"));
+        assert!(!NameSyntax::Namespaced.is_valid_name(".a"));
+        assert!(!NameSyntax::Ascii.is_valid_name("著者"));
+    }
 }
@@ -1,24 +1,33 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::build::step1::Span;
 use crate::build::step2::Unit;
 use crate::build::step2::UnitStream;
 use crate::build::step3::attribute::parse_attributes;
 use crate::build::step3::attribute::Attributes;
-use crate::build::step3::attribute::NamelessAttributeValues;
 use crate::build::step3::call_parser;
 use crate::build::step3::symbol;
+use crate::build::step3::symbol::NameSyntax;
 use crate::build::step3::ContentModel;
 use crate::build::step3::ParseContext;
 use crate::build::step3::ParseResult;
 
 /// タグ名
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct TagName {
     name: String,
     abbreviation: bool,
+    span: Span,
 }
 
 impl TagName {
-    pub fn new(name: String, abbreviation: bool) -> TagName {
-        TagName { name, abbreviation }
+    pub fn new(name: String, abbreviation: bool, span: Span) -> TagName {
+        TagName {
+            name,
+            abbreviation,
+            span,
+        }
     }
 
     pub fn name(&self) -> &str {
@@ -28,13 +37,167 @@ impl TagName {
     pub fn abbreviation(&self) -> bool {
         self.abbreviation
     }
+
+    /// コロンか省略記法の記号からタグ名の終わりまでの範囲
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    pub fn span_mut(&mut self) -> &mut Span {
+        &mut self.span
+    }
 }
 
 impl ContentModel for TagName {
     #[cfg(test)]
     fn to_json(&self) -> String {
-        format!("\"{}\"", self.name())
+        self.name.to_json()
+    }
+}
+
+/// 標準の省略記法の記号とタグ名の対応
+const ABBREVIATIONS: [(char, &str); 12] = [
+    ('*', "b"),
+    ('/', "i"),
+    ('_', "u"),
+    ('-', "del"),
+    ('"', "q"),
+    ('`', "code"),
+    ('\\', "raw"),
+    ('%', "image"),
+    ('#', "sequence"),
+    ('$', "section"),
+    ('&', "link"),
+    ('@', "apply-template"),
+];
+
+/// 省略記法の記号とタグ名の表
+///
+/// 省略記法はタグの直後にしか書けない。
+/// タグの構文に使う記号、空白、英数字は記号にできない。
+#[derive(Clone, Debug, PartialEq)]
+pub struct Abbreviations {
+    /// 変更した表。変更していなければ標準の表を使う
+    custom: Option<Vec<(char, String)>>,
+}
+
+impl Abbreviations {
+    /// 標準の表
+    pub const fn new() -> Abbreviations {
+        Abbreviations { custom: None }
+    }
+
+    /// 空の表
+    pub fn empty() -> Abbreviations {
+        Abbreviations {
+            custom: Some(Vec::new()),
+        }
+    }
+
+    /// 記号とタグ名の組を追加する。記号がすでにあればタグ名を置き換える。
+    pub fn insert<T: Into<String>>(
+        &mut self,
+        symbol: char,
+        tag_name: T,
+    ) -> Result<(), AbbreviationError> {
+        let tag_name = tag_name.into();
+        check_abbreviation(symbol, &tag_name)?;
+
+        let entries = self.entries_mut();
+        match entries.iter_mut().find(|(s, _)| *s == symbol) {
+            Some(entry) => entry.1 = tag_name,
+            None => entries.push((symbol, tag_name)),
+        }
+        Ok(())
     }
+
+    /// 記号を取り除く。
+    pub fn remove(&mut self, symbol: char) {
+        self.entries_mut().retain(|(s, _)| *s != symbol);
+    }
+
+    /// 記号が表すタグ名
+    pub fn tag_name(&self, symbol: char) -> Option<&str> {
+        self.iter()
+            .find(|(s, _)| *s == symbol)
+            .map(|(_, tag_name)| tag_name)
+    }
+
+    /// タグ名を表す記号。複数あれば最初の記号。
+    pub fn symbol(&self, tag_name: &str) -> Option<char> {
+        self.iter()
+            .find(|(_, name)| *name == tag_name)
+            .map(|(symbol, _)| symbol)
+    }
+
+    /// 記号とタグ名の組を順に返す。
+    pub fn iter(&self) -> impl Iterator<Item = (char, &str)> {
+        let (standard, custom) = match &self.custom {
+            Some(custom) => (&[][..], &custom[..]),
+            None => (&ABBREVIATIONS[..], &[][..]),
+        };
+        standard
+            .iter()
+            .map(|(symbol, tag_name)| (*symbol, *tag_name))
+            .chain(
+                custom
+                    .iter()
+                    .map(|(symbol, tag_name)| (*symbol, tag_name.as_str())),
+            )
+    }
+
+    fn entries_mut(&mut self) -> &mut Vec<(char, String)> {
+        self.custom.get_or_insert_with(|| {
+            ABBREVIATIONS
+                .iter()
+                .map(|(symbol, tag_name)| (*symbol, (*tag_name).to_owned()))
+                .collect()
+        })
+    }
+}
+
+impl Default for Abbreviations {
+    fn default() -> Abbreviations {
+        Abbreviations::new()
+    }
+}
+
+/// 省略記法を追加できなかった時のエラー
+#[derive(Debug, PartialEq)]
+pub struct AbbreviationError {
+    pub symbol: char,
+    pub message: String,
+}
+
+impl fmt::Display for AbbreviationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?})", self.message, self.symbol)
+    }
+}
+
+impl Error for AbbreviationError {}
+
+/// 記号とタグ名が省略記法に使えるか検査する。
+fn check_abbreviation(symbol: char, tag_name: &str) -> Result<(), AbbreviationError> {
+    let message = if matches!(symbol, ':' | '[' | ']' | '{' | '}') {
+        // タグの開始、属性、内容と区別できない
+        "The symbol is used in the tag syntax."
+    } else if symbol.is_whitespace() || symbol.is_control() {
+        // タグの終わりと区別できない
+        "The symbol is a whitespace or control character."
+    } else if symbol.is_alphanumeric() {
+        // タグ名の続きと区別できない
+        "The symbol is alphanumeric."
+    } else if tag_name.is_empty() || !NameSyntax::Namespaced.is_valid_name(tag_name) {
+        "The tag name is invalid."
+    } else {
+        return Ok(());
+    };
+
+    Err(AbbreviationError {
+        symbol,
+        message: message.to_owned(),
+    })
 }
 
 /// タグをパースする。
@@ -43,36 +206,29 @@ impl ContentModel for TagName {
 ///
 /// 開始位置はコロンである想定。
 pub fn parse_tag(unit_stream: &mut UnitStream, context: &mut ParseContext) -> ParseResult<TagName> {
+    let start = unit_stream.next_position();
+
     // 開始がコロンか省略記法でなければ不適合
     if let (Unit::Char(c), _) = unit_stream.read() {
-        let abbreviated_tag_name = match c {
-            '*' => Some("b".to_owned()),
-            '/' => Some("i".to_owned()),
-            '_' => Some("u".to_owned()),
-            '-' => Some("del".to_owned()),
-            '"' => Some("q".to_owned()),
-            '`' => Some("code".to_owned()),
-            '\\' => Some("raw".to_owned()),
-            '%' => Some("image".to_owned()),
-            '#' => Some("sequence".to_owned()),
-            '$' => Some("section".to_owned()),
-            '&' => Some("link".to_owned()),
-            '@' => Some("apply-template".to_owned()),
-            _ => None,
-        };
-
-        if let Some(abbreviated_tag_name) = abbreviated_tag_name {
-            return Ok(Some(TagName::new(abbreviated_tag_name, true)));
+        if let Some(abbreviated_tag_name) = context.options().get_abbreviations().tag_name(c) {
+            let span = Span::new(start, unit_stream.end_position());
+            return Ok(Some(TagName::new(
+                abbreviated_tag_name.to_owned(),
+                true,
+                span,
+            )));
         }
 
         if c == ':' {
-            if let Some(tag_name) = call_parser(symbol::parse_symbol, unit_stream, context)? {
-                return Ok(Some(TagName::new(tag_name, false)));
-            } else {
-                return Ok(Some(TagName::new("".to_owned(), false)));
-            }
+            let tag_name = call_parser(symbol::parse_symbol, unit_stream, context)?;
+            let span = Span::new(start, unit_stream.end_position());
+            Ok(Some(TagName::new(
+                tag_name.unwrap_or_default(),
+                false,
+                span,
+            )))
         } else {
-            return Ok(None);
+            Ok(None)
         }
     } else {
         Ok(None)
@@ -82,22 +238,83 @@ pub fn parse_tag(unit_stream: &mut UnitStream, context: &mut ParseContext) -> Pa
 pub fn parse_tag_and_attributes(
     unit_stream: &mut UnitStream,
     context: &mut ParseContext,
-) -> ParseResult<(TagName, Attributes, NamelessAttributeValues)> {
+) -> ParseResult<(TagName, Attributes)> {
     let tag_name = match call_parser(parse_tag, unit_stream, context)? {
         Some(tag_name) => tag_name,
         None => return Ok(None),
     };
 
-    let (attributes, nameless_attribute_values) =
-        match call_parser(parse_attributes, unit_stream, context)? {
-            Some(x) => x,
-            None => (
-                Attributes::with_capacity(0),
-                NamelessAttributeValues::with_capacity(0),
-            ),
-        };
+    let attributes = match call_parser(
+        parse_attributes,
+        unit_stream,
+        &mut context.change_tag_name(Some(tag_name.name().to_owned())),
+    )? {
+        Some(attributes) => attributes,
+        None => Attributes::new(),
+    };
 
-    Ok(Some((tag_name, attributes, nameless_attribute_values)))
+    Ok(Some((tag_name, attributes)))
+}
+
+#[cfg(test)]
+mod test_abbreviations {
+    use super::Abbreviations;
+
+    #[test]
+    fn test_standard() {
+        let abbreviations = Abbreviations::new();
+        assert_eq!(abbreviations.tag_name('*'), Some("b"));
+        assert_eq!(abbreviations.symbol("apply-template"), Some('@'));
+        assert_eq!(abbreviations.iter().count(), 12);
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut abbreviations = Abbreviations::new();
+        abbreviations.insert('^', "sup").unwrap();
+        abbreviations.insert('*', "strong").unwrap();
+        abbreviations.remove('_');
+        assert_eq!(abbreviations.tag_name('^'), Some("sup"));
+        assert_eq!(abbreviations.tag_name('*'), Some("strong"));
+        assert_eq!(abbreviations.tag_name('_'), None);
+        assert_eq!(abbreviations.symbol("b"), None);
+        assert_eq!(abbreviations.iter().count(), 12);
+
+        let mut abbreviations = Abbreviations::empty();
+        assert_eq!(abbreviations.tag_name('*'), None);
+        abbreviations.insert('=', "highlight").unwrap();
+        assert_eq!(
+            abbreviations.iter().collect::<Vec<_>>(),
+            vec![('=', "highlight")]
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut abbreviations = Abbreviations::new();
+        for (symbol, tag_name, message) in [
+            (':', "x", "The symbol is used in the tag syntax. (':')"),
+            ('{', "x", "The symbol is used in the tag syntax. ('{')"),
+            (
+                ' ',
+                "x",
+                "The symbol is a whitespace or control character. (' ')",
+            ),
+            ('a', "x", "The symbol is alphanumeric. ('a')"),
+            ('注', "x", "The symbol is alphanumeric. ('注')"),
+            ('^', "", "The tag name is invalid. ('^')"),
+            ('^', "a b", "The tag name is invalid. ('^')"),
+        ] {
+            assert_eq!(
+                abbreviations
+                    .insert(symbol, tag_name)
+                    .unwrap_err()
+                    .to_string(),
+                message
+            );
+        }
+        assert_eq!(abbreviations, Abbreviations::new());
+    }
 }
 
 #[cfg(test)]
@@ -105,9 +322,13 @@ mod test_parse_tag {
     use std::error::Error;
 
     use super::parse_tag;
+    use super::Abbreviations;
     use super::TagName;
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
     use crate::build::step2::test_utils::unit_stream;
     use crate::build::step3::ParseContext;
+    use crate::build::step3::ParseOptions;
 
     #[test]
     fn test_normal() -> Result<(), Box<dyn Error>> {
@@ -117,7 +338,14 @@ mod test_parse_tag {
         let mut context = ParseContext::new(&mut warnings);
         assert_eq!(
             parse_tag(&mut us, &mut context).unwrap(),
-            Some(TagName::new("mytag".to_owned(), false))
+            Some(TagName::new(
+                "mytag".to_owned(),
+                false,
+                Span::new(
+                    Position::with_byte_offset(1, 1, 0),
+                    Position::with_byte_offset(1, 7, 6)
+                )
+            ))
         );
         Ok(())
     }
@@ -130,11 +358,48 @@ mod test_parse_tag {
         let mut context = ParseContext::new(&mut warnings);
         assert_eq!(
             parse_tag(&mut us, &mut context).unwrap(),
-            Some(TagName::new("link".to_owned(), true))
+            Some(TagName::new(
+                "link".to_owned(),
+                true,
+                Span::new(
+                    Position::with_byte_offset(1, 1, 0),
+                    Position::with_byte_offset(1, 2, 1)
+                )
+            ))
         );
         Ok(())
     }
 
+    #[test]
+    fn test_custom_abbreviation() -> Result<(), Box<dyn Error>> {
+        let mut abbreviations = Abbreviations::new();
+        abbreviations.insert('=', "highlight")?;
+        abbreviations.remove('&');
+        let options = ParseOptions::new().abbreviations(abbreviations);
+
+        let mut us = unit_stream("={a}")?;
+        us.read();
+        let mut warnings = vec![];
+        let mut context = ParseContext::with_options(&mut warnings, &options);
+        assert_eq!(
+            parse_tag(&mut us, &mut context).unwrap(),
+            Some(TagName::new(
+                "highlight".to_owned(),
+                true,
+                Span::new(
+                    Position::with_byte_offset(1, 1, 0),
+                    Position::with_byte_offset(1, 2, 1)
+                )
+            ))
+        );
+
+        let mut us = unit_stream("&[a]")?;
+        us.read();
+        let mut context = ParseContext::with_options(&mut warnings, &options);
+        assert_eq!(parse_tag(&mut us, &mut context).unwrap(), None);
+        Ok(())
+    }
+
     #[test]
     fn test_mismatched() -> Result<(), Box<dyn Error>> {
         let mut us = unit_stream("<{}")?;
@@ -161,7 +426,6 @@ mod test_parse_tag_and_attributes {
     use std::error::Error;
 
     use super::parse_tag_and_attributes;
-    use super::TagName;
     use crate::build::step2::test_utils::unit_stream;
     use crate::build::step3::ParseContext;
 
@@ -171,13 +435,15 @@ mod test_parse_tag_and_attributes {
         us.read();
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
-        let (tag_name, attributes, values) = parse_tag_and_attributes(&mut us, &mut context)
+        let (tag_name, attributes) = parse_tag_and_attributes(&mut us, &mut context)
             .unwrap()
             .unwrap();
-        assert_eq!(tag_name, TagName::new("font".to_owned(), false));
-        assert_eq!(attributes.len(), 0);
+        assert_eq!(tag_name.name(), "font");
+        assert!(!tag_name.abbreviation());
+        assert_eq!(attributes.named().count(), 0);
+        let values = attributes.nameless().collect::<Vec<_>>();
         assert_eq!(values.len(), 1);
-        assert_eq!(values[0], "gothic");
+        assert_eq!(*values[0], "gothic");
         Ok(())
     }
 
@@ -187,12 +453,12 @@ mod test_parse_tag_and_attributes {
         us.read();
         let mut warnings = vec![];
         let mut context = ParseContext::new(&mut warnings);
-        let (tag_name, attributes, values) = parse_tag_and_attributes(&mut us, &mut context)
+        let (tag_name, attributes) = parse_tag_and_attributes(&mut us, &mut context)
             .unwrap()
             .unwrap();
-        assert_eq!(tag_name, TagName::new("i".to_owned(), false));
-        assert_eq!(attributes.len(), 0);
-        assert_eq!(values.len(), 0);
+        assert_eq!(tag_name.name(), "i");
+        assert!(!tag_name.abbreviation());
+        assert!(attributes.is_empty());
         Ok(())
     }
 
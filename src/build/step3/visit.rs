@@ -0,0 +1,655 @@
+use std::mem;
+
+use crate::build::step1::Span;
+use crate::build::step3::attribute::AttributeItem;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::block_tag_header::BlockTagHeader;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::paragraph::Paragraph;
+use crate::build::step3::tag::TagName;
+use crate::build::step3::BlockContents;
+use crate::build::step3::BlockNode;
+use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
+use crate::build::step3::Text;
+
+/// 走査中の要素を囲んでいるタグ。
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ancestor<'a> {
+    BlockTag(&'a BlockTag),
+    InlineTag(&'a InlineTag),
+}
+
+impl<'a> Ancestor<'a> {
+    pub fn name(&self) -> &'a TagName {
+        match self {
+            Ancestor::BlockTag(block_tag) => block_tag.name(),
+            Ancestor::InlineTag(inline_tag) => inline_tag.name(),
+        }
+    }
+}
+
+/// ツリーを深さ優先で走査する時に呼ばれるフック。
+/// ancestorsは外側のタグから順に並んでいて、走査中の要素自身は含まない。
+pub trait Visitor<'a> {
+    fn enter_block(&mut self, _block: &'a Block, _ancestors: &[Ancestor<'a>]) {}
+
+    fn leave_block(&mut self, _block: &'a Block, _ancestors: &[Ancestor<'a>]) {}
+
+    fn enter_paragraph(&mut self, _paragraph: &'a Paragraph, _ancestors: &[Ancestor<'a>]) {}
+
+    fn leave_paragraph(&mut self, _paragraph: &'a Paragraph, _ancestors: &[Ancestor<'a>]) {}
+
+    fn enter_block_tag(&mut self, _block_tag: &'a BlockTag, _ancestors: &[Ancestor<'a>]) {}
+
+    fn leave_block_tag(&mut self, _block_tag: &'a BlockTag, _ancestors: &[Ancestor<'a>]) {}
+
+    fn enter_block_tag_header(&mut self, _header: &'a BlockTagHeader, _ancestors: &[Ancestor<'a>]) {
+    }
+
+    fn leave_block_tag_header(&mut self, _header: &'a BlockTagHeader, _ancestors: &[Ancestor<'a>]) {
+    }
+
+    fn enter_inline_tag(&mut self, _inline_tag: &'a InlineTag, _ancestors: &[Ancestor<'a>]) {}
+
+    fn leave_inline_tag(&mut self, _inline_tag: &'a InlineTag, _ancestors: &[Ancestor<'a>]) {}
+
+    fn visit_text(&mut self, _text: &'a Text, _ancestors: &[Ancestor<'a>]) {}
+
+    fn visit_blank_line(&mut self, _span: &'a Span, _ancestors: &[Ancestor<'a>]) {}
+
+    fn visit_error(&mut self, _span: &'a Span, _ancestors: &[Ancestor<'a>]) {}
+}
+
+/// ブロックを深さ優先で走査する。
+pub fn walk_block<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, block: &'a Block) {
+    Walker {
+        visitor,
+        ancestors: vec![],
+    }
+    .walk_block(block);
+}
+
+struct Walker<'a, 'v, V: Visitor<'a> + ?Sized> {
+    visitor: &'v mut V,
+    ancestors: Vec<Ancestor<'a>>,
+}
+
+impl<'a, 'v, V: Visitor<'a> + ?Sized> Walker<'a, 'v, V> {
+    fn walk_block(&mut self, block: &'a Block) {
+        self.visitor.enter_block(block, &self.ancestors);
+        for node in block.contents() {
+            self.walk_block_node(node);
+        }
+        self.visitor.leave_block(block, &self.ancestors);
+    }
+
+    fn walk_block_node(&mut self, node: &'a BlockNode) {
+        match node {
+            BlockNode::Paragraph(paragraph) => {
+                self.visitor.enter_paragraph(paragraph, &self.ancestors);
+                self.walk_inline_contents(paragraph.contents());
+                self.visitor.leave_paragraph(paragraph, &self.ancestors);
+            }
+            BlockNode::BlockTag(block_tag) => {
+                self.visitor.enter_block_tag(block_tag, &self.ancestors);
+                self.ancestors.push(Ancestor::BlockTag(block_tag));
+                if let Some(header) = block_tag.header() {
+                    self.visitor.enter_block_tag_header(header, &self.ancestors);
+                    self.walk_inline_contents(header.contents());
+                    self.visitor.leave_block_tag_header(header, &self.ancestors);
+                }
+                if let Some(block) = block_tag.contents() {
+                    self.walk_block(block);
+                }
+                self.ancestors.pop();
+                self.visitor.leave_block_tag(block_tag, &self.ancestors);
+            }
+            BlockNode::Block(block) => self.walk_block(block),
+            BlockNode::BlankLine(span) => self.visitor.visit_blank_line(span, &self.ancestors),
+            BlockNode::Error(span) => self.visitor.visit_error(span, &self.ancestors),
+        }
+    }
+
+    fn walk_inline_contents(&mut self, contents: &'a InlineContents) {
+        for node in contents {
+            match node {
+                InlineNode::Text(text) => self.visitor.visit_text(text, &self.ancestors),
+                InlineNode::InlineTag(inline_tag) => {
+                    self.visitor.enter_inline_tag(inline_tag, &self.ancestors);
+                    self.ancestors.push(Ancestor::InlineTag(inline_tag));
+                    self.walk_inline_contents(inline_tag.contents());
+                    self.ancestors.pop();
+                    self.visitor.leave_inline_tag(inline_tag, &self.ancestors);
+                }
+            }
+        }
+    }
+}
+
+/// ツリーを組み替える。
+/// 各メソッドは受け取った要素の代わりに置く要素を返す。
+/// 空なら要素を取り除き、複数なら展開する。
+/// デフォルトの実装は子要素を再帰的に組み替えてから自身を返す。
+pub trait Fold {
+    fn fold_block(&mut self, block: Block) -> Vec<BlockNode> {
+        let block = fold_block_contents(self, block);
+        if block.contents().is_empty() {
+            vec![]
+        } else {
+            vec![BlockNode::Block(block)]
+        }
+    }
+
+    fn fold_paragraph(&mut self, paragraph: Paragraph) -> Vec<BlockNode> {
+        let paragraph = fold_paragraph_contents(self, paragraph);
+        if paragraph.contents().is_empty() {
+            vec![]
+        } else {
+            vec![BlockNode::Paragraph(paragraph)]
+        }
+    }
+
+    fn fold_block_tag(&mut self, block_tag: BlockTag) -> Vec<BlockNode> {
+        vec![BlockNode::BlockTag(Box::new(fold_block_tag_contents(
+            self, block_tag,
+        )))]
+    }
+
+    fn fold_blank_line(&mut self, span: Span) -> Vec<BlockNode> {
+        vec![BlockNode::BlankLine(span)]
+    }
+
+    fn fold_error(&mut self, span: Span) -> Vec<BlockNode> {
+        vec![BlockNode::Error(span)]
+    }
+
+    fn fold_inline_tag(&mut self, inline_tag: InlineTag) -> Vec<InlineNode> {
+        vec![InlineNode::InlineTag(fold_inline_tag_contents(
+            self, inline_tag,
+        ))]
+    }
+
+    fn fold_text(&mut self, text: Text) -> Vec<InlineNode> {
+        vec![InlineNode::Text(text)]
+    }
+}
+
+/// ブロックの要素を組み替える。
+pub fn fold_block_contents<F: Fold + ?Sized>(folder: &mut F, mut block: Block) -> Block {
+    let contents = mem::take(block.contents_mut());
+    *block.contents_mut() = fold_block_nodes(folder, contents);
+    block
+}
+
+/// 段落の内容を組み替える。
+pub fn fold_paragraph_contents<F: Fold + ?Sized>(
+    folder: &mut F,
+    mut paragraph: Paragraph,
+) -> Paragraph {
+    let contents = mem::take(paragraph.contents_mut());
+    *paragraph.contents_mut() = fold_inline_nodes(folder, contents);
+    paragraph
+}
+
+/// ブロックタグのヘッダーと内容を組み替える。
+/// 空になったヘッダーと内容は取り除く。
+pub fn fold_block_tag_contents<F: Fold + ?Sized>(
+    folder: &mut F,
+    mut block_tag: BlockTag,
+) -> BlockTag {
+    if let Some(mut header) = block_tag.header_mut().take() {
+        let contents = mem::take(header.contents_mut());
+        *header.contents_mut() = fold_inline_nodes(folder, contents);
+        if !header.contents().is_empty() {
+            *block_tag.header_mut() = Some(header);
+        }
+    }
+
+    if let Some(block) = block_tag.contents_mut().take() {
+        let block = fold_block_contents(folder, block);
+        if !block.contents().is_empty() {
+            *block_tag.contents_mut() = Some(block);
+        }
+    }
+
+    block_tag
+}
+
+/// インラインタグの内容を組み替える。
+pub fn fold_inline_tag_contents<F: Fold + ?Sized>(
+    folder: &mut F,
+    mut inline_tag: InlineTag,
+) -> InlineTag {
+    let contents = mem::take(inline_tag.contents_mut());
+    *inline_tag.contents_mut() = fold_inline_nodes(folder, contents);
+    inline_tag
+}
+
+fn fold_block_nodes<F: Fold + ?Sized>(folder: &mut F, contents: BlockContents) -> BlockContents {
+    let mut result = vec![];
+
+    for node in contents {
+        let nodes = match node {
+            BlockNode::Paragraph(paragraph) => folder.fold_paragraph(paragraph),
+            BlockNode::BlockTag(block_tag) => folder.fold_block_tag(*block_tag),
+            BlockNode::Block(block) => folder.fold_block(block),
+            BlockNode::BlankLine(span) => folder.fold_blank_line(span),
+            BlockNode::Error(span) => folder.fold_error(span),
+        };
+        result.extend(nodes);
+    }
+
+    result
+}
+
+fn fold_inline_nodes<F: Fold + ?Sized>(folder: &mut F, contents: InlineContents) -> InlineContents {
+    let mut result: InlineContents = vec![];
+
+    for node in contents {
+        let nodes = match node {
+            InlineNode::Text(text) => folder.fold_text(text),
+            InlineNode::InlineTag(inline_tag) => folder.fold_inline_tag(inline_tag),
+        };
+
+        for node in nodes {
+            // パーサーと同じくテキストが連続しないように連結する
+            if let (Some(InlineNode::Text(last)), InlineNode::Text(text)) =
+                (result.last_mut(), &node)
+            {
+                last.value_mut().push_str(text.value());
+                last.span_mut().end = text.span().end.clone();
+                continue;
+            }
+            result.push(node);
+        }
+    }
+
+    result
+}
+
+/// ブロックの要素と子孫の範囲を全て書き換える。
+pub fn map_spans<F: FnMut(&mut Span)>(nodes: &mut [BlockNode], f: &mut F) {
+    for node in nodes {
+        match node {
+            BlockNode::Paragraph(paragraph) => {
+                f(paragraph.span_mut());
+                map_inline_spans(paragraph.contents_mut(), f);
+            }
+            BlockNode::BlockTag(block_tag) => {
+                f(block_tag.span_mut());
+                f(block_tag.name_mut().span_mut());
+                map_attribute_spans(block_tag.attributes_mut(), f);
+                if let Some(header) = block_tag.header_mut() {
+                    f(header.span_mut());
+                    map_inline_spans(header.contents_mut(), f);
+                }
+                if let Some(contents) = block_tag.contents_mut() {
+                    f(contents.span_mut());
+                    map_spans(contents.contents_mut(), f);
+                }
+            }
+            BlockNode::Block(block) => {
+                f(block.span_mut());
+                map_spans(block.contents_mut(), f);
+            }
+            BlockNode::BlankLine(span) | BlockNode::Error(span) => f(span),
+        }
+    }
+}
+
+/// インラインの要素と子孫の範囲を全て書き換える。
+pub fn map_inline_spans<F: FnMut(&mut Span)>(nodes: &mut [InlineNode], f: &mut F) {
+    for node in nodes {
+        match node {
+            InlineNode::Text(text) => f(text.span_mut()),
+            InlineNode::InlineTag(inline_tag) => {
+                f(inline_tag.span_mut());
+                f(inline_tag.name_mut().span_mut());
+                map_attribute_spans(inline_tag.attributes_mut(), f);
+                map_inline_spans(inline_tag.contents_mut(), f);
+            }
+        }
+    }
+}
+
+fn map_attribute_spans<F: FnMut(&mut Span)>(attributes: &mut Attributes, f: &mut F) {
+    for item in attributes.items_mut() {
+        match item {
+            AttributeItem::Named(attribute) => {
+                f(attribute.name_span_mut());
+                f(attribute.value_mut().span_mut());
+            }
+            AttributeItem::Nameless(value) => f(value.span_mut()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_walk_block {
+    use super::walk_block;
+    use super::Ancestor;
+    use super::Visitor;
+    use crate::build::step1::Span;
+    use crate::build::step2::test_utils::unit_stream;
+    use crate::build::step3::block::parse_block;
+    use crate::build::step3::block::Block;
+    use crate::build::step3::block_tag::BlockTag;
+    use crate::build::step3::block_tag_header::BlockTagHeader;
+    use crate::build::step3::inline_tag::InlineTag;
+    use crate::build::step3::paragraph::Paragraph;
+    use crate::build::step3::ParseContext;
+    use crate::build::step3::Text;
+    use indoc::indoc;
+
+    fn parse(source: &str) -> Block {
+        let mut us = unit_stream(source).unwrap();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        parse_block(&mut us, &mut context).unwrap().unwrap()
+    }
+
+    /// 呼ばれたフックを記録する
+    struct Recorder {
+        events: Vec<String>,
+    }
+
+    fn path(ancestors: &[Ancestor]) -> String {
+        ancestors
+            .iter()
+            .map(|ancestor| ancestor.name().name())
+            .collect::<Vec<&str>>()
+            .join("/")
+    }
+
+    impl<'a> Visitor<'a> for Recorder {
+        fn enter_block(&mut self, _: &'a Block, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!("+b {}", path(ancestors)));
+        }
+
+        fn leave_block(&mut self, _: &'a Block, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!("-b {}", path(ancestors)));
+        }
+
+        fn enter_paragraph(&mut self, _: &'a Paragraph, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!("+p {}", path(ancestors)));
+        }
+
+        fn leave_paragraph(&mut self, _: &'a Paragraph, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!("-p {}", path(ancestors)));
+        }
+
+        fn enter_block_tag(&mut self, block_tag: &'a BlockTag, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!(
+                "+bt:{} {}",
+                block_tag.name().name(),
+                path(ancestors)
+            ));
+        }
+
+        fn leave_block_tag(&mut self, block_tag: &'a BlockTag, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!(
+                "-bt:{} {}",
+                block_tag.name().name(),
+                path(ancestors)
+            ));
+        }
+
+        fn enter_block_tag_header(&mut self, _: &'a BlockTagHeader, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!("+h {}", path(ancestors)));
+        }
+
+        fn leave_block_tag_header(&mut self, _: &'a BlockTagHeader, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!("-h {}", path(ancestors)));
+        }
+
+        fn enter_inline_tag(&mut self, inline_tag: &'a InlineTag, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!(
+                "+it:{} {}",
+                inline_tag.name().name(),
+                path(ancestors)
+            ));
+        }
+
+        fn leave_inline_tag(&mut self, inline_tag: &'a InlineTag, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!(
+                "-it:{} {}",
+                inline_tag.name().name(),
+                path(ancestors)
+            ));
+        }
+
+        fn visit_text(&mut self, text: &'a Text, ancestors: &[Ancestor<'a>]) {
+            self.events
+                .push(format!("t:{} {}", text.value().trim_end(), path(ancestors)));
+        }
+
+        fn visit_blank_line(&mut self, _: &'a Span, ancestors: &[Ancestor<'a>]) {
+            self.events.push(format!("bl {}", path(ancestors)));
+        }
+    }
+
+    /// 深さ優先で祖先のタグと共にフックが呼ばれる
+    #[test]
+    fn test_order() {
+        let block = parse(indoc! {"
+            abc
+
+            :table head:b{x}
+                :column
+                    :link{y}
+        "});
+
+        let mut recorder = Recorder { events: vec![] };
+        walk_block(&mut recorder, &block);
+
+        assert_eq!(
+            recorder.events,
+            vec![
+                "+b ",
+                "+p ",
+                "t:abc ",
+                "-p ",
+                "bl ",
+                "+bt:table ",
+                "+h table",
+                "t:head table",
+                "+it:b table",
+                "t:x table/b",
+                "-it:b table",
+                "-h table",
+                "+b table",
+                "+bt:column table",
+                "+b table/column",
+                "+p table/column",
+                "+it:link table/column",
+                "t:y table/column/link",
+                "-it:link table/column",
+                "t: table/column",
+                "-p table/column",
+                "-b table/column",
+                "-bt:column table",
+                "-b table",
+                "-bt:table ",
+                "-b ",
+            ]
+        );
+    }
+
+    /// 参照をツリーと同じライフタイムで集められる
+    #[test]
+    fn test_collect() {
+        struct Collector<'a> {
+            links: Vec<&'a InlineTag>,
+        }
+
+        impl<'a> Visitor<'a> for Collector<'a> {
+            fn enter_inline_tag(&mut self, inline_tag: &'a InlineTag, _: &[Ancestor<'a>]) {
+                if inline_tag.name().name() == "link" {
+                    self.links.push(inline_tag);
+                }
+            }
+        }
+
+        let block = parse(":link[a]{x} :b{:link[b]{y}}");
+
+        let mut collector = Collector { links: vec![] };
+        walk_block(&mut collector, &block);
+
+        assert_eq!(collector.links.len(), 2);
+        assert_eq!(
+            collector.links[0].attributes().nameless().next().unwrap(),
+            "a"
+        );
+        assert_eq!(
+            collector.links[1].attributes().nameless().next().unwrap(),
+            "b"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_fold {
+    use super::fold_block_contents;
+    use super::fold_inline_tag_contents;
+    use super::Fold;
+    use crate::build::step2::test_utils::unit_stream;
+    use crate::build::step3::block::parse_block;
+    use crate::build::step3::block::Block;
+    use crate::build::step3::block_tag::BlockTag;
+    use crate::build::step3::inline_tag::InlineTag;
+    use crate::build::step3::test_utils::assert_model;
+    use crate::build::step3::BlockNode;
+    use crate::build::step3::InlineNode;
+    use crate::build::step3::ParseContext;
+    use crate::build::step3::Text;
+    use indoc::indoc;
+
+    fn parse(source: &str) -> Block {
+        let mut us = unit_stream(source).unwrap();
+        let mut warnings = vec![];
+        let mut context = ParseContext::new(&mut warnings);
+        parse_block(&mut us, &mut context).unwrap().unwrap()
+    }
+
+    /// 何もしなければ元のツリーと同じ
+    #[test]
+    fn test_identity() {
+        struct Identity;
+        impl Fold for Identity {}
+
+        let source = indoc! {"
+            abc:b{x}
+
+            :tag[a=1] head
+                xyz
+        "};
+
+        let block = fold_block_contents(&mut Identity, parse(source));
+
+        assert_eq!(block, parse(source));
+    }
+
+    /// 属性を書き換える
+    #[test]
+    fn test_rewrite_attribute() {
+        struct RewriteLinks;
+        impl Fold for RewriteLinks {
+            fn fold_inline_tag(&mut self, inline_tag: InlineTag) -> Vec<InlineNode> {
+                let mut inline_tag = fold_inline_tag_contents(self, inline_tag);
+                if inline_tag.name().name() == "link" {
+                    for value in inline_tag.attributes_mut().nameless_mut() {
+                        *value.value_mut() = format!("https://example.com/{}", value.value());
+                    }
+                }
+                vec![InlineNode::InlineTag(inline_tag)]
+            }
+        }
+
+        let block = fold_block_contents(&mut RewriteLinks, parse(":b{:link[a]{x}}"));
+
+        assert_model(
+            &block,
+            r#"{"b":[{"p":[
+                {"it":"b","c":[
+                    {"it":"link","v":["https://example.com/a"],"c":["x"]}
+                ]}
+            ]}]}"#,
+        );
+    }
+
+    /// 要素を取り除く
+    /// 前後のテキストは連結され、空になったブロックタグの内容は取り除かれる
+    #[test]
+    fn test_remove() {
+        struct StripRawHtml;
+        impl Fold for StripRawHtml {
+            fn fold_inline_tag(&mut self, inline_tag: InlineTag) -> Vec<InlineNode> {
+                if inline_tag.name().name() == "raw-html" {
+                    vec![]
+                } else {
+                    vec![InlineNode::InlineTag(fold_inline_tag_contents(
+                        self, inline_tag,
+                    ))]
+                }
+            }
+
+            fn fold_block_tag(&mut self, block_tag: BlockTag) -> Vec<BlockNode> {
+                if block_tag.name().name() == "raw-html" {
+                    vec![]
+                } else {
+                    vec![BlockNode::BlockTag(Box::new(
+                        super::fold_block_tag_contents(self, block_tag),
+                    ))]
+                }
+            }
+        }
+
+        let block = fold_block_contents(
+            &mut StripRawHtml,
+            parse(indoc! {"
+                abc:raw-html{<br>}xyz
+
+                :tag
+                    :raw-html
+                        <hr>
+            "}),
+        );
+
+        assert_model(
+            &block,
+            r#"{"b":[
+                {"p":["abcxyz\n"]},
+                "<bl>",
+                {"bt":"tag"}
+            ]}"#,
+        );
+    }
+
+    /// 1つの要素を複数に展開する
+    #[test]
+    fn test_expand() {
+        struct Unwrap;
+        impl Fold for Unwrap {
+            fn fold_inline_tag(&mut self, inline_tag: InlineTag) -> Vec<InlineNode> {
+                let mut inline_tag = fold_inline_tag_contents(self, inline_tag);
+                if inline_tag.name().name() == "span" {
+                    std::mem::take(inline_tag.contents_mut())
+                } else {
+                    vec![InlineNode::InlineTag(inline_tag)]
+                }
+            }
+
+            fn fold_text(&mut self, mut text: Text) -> Vec<InlineNode> {
+                *text.value_mut() = text.value().to_uppercase();
+                vec![InlineNode::Text(text)]
+            }
+        }
+
+        let block = fold_block_contents(&mut Unwrap, parse("a:span{b:i{c}d}e"));
+
+        assert_model(&block, r#"{"b":[{"p":["AB",{"it":"i","c":["C"]},"DE"]}]}"#);
+    }
+}
@@ -0,0 +1,379 @@
+use std::path::PathBuf;
+
+use crate::build::step1::Position;
+use crate::build::step1::Span;
+use crate::build::step3::attribute::Attribute;
+use crate::build::step3::attribute::AttributeItem;
+use crate::build::step3::attribute::AttributeValue;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::block_tag_header::BlockTagHeader;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::paragraph::Paragraph;
+use crate::build::step3::tag::TagName;
+use crate::build::step3::BlockContents;
+use crate::build::step3::BlockNode;
+use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
+use crate::build::step3::Text;
+use crate::document::Document;
+
+/// 組み立てた要素の範囲。ソースがないので先頭の位置にする。
+fn empty_span() -> Span {
+    Span::new(Position::new(1, 1), Position::new(1, 1))
+}
+
+/// ブロックを組み立てる。
+///
+/// 段落はパースした時と同じになるように、最後を改行にして、続く段落との間に空行を入れる。
+#[derive(Default)]
+pub struct BlockBuilder {
+    contents: BlockContents,
+}
+
+impl BlockBuilder {
+    pub fn new() -> BlockBuilder {
+        BlockBuilder::default()
+    }
+
+    pub fn paragraph(mut self, contents: InlineBuilder) -> BlockBuilder {
+        let mut contents = contents.build();
+        if contents.is_empty() {
+            return self;
+        }
+
+        match contents.last_mut() {
+            Some(InlineNode::Text(text)) if text.value().ends_with('\n') => {}
+            Some(InlineNode::Text(text)) => text.value_mut().push('\n'),
+            _ => contents.push(InlineNode::Text(Text::new("\n".to_owned(), empty_span()))),
+        }
+
+        if let Some(BlockNode::Paragraph(_)) = self.contents.last() {
+            self.contents.push(BlockNode::BlankLine(empty_span()));
+        }
+
+        self.contents
+            .push(BlockNode::Paragraph(Paragraph::new(contents, empty_span())));
+        self
+    }
+
+    pub fn block_tag(mut self, block_tag: BlockTagBuilder) -> BlockBuilder {
+        self.contents
+            .push(BlockNode::BlockTag(Box::new(block_tag.build())));
+        self
+    }
+
+    /// 一段深いブロックを追加する。
+    pub fn block(mut self, block: BlockBuilder) -> BlockBuilder {
+        if !block.contents.is_empty() {
+            self.contents.push(BlockNode::Block(block.build()));
+        }
+        self
+    }
+
+    pub fn blank_line(mut self) -> BlockBuilder {
+        self.contents.push(BlockNode::BlankLine(empty_span()));
+        self
+    }
+
+    pub fn build(self) -> Block {
+        Block::new(self.contents, empty_span())
+    }
+
+    /// ブロックをルートにしたドキュメントを組み立てる。
+    pub fn build_document<P: Into<PathBuf>>(self, filepath: P) -> Document {
+        Document::new(filepath.into(), self.build(), vec![])
+    }
+}
+
+/// インラインの要素を組み立てる。
+/// 続けて追加したテキストは1つにまとめる。
+#[derive(Default)]
+pub struct InlineBuilder {
+    contents: InlineContents,
+}
+
+impl InlineBuilder {
+    pub fn new() -> InlineBuilder {
+        InlineBuilder::default()
+    }
+
+    pub fn text<S: Into<String>>(mut self, text: S) -> InlineBuilder {
+        let text = text.into();
+        if text.is_empty() {
+            return self;
+        }
+
+        match self.contents.last_mut() {
+            Some(InlineNode::Text(last)) => last.value_mut().push_str(&text),
+            _ => self
+                .contents
+                .push(InlineNode::Text(Text::new(text, empty_span()))),
+        }
+        self
+    }
+
+    pub fn inline_tag(mut self, inline_tag: InlineTagBuilder) -> InlineBuilder {
+        self.contents
+            .push(InlineNode::InlineTag(inline_tag.build()));
+        self
+    }
+
+    pub fn build(self) -> InlineContents {
+        self.contents
+    }
+}
+
+/// タグの属性を組み立てる。
+#[derive(Default)]
+struct AttributesBuilder {
+    attributes: Attributes,
+}
+
+impl AttributesBuilder {
+    fn attribute(&mut self, name: String, value: String) {
+        let attribute =
+            Attribute::new(name, empty_span(), AttributeValue::new(value, empty_span()));
+        self.attributes.push(AttributeItem::Named(attribute));
+    }
+
+    fn nameless_attribute(&mut self, value: String) {
+        self.attributes
+            .push(AttributeItem::Nameless(AttributeValue::new(
+                value,
+                empty_span(),
+            )));
+    }
+}
+
+/// ブロックタグを組み立てる。
+pub struct BlockTagBuilder {
+    name: String,
+    attributes: AttributesBuilder,
+    header: InlineContents,
+    contents: BlockContents,
+}
+
+impl BlockTagBuilder {
+    pub fn new<S: Into<String>>(name: S) -> BlockTagBuilder {
+        BlockTagBuilder {
+            name: name.into(),
+            attributes: AttributesBuilder::default(),
+            header: vec![],
+            contents: vec![],
+        }
+    }
+
+    /// 名前付きの属性を追加する。属性は追加した順に並ぶ。
+    pub fn attribute<N: Into<String>, V: Into<String>>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> BlockTagBuilder {
+        self.attributes.attribute(name.into(), value.into());
+        self
+    }
+
+    pub fn nameless_attribute<V: Into<String>>(mut self, value: V) -> BlockTagBuilder {
+        self.attributes.nameless_attribute(value.into());
+        self
+    }
+
+    pub fn header(mut self, header: InlineBuilder) -> BlockTagBuilder {
+        self.header = header.build();
+        self
+    }
+
+    pub fn contents(mut self, contents: BlockBuilder) -> BlockTagBuilder {
+        self.contents = contents.contents;
+        self
+    }
+
+    pub fn build(self) -> BlockTag {
+        BlockTag::new(
+            TagName::new(self.name, false, empty_span()),
+            self.attributes.attributes,
+            (!self.header.is_empty()).then(|| BlockTagHeader::new(self.header, empty_span())),
+            (!self.contents.is_empty()).then(|| Block::new(self.contents, empty_span())),
+            empty_span(),
+        )
+    }
+}
+
+/// インラインタグを組み立てる。
+pub struct InlineTagBuilder {
+    name: String,
+    attributes: AttributesBuilder,
+    contents: InlineContents,
+}
+
+impl InlineTagBuilder {
+    pub fn new<S: Into<String>>(name: S) -> InlineTagBuilder {
+        InlineTagBuilder {
+            name: name.into(),
+            attributes: AttributesBuilder::default(),
+            contents: vec![],
+        }
+    }
+
+    /// 名前付きの属性を追加する。属性は追加した順に並ぶ。
+    pub fn attribute<N: Into<String>, V: Into<String>>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> InlineTagBuilder {
+        self.attributes.attribute(name.into(), value.into());
+        self
+    }
+
+    pub fn nameless_attribute<V: Into<String>>(mut self, value: V) -> InlineTagBuilder {
+        self.attributes.nameless_attribute(value.into());
+        self
+    }
+
+    pub fn contents(mut self, contents: InlineBuilder) -> InlineTagBuilder {
+        self.contents = contents.build();
+        self
+    }
+
+    pub fn build(self) -> InlineTag {
+        InlineTag::new(
+            TagName::new(self.name, false, empty_span()),
+            self.attributes.attributes,
+            self.contents,
+            empty_span(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_builder {
+    use super::BlockBuilder;
+    use super::BlockTagBuilder;
+    use super::InlineBuilder;
+    use super::InlineTagBuilder;
+    use crate::build::step3::test_utils::assert_model;
+    use crate::format::emit_source;
+    use crate::format::FormatOptions;
+    use indoc::indoc;
+    use std::error::Error;
+
+    #[test]
+    fn test_build() {
+        let block = BlockBuilder::new()
+            .block_tag(
+                BlockTagBuilder::new("section")
+                    .attribute("id", "s1")
+                    .header(InlineBuilder::new().text("Title")),
+            )
+            .paragraph(
+                InlineBuilder::new().text("a ").text("b ").inline_tag(
+                    InlineTagBuilder::new("link")
+                        .nameless_attribute("https://example.com")
+                        .contents(InlineBuilder::new().text("c")),
+                ),
+            )
+            .paragraph(InlineBuilder::new().text("d"))
+            .build();
+
+        assert_model(
+            &block,
+            r#"{"b":[
+                {"bt":"section","a":{"id":"s1"},"h":["Title"]},
+                {"p":[
+                    "a b ",
+                    {"it":"link","v":["https://example.com"],"c":["c"]},
+                    "\n"
+                ]},
+                "<bl>",
+                {"p":["d\n"]}
+            ]}"#,
+        );
+    }
+
+    #[test]
+    fn test_emit() -> Result<(), Box<dyn Error>> {
+        let block = BlockBuilder::new()
+            .block_tag(
+                BlockTagBuilder::new("section")
+                    .attribute("title", "say \"hello\"")
+                    .nameless_attribute("")
+                    .nameless_attribute("a=b")
+                    .contents(
+                        BlockBuilder::new()
+                            .paragraph(
+                                InlineBuilder::new().text("text ").inline_tag(
+                                    InlineTagBuilder::new("b")
+                                        .contents(InlineBuilder::new().text("bold")),
+                                ),
+                            )
+                            .paragraph(
+                                InlineBuilder::new()
+                                    .inline_tag(InlineTagBuilder::new("br"))
+                                    .text(" next"),
+                            ),
+                    ),
+            )
+            .block_tag(
+                BlockTagBuilder::new("code-block").contents(
+                    BlockBuilder::new()
+                        .paragraph(InlineBuilder::new().text("fn main() {}"))
+                        .blank_line()
+                        .blank_line()
+                        .paragraph(InlineBuilder::new().text("}")),
+                ),
+            )
+            .build();
+
+        assert_eq!(
+            emit_source(&block, &FormatOptions::default())?,
+            indoc! {r#"
+                :section[title="say ""hello""" "" "a=b"]
+                    text :b{bold}
+
+                    :br{} next
+                :code-block
+                    fn main() {}
+
+
+                    }
+            "#}
+        );
+
+        Ok(())
+    }
+
+    /// ソースにできないものはエラー
+    #[test]
+    fn test_error() {
+        let options = FormatOptions::default();
+
+        let block = BlockBuilder::new()
+            .block_tag(BlockTagBuilder::new("a b"))
+            .build();
+        assert_eq!(
+            emit_source(&block, &options).unwrap_err().message,
+            "The tag name is invalid. (\"a b\")"
+        );
+
+        let block = BlockBuilder::new()
+            .block_tag(BlockTagBuilder::new("a").attribute("x", "1\n2"))
+            .build();
+        assert_eq!(
+            emit_source(&block, &options).unwrap_err().message,
+            "An attribute value cannot contain a new line. (\"1\\n2\")"
+        );
+
+        let block =
+            BlockBuilder::new()
+                .paragraph(InlineBuilder::new().inline_tag(
+                    InlineTagBuilder::new("code").contents(InlineBuilder::new().text("}")),
+                ))
+                .build();
+        assert_eq!(
+            emit_source(&block, &options).unwrap_err().message,
+            "The formatted source does not parse to the same tree."
+        );
+    }
+}
@@ -0,0 +1,873 @@
+//! ロスレスな具象構文木。
+//!
+//! パースしたツリーの範囲を元のソースに対応させて、
+//! パースで捨てられる空白、インデント、改行の種類、区切り記号、引用符、省略記法の記号、
+//! コメントをトークンとして保持する。
+//! 全てのトークンを順に連結すると元のソースと完全に一致する。
+
+use std::fmt;
+
+use crate::build::step1::decode;
+use crate::build::step1::Position;
+use crate::build::step2::IndentStyle;
+use crate::build::step3::attribute::AttributeItem;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::tag::TagName;
+use crate::build::step3::BlockNode;
+use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
+use crate::build::step3::ParseOptions;
+use crate::build::step3::Text;
+use crate::document::parse_binary;
+use crate::document::read_source;
+use crate::document::Diagnostics;
+use crate::document::Document;
+use crate::document::Source;
+use crate::schema::Schema;
+
+/// ノードの種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NodeKind {
+    Document,
+    Block,
+    Paragraph,
+    BlankLine,
+    /// パースエラーから回復した時に読み飛ばした範囲
+    Error,
+    BlockTag,
+    BlockTagHeader,
+    InlineTag,
+    Attributes,
+    Attribute,
+    Text,
+}
+
+/// トークンの種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    /// バイトオーダーマーク
+    Bom,
+    /// 改行。"\n"、"\r\n"、"\r"のいずれか。
+    NewLine,
+    /// 行頭のインデント
+    Indent,
+    /// インデント以外の読み飛ばされる空白
+    Whitespace,
+    /// 次の文字をそのままの文字にするバックスラッシュ
+    Escape,
+    /// タグの開始の":"
+    Colon,
+    /// 省略記法の記号
+    Abbreviation,
+    TagName,
+    LeftBracket,
+    RightBracket,
+    AttributeName,
+    Equals,
+    /// 引用符を含む、引用符付き属性値
+    QuotedAttributeValue,
+    SimpleAttributeValue,
+    LeftBrace,
+    RightBrace,
+    Text,
+    /// ":;"から行末までのコメント。ブロックのコメントの内容の行も1行ずつコメントにする。
+    Comment,
+    /// ツリーに含まれない文字。重複して捨てられた属性など。
+    Unknown,
+}
+
+/// トークン
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxToken {
+    kind: TokenKind,
+    text: String,
+}
+
+impl SyntaxToken {
+    pub fn kind(&self) -> TokenKind {
+        self.kind
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+}
+
+/// ノードの子要素
+#[derive(Clone, Debug, PartialEq)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+/// ノード
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxNode {
+    kind: NodeKind,
+    children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    fn new(kind: NodeKind) -> SyntaxNode {
+        SyntaxNode {
+            kind,
+            children: vec![],
+        }
+    }
+
+    pub fn kind(&self) -> NodeKind {
+        self.kind
+    }
+
+    pub fn children(&self) -> &[SyntaxElement] {
+        &self.children
+    }
+
+    /// 子孫のトークンを出現順に返す。
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a SyntaxToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token) => tokens.push(token),
+            }
+        }
+    }
+
+    /// 子孫のノードを深さ優先で返す。自身は含まない。
+    pub fn descendants(&self) -> Vec<&SyntaxNode> {
+        let mut nodes = vec![];
+        self.collect_nodes(&mut nodes);
+        nodes
+    }
+
+    fn collect_nodes<'a>(&'a self, nodes: &mut Vec<&'a SyntaxNode>) {
+        for child in &self.children {
+            if let SyntaxElement::Node(node) = child {
+                nodes.push(node);
+                node.collect_nodes(nodes);
+            }
+        }
+    }
+
+    /// ノードのソース上の文字列。
+    pub fn text(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in self.tokens() {
+            write!(f, "{}", token.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// ソースを読み込んで、ドキュメントとその具象構文木にパースする。
+pub fn parse_syntax_tree<S: Into<Source>>(
+    source: S,
+) -> Result<(Document, SyntaxNode), Diagnostics> {
+    parse_syntax_tree_with_options(source, &ParseOptions::default())
+}
+
+/// オプションを指定してソースを読み込み、ドキュメントとその具象構文木にパースする。
+pub fn parse_syntax_tree_with_options<S: Into<Source>>(
+    source: S,
+    options: &ParseOptions,
+) -> Result<(Document, SyntaxNode), Diagnostics> {
+    let (filepath, binary) = read_source(source.into())?;
+    let document = parse_binary(filepath, binary.clone(), options)?;
+
+    // 具象構文木はデコードした文字列に対して作る
+    let source = decode(&binary, options.get_encoding()).text;
+    let syntax_tree = build_syntax_tree_with_options(&source, document.block(), options);
+
+    Ok((document, syntax_tree))
+}
+
+/// ソースとそれをパースしたブロックから具象構文木を作る。
+pub fn build_syntax_tree(source: &str, block: &Block) -> SyntaxNode {
+    build_syntax_tree_with_options(source, block, &ParseOptions::default())
+}
+
+/// パースした時のオプションを指定して具象構文木を作る。
+pub fn build_syntax_tree_with_options(
+    source: &str,
+    block: &Block,
+    options: &ParseOptions,
+) -> SyntaxNode {
+    let mut node = SyntaxNode::new(NodeKind::Document);
+
+    let source = match source.strip_prefix('\u{feff}') {
+        Some(source) => {
+            node.children.push(SyntaxElement::Token(SyntaxToken {
+                kind: TokenKind::Bom,
+                text: '\u{feff}'.to_string(),
+            }));
+            source
+        }
+        None => source,
+    };
+
+    let mut builder = Builder::new(source, options.get_indent_style(), options.get_schema());
+
+    if !block.contents().is_empty() {
+        let start = builder.offset(&block.span().start);
+        builder.gap(&mut node, start, true);
+        let block = builder.block(block, 0);
+        node.children.push(SyntaxElement::Node(block));
+    }
+
+    builder.gap(&mut node, builder.chars.len(), true);
+
+    node
+}
+
+/// ソースを先頭から順にトークンに切り出していく。
+struct Builder<'a> {
+    chars: Vec<char>,
+    /// 各行の開始位置
+    line_starts: Vec<usize>,
+    cursor: usize,
+    indent_style: IndentStyle,
+    /// 内容をパースしないタグの中か。中ならバックスラッシュはエスケープではない
+    raw: bool,
+    /// 内容をパースしないタグを判断する定義
+    schema: &'a Schema,
+}
+
+impl<'a> Builder<'a> {
+    fn new(source: &str, indent_style: IndentStyle, schema: &'a Schema) -> Builder<'a> {
+        let chars = source.chars().collect::<Vec<char>>();
+
+        // 改行の扱いはCharStreamに合わせる
+        let mut line_starts = vec![0];
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '\r' if chars.get(i + 1) == Some(&'\n') => {
+                    i += 2;
+                    line_starts.push(i);
+                }
+                '\r' | '\n' => {
+                    i += 1;
+                    line_starts.push(i);
+                }
+                _ => i += 1,
+            }
+        }
+
+        Builder {
+            chars,
+            line_starts,
+            cursor: 0,
+            indent_style,
+            raw: false,
+            schema,
+        }
+    }
+
+    fn offset(&self, position: &Position) -> usize {
+        let line = position.line_number as usize - 1;
+        match self.line_starts.get(line) {
+            Some(start) => (start + position.column_number as usize - 1).min(self.chars.len()),
+            None => self.chars.len(),
+        }
+    }
+
+    fn is_new_line(&self, i: usize) -> bool {
+        matches!(self.chars.get(i), Some('\r') | Some('\n'))
+    }
+
+    fn is_head_of_line(&self) -> bool {
+        self.cursor == 0 || self.is_new_line(self.cursor - 1)
+    }
+
+    /// 読み込み位置からendまでをトークンにする。
+    fn token(&mut self, node: &mut SyntaxNode, kind: TokenKind, end: usize) {
+        if end <= self.cursor {
+            return;
+        }
+        let text = self.chars[self.cursor..end].iter().collect::<String>();
+        node.children
+            .push(SyntaxElement::Token(SyntaxToken { kind, text }));
+        self.cursor = end;
+    }
+
+    /// 改行を1つトークンにする。
+    fn new_line(&mut self, node: &mut SyntaxNode) {
+        let length =
+            if self.chars[self.cursor] == '\r' && self.chars.get(self.cursor + 1) == Some(&'\n') {
+                2
+            } else {
+                1
+            };
+        self.token(node, TokenKind::NewLine, self.cursor + length);
+    }
+
+    /// iからコメントが始まるか
+    fn is_comment(&self, i: usize) -> bool {
+        !self.raw && self.chars.get(i) == Some(&':') && self.chars.get(i + 1) == Some(&';')
+    }
+
+    /// 読み込み位置の行の終わり
+    fn line_end(&self, end: usize) -> usize {
+        let mut i = self.cursor;
+        while i < end && !self.is_new_line(i) {
+            i += 1;
+        }
+        i
+    }
+
+    /// 読み込み位置が行頭の空白の後ろなら、その空白の桁数を返す。
+    fn indent_columns(&self) -> Option<u64> {
+        let line_start = self.chars[..self.cursor]
+            .iter()
+            .rposition(|c| matches!(c, '\r' | '\n'))
+            .map_or(0, |i| i + 1);
+        self.chars[line_start..self.cursor]
+            .iter()
+            .try_fold(0, |columns, c| self.indent_style.advance(columns, *c))
+    }
+
+    /// 空白の連続の終わり
+    fn spaces_end(&self, end: usize) -> usize {
+        let mut i = self.cursor;
+        while i < end && matches!(self.chars[i], ' ' | '\t') {
+            i += 1;
+        }
+        i
+    }
+
+    /// 読み込み位置から、indentの桁数までのインデントの終わり
+    fn indent_end(&self, end: usize, indent: u64) -> usize {
+        let mut i = self.cursor;
+        let mut columns = 0;
+        while i < end && columns < indent {
+            match self.indent_style.advance(columns, self.chars[i]) {
+                Some(next_columns) => columns = next_columns,
+                None => break,
+            }
+            i += 1;
+        }
+        i
+    }
+
+    /// ツリーの要素の間にある空白や改行をトークンにする。
+    /// indentなら行頭の空白をインデントと見なす。
+    fn gap(&mut self, node: &mut SyntaxNode, end: usize, indent: bool) {
+        // ブロックのコメントの内容の行のインデントの桁数
+        let mut comment_body: Option<u64> = None;
+
+        while self.cursor < end {
+            if let Some(columns) = comment_body.filter(|_| self.is_head_of_line()) {
+                let spaces_end = self.spaces_end(end);
+                if spaces_end < end && !self.is_new_line(spaces_end) {
+                    let indent_end = self.indent_end(end, columns);
+                    let indent_columns = self.chars[self.cursor..indent_end]
+                        .iter()
+                        .try_fold(0, |c, ch| self.indent_style.advance(c, *ch));
+                    if indent_columns.is_some_and(|c| c >= columns) {
+                        self.token(node, TokenKind::Indent, indent_end);
+                        let line_end = self.line_end(end);
+                        self.token(node, TokenKind::Comment, line_end);
+                        continue;
+                    }
+                    // インデントが浅い行で内容は終わる
+                    comment_body = None;
+                }
+            }
+
+            if self.is_new_line(self.cursor) {
+                self.new_line(node);
+            } else if self.is_comment(self.cursor) {
+                // 行頭に書いたコメントはブロックのコメント
+                if indent {
+                    comment_body = self
+                        .indent_columns()
+                        .map(|columns| columns + self.indent_style.width);
+                }
+                let line_end = self.line_end(end);
+                self.token(node, TokenKind::Comment, line_end);
+            } else if matches!(self.chars[self.cursor], ' ' | '\t') {
+                let spaces_end = self.spaces_end(end);
+                // 空白だけの行の空白はインデントではない
+                let kind = if indent
+                    && self.is_head_of_line()
+                    && spaces_end < self.chars.len()
+                    && !self.is_new_line(spaces_end)
+                {
+                    TokenKind::Indent
+                } else {
+                    TokenKind::Whitespace
+                };
+                self.token(node, kind, spaces_end);
+            } else {
+                let mut i = self.cursor + 1;
+                while i < end
+                    && !self.is_new_line(i)
+                    && !matches!(self.chars[i], ' ' | '\t')
+                    && !self.is_comment(i)
+                {
+                    i += 1;
+                }
+                self.token(node, TokenKind::Unknown, i);
+            }
+        }
+    }
+
+    fn block(&mut self, block: &Block, indent: u64) -> SyntaxNode {
+        let mut node = SyntaxNode::new(NodeKind::Block);
+
+        for content in block.contents() {
+            let start = self.offset(&content.span().start);
+            self.gap(&mut node, start, true);
+
+            let child = match content {
+                BlockNode::Paragraph(paragraph) => {
+                    let mut child = SyntaxNode::new(NodeKind::Paragraph);
+                    self.inline_contents(&mut child, paragraph.contents(), Some(indent));
+                    child
+                }
+                BlockNode::BlockTag(block_tag) => self.block_tag(block_tag, indent),
+                BlockNode::Block(block) => self.block(block, indent + self.indent_style.width),
+                BlockNode::BlankLine(span) => {
+                    let mut child = SyntaxNode::new(NodeKind::BlankLine);
+                    let end = self.offset(&span.end);
+                    self.gap(&mut child, end, false);
+                    child
+                }
+                BlockNode::Error(span) => {
+                    let mut child = SyntaxNode::new(NodeKind::Error);
+                    let end = self.offset(&span.end);
+                    self.gap(&mut child, end, false);
+                    child
+                }
+            };
+            node.children.push(SyntaxElement::Node(child));
+        }
+
+        node
+    }
+
+    fn block_tag(&mut self, block_tag: &BlockTag, indent: u64) -> SyntaxNode {
+        let mut node = SyntaxNode::new(NodeKind::BlockTag);
+
+        self.tag(&mut node, block_tag.name(), block_tag.attributes());
+
+        if let Some(header) = block_tag.header() {
+            let start = self.offset(&header.span().start);
+            self.gap(&mut node, start, false);
+
+            let mut child = SyntaxNode::new(NodeKind::BlockTagHeader);
+            self.inline_contents(&mut child, header.contents(), None);
+            node.children.push(SyntaxElement::Node(child));
+        }
+
+        if let Some(contents) = block_tag.contents() {
+            let start = self.offset(&contents.span().start);
+            // タグの直後に続くタグは同じ行にあるのでインデントは深くならない
+            let indent = if start == self.cursor {
+                indent
+            } else {
+                self.gap(&mut node, start, true);
+                indent + self.indent_style.width
+            };
+            let outer_raw = self.raw;
+            self.raw = self.raw || self.schema.is_raw_block_tag(block_tag.name().name());
+            let child = self.block(contents, indent);
+            self.raw = outer_raw;
+            node.children.push(SyntaxElement::Node(child));
+        }
+
+        node
+    }
+
+    fn inline_tag(&mut self, inline_tag: &InlineTag) -> SyntaxNode {
+        let mut node = SyntaxNode::new(NodeKind::InlineTag);
+
+        self.tag(&mut node, inline_tag.name(), inline_tag.attributes());
+
+        let end = self.offset(&inline_tag.span().end);
+        if self.cursor < end {
+            if self.chars[self.cursor] == '{' {
+                let outer_raw = self.raw;
+                self.raw = self.raw || self.schema.is_raw_inline_tag(inline_tag.name().name());
+                self.token(&mut node, TokenKind::LeftBrace, self.cursor + 1);
+                self.inline_contents(&mut node, inline_tag.contents(), None);
+                self.gap(&mut node, end - 1, false);
+                self.token(&mut node, TokenKind::RightBrace, end);
+                self.raw = outer_raw;
+            } else {
+                // タグの直後に続くタグ
+                self.inline_contents(&mut node, inline_tag.contents(), None);
+            }
+        }
+
+        node
+    }
+
+    /// タグ名と属性をトークンにする。
+    fn tag(&mut self, node: &mut SyntaxNode, name: &TagName, attributes: &Attributes) {
+        if name.abbreviation() {
+            self.token(node, TokenKind::Abbreviation, self.cursor + 1);
+        } else {
+            self.token(node, TokenKind::Colon, self.cursor + 1);
+            let end = self.offset(&name.span().end);
+            self.token(node, TokenKind::TagName, end);
+        }
+
+        if self.chars.get(self.cursor) == Some(&'[') {
+            let child = self.attributes(attributes);
+            node.children.push(SyntaxElement::Node(child));
+        }
+    }
+
+    fn attributes(&mut self, attributes: &Attributes) -> SyntaxNode {
+        let mut node = SyntaxNode::new(NodeKind::Attributes);
+        self.token(&mut node, TokenKind::LeftBracket, self.cursor + 1);
+
+        // 属性は書かれた順に並んでいる
+        for item in attributes.items() {
+            let (name_span, value) = match item {
+                AttributeItem::Named(attribute) => (Some(attribute.name_span()), attribute.value()),
+                AttributeItem::Nameless(value) => (None, value),
+            };
+            let start = self.offset(&item.span().start);
+            self.gap(&mut node, start, false);
+
+            let mut child = SyntaxNode::new(NodeKind::Attribute);
+            if let Some(name_span) = name_span {
+                let end = self.offset(&name_span.end);
+                self.token(&mut child, TokenKind::AttributeName, end);
+                let value_start = self.offset(&value.span().start);
+                self.token(&mut child, TokenKind::Equals, value_start);
+            }
+            let kind = if self.chars.get(self.cursor) == Some(&'"') {
+                TokenKind::QuotedAttributeValue
+            } else {
+                TokenKind::SimpleAttributeValue
+            };
+            let end = self.offset(&value.span().end);
+            self.token(&mut child, kind, end);
+            node.children.push(SyntaxElement::Node(child));
+        }
+
+        // 引用符の中を除いて"]"を探す
+        let mut end = self.cursor;
+        let mut quoted = false;
+        while end < self.chars.len() {
+            match self.chars[end] {
+                '"' => quoted = !quoted,
+                ']' if !quoted => break,
+                _ => {}
+            }
+            end += 1;
+        }
+        self.gap(&mut node, end, false);
+        self.token(
+            &mut node,
+            TokenKind::RightBracket,
+            (end + 1).min(self.chars.len()),
+        );
+
+        node
+    }
+
+    /// インラインの要素をノードにする。
+    /// indentがあれば改行の後のその幅の空白をインデントと見なす。
+    fn inline_contents(
+        &mut self,
+        node: &mut SyntaxNode,
+        contents: &InlineContents,
+        indent: Option<u64>,
+    ) {
+        for content in contents {
+            let start = self.offset(&content.span().start);
+            self.gap(node, start, indent.is_some());
+
+            let child = match content {
+                InlineNode::Text(text) => self.text(text, indent),
+                InlineNode::InlineTag(inline_tag) => self.inline_tag(inline_tag),
+            };
+            node.children.push(SyntaxElement::Node(child));
+        }
+    }
+
+    /// テキストの値とソースを突き合わせて、値に含まれない空白や改行の種類を区別する。
+    fn text(&mut self, text: &Text, indent: Option<u64>) -> SyntaxNode {
+        let mut node = SyntaxNode::new(NodeKind::Text);
+
+        let end = self.offset(&text.span().end);
+        let value = text.value().chars().collect::<Vec<char>>();
+        let mut i = 0;
+
+        while self.cursor < end {
+            if self.is_new_line(self.cursor) {
+                self.new_line(&mut node);
+                if value.get(i) == Some(&'\n') {
+                    i += 1;
+                }
+                if let Some(indent) = indent {
+                    let indent_end = self.indent_end(end, indent);
+                    self.token(&mut node, TokenKind::Indent, indent_end);
+                }
+            } else if self.is_comment(self.cursor) {
+                let line_end = self.line_end(end);
+                self.token(&mut node, TokenKind::Comment, line_end);
+            } else if !self.raw
+                && self.chars[self.cursor] == '\\'
+                && self.cursor + 1 < end
+                && !self.is_new_line(self.cursor + 1)
+            {
+                self.token(&mut node, TokenKind::Escape, self.cursor + 1);
+                self.token(&mut node, TokenKind::Text, self.cursor + 1);
+                i += 1;
+            } else if value.get(i) == Some(&self.chars[self.cursor]) {
+                let mut text_end = self.cursor;
+                while text_end < end
+                    && !self.is_new_line(text_end)
+                    && (self.raw || text_end == self.cursor || self.chars[text_end] != '\\')
+                    && !self.is_comment(text_end)
+                    && value.get(i) == Some(&self.chars[text_end])
+                {
+                    text_end += 1;
+                    i += 1;
+                }
+                self.token(&mut node, TokenKind::Text, text_end);
+            } else if self.chars[self.cursor] == ' ' {
+                // 行末の空白
+                let spaces_end = self.spaces_end(end);
+                self.token(&mut node, TokenKind::Whitespace, spaces_end);
+            } else {
+                self.token(&mut node, TokenKind::Unknown, self.cursor + 1);
+            }
+        }
+
+        node
+    }
+}
+
+#[cfg(test)]
+mod test_build_syntax_tree {
+    use super::parse_syntax_tree;
+    use super::parse_syntax_tree_with_options;
+    use super::NodeKind;
+    use super::SyntaxNode;
+    use super::TokenKind;
+    use crate::build::step2::TabPolicy;
+    use crate::build::step3::ParseOptions;
+    use indoc::indoc;
+
+    fn parse(source: &str) -> SyntaxNode {
+        parse_syntax_tree(source.as_bytes()).unwrap().1
+    }
+
+    fn tokens(node: &SyntaxNode, kind: TokenKind) -> Vec<String> {
+        node.tokens()
+            .iter()
+            .filter(|token| token.kind() == kind)
+            .map(|token| token.text().to_owned())
+            .collect()
+    }
+
+    /// 元のソースと完全に一致する
+    #[test]
+    fn test_round_trip() {
+        let sources = [
+            "",
+            "abc",
+            "\n\n  \n",
+            "abc   \nxyz  ",
+            "abc\r\nxyz\rend\r\n",
+            "\u{feff}abc\n",
+            indoc! {r#"
+                abc:b{xyz} :link[https://example.com "a ""b"""]{link}
+                :i[a=1
+                    b="2" c]{x
+                  y}
+
+                :section[id=s1] header :i{x}
+                    :code-block[rust]
+                        fn main() {
+                            println!("{}", 1);
+                        }
+
+                    text
+                        nested
+                    :a:b
+                        deep
+
+                    :tag[a=x a=y]
+            "#},
+            ":*{bold}\n:tag[\"]\"]\n",
+            "    indented\n        more\n",
+            ":code-block\n    a  \n\t\n    \tb\n  \n        c\n\nx\n",
+        ];
+
+        for source in sources {
+            let tree = parse(source);
+            assert_eq!(tree.text(), source);
+        }
+    }
+
+    /// 省略記法や引用符などの書き方が残る
+    #[test]
+    fn test_tokens() {
+        let tree = parse(indoc! {r#"
+            :tag[a="x""y" b=z  q] header
+                :b*{x}
+        "#});
+
+        assert_eq!(tokens(&tree, TokenKind::TagName), vec!["tag", "b"]);
+        assert_eq!(tokens(&tree, TokenKind::Abbreviation), vec!["*"]);
+        assert_eq!(
+            tokens(&tree, TokenKind::QuotedAttributeValue),
+            vec![r#""x""y""#]
+        );
+        assert_eq!(
+            tokens(&tree, TokenKind::SimpleAttributeValue),
+            vec!["z", "q"]
+        );
+        assert_eq!(tokens(&tree, TokenKind::Whitespace), vec![" ", "  ", " "]);
+        assert_eq!(tokens(&tree, TokenKind::Indent), vec!["    "]);
+        assert_eq!(tokens(&tree, TokenKind::LeftBrace), vec!["{"]);
+        assert_eq!(tokens(&tree, TokenKind::Unknown), Vec::<String>::new());
+    }
+
+    /// ノードの構造
+    #[test]
+    fn test_nodes() {
+        let tree = parse("abc\n\n:tag[a=1] h\n    x\n");
+
+        let kinds = tree
+            .descendants()
+            .iter()
+            .map(|node| node.kind())
+            .collect::<Vec<NodeKind>>();
+        assert_eq!(
+            kinds,
+            vec![
+                NodeKind::Block,
+                NodeKind::Paragraph,
+                NodeKind::Text,
+                NodeKind::BlankLine,
+                NodeKind::BlockTag,
+                NodeKind::Attributes,
+                NodeKind::Attribute,
+                NodeKind::BlockTagHeader,
+                NodeKind::Text,
+                NodeKind::Block,
+                NodeKind::Paragraph,
+                NodeKind::Text,
+            ]
+        );
+
+        let block_tag = tree.descendants()[4];
+        assert_eq!(&block_tag.text(), ":tag[a=1] h\n    x\n");
+    }
+
+    /// インデントと改行の種類
+    #[test]
+    fn test_trivia() {
+        let tree = parse("a\r\n    b  \r\n\n");
+
+        assert_eq!(
+            tokens(&tree, TokenKind::NewLine),
+            vec!["\r\n", "\r\n", "\n"]
+        );
+        assert_eq!(tokens(&tree, TokenKind::Indent), vec!["    "]);
+        assert_eq!(tokens(&tree, TokenKind::Whitespace), vec!["  "]);
+        assert_eq!(tokens(&tree, TokenKind::Text), vec!["a", "b"]);
+    }
+
+    /// タブやインデントの幅が違ってもインデントになる
+    #[test]
+    fn test_indent_style() {
+        let options = ParseOptions::new()
+            .indent_width(2)
+            .tab_policy(TabPolicy::Expand(2));
+        let source = ":code-block\n\tfn f() {\n\t x\n\t}\n";
+        let tree = parse_syntax_tree_with_options(source.as_bytes(), &options)
+            .unwrap()
+            .1;
+
+        assert_eq!(tokens(&tree, TokenKind::Indent), vec!["\t", "\t", "\t"]);
+        assert_eq!(tokens(&tree, TokenKind::Unknown), Vec::<String>::new());
+        assert_eq!(tree.text(), source);
+    }
+
+    /// エスケープのバックスラッシュ。rawタグの中ではエスケープではない
+    #[test]
+    fn test_escape() {
+        let source = "a\\:b\\\\ :i{\\}} :code{\\}\\\n";
+        let tree = parse(source);
+
+        assert_eq!(tokens(&tree, TokenKind::Escape), vec!["\\", "\\", "\\"]);
+        assert_eq!(
+            tokens(&tree, TokenKind::Text),
+            vec!["a", ":", "b", "\\", " ", "}", " ", "\\", "\\"]
+        );
+        assert_eq!(tokens(&tree, TokenKind::Unknown), Vec::<String>::new());
+        assert_eq!(tree.text(), source);
+    }
+
+    /// コメントはトークンに残す
+    #[test]
+    fn test_comments() {
+        let source = indoc! {"
+            :; block
+                body
+                    deeper
+
+                end
+            a :; trailing
+            :; whole line
+            b :i{x :; in braces
+            y}
+
+            :tag[a=1 :; attribute
+                b=2] head :; header
+                :; only comment
+            :code{:; not comment}
+        "};
+        let tree = parse(source);
+
+        assert_eq!(
+            tokens(&tree, TokenKind::Comment),
+            vec![
+                ":; block",
+                "body",
+                "    deeper",
+                "end",
+                ":; trailing",
+                ":; whole line",
+                ":; in braces",
+                ":; attribute",
+                ":; header",
+                ":; only comment",
+            ]
+        );
+        assert_eq!(tokens(&tree, TokenKind::Unknown), Vec::<String>::new());
+        assert_eq!(tree.text(), source);
+    }
+
+    /// ツリーに含まれない文字も残す
+    #[test]
+    fn test_unknown() {
+        let tree = parse(":tag[a=x a=y]\n");
+
+        assert_eq!(tokens(&tree, TokenKind::Unknown), vec!["a=y"]);
+        assert_eq!(&tree.text(), ":tag[a=x a=y]\n");
+    }
+}
@@ -0,0 +1,693 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::build::step1::CharStream;
+use crate::build::step1::Position;
+use crate::build::step1::Span;
+use crate::build::step2::FilePosition;
+use crate::build::step2::IndentDiagnostic;
+use crate::build::step2::UnitStream;
+use crate::build::step3::block::parse_block;
+use crate::build::step3::block::Block;
+use crate::build::step3::comment::Comment;
+use crate::build::step3::ParseContext;
+use crate::build::step3::ParseError;
+use crate::build::step3::ParseOptions;
+
+mod include;
+
+use include::IncludeStack;
+
+/// パースするソース。
+/// ファイルパスか、ファイルパスとバイナリの組。
+#[derive(Clone, Debug, PartialEq)]
+pub enum Source {
+    File(PathBuf),
+    Binary(PathBuf, Vec<u8>),
+}
+
+impl From<PathBuf> for Source {
+    fn from(filepath: PathBuf) -> Source {
+        Source::File(filepath)
+    }
+}
+
+impl From<&Path> for Source {
+    fn from(filepath: &Path) -> Source {
+        Source::File(filepath.to_path_buf())
+    }
+}
+
+impl From<Vec<u8>> for Source {
+    fn from(binary: Vec<u8>) -> Source {
+        Source::Binary(PathBuf::new(), binary)
+    }
+}
+
+impl From<&[u8]> for Source {
+    fn from(binary: &[u8]) -> Source {
+        Source::Binary(PathBuf::new(), binary.to_vec())
+    }
+}
+
+/// パースしたドキュメント。
+/// ルートのブロックとパース中に発生した警告、ツリーに含まれないコメントを持つ。
+/// エラーから回復してパースした時はエラーも持つ。
+#[derive(Debug, PartialEq)]
+pub struct Document {
+    filepath: PathBuf,
+    block: Block,
+    errors: Vec<ParseError>,
+    warnings: Vec<ParseError>,
+    comments: Vec<Comment>,
+}
+
+impl Document {
+    pub fn new(filepath: PathBuf, block: Block, warnings: Vec<ParseError>) -> Document {
+        Document {
+            filepath,
+            block,
+            errors: vec![],
+            warnings,
+            comments: vec![],
+        }
+    }
+
+    /// 回復したエラーを指定する。
+    pub fn with_errors(mut self, errors: Vec<ParseError>) -> Document {
+        self.errors = errors;
+        self
+    }
+
+    /// コメントを指定する。
+    pub fn with_comments(mut self, comments: Vec<Comment>) -> Document {
+        self.comments = comments;
+        self
+    }
+
+    pub fn filepath(&self) -> &Path {
+        &self.filepath
+    }
+
+    /// 範囲の始まりのファイル上の位置。
+    /// インクルードしたファイルの範囲なら、そのファイルとインクルードしたタグの位置になる。
+    pub fn file_position(&self, span: &Span) -> FilePosition {
+        match &span.file {
+            Some(file) => file.file_position(span),
+            None => FilePosition {
+                filepath: self.filepath.clone(),
+                position: Some(span.start.clone()),
+                included_from: None,
+            },
+        }
+    }
+
+    pub fn block(&self) -> &Block {
+        &self.block
+    }
+
+    pub fn block_mut(&mut self) -> &mut Block {
+        &mut self.block
+    }
+
+    /// エラーから回復してパースした時のエラー。回復しなければ空。
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
+    pub fn warnings(&self) -> &[ParseError] {
+        &self.warnings
+    }
+
+    /// コメントを出現順に返す。
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+}
+
+/// パースに失敗した時のエラーと、それまでに発生した警告。
+#[derive(Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    errors: Vec<ParseError>,
+    warnings: Vec<ParseError>,
+}
+
+impl Diagnostics {
+    fn from_error(error: ParseError) -> Diagnostics {
+        Diagnostics {
+            errors: vec![error],
+            warnings: vec![],
+        }
+    }
+
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
+    pub fn warnings(&self) -> &[ParseError] {
+        &self.warnings
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for error in self.errors.iter().chain(self.warnings.iter()) {
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostics {}
+
+/// ソースを読み込んでドキュメントにパースする。
+pub fn parse_document<S: Into<Source>>(source: S) -> Result<Document, Diagnostics> {
+    parse_document_with_options(source, &ParseOptions::default())
+}
+
+/// オプションを指定してソースを読み込み、ドキュメントにパースする。
+/// ファイルは読み込みながらパースする。
+pub fn parse_document_with_options<S: Into<Source>>(
+    source: S,
+    options: &ParseOptions,
+) -> Result<Document, Diagnostics> {
+    match source.into() {
+        Source::File(filepath) => match File::open(&filepath) {
+            Ok(file) => parse_reader(filepath, file, options),
+            Err(e) => Err(Diagnostics::from_error(read_error(filepath, &e))),
+        },
+        Source::Binary(filepath, binary) => parse_binary(filepath, binary, options),
+    }
+}
+
+/// 標準入力などから読み込みながらドキュメントにパースする。
+/// filepathは警告やエラーの位置に使う。
+pub fn parse_reader<R: Read + 'static>(
+    filepath: PathBuf,
+    reader: R,
+    options: &ParseOptions,
+) -> Result<Document, Diagnostics> {
+    let char_stream = CharStream::from_reader(reader, options.get_encoding());
+    parse_char_stream(filepath, char_stream, options)
+}
+
+/// ソースのファイルパスとバイナリを取得する。
+pub(crate) fn read_source(source: Source) -> Result<(PathBuf, Vec<u8>), Diagnostics> {
+    match source {
+        Source::File(filepath) => match fs::read(&filepath) {
+            Ok(binary) => Ok((filepath, binary)),
+            Err(e) => Err(Diagnostics::from_error(read_error(filepath, &e))),
+        },
+        Source::Binary(filepath, binary) => Ok((filepath, binary)),
+    }
+}
+
+fn read_error(filepath: PathBuf, e: &io::Error) -> ParseError {
+    ParseError::new(
+        FilePosition {
+            filepath,
+            position: None,
+            included_from: None,
+        },
+        None,
+        format!("The file cannot be read. ({})", e),
+    )
+}
+
+/// バイナリをドキュメントにパースする。
+pub(crate) fn parse_binary(
+    filepath: PathBuf,
+    binary: Vec<u8>,
+    options: &ParseOptions,
+) -> Result<Document, Diagnostics> {
+    let char_stream = CharStream::with_encoding(binary, options.get_encoding());
+    parse_char_stream(filepath, char_stream, options)
+}
+
+fn parse_char_stream(
+    filepath: PathBuf,
+    char_stream: CharStream,
+    options: &ParseOptions,
+) -> Result<Document, Diagnostics> {
+    let mut unit_stream =
+        UnitStream::with_indent_style(filepath.clone(), char_stream, options.get_indent_style());
+
+    let mut parse_warnings = vec![];
+    let mut comments = vec![];
+    let mut parse_errors = vec![];
+    let result = {
+        let mut context = ParseContext::with_comments(&mut parse_warnings, options, &mut comments);
+        if options.get_recover_errors() {
+            context = context.recover_errors(&mut parse_errors);
+        }
+        parse_block(&mut unit_stream, &mut context)
+    };
+
+    // デコードできなかったバイト列は置換文字にして警告する。
+    // 読み込みながらデコードするので、パースした後にわかる
+    let char_stream = unit_stream.char_stream();
+    let mut warnings = char_stream
+        .decode_errors()
+        .iter()
+        .map(|error| {
+            ParseError::new(
+                FilePosition {
+                    filepath: filepath.clone(),
+                    position: Some(error.position.clone()),
+                    included_from: None,
+                },
+                None,
+                format!(
+                    "The bytes at offset {} cannot be decoded as {}.",
+                    error.byte_offset,
+                    char_stream.encoding()
+                ),
+            )
+        })
+        .collect::<Vec<_>>();
+    warnings.append(&mut parse_warnings);
+
+    // インデントの警告はパースした結果の警告の後に並べる
+    warnings.extend(
+        unit_stream
+            .indent_warnings()
+            .iter()
+            .map(|warning| indent_diagnostic_to_error(&filepath, warning)),
+    );
+
+    // 読み込みかインデントのエラーがあればパースできても失敗にする
+    // エラーから回復するなら、パースしたエラーの後に並べてドキュメントに残す
+    let mut errors = char_stream
+        .read_error()
+        .map(|e| read_error(filepath.clone(), e))
+        .into_iter()
+        .chain(
+            unit_stream
+                .indent_errors()
+                .iter()
+                .map(|error| indent_diagnostic_to_error(&filepath, error)),
+        )
+        .collect::<Vec<_>>();
+
+    let recover_errors = options.get_recover_errors();
+    if recover_errors {
+        parse_errors.append(&mut errors);
+        errors = parse_errors;
+    }
+
+    match result {
+        Ok(block) if recover_errors || errors.is_empty() => {
+            let mut block = block.unwrap_or_else(|| {
+                let position = Position::new(1, 1);
+                Block::new(vec![], Span::new(position.clone(), position))
+            });
+            if options.get_resolve_includes() {
+                if let Err(mut include_errors) =
+                    IncludeStack::new().resolve(&filepath, &mut block, options, &mut warnings)
+                {
+                    if !recover_errors {
+                        return Err(Diagnostics {
+                            errors: include_errors,
+                            warnings,
+                        });
+                    }
+                    errors.append(&mut include_errors);
+                }
+            }
+            Ok(Document {
+                filepath,
+                block,
+                errors,
+                warnings,
+                comments,
+            })
+        }
+        Ok(_) => Err(Diagnostics { errors, warnings }),
+        Err(error) => {
+            errors.insert(0, error);
+            Err(Diagnostics { errors, warnings })
+        }
+    }
+}
+
+fn indent_diagnostic_to_error(filepath: &Path, diagnostic: &IndentDiagnostic) -> ParseError {
+    ParseError::new(
+        FilePosition {
+            filepath: filepath.to_path_buf(),
+            position: Some(diagnostic.position.clone()),
+            included_from: None,
+        },
+        None,
+        diagnostic.message.clone(),
+    )
+}
+
+#[cfg(test)]
+mod test_parse_document {
+    use super::parse_document;
+    use super::parse_document_with_options;
+    use super::parse_reader;
+    use super::Source;
+    use crate::build::step1::Encoding;
+    use crate::build::step1::Position;
+    use crate::build::step2::TabPolicy;
+    use crate::build::step3::comment::CommentKind;
+    use crate::build::step3::test_utils::assert_model;
+    use crate::build::step3::ParseOptions;
+    use indoc::indoc;
+    use std::io;
+    use std::io::Cursor;
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_binary() {
+        let document = parse_document(
+            indoc! {"
+                abc
+
+                :tag[a=1]
+                    xyz
+            "}
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_model(
+            document.block(),
+            r#"{"b":[
+                {"p":["abc\n"]},
+                "<bl>",
+                {"bt":"tag","a":{"a":"1"},"c":{"b":[{"p":["xyz\n"]}]}}
+            ]}"#,
+        );
+        assert_eq!(document.filepath(), PathBuf::new());
+        assert!(document.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_file() {
+        let filepath = PathBuf::from("resources/test/source_unit_reader/source_1.oreno");
+        let document = parse_document(filepath.clone()).unwrap();
+
+        assert_eq!(document.filepath(), filepath);
+        assert!(document.warnings().is_empty());
+    }
+
+    /// 空のソースなら空のブロック
+    #[test]
+    fn test_empty() {
+        let document = parse_document(Source::Binary(PathBuf::from("a/b.c"), vec![])).unwrap();
+
+        assert_model(document.block(), r#"{"b":[]}"#);
+        assert_eq!(document.filepath(), PathBuf::from("a/b.c"));
+    }
+
+    /// 警告はドキュメントに含まれる
+    #[test]
+    fn test_warnings() {
+        let document = parse_document(":tag;".as_bytes()).unwrap();
+
+        assert_model(document.block(), r#"{"b":[{"p":[":tag;"]}]}"#);
+        assert_eq!(document.warnings().len(), 2);
+        assert_eq!(
+            &*document.warnings()[0].message,
+            "There is an illegal character. ';'"
+        );
+    }
+
+    /// コメントはツリーに含めずにドキュメントに残す
+    #[test]
+    fn test_comments() {
+        let document = parse_document(
+            indoc! {"
+                :; block
+                    body
+                a :; trailing
+                :; whole line
+                b :i{x :; in braces
+                y}
+
+                :tag[a=1 :; attribute
+                    b=2] head :; header
+                    c
+            "}
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_model(
+            document.block(),
+            r#"{"b":[
+                {"p":["a \nb ",{"it":"i","c":["x \ny"]},"\n"]},
+                "<bl>",
+                {"bt":"tag","a":{"a":"1","b":"2"},"h":["head "],"c":{"b":[{"p":["c\n"]}]}}
+            ]}"#,
+        );
+        assert!(document.warnings().is_empty());
+        assert_eq!(
+            document
+                .comments()
+                .iter()
+                .map(|comment| (comment.kind(), comment.text(), comment.span().start.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    CommentKind::Block,
+                    " block\nbody",
+                    Position::with_byte_offset(1, 1, 0)
+                ),
+                (
+                    CommentKind::Line,
+                    " trailing",
+                    Position::with_byte_offset(3, 3, 20)
+                ),
+                (
+                    CommentKind::Line,
+                    " whole line",
+                    Position::with_byte_offset(4, 1, 32)
+                ),
+                (
+                    CommentKind::Line,
+                    " in braces",
+                    Position::with_byte_offset(5, 8, 53)
+                ),
+                (
+                    CommentKind::Line,
+                    " attribute",
+                    Position::with_byte_offset(8, 10, 79)
+                ),
+                (
+                    CommentKind::Line,
+                    " header",
+                    Position::with_byte_offset(9, 15, 106)
+                ),
+            ]
+        );
+    }
+
+    /// 段落の最後のコメントだけの行はブロックのコメントにする
+    #[test]
+    fn test_comment_after_paragraph() {
+        let document = parse_document("a\n:; end\n    body\n\nb\n".as_bytes()).unwrap();
+
+        assert_model(
+            document.block(),
+            r#"{"b":[{"p":["a\n"]},"<bl>",{"p":["b\n"]}]}"#,
+        );
+        assert_eq!(
+            document
+                .comments()
+                .iter()
+                .map(|comment| (comment.kind(), comment.text()))
+                .collect::<Vec<_>>(),
+            vec![(CommentKind::Block, " end\nbody")]
+        );
+    }
+
+    /// パースをやり直したタグの中のコメントは二重に数えない
+    #[test]
+    fn test_comment_in_backtracked_tag() {
+        let document = parse_document("x :i[a=1 :; c\n".as_bytes()).unwrap();
+
+        assert_eq!(document.comments().len(), 1);
+    }
+
+    /// ファイルが読み込めなければエラー
+    #[test]
+    fn test_file_not_found() {
+        let diagnostics = parse_document(PathBuf::from("resources/test/not_found.oreno"))
+            .err()
+            .unwrap();
+
+        assert_eq!(diagnostics.errors().len(), 1);
+        assert!(diagnostics.errors()[0]
+            .message
+            .starts_with("The file cannot be read."));
+        assert_eq!(diagnostics.errors()[0].file_position.position, None);
+    }
+
+    /// デコードできないバイト列は警告して、置換文字にする
+    #[test]
+    fn test_undecodable() {
+        let document = parse_document(&b"abc\n\xFFd\n"[..]).unwrap();
+
+        assert_model(document.block(), r#"{"b":[{"p":["abc\n\ufffdd\n"]}]}"#);
+        assert_eq!(document.warnings().len(), 1);
+        assert_eq!(
+            document.warnings()[0].file_position.position,
+            Some(Position::with_byte_offset(2, 1, 4))
+        );
+        assert_eq!(
+            &*document.warnings()[0].message,
+            "The bytes at offset 4 cannot be decoded as UTF-8."
+        );
+    }
+
+    /// BOMがなければオプションのエンコーディングでデコードする
+    #[test]
+    fn test_encoding() {
+        let options = ParseOptions::new().encoding(Encoding::ShiftJis);
+        let document = parse_document_with_options(&b"a:b{\x82\xA0}"[..], &options).unwrap();
+
+        assert_model(
+            document.block(),
+            r#"{"b":[{"p":["a",{"it":"b","c":["あ"]}]}]}"#,
+        );
+        assert_eq!(document.warnings(), &[]);
+    }
+
+    /// インデントのタブは指定がなければ文字として読み込み、Rejectならエラー
+    #[test]
+    fn test_tab_indent() {
+        // 既定ではタブは文字として読み込む
+        let document = parse_document(":a\n\tb\n".as_bytes()).unwrap();
+        assert_model(document.block(), r#"{"b":[{"bt":"a"},{"p":["\tb\n"]}]}"#);
+
+        let options = ParseOptions::new().tab_policy(TabPolicy::Reject);
+        let diagnostics = parse_document_with_options(":a\n\tb\n".as_bytes(), &options)
+            .err()
+            .unwrap();
+
+        assert_eq!(diagnostics.errors().len(), 1);
+        assert_eq!(
+            diagnostics.errors()[0].file_position.position,
+            Some(Position::with_byte_offset(2, 1, 3))
+        );
+        assert_eq!(
+            &*diagnostics.errors()[0].message,
+            "A tab cannot be used for indentation."
+        );
+
+        let options = ParseOptions::new().tab_policy(TabPolicy::Indent);
+        let document = parse_document_with_options(":a\n\tb\n".as_bytes(), &options).unwrap();
+        assert_model(
+            document.block(),
+            r#"{"b":[{"bt":"a","c":{"b":[{"p":["b\n"]}]}}]}"#,
+        );
+    }
+
+    /// 厳密なインデントの検査の警告もドキュメントに含まれる
+    #[test]
+    fn test_strict_indent() {
+        let options = ParseOptions::new().strict_indent(true);
+        let document = parse_document_with_options(":a\n  b\n".as_bytes(), &options).unwrap();
+
+        assert_eq!(document.warnings().len(), 1);
+        assert_eq!(
+            document.warnings()[0].to_string(),
+            ":2:1: The indentation of 2 columns is not a multiple of 4 columns. It is read as 0 levels of indentation followed by 2 columns of text."
+        );
+    }
+
+    /// 読み込みながらパースする
+    #[test]
+    fn test_reader() {
+        let document = parse_reader(
+            PathBuf::from("a/b.c"),
+            Cursor::new(b":tag[a=1]\n    xyz\n".to_vec()),
+            &ParseOptions::new(),
+        )
+        .unwrap();
+
+        assert_model(
+            document.block(),
+            r#"{"b":[{"bt":"tag","a":{"a":"1"},"c":{"b":[{"p":["xyz\n"]}]}}]}"#,
+        );
+        assert_eq!(document.filepath(), PathBuf::from("a/b.c"));
+    }
+
+    /// 読み込みに失敗したらエラー
+    #[test]
+    fn test_reader_error() {
+        let reader = Cursor::new(b"abc\n".to_vec()).chain(FailingReader);
+        let diagnostics = parse_reader(PathBuf::new(), reader, &ParseOptions::new())
+            .err()
+            .unwrap();
+
+        assert_eq!(diagnostics.errors().len(), 1);
+        assert_eq!(
+            &*diagnostics.errors()[0].message,
+            "The file cannot be read. (broken)"
+        );
+    }
+
+    /// エラーから回復するなら、エラーがあってもドキュメントを返す
+    #[test]
+    fn test_recover_errors() {
+        let options = ParseOptions::new()
+            .tab_policy(TabPolicy::Reject)
+            .recover_errors(true);
+        let document = parse_document_with_options(":a\n\tb\n:c\n".as_bytes(), &options).unwrap();
+
+        assert_eq!(document.errors().len(), 1);
+        assert_eq!(
+            document.errors()[0].to_string(),
+            ":2:1: A tab cannot be used for indentation."
+        );
+        assert_model(
+            document.block(),
+            r#"{"b":[{"bt":"a"},{"p":["\tb\n",{"it":"c"},"\n"]}]}"#,
+        );
+
+        let document = parse_document_with_options("abc\n".as_bytes(), &options).unwrap();
+        assert!(document.errors().is_empty());
+    }
+
+    /// 読み込みに失敗しても、読み込めた範囲のドキュメントを返す
+    #[test]
+    fn test_recover_reader_error() {
+        let reader = Cursor::new(b"abc\n".to_vec()).chain(FailingReader);
+        let document = parse_reader(
+            PathBuf::new(),
+            reader,
+            &ParseOptions::new().recover_errors(true),
+        )
+        .unwrap();
+
+        assert_eq!(
+            &*document.errors()[0].message,
+            "The file cannot be read. (broken)"
+        );
+        assert_model(document.block(), r#"{"b":[{"p":["abc\n"]}]}"#);
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("broken"))
+        }
+    }
+}
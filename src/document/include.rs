@@ -0,0 +1,572 @@
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::build::step1::CharStream;
+use crate::build::step1::Span;
+use crate::build::step2::include_span;
+use crate::build::step2::FilePosition;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::visit::map_spans;
+use crate::build::step3::BlockNode;
+use crate::build::step3::ParseError;
+use crate::build::step3::ParseOptions;
+
+use super::parse_char_stream;
+use super::read_error;
+
+/// 別のファイルの内容に置き換えるタグ
+const INCLUDE_TAG_NAME: &str = "include";
+
+/// インクルードしたファイルから選ぶ範囲
+enum Selection<'a> {
+    All,
+    /// 最初と最後の行番号。最後がなければファイルの終わりまで
+    Lines(u64, Option<u64>),
+    /// id属性が一致するブロックタグの内容
+    Section(&'a str),
+}
+
+/// パースしているファイルの並び。インクルードの循環を見つけるのに使う。
+pub(super) struct IncludeStack {
+    /// 正規化したファイルパス。外側のファイルから並ぶ
+    filepaths: Vec<PathBuf>,
+}
+
+impl IncludeStack {
+    pub(super) fn new() -> IncludeStack {
+        IncludeStack { filepaths: vec![] }
+    }
+
+    /// ブロックの中のincludeタグを、指定したファイルの要素に置き換える。
+    /// インクルードしたファイルの警告はwarningsに追加する。
+    pub(super) fn resolve(
+        &mut self,
+        filepath: &Path,
+        block: &mut Block,
+        options: &ParseOptions,
+        warnings: &mut Vec<ParseError>,
+    ) -> Result<(), Vec<ParseError>> {
+        // ファイルパスのないソースは循環を調べる対象にしない
+        let canonical = fs::canonicalize(filepath).ok();
+        if let Some(canonical) = &canonical {
+            self.filepaths.push(canonical.clone());
+        }
+        let result = self.resolve_block(filepath, block, options, warnings);
+        if canonical.is_some() {
+            self.filepaths.pop();
+        }
+        result
+    }
+
+    fn resolve_block(
+        &mut self,
+        filepath: &Path,
+        block: &mut Block,
+        options: &ParseOptions,
+        warnings: &mut Vec<ParseError>,
+    ) -> Result<(), Vec<ParseError>> {
+        let contents = std::mem::take(block.contents_mut());
+        let mut resolved = Vec::with_capacity(contents.len());
+        // エラーから回復するなら、置き換えられないincludeタグをエラーの要素にして続ける
+        let mut errors = vec![];
+
+        for node in contents {
+            let result = match node {
+                BlockNode::BlockTag(block_tag) if block_tag.name().name() == INCLUDE_TAG_NAME => {
+                    match self.include(filepath, &block_tag, options, warnings) {
+                        Ok(nodes) => {
+                            resolved.extend(nodes);
+                            Ok(())
+                        }
+                        Err(errors) => {
+                            resolved.push(BlockNode::Error(block_tag.span().clone()));
+                            Err(errors)
+                        }
+                    }
+                }
+                BlockNode::BlockTag(mut block_tag) => {
+                    let mut result = Ok(());
+                    if !options
+                        .get_schema()
+                        .is_raw_block_tag(block_tag.name().name())
+                    {
+                        if let Some(contents) = block_tag.contents_mut() {
+                            result = self.resolve_block(filepath, contents, options, warnings);
+                        }
+                    }
+                    resolved.push(BlockNode::BlockTag(block_tag));
+                    result
+                }
+                BlockNode::Block(mut nested) => {
+                    let result = self.resolve_block(filepath, &mut nested, options, warnings);
+                    resolved.push(BlockNode::Block(nested));
+                    result
+                }
+                node => {
+                    resolved.push(node);
+                    Ok(())
+                }
+            };
+            if let Err(mut node_errors) = result {
+                if !options.get_recover_errors() {
+                    return Err(node_errors);
+                }
+                errors.append(&mut node_errors);
+            }
+        }
+
+        *block.contents_mut() = resolved;
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// includeタグが指定したファイルをパースして、選んだ範囲の要素を返す。
+    fn include(
+        &mut self,
+        filepath: &Path,
+        block_tag: &BlockTag,
+        options: &ParseOptions,
+        warnings: &mut Vec<ParseError>,
+    ) -> Result<Vec<BlockNode>, Vec<ParseError>> {
+        let file_position = FilePosition {
+            filepath: filepath.to_path_buf(),
+            position: Some(block_tag.span().start.clone()),
+            included_from: None,
+        };
+        let error = |message: String| vec![ParseError::new(file_position.clone(), None, message)];
+
+        let attributes = block_tag.attributes();
+        let mut nameless = attributes.nameless();
+        let path = match (nameless.next(), nameless.next()) {
+            (Some(path), None) => path.value(),
+            _ => return Err(error("The include tag needs one file path.".to_owned())),
+        };
+        let selection = match (attributes.get("lines"), attributes.get("section")) {
+            (Some(_), Some(_)) => {
+                return Err(error(
+                    "The line range and the section cannot be specified together.".to_owned(),
+                ))
+            }
+            (Some(lines), None) => {
+                let lines = lines.value().value();
+                match parse_line_range(lines) {
+                    Some((first, last)) => Selection::Lines(first, last),
+                    None => return Err(error(format!("The line range is invalid. ({:?})", lines))),
+                }
+            }
+            (None, Some(section)) => Selection::Section(section.value().value()),
+            (None, None) => Selection::All,
+        };
+
+        // インクルードするファイルからの相対パスにする
+        let include_path = filepath.parent().unwrap_or(Path::new("")).join(path);
+        if let Ok(canonical) = fs::canonicalize(&include_path) {
+            if self.filepaths.contains(&canonical) {
+                return Err(error(format!(
+                    "The file is included recursively. ({:?})",
+                    path
+                )));
+            }
+        }
+
+        let binary = fs::read(&include_path).map_err(|e| {
+            vec![included(
+                read_error(include_path.clone(), &e),
+                &file_position,
+            )]
+        })?;
+        let char_stream = CharStream::with_encoding(binary, options.get_encoding());
+        // 選んだ範囲のincludeタグだけを置き換えるので、選ぶまでは置き換えない
+        // インクルードしたファイルのエラーはincludeタグのエラーにするので回復しない
+        let parse_options = options
+            .clone()
+            .resolve_includes(false)
+            .recover_errors(false);
+        let document = match parse_char_stream(include_path.clone(), char_stream, &parse_options) {
+            Ok(document) => document,
+            Err(diagnostics) => {
+                warnings.extend(
+                    diagnostics
+                        .warnings
+                        .into_iter()
+                        .map(|warning| included(warning, &file_position)),
+                );
+                return Err(diagnostics
+                    .errors
+                    .into_iter()
+                    .map(|error| included(error, &file_position))
+                    .collect());
+            }
+        };
+
+        warnings.extend(
+            document
+                .warnings
+                .into_iter()
+                .map(|warning| included(warning, &file_position)),
+        );
+
+        let mut block = document.block;
+        let contents = std::mem::take(block.contents_mut());
+        *block.contents_mut() = match selection {
+            Selection::All => contents,
+            Selection::Lines(first, last) => {
+                select_lines(contents, first, last.unwrap_or(u64::MAX))
+            }
+            Selection::Section(name) => match select_section(contents, name) {
+                Some(nodes) => nodes,
+                None => return Err(error(format!("The section is not found. ({:?})", name))),
+            },
+        };
+
+        let mut nested_warnings = vec![];
+        let result = self.resolve(&include_path, &mut block, options, &mut nested_warnings);
+        warnings.extend(
+            nested_warnings
+                .into_iter()
+                .map(|warning| included(warning, &file_position)),
+        );
+        result.map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|error| included(error, &file_position))
+                .collect::<Vec<_>>()
+        })?;
+
+        let mut nodes = std::mem::take(block.contents_mut());
+        // 前後の空行は入れない
+        let blank_lines = nodes
+            .iter()
+            .rev()
+            .take_while(|node| node.is_blank_line())
+            .count();
+        nodes.truncate(nodes.len() - blank_lines);
+        let blank_lines = nodes.iter().take_while(|node| node.is_blank_line()).count();
+        nodes.drain(..blank_lines);
+
+        // 要素の範囲にインクルードしたファイルを記録する
+        map_spans(
+            &mut nodes,
+            &mut include_span(include_path, file_position.clone()),
+        );
+
+        Ok(nodes)
+    }
+}
+
+/// インクルードしたタグの位置を警告とエラーの位置の一番外側に追加する。
+fn included(mut error: ParseError, file_position: &FilePosition) -> ParseError {
+    error
+        .file_position
+        .push_included_from(file_position.clone());
+    error
+}
+
+/// "10-20"、"10-"、"10"の形の行の範囲。
+fn parse_line_range(lines: &str) -> Option<(u64, Option<u64>)> {
+    let (first, last) = match lines.split_once('-') {
+        Some((first, "")) => (first, None),
+        Some((first, last)) => (first, Some(last)),
+        None => (lines, Some(lines)),
+    };
+    let first = first.parse::<u64>().ok().filter(|first| *first >= 1)?;
+    match last {
+        Some(last) => {
+            let last = last.parse::<u64>().ok().filter(|last| *last >= first)?;
+            Some((first, Some(last)))
+        }
+        None => Some((first, None)),
+    }
+}
+
+/// 行の範囲にある要素を選ぶ。
+/// 範囲の境界をまたぐブロックとブロックタグは内容から選び、
+/// 段落と空行は始まる行が範囲にあれば選ぶ。
+fn select_lines(nodes: Vec<BlockNode>, first: u64, last: u64) -> Vec<BlockNode> {
+    let mut selected = vec![];
+    for node in nodes {
+        let (start, end) = line_range(node.span());
+        if end < first || last < start {
+            continue;
+        }
+        if first <= start && end <= last {
+            selected.push(node);
+            continue;
+        }
+
+        match node {
+            BlockNode::Block(mut block) => {
+                let contents = std::mem::take(block.contents_mut());
+                selected.extend(select_lines(contents, first, last));
+            }
+            BlockNode::BlockTag(mut block_tag) => {
+                if let Some(mut contents) = block_tag.contents_mut().take() {
+                    let contents = std::mem::take(contents.contents_mut());
+                    selected.extend(select_lines(contents, first, last));
+                }
+            }
+            node => {
+                if first <= start {
+                    selected.push(node);
+                }
+            }
+        }
+    }
+    selected
+}
+
+/// 範囲の最初と最後の行番号。範囲が改行で終わるなら最後の行は改行の行。
+fn line_range(span: &Span) -> (u64, u64) {
+    let start = span.start.line_number;
+    let end = if span.end.column_number == 1 && span.end.line_number > start {
+        span.end.line_number - 1
+    } else {
+        span.end.line_number
+    };
+    (start, end)
+}
+
+/// id属性がnameのブロックタグを探して内容を返す。
+fn select_section(nodes: Vec<BlockNode>, name: &str) -> Option<Vec<BlockNode>> {
+    for node in nodes {
+        let contents = match node {
+            BlockNode::BlockTag(mut block_tag) => {
+                let id = block_tag.attributes().get("id");
+                if id.is_some_and(|id| id.value().value() == name) {
+                    return Some(
+                        block_tag
+                            .contents_mut()
+                            .take()
+                            .map(|mut contents| std::mem::take(contents.contents_mut()))
+                            .unwrap_or_default(),
+                    );
+                }
+                match block_tag.contents_mut().take() {
+                    Some(mut contents) => std::mem::take(contents.contents_mut()),
+                    None => continue,
+                }
+            }
+            BlockNode::Block(mut block) => std::mem::take(block.contents_mut()),
+            _ => continue,
+        };
+        if let Some(section) = select_section(contents, name) {
+            return Some(section);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test_resolve {
+    use std::path::PathBuf;
+
+    use crate::build::step1::Span;
+    use crate::build::step3::test_utils::assert_model;
+    use crate::build::step3::BlockNode;
+    use crate::build::step3::ParseOptions;
+    use crate::document::parse_document_with_options;
+
+    fn options() -> ParseOptions {
+        ParseOptions::new().resolve_includes(true)
+    }
+
+    /// インクルードしたファイルの要素をタグの位置に入れる
+    /// 警告にはインクルードしたタグの位置が付く
+    #[test]
+    fn test_include() {
+        let document = parse_document_with_options(
+            PathBuf::from("resources/test/include/book.oreno"),
+            &options(),
+        )
+        .unwrap();
+
+        assert_model(
+            document.block(),
+            r#"{"b":[
+                {"bt":"section","a":{"id":"book"},"c":{"b":[
+                    {"p":["Book\n"]},
+                    "<bl>",
+                    {"p":["Chapter 1\n"]},
+                    "<bl>",
+                    {"bt":"tag","a":{"a":"1"}}
+                ]}},
+                "<bl>",
+                {"p":["Intro\n"]}
+            ]}"#,
+        );
+        assert_eq!(
+            document
+                .warnings()
+                .iter()
+                .map(|warning| warning.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "resources/test/include/chapter1.oreno:4:13: The attributes are duplicated. \
+                 (included from resources/test/include/book.oreno:4:5)"
+            ]
+        );
+    }
+
+    /// インクルードした要素の範囲から、書かれていたファイルとインクルードしたタグの位置がわかる
+    #[test]
+    fn test_source_file() {
+        let document = parse_document_with_options(
+            PathBuf::from("resources/test/include/book.oreno"),
+            &options(),
+        )
+        .unwrap();
+        let locate = |span: &Span| {
+            let file_position = document.file_position(span);
+            std::iter::once(&file_position)
+                .chain(file_position.include_chain())
+                .map(|file_position| file_position.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let root = document.block().contents();
+        let section = match &root[0] {
+            BlockNode::BlockTag(section) => section.contents().unwrap().contents(),
+            _ => unreachable!(),
+        };
+        // インクルードしたタグのファイルの要素はそのまま
+        assert!(section[0].span().file.is_none());
+        assert_eq!(
+            locate(section[0].span()),
+            vec!["resources/test/include/book.oreno:2:5"]
+        );
+        assert_eq!(
+            locate(section[2].span()),
+            vec![
+                "resources/test/include/chapter1.oreno:2:1",
+                "resources/test/include/book.oreno:4:5"
+            ]
+        );
+        // 子孫の要素にも付く
+        let tag = match &section[4] {
+            BlockNode::BlockTag(tag) => tag,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            locate(&tag.attributes().items()[0].span()),
+            vec![
+                "resources/test/include/chapter1.oreno:4:6",
+                "resources/test/include/book.oreno:4:5"
+            ]
+        );
+        // 入れ子のインクルードは内側から並ぶ
+        assert_eq!(
+            locate(root[2].span()),
+            vec![
+                "resources/test/include/chapters/part.oreno:4:5",
+                "resources/test/include/chapters/chapter2.oreno:3:1",
+                "resources/test/include/book.oreno:6:1"
+            ]
+        );
+    }
+
+    /// オプションを指定しなければ置き換えない
+    #[test]
+    fn test_not_resolved() {
+        let document = parse_document_with_options(
+            PathBuf::from("resources/test/include/lines_book.oreno"),
+            &ParseOptions::new(),
+        )
+        .unwrap();
+
+        assert_model(
+            document.block(),
+            r#"{"b":[{"bt":"include","a":{"lines":"5-6"},"v":["lines.oreno"]}]}"#,
+        );
+    }
+
+    /// 行の範囲をまたぐタグは内容から選ぶ
+    #[test]
+    fn test_lines() {
+        let document = parse_document_with_options(
+            PathBuf::from("resources/test/include/lines_book.oreno"),
+            &options(),
+        )
+        .unwrap();
+
+        assert_model(document.block(), r#"{"b":[{"p":["second\nthird\n"]}]}"#);
+    }
+
+    /// 循環するインクルードはエラー
+    #[test]
+    fn test_cycle() {
+        let diagnostics = parse_document_with_options(
+            PathBuf::from("resources/test/include/cycle_a.oreno"),
+            &options(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            diagnostics.to_string(),
+            "resources/test/include/cycle_b.oreno:3:1: The file is included recursively. \
+             (\"cycle_a.oreno\") (included from resources/test/include/cycle_a.oreno:3:1)"
+        );
+    }
+
+    /// 読み込めないファイルはエラー
+    #[test]
+    fn test_not_found() {
+        let diagnostics = parse_document_with_options(
+            PathBuf::from("resources/test/include/absent_include.oreno"),
+            &options(),
+        )
+        .unwrap_err();
+
+        assert_eq!(diagnostics.errors().len(), 1);
+        let error = &diagnostics.errors()[0];
+        assert_eq!(
+            error.file_position.filepath,
+            PathBuf::from("resources/test/include/absent.oreno")
+        );
+        assert_eq!(
+            error
+                .file_position
+                .include_chain()
+                .next()
+                .unwrap()
+                .to_string(),
+            "resources/test/include/absent_include.oreno:3:1"
+        );
+    }
+
+    /// エラーから回復するなら、置き換えられないincludeタグをエラーの要素にする
+    #[test]
+    fn test_recover_errors() {
+        let document = parse_document_with_options(
+            PathBuf::from("resources/test/include/absent_include.oreno"),
+            &options().recover_errors(true),
+        )
+        .unwrap();
+
+        assert_eq!(document.errors().len(), 1);
+        assert_eq!(
+            document.errors()[0].file_position.filepath,
+            PathBuf::from("resources/test/include/absent.oreno")
+        );
+        assert_model(
+            document.block(),
+            r#"{"b":[{"p":["text\n"]},"<bl>","<err>"]}"#,
+        );
+    }
+
+    #[test]
+    fn test_parse_line_range() {
+        use super::parse_line_range;
+
+        assert_eq!(parse_line_range("3-10"), Some((3, Some(10))));
+        assert_eq!(parse_line_range("3-"), Some((3, None)));
+        assert_eq!(parse_line_range("3"), Some((3, Some(3))));
+        assert_eq!(parse_line_range("0-3"), None);
+        assert_eq!(parse_line_range("5-3"), None);
+        assert_eq!(parse_line_range("a"), None);
+    }
+}
@@ -0,0 +1,1172 @@
+use std::error::Error;
+use std::fmt;
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::build::step1::Position;
+use crate::build::step3::attribute::AttributeItem;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::comment::Comment;
+use crate::build::step3::comment::CommentKind;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::symbol::NameSyntax;
+use crate::build::step3::tag::TagName;
+use crate::build::step3::BlockNode;
+use crate::build::step3::InlineNode;
+use crate::build::step3::ParseOptions;
+use crate::document::parse_document_with_options;
+use crate::document::Document;
+use crate::schema::Schema;
+
+/// 属性の並べ方
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AttributeOrder {
+    /// ソースに書かれた順
+    #[default]
+    Source,
+    /// 名前付きの属性を名前順に並べ、その後に無名属性を書かれた順に並べる
+    Sorted,
+}
+
+/// タグ名の書き方
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TagSpelling {
+    /// 省略記法で書かれたタグもタグ名で書く
+    #[default]
+    Full,
+    /// 省略記法で書かれたタグは書ける限り省略記法のままにする
+    Preserve,
+}
+
+/// 整形のオプション
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FormatOptions {
+    pub attribute_order: AttributeOrder,
+    pub tag_spelling: TagSpelling,
+    /// パースした時のオプション。
+    /// インデントの幅に合わせて出力し、確かめるためにパースし直す時にも使う
+    pub parse_options: ParseOptions,
+}
+
+/// 整形できなかった時のエラー
+#[derive(Debug, PartialEq)]
+pub struct FormatError {
+    pub message: String,
+}
+
+impl FormatError {
+    fn new(message: String) -> FormatError {
+        FormatError { message }
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for FormatError {}
+
+/// ドキュメントを正規の形式のソースにする。
+///
+/// インデントは4つの空白、属性の間は1つの空白にして、属性値は必要な時だけ引用符で囲む。
+/// 連続した空行は1行にまとめる。ただしコードブロックなど内容をパースしないタグの中は除く。
+/// テキストのコロンとバックスラッシュ、"{}"の中の括弧はエスケープする。
+/// 行末までのコメントは書かれていた行の終わりに書く。
+/// 整形したソースをパースし直して同じツリーにならなければエラーにする。
+pub fn format_document(
+    document: &Document,
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    emit_source_with_comments(document.block(), document.comments(), options)
+}
+
+/// ブロックをソースにする。
+/// 書き方はformat_documentと同じで、パースし直して同じツリーにならなければエラーにする。
+pub fn emit_source(block: &Block, options: &FormatOptions) -> Result<String, FormatError> {
+    emit_source_with_comments(block, &[], options)
+}
+
+/// コメントも出力する。
+/// パースし直して同じコメントにならなければエラーにする。
+fn emit_source_with_comments(
+    block: &Block,
+    comments: &[Comment],
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    let (line_comments, block_comments) = comments
+        .iter()
+        .partition(|comment| comment.kind() == CommentKind::Line);
+    let mut emitter = Emitter {
+        options,
+        output: String::new(),
+        depth: 0,
+        raw: false,
+        comments: block_comments,
+        next_comment: 0,
+        bound: None,
+        line_comments,
+        next_line_comment: 0,
+        source_line: 1,
+    };
+    emitter.block(block, false)?;
+    // 改行で終わっていない最後の行のコメント
+    emitter.write_line_comments(None);
+    let output = emitter.output;
+
+    // 出力したソースのincludeタグはそのまま比べる
+    // パースできないソースを出力したら失敗にするので、エラーから回復しない
+    let parse_options = options
+        .parse_options
+        .clone()
+        .resolve_includes(false)
+        .recover_errors(false);
+    let reparsed =
+        parse_document_with_options(output.as_bytes(), &parse_options).map_err(|diagnostics| {
+            FormatError::new(format!(
+                "The formatted source cannot be parsed. ({})",
+                diagnostics
+            ))
+        })?;
+
+    if shape(block, options)? != shape(reparsed.block(), options)? {
+        return Err(FormatError::new(
+            "The formatted source does not parse to the same tree.".to_owned(),
+        ));
+    }
+
+    if !comments
+        .iter()
+        .map(Comment::text)
+        .eq(reparsed.comments().iter().map(Comment::text))
+    {
+        return Err(FormatError::new(
+            "The formatted source does not keep the comments.".to_owned(),
+        ));
+    }
+
+    Ok(output)
+}
+
+/// 内容をパースしないブロックタグの内容のソース。インデントは含めない。
+/// 最後に続く空行は内容にならないので取り除き、改行で終わるようにする。
+fn raw_block_source(block: &Block, options: &FormatOptions) -> Result<String, FormatError> {
+    let mut emitter = Emitter {
+        options,
+        output: String::new(),
+        depth: 0,
+        raw: true,
+        comments: vec![],
+        next_comment: 0,
+        bound: None,
+        line_comments: vec![],
+        next_line_comment: 0,
+        source_line: 1,
+    };
+    emitter.block(block, true)?;
+
+    let mut source = emitter.output;
+    source.truncate(source.trim_end_matches('\n').len());
+    if !source.is_empty() {
+        source.push('\n');
+    }
+    Ok(source)
+}
+
+struct Emitter<'a> {
+    options: &'a FormatOptions,
+    output: String,
+    depth: usize,
+    /// 内容をパースしないタグの中か。中ならテキストをエスケープしない
+    raw: bool,
+    /// ブロックの要素の位置に書いたコメント。出現順
+    comments: Vec<&'a Comment>,
+    /// まだ出力していない最初のコメント
+    next_comment: usize,
+    /// 出力中のブロックの後に続く要素の開始位置。ブロックの終わりではここより前のコメントを出力する
+    bound: Option<Position>,
+    /// 行末までのコメント。出現順
+    line_comments: Vec<&'a Comment>,
+    /// まだ出力していない最初の行末までのコメント
+    next_line_comment: usize,
+    /// 出力中の行が書かれていたソースの行番号
+    source_line: u64,
+}
+
+impl Emitter<'_> {
+    /// 出力する。indentedなら行頭にインデントを入れる。
+    fn write(&mut self, s: &str, indented: bool) {
+        for c in s.chars() {
+            if indented && c != '\n' && (self.output.is_empty() || self.output.ends_with('\n')) {
+                for _ in
+                    0..self.depth * self.options.parse_options.get_indent_style().width as usize
+                {
+                    self.output.push(' ');
+                }
+            }
+            self.output.push(c);
+        }
+    }
+
+    fn name_syntax(&self) -> NameSyntax {
+        self.options.parse_options.get_name_syntax()
+    }
+
+    fn schema(&self) -> &Schema {
+        self.options.parse_options.get_schema()
+    }
+
+    fn block(&mut self, block: &Block, raw: bool) -> Result<(), FormatError> {
+        // 先頭の空行は取り除く
+        let mut after_blank_line = !raw;
+
+        for (i, node) in block.contents().iter().enumerate() {
+            self.source_line = node.span().start.line_number;
+
+            // インデントしたブロックの前の深いコメントはブロックの中で出力する
+            let depths = match node {
+                BlockNode::Block(_) => 0..self.depth + 1,
+                _ => 0..usize::MAX,
+            };
+            if self.write_comments(Some(&node.span().start), depths) {
+                after_blank_line = false;
+            }
+
+            // 後に続く要素の前のコメントは、この要素の中のブロックの終わりか、後に続く要素の前で出力する
+            let bound = match block.contents().get(i + 1) {
+                Some(next) => Some(next.span().start.clone()),
+                None => self.bound.clone(),
+            };
+            let parent_bound = std::mem::replace(&mut self.bound, bound);
+            self.node(block, i, raw, &mut after_blank_line)?;
+            self.bound = parent_bound;
+        }
+
+        // ブロックの中にインデントしたコメントはブロックの終わりで出力する
+        let bound = self.bound.clone();
+        self.write_comments(bound.as_ref(), self.depth..usize::MAX);
+
+        Ok(())
+    }
+
+    /// boundより前から始まり、インデントの深さがdepthsに入るコメントを出力する。
+    /// boundがNoneなら後ろのコメントすべてが対象。出力したらtrue。
+    fn write_comments(&mut self, bound: Option<&Position>, depths: Range<usize>) -> bool {
+        let width = self.options.parse_options.get_indent_style().width;
+        let start = self.next_comment;
+        while let Some(comment) = self.comments.get(self.next_comment).copied() {
+            let position = &comment.span().start;
+            let before_bound = bound.is_none_or(|bound| is_before(position, bound));
+            let depth = ((position.column_number - 1) / width.max(1)) as usize;
+            if !before_bound || !depths.contains(&depth) {
+                break;
+            }
+
+            let mut lines = comment.text().split('\n');
+            self.write(&format!(":;{}\n", lines.next().unwrap_or_default()), true);
+            self.depth += 1;
+            for line in lines {
+                self.write(&format!("{}\n", line), true);
+            }
+            self.depth -= 1;
+            self.next_comment += 1;
+        }
+        self.next_comment > start
+    }
+
+    /// 行番号がsource_line以下の行末までのコメントを、出力中の行の終わりに続けて書く。
+    /// source_lineがNoneなら残りのコメントすべてを書く。
+    fn write_line_comments(&mut self, source_line: Option<u64>) {
+        while let Some(comment) = self.line_comments.get(self.next_line_comment).copied() {
+            if source_line.is_some_and(|line| comment.span().start.line_number > line) {
+                break;
+            }
+            self.write(&format!(":;{}", comment.text()), true);
+            self.next_line_comment += 1;
+        }
+    }
+
+    /// 出力中の行のコメントを書いて改行する。
+    /// in_paragraphなら、段落の中でコメントだけだった次の行も書く。
+    fn end_line(&mut self, in_paragraph: bool) {
+        self.write_line_comments(Some(self.source_line));
+        self.write("\n", false);
+        self.source_line += 1;
+
+        if !in_paragraph {
+            return;
+        }
+        let head = self.depth as u64 * self.options.parse_options.get_indent_style().width + 1;
+        while let Some(comment) = self.line_comments.get(self.next_line_comment).copied() {
+            let position = &comment.span().start;
+            if position.line_number != self.source_line || position.column_number != head {
+                break;
+            }
+            self.write(&format!(":;{}\n", comment.text()), true);
+            self.next_line_comment += 1;
+            self.source_line += 1;
+        }
+    }
+
+    /// ブロックのi番目の要素を出力する。
+    fn node(
+        &mut self,
+        block: &Block,
+        i: usize,
+        raw: bool,
+        after_blank_line: &mut bool,
+    ) -> Result<(), FormatError> {
+        let node = &block.contents()[i];
+        match node {
+            BlockNode::BlankLine(_) => {
+                if raw || !*after_blank_line {
+                    self.write("\n", false);
+                }
+                *after_blank_line = true;
+                return Ok(());
+            }
+            BlockNode::Paragraph(paragraph) => {
+                self.paragraph(paragraph.contents())?;
+            }
+            BlockNode::BlockTag(block_tag) => {
+                // 後にブロックが続くなら、直後に続くタグの内容と見なされないようにする
+                let followed_by_block = matches!(
+                    block.contents()[i + 1..]
+                        .iter()
+                        .find(|node| !node.is_blank_line()),
+                    Some(BlockNode::Block(_))
+                );
+                self.block_tag(block_tag, followed_by_block)?;
+            }
+            BlockNode::Block(block) => {
+                self.depth += 1;
+                self.block(block, raw)?;
+                self.depth -= 1;
+            }
+            // 読み飛ばしたソースはツリーに残っていないので書けない
+            BlockNode::Error(span) => {
+                return Err(FormatError::new(format!(
+                    "The parse error at {} cannot be formatted.",
+                    span.start
+                )));
+            }
+        }
+        *after_blank_line = false;
+
+        Ok(())
+    }
+
+    /// ブロックタグを出力する。
+    /// nestedなら内容のタグを直後に続けて書く。
+    fn block_tag(&mut self, block_tag: &BlockTag, nested: bool) -> Result<(), FormatError> {
+        check_name(block_tag.name().name(), "tag name", self.name_syntax())?;
+        self.write(&format!(":{}", block_tag.name().name()), true);
+        // 属性の後に続くのはヘッダーか内容
+        let attributes_bound = match (block_tag.header(), block_tag.contents()) {
+            (Some(header), _) => header.span().start.clone(),
+            (None, Some(contents)) => contents.span().start.clone(),
+            (None, None) => block_tag.span().end.clone(),
+        };
+        self.attributes(block_tag.attributes(), &attributes_bound)?;
+
+        if let Some(header) = block_tag.header() {
+            self.write(" ", true);
+            self.inline_contents(header.contents(), true, false)?;
+        } else if let Some(BlockNode::BlockTag(nested_tag)) = block_tag
+            .contents()
+            .map(|contents| contents.contents().as_slice())
+            .filter(|_| nested)
+            .and_then(|contents| match contents {
+                [node] => Some(node),
+                _ => None,
+            })
+        {
+            return self.block_tag(nested_tag, true);
+        }
+        self.end_line(false);
+
+        // 普段はタグの直後に続くタグも次の行に書く
+        if let Some(contents) = block_tag.contents() {
+            self.depth += 1;
+            if self.schema().is_raw_block_tag(block_tag.name().name()) {
+                let source = raw_block_source(contents, self.options)?;
+                self.write(&source, true);
+            } else {
+                self.block(contents, false)?;
+            }
+            self.depth -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// 段落を出力する。
+    fn paragraph(&mut self, contents: &[InlineNode]) -> Result<(), FormatError> {
+        // コロンから始まる段落は最初の行がブロックタグと見なされないようにする
+        let first_line_length = match contents.first() {
+            Some(InlineNode::Text(text)) if !text.value().starts_with(':') => 0,
+            _ => contents
+                .iter()
+                .position(
+                    |node| matches!(node, InlineNode::Text(text) if text.value().contains('\n')),
+                )
+                .unwrap_or(contents.len()),
+        };
+
+        self.inline_nodes(contents, true, false, first_line_length)
+    }
+
+    /// インラインの要素を出力する。
+    /// in_bracesなら"{}"の中なのでインデントしない。
+    fn inline_contents(
+        &mut self,
+        contents: &[InlineNode],
+        indented: bool,
+        in_braces: bool,
+    ) -> Result<(), FormatError> {
+        self.inline_nodes(contents, indented, in_braces, 0)
+    }
+
+    /// インラインの要素を出力する。
+    /// 先頭からkept_braces個の要素のタグは内容がなくても"{}"を省略しない。
+    fn inline_nodes(
+        &mut self,
+        contents: &[InlineNode],
+        indented: bool,
+        in_braces: bool,
+        kept_braces: usize,
+    ) -> Result<(), FormatError> {
+        for (i, node) in contents.iter().enumerate() {
+            self.source_line = node.span().start.line_number;
+            match node {
+                InlineNode::Text(text) if self.raw => self.write(text.value(), indented),
+                InlineNode::Text(text) => {
+                    let escaped = escape_text(text.value(), in_braces);
+                    for (j, line) in escaped.split('\n').enumerate() {
+                        if j > 0 {
+                            self.end_line(!in_braces);
+                        }
+                        self.write(line, indented);
+                    }
+                }
+                InlineNode::InlineTag(inline_tag) => {
+                    // 内容のないタグは空白、改行、段落の終わりの前にしか書けない
+                    let can_omit_contents = match contents.get(i + 1) {
+                        Some(InlineNode::Text(text)) => text.value().starts_with([' ', '\n']),
+                        Some(InlineNode::InlineTag(_)) => false,
+                        None => !in_braces,
+                    };
+                    let can_omit_contents = can_omit_contents && i >= kept_braces;
+                    self.inline_tag(inline_tag, indented, false, can_omit_contents)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// インラインタグを出力する。
+    /// nestedならタグの直後に続くタグ。
+    fn inline_tag(
+        &mut self,
+        inline_tag: &InlineTag,
+        indented: bool,
+        nested: bool,
+        can_omit_contents: bool,
+    ) -> Result<(), FormatError> {
+        check_name(inline_tag.name().name(), "tag name", self.name_syntax())?;
+        let abbreviated = self.tag_name(inline_tag.name(), indented, nested);
+        // 属性の後に続くのは内容
+        let attributes_bound = match inline_tag.contents().first() {
+            Some(node) => node.span().start.clone(),
+            None => inline_tag.span().end.clone(),
+        };
+        self.attributes(inline_tag.attributes(), &attributes_bound)?;
+
+        let raw = self.schema().is_raw_inline_tag(inline_tag.name().name());
+
+        match inline_tag.contents().as_slice() {
+            // 内容がタグ1つだけならタグの直後に続けて書く
+            [InlineNode::InlineTag(nested_tag)] if !raw && !abbreviated => {
+                self.inline_tag(nested_tag, indented, true, can_omit_contents)?;
+            }
+            [] if can_omit_contents && !abbreviated => {}
+            contents => {
+                let outer_raw = self.raw;
+                self.raw = self.raw || raw;
+                self.write("{", false);
+                self.inline_contents(contents, false, true)?;
+                self.write("}", false);
+                self.raw = outer_raw;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// タグ名を出力する。省略記法で書いたらtrueを返す。
+    fn tag_name(&mut self, name: &TagName, indented: bool, nested: bool) -> bool {
+        let symbol = match self.options.tag_spelling {
+            // 省略記法はタグの直後にしか書けず、
+            // 記号がタグ名に使える文字だと属性のないタグのタグ名の一部になってしまう
+            TagSpelling::Preserve if name.abbreviation() && nested => {
+                let syntax = self.name_syntax();
+                self.options
+                    .parse_options
+                    .get_abbreviations()
+                    .symbol(name.name())
+                    .filter(|symbol| {
+                        !syntax.is_name_char(*symbol, false)
+                            || !matches!(self.output.chars().last(),
+                            Some(c) if syntax.is_name_char(c, false) || c == ':')
+                    })
+            }
+            _ => None,
+        };
+
+        match symbol {
+            Some(symbol) => {
+                self.write(&symbol.to_string(), false);
+                true
+            }
+            None => {
+                self.write(&format!(":{}", name.name()), indented);
+                false
+            }
+        }
+    }
+
+    /// 属性を出力する。boundは属性の後に続く要素の開始位置。
+    ///
+    /// 属性の間の行末までのコメントは、書かれた順なら前にあった属性の後に、
+    /// 並べ替えたら"]"の前にまとめて書いて改行する。
+    fn attributes(&mut self, attributes: &Attributes, bound: &Position) -> Result<(), FormatError> {
+        let (first, last) = match (attributes.items().first(), attributes.items().last()) {
+            (Some(first), Some(last)) => (first.span(), last.span()),
+            _ => return Ok(()),
+        };
+
+        let sorted = self.options.attribute_order == AttributeOrder::Sorted;
+        let mut items = attributes.items().iter().collect::<Vec<_>>();
+        if sorted {
+            sort_attribute_items(&mut items);
+        }
+
+        // 改行した後の属性は1段深くインデントする
+        self.depth += 1;
+        self.write("[", false);
+        self.attribute_comments(&first.start);
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 && !self.output.ends_with('\n') {
+                self.write(" ", false);
+            }
+            if let Some(name) = item.name() {
+                check_name(name, "attribute name", self.name_syntax())?;
+                self.write(&format!("{}=", name), true);
+            }
+            self.write(&quote_attribute_value(item.value().value())?, true);
+
+            if !sorted {
+                if let Some(next) = items.get(i + 1) {
+                    self.attribute_comments(&next.span().start);
+                }
+            }
+        }
+        // 最後の属性の後のコメント
+        self.attribute_comments(bound);
+        self.write("]", true);
+        self.depth -= 1;
+        self.source_line = last.end.line_number;
+
+        Ok(())
+    }
+
+    /// boundより前から始まる行末までのコメントを、属性の間に書いて改行する。
+    fn attribute_comments(&mut self, bound: &Position) {
+        while let Some(comment) = self.line_comments.get(self.next_line_comment).copied() {
+            if !is_before(&comment.span().start, bound) {
+                break;
+            }
+            if !self.output.ends_with(['[', ' ', '\n']) {
+                self.write(" ", false);
+            }
+            self.write(&format!(":;{}\n", comment.text()), true);
+            self.next_line_comment += 1;
+        }
+    }
+}
+
+/// aがbより前の位置ならtrue。
+fn is_before(a: &Position, b: &Position) -> bool {
+    (a.line_number, a.column_number) < (b.line_number, b.column_number)
+}
+
+/// 名前付きの属性を名前順に並べ、その後に無名属性を並べる。
+/// 同じ名前の属性や無名属性は書かれた順のままにする。
+fn sort_attribute_items(items: &mut [&AttributeItem]) {
+    items.sort_by_key(|item| (item.name().is_none(), item.name()));
+}
+
+/// テキストをエスケープする。
+/// タグと見なされないように":"を、"{}"の中なら括弧もエスケープする。
+fn escape_text(text: &str, in_braces: bool) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | ':') || (in_braces && matches!(c, '{' | '}')) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// タグ名か属性名に使えない文字があればエラーにする。
+fn check_name(name: &str, kind: &str, syntax: NameSyntax) -> Result<(), FormatError> {
+    if syntax.is_valid_name(name) {
+        Ok(())
+    } else {
+        Err(FormatError::new(format!(
+            "The {} is invalid. ({:?})",
+            kind, name
+        )))
+    }
+}
+
+/// 属性値を必要なら引用符で囲む。
+fn quote_attribute_value(value: &str) -> Result<String, FormatError> {
+    if value.contains(['\n', '\r']) {
+        return Err(FormatError::new(format!(
+            "An attribute value cannot contain a new line. ({:?})",
+            value
+        )));
+    }
+
+    if value.is_empty() || value.contains([' ', '"', '=', ']']) {
+        Ok(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Ok(value.to_owned())
+    }
+}
+
+/// 整形で変わらない部分だけを表した文字列。
+/// 範囲、省略記法かどうか、連続した空行の数を含まない。
+/// 内容をパースしないブロックタグの内容は、出力するソースで表す。
+fn shape(block: &Block, options: &FormatOptions) -> Result<String, FormatError> {
+    let mut s = String::new();
+    shape_block(&mut s, block, options)?;
+    Ok(s)
+}
+
+fn shape_block(s: &mut String, block: &Block, options: &FormatOptions) -> Result<(), FormatError> {
+    s.push_str("B[");
+    let mut after_blank_line = true;
+    for node in block.contents() {
+        match node {
+            BlockNode::BlankLine(_) => {
+                if !after_blank_line {
+                    s.push_str("_,");
+                }
+                after_blank_line = true;
+                continue;
+            }
+            BlockNode::Paragraph(paragraph) => {
+                s.push_str("P[");
+                shape_inline_contents(s, paragraph.contents());
+                s.push(']');
+            }
+            BlockNode::BlockTag(block_tag) => {
+                write!(s, "BT{:?}", block_tag.name().name()).unwrap();
+                shape_attributes(s, block_tag.attributes());
+                if let Some(header) = block_tag.header() {
+                    s.push_str("H[");
+                    shape_inline_contents(s, header.contents());
+                    s.push(']');
+                }
+                if let Some(contents) = block_tag.contents() {
+                    if options
+                        .parse_options
+                        .get_schema()
+                        .is_raw_block_tag(block_tag.name().name())
+                    {
+                        write!(s, "R{:?}", raw_block_source(contents, options)?).unwrap();
+                    } else {
+                        shape_block(s, contents, options)?;
+                    }
+                }
+            }
+            BlockNode::Block(block) => shape_block(s, block, options)?,
+            BlockNode::Error(_) => s.push('E'),
+        }
+        s.push(',');
+        after_blank_line = false;
+    }
+    s.push(']');
+    Ok(())
+}
+
+fn shape_inline_contents(s: &mut String, contents: &[InlineNode]) {
+    for node in contents {
+        match node {
+            InlineNode::Text(text) => write!(s, "{:?},", text.value()).unwrap(),
+            InlineNode::InlineTag(inline_tag) => {
+                write!(s, "IT{:?}", inline_tag.name().name()).unwrap();
+                shape_attributes(s, inline_tag.attributes());
+                s.push('[');
+                shape_inline_contents(s, inline_tag.contents());
+                s.push_str("],");
+            }
+        }
+    }
+}
+
+fn shape_attributes(s: &mut String, attributes: &Attributes) {
+    // 並べ替えても同じになるようにする
+    let mut items = attributes.items().iter().collect::<Vec<_>>();
+    sort_attribute_items(&mut items);
+    s.push('{');
+    for item in items {
+        if let Some(name) = item.name() {
+            write!(s, "{:?}=", name).unwrap();
+        }
+        write!(s, "{:?},", item.value().value()).unwrap();
+    }
+    s.push('}');
+}
+
+#[cfg(test)]
+mod test_format_document {
+    use super::format_document;
+    use super::AttributeOrder;
+    use super::FormatOptions;
+    use super::TagSpelling;
+    use crate::build::step3::symbol::NameSyntax;
+    use crate::build::step3::tag::Abbreviations;
+    use crate::build::step3::ParseOptions;
+    use crate::document::parse_document;
+    use crate::document::parse_document_with_options;
+    use indoc::indoc;
+
+    fn format(source: &str, options: &FormatOptions) -> String {
+        let document = parse_document(source.as_bytes()).unwrap();
+        format_document(&document, options).unwrap()
+    }
+
+    /// インデント、属性、空行を正規化する
+    #[test]
+    fn test_normalize() {
+        let source = indoc! {r#"
+
+
+            abc   :b[ x   y="1"  z="a b" ]{xyz}
+            def
+
+
+
+            :section[id="s1"  ] header
+                :p
+                    text
+
+
+
+                :code-block[rust]
+                    fn main() {
+
+
+                        let a = 1;
+                    }
+        "#};
+
+        assert_eq!(
+            format(source, &FormatOptions::default()),
+            indoc! {r#"
+                abc   :b[x y=1 z="a b"]{xyz}
+                def
+
+                :section[id=s1] header
+                    :p
+                        text
+
+                    :code-block[rust]
+                        fn main() {
+
+
+                            let a = 1;
+                        }
+            "#}
+        );
+    }
+
+    /// rawタグの内容は空白も空行もそのまま残す
+    #[test]
+    fn test_raw_block() {
+        let source = concat!(
+            ":section\n",
+            "    :code-block[make]\n",
+            "        all:  \n",
+            "        \tcc -o a a.c\n",
+            "\n",
+            "\n",
+            "          \n",
+            "            nested\n",
+            "\n",
+            "\n",
+            "    text\n",
+        );
+
+        assert_eq!(
+            format(source, &FormatOptions::default()),
+            source.replacen("\n\n\n    text", "\n\n    text", 1)
+        );
+    }
+
+    /// タグと見なされる文字をエスケープする。rawタグの中はエスケープしない
+    #[test]
+    fn test_escape() {
+        let source = "a\\:b \\\\ \\*{x} :i{x\\}y\\{} :code{a\\b}\n";
+
+        assert_eq!(
+            format(source, &FormatOptions::default()),
+            "a\\:b \\\\ *{x} :i{x\\}y\\{} :code{a\\b}\n"
+        );
+    }
+
+    /// 正規の形式なら変わらない
+    #[test]
+    fn test_idempotent() {
+        let source = indoc! {r#"
+            abc :link[https://example.com "a ""b"""]{link} :br
+            :i{x
+              y} z
+
+            :a
+                :b header
+                    text
+        "#};
+
+        let formatted = format(source, &FormatOptions::default());
+        assert_eq!(&formatted, source);
+        assert_eq!(format(&formatted, &FormatOptions::default()), formatted);
+    }
+
+    /// 属性の順番
+    #[test]
+    fn test_attribute_order() {
+        let source = ":tag[z=1 \"x\" a=2 y]\n";
+
+        assert_eq!(
+            format(source, &FormatOptions::default()),
+            ":tag[z=1 x a=2 y]\n"
+        );
+        assert_eq!(
+            format(
+                source,
+                &FormatOptions {
+                    attribute_order: AttributeOrder::Sorted,
+                    ..FormatOptions::default()
+                }
+            ),
+            ":tag[a=2 z=1 x y]\n"
+        );
+    }
+
+    /// 繰り返しを許した属性は書かれた順に残す
+    #[test]
+    fn test_repeated_attribute() {
+        let parse_options = ParseOptions::new().allow_repeated_attribute("tag", "class");
+        let document =
+            parse_document_with_options(":tag[class=b x class=a]\n".as_bytes(), &parse_options)
+                .unwrap();
+
+        let options = FormatOptions {
+            parse_options,
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            format_document(&document, &options).unwrap(),
+            ":tag[class=b x class=a]\n"
+        );
+
+        let options = FormatOptions {
+            attribute_order: AttributeOrder::Sorted,
+            ..options
+        };
+        assert_eq!(
+            format_document(&document, &options).unwrap(),
+            ":tag[class=b class=a x]\n"
+        );
+    }
+
+    /// パースした時のインデントの幅で出力する
+    #[test]
+    fn test_indent_width() {
+        let parse_options = ParseOptions::new().indent_width(2);
+        let document =
+            parse_document_with_options(":a\n  :b\n    c\n".as_bytes(), &parse_options).unwrap();
+
+        assert_eq!(
+            format_document(
+                &document,
+                &FormatOptions {
+                    parse_options,
+                    ..FormatOptions::default()
+                }
+            )
+            .unwrap(),
+            ":a\n  :b\n    c\n"
+        );
+    }
+
+    /// タグ名と属性名に使える文字
+    #[test]
+    fn test_name_syntax() {
+        assert_eq!(
+            format(":注意[著者=a]\n    b :強調{c}\n", &FormatOptions::default()),
+            ":注意[著者=a]\n    b :強調{c}\n"
+        );
+
+        let parse_options = ParseOptions::new().name_syntax(NameSyntax::Namespaced);
+        let document =
+            parse_document_with_options("a :x.b_{c}\n".as_bytes(), &parse_options).unwrap();
+        let options = FormatOptions {
+            parse_options,
+            tag_spelling: TagSpelling::Preserve,
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            format_document(&document, &options).unwrap(),
+            "a :x.b_{c}\n"
+        );
+
+        // 区切りを使わない書式では省略記法のままにする
+        let document = parse_document("a :b_{c}\n".as_bytes()).unwrap();
+        let options = FormatOptions {
+            tag_spelling: TagSpelling::Preserve,
+            ..FormatOptions::default()
+        };
+        assert_eq!(format_document(&document, &options).unwrap(), "a :b_{c}\n");
+
+        let document = parse_document(":注意\n".as_bytes()).unwrap();
+        let options = FormatOptions {
+            parse_options: ParseOptions::new().name_syntax(NameSyntax::Ascii),
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            format_document(&document, &options)
+                .unwrap_err()
+                .to_string(),
+            "The tag name is invalid. (\"注意\")"
+        );
+    }
+
+    /// 変更した省略記法の表で書き方を残す
+    #[test]
+    fn test_custom_abbreviations() {
+        let mut abbreviations = Abbreviations::empty();
+        abbreviations.insert('=', "highlight").unwrap();
+        let parse_options = ParseOptions::new().abbreviations(abbreviations);
+        let document =
+            parse_document_with_options("a :p={b} :p:b{c}\n".as_bytes(), &parse_options).unwrap();
+
+        let options = FormatOptions {
+            parse_options,
+            tag_spelling: TagSpelling::Preserve,
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            format_document(&document, &options).unwrap(),
+            "a :p={b} :p:b{c}\n"
+        );
+    }
+
+    /// 属性値は必要な時だけ引用符で囲む
+    #[test]
+    fn test_quote() {
+        assert_eq!(
+            format(
+                r#":tag[a="" "b""c" d="e=f" "g]"]"#,
+                &FormatOptions::default()
+            ),
+            ":tag[a=\"\" \"b\"\"c\" d=\"e=f\" \"g]\"]\n"
+        );
+    }
+
+    /// タグの書き方
+    #[test]
+    fn test_tag_spelling() {
+        let source = "a :p[x]*{b} :p:i{c} :q{:b{d}}\n";
+
+        assert_eq!(
+            format(source, &FormatOptions::default()),
+            "a :p[x]:b{b} :p:i{c} :q:b{d}\n"
+        );
+        assert_eq!(
+            format(
+                source,
+                &FormatOptions {
+                    tag_spelling: TagSpelling::Preserve,
+                    ..FormatOptions::default()
+                }
+            ),
+            "a :p[x]*{b} :p:i{c} :q:b{d}\n"
+        );
+    }
+
+    /// タグの直後に続くブロックタグは次の行に書く
+    #[test]
+    fn test_nested_block_tag() {
+        assert_eq!(
+            format(":a:b\n    x\n", &FormatOptions::default()),
+            ":a\n    :b\n        x\n"
+        );
+    }
+
+    /// タグの直後に続くブロックタグの後にブロックがあれば直後に続けたまま書く
+    #[test]
+    fn test_nested_block_tag_followed_by_block() {
+        assert_eq!(
+            format(":a:b:c\n\n    x\n", &FormatOptions::default()),
+            ":a:b:c\n\n    x\n"
+        );
+    }
+
+    /// ブロックタグと見なされないようにする
+    /// タグにならなかったコロンはエスケープする
+    #[test]
+    fn test_not_block_tag() {
+        assert_eq!(
+            format(":code:code-block{}\n", &FormatOptions::default()),
+            "\\:code:code-block{}\n"
+        );
+    }
+
+    /// 内容のないタグ
+    #[test]
+    fn test_no_contents() {
+        assert_eq!(
+            format("a :br{}b :br\n", &FormatOptions::default()),
+            "a :br{}b :br\n"
+        );
+    }
+
+    /// ブロックの要素の位置に書いたコメントは残す
+    #[test]
+    fn test_block_comment() {
+        let source = indoc! {"
+            :; first
+                body
+
+                    deeper
+            p
+
+            :sequence
+                :; in tag
+                x
+                :; after x
+
+
+            q
+                :; only
+            r
+                :; before y
+                y
+            :; last
+        "};
+        let expected = indoc! {"
+            :; first
+                body
+
+                    deeper
+            p
+
+            :sequence
+                :; in tag
+                x
+                :; after x
+
+            q
+                :; only
+            r
+                :; before y
+                y
+            :; last
+        "};
+        assert_eq!(format(source, &FormatOptions::default()), expected);
+    }
+
+    /// 段落、ヘッダー、属性の中の行末までのコメントは書かれていた行に残す
+    #[test]
+    fn test_line_comment() {
+        let source = indoc! {"
+            :section[id=s1   :; id
+                     class=a :; class
+            ] title :b{x} :; header
+                abc :; first
+                :; only
+                def :i{y
+            z} :; last
+        "};
+        let expected = indoc! {"
+            :section[id=s1 :; id
+                class=a :; class
+                ] title :b{x} :; header
+                abc :; first
+                :; only
+                def :i{y
+            z} :; last
+        "};
+
+        let formatted = format(source, &FormatOptions::default());
+        assert_eq!(formatted, expected);
+        assert_eq!(format(&formatted, &FormatOptions::default()), formatted);
+    }
+
+    /// 並べ替えた属性の間のコメントは"]"の前にまとめる
+    #[test]
+    fn test_line_comment_sorted_attributes() {
+        let source = indoc! {"
+            :tag[b=2 :; b
+                a=1 :; a
+                c=3] text :; header
+        "};
+        let options = FormatOptions {
+            attribute_order: AttributeOrder::Sorted,
+            ..FormatOptions::default()
+        };
+
+        assert_eq!(
+            format(source, &options),
+            indoc! {"
+                :tag[a=1 b=2 c=3 :; b
+                    :; a
+                    ] text :; header
+            "}
+        );
+    }
+
+    /// 改行で終わらない最後の行のコメントも残す
+    #[test]
+    fn test_line_comment_at_eof() {
+        assert_eq!(format("a :; note", &FormatOptions::default()), "a :; note");
+    }
+
+    /// エラーから回復して読み飛ばした範囲は書けないのでエラーにする
+    #[test]
+    fn test_error_node() {
+        let document = parse_document_with_options(
+            std::path::PathBuf::from("resources/test/include/absent_include.oreno"),
+            &ParseOptions::new()
+                .resolve_includes(true)
+                .recover_errors(true),
+        )
+        .unwrap();
+        assert_eq!(
+            format_document(&document, &FormatOptions::default())
+                .unwrap_err()
+                .to_string(),
+            "The parse error at 3:1 cannot be formatted."
+        );
+    }
+}
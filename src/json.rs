@@ -0,0 +1,997 @@
+//! ドキュメントとJSONの相互変換。
+//!
+//! JSONのスキーマはバージョン付きで、構造を変える時はバージョンを上げる。
+//! 読み込み時にバージョンが異なればエラーにする。
+//!
+//! バージョン1のスキーマ
+//!
+//! ```text
+//! ドキュメント
+//!     {"schema": "oreno-document", "version": 1, "filepath": string,
+//!      "errors": [警告], "warnings": [警告], "comments": [コメント], "root": Block}
+//!     errorsはエラーから回復してパースした時のエラー。
+//!
+//! 警告
+//!     {"filepath": string, "position": 位置 | null, "included_from": [インクルードの位置],
+//!      "parser_name": string | null, "message": string, "span": 範囲 | null}
+//!     spanはブロックの開始や終了についてのエラーなら、それを生んだインデントの範囲。
+//!
+//! インクルードの位置
+//!     {"filepath": string, "position": 位置 | null}
+//!     警告のファイルをインクルードしたタグの位置。内側のインクルードから並べる。
+//!
+//! 位置
+//!     {"line": number, "column": number, "byte_offset": number | null,
+//!      "utf16_column": number, "utf8_column": number}
+//!     columnは文字数、utf16_columnはUTF-16のコードユニット数、utf8_columnはバイト数で数えた桁。
+//!     byte_offsetは元のファイルでのバイトオフセットで、わからなければnull。
+//!
+//! 範囲
+//!     {"start": 位置, "end": 位置, "file": 持ち込んだファイル | null}
+//!     endは範囲の最後の文字の次の位置。
+//!     fileはインクルードしたファイルやテンプレートから持ち込んだ範囲なら、書かれていたファイル。
+//!
+//! 持ち込んだファイル
+//!     {"filepath": string, "included_from": [インクルードの位置]}
+//!     included_fromは持ち込んだタグの位置から外側に並べる。
+//!
+//! コメント
+//!     {"kind": "Line" | "Block", "text": string, "source_position": 範囲}
+//!     コメントは出現順に並べる。
+//!
+//! 要素は全てtypeと範囲のsource_positionを持つ。
+//!     {"type": "Block", "contents": [ブロックの要素]}
+//!     {"type": "Paragraph", "contents": [インラインの要素]}
+//!     {"type": "BlankLine"}
+//!     {"type": "Error"}
+//!     {"type": "BlockTag", "name": string, "abbreviation": bool, "name_position": 範囲,
+//!      "attributes": [属性], "header": BlockTagHeader | null, "contents": Block | null}
+//!     {"type": "BlockTagHeader", "contents": [インラインの要素]}
+//!     {"type": "InlineTag", "name": string, "abbreviation": bool, "name_position": 範囲,
+//!      "attributes": [属性], "contents": [インラインの要素]}
+//!     {"type": "Text", "value": string}
+//!     ブロックの要素はBlock、Paragraph、BlankLine、Error、BlockTagのいずれか。
+//!     Errorはエラーから回復した時に読み飛ばした範囲。
+//!     インラインの要素はText、InlineTagのいずれか。
+//!
+//! 属性
+//!     {"name": string | null, "name_position": 範囲 | null,
+//!      "value": string, "source_position": 範囲}
+//!     無名属性はnameとname_positionがnull。
+//!     source_positionは属性値の範囲で、引用符があれば引用符も含む。
+//!     属性は書かれた順に並べる。同じ名前の属性が複数あってもよい。
+//! ```
+
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use serde_json::json;
+use serde_json::Value;
+
+use crate::build::step1::Position;
+use crate::build::step1::Span;
+use crate::build::step2::FilePosition;
+use crate::build::step2::SourceFile;
+use crate::build::step3::attribute::Attribute;
+use crate::build::step3::attribute::AttributeItem;
+use crate::build::step3::attribute::AttributeValue;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::block_tag_header::BlockTagHeader;
+use crate::build::step3::comment::Comment;
+use crate::build::step3::comment::CommentKind;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::paragraph::Paragraph;
+use crate::build::step3::tag::TagName;
+use crate::build::step3::BlockContents;
+use crate::build::step3::BlockNode;
+use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
+use crate::build::step3::ParseError;
+use crate::build::step3::Text;
+use crate::document::Document;
+
+/// スキーマの名前
+pub const JSON_SCHEMA_NAME: &str = "oreno-document";
+
+/// スキーマのバージョン
+pub const JSON_SCHEMA_VERSION: u64 = 1;
+
+/// JSONからドキュメントを復元できなかった時のエラー。
+/// pointerはエラーの箇所を示すJSON Pointer。
+#[derive(Debug, PartialEq)]
+pub struct JsonError {
+    pub pointer: String,
+    pub message: String,
+}
+
+impl JsonError {
+    fn new(pointer: &str, message: String) -> JsonError {
+        JsonError {
+            pointer: pointer.to_owned(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.pointer.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.pointer, self.message)
+        }
+    }
+}
+
+impl Error for JsonError {}
+
+impl Document {
+    /// JSONの値に変換する。
+    pub fn to_json_value(&self) -> Value {
+        json!({
+            "schema": JSON_SCHEMA_NAME,
+            "version": JSON_SCHEMA_VERSION,
+            "filepath": self.filepath().to_string_lossy(),
+            "errors": self.errors().iter().map(write_warning).collect::<Vec<Value>>(),
+            "warnings": self.warnings().iter().map(write_warning).collect::<Vec<Value>>(),
+            "comments": self.comments().iter().map(write_comment).collect::<Vec<Value>>(),
+            "root": write_block(self.block()),
+        })
+    }
+
+    /// JSONの文字列に変換する。
+    pub fn to_json(&self) -> String {
+        self.to_json_value().to_string()
+    }
+
+    /// JSONの値から復元する。
+    pub fn from_json_value(value: &Value) -> Result<Document, JsonError> {
+        let reader = Reader::new(value);
+
+        let schema = reader.field("schema")?;
+        if schema.string()? != JSON_SCHEMA_NAME {
+            return Err(schema.error(format!("The schema is not {}.", JSON_SCHEMA_NAME)));
+        }
+
+        let version = reader.field("version")?;
+        if version.number()? != JSON_SCHEMA_VERSION {
+            return Err(version.error(format!(
+                "The version {} is not supported.",
+                version.number()?
+            )));
+        }
+
+        let filepath = PathBuf::from(reader.field("filepath")?.string()?);
+
+        let errors = reader
+            .field("errors")?
+            .array()?
+            .iter()
+            .map(read_warning)
+            .collect::<Result<Vec<ParseError>, JsonError>>()?;
+
+        let warnings = reader
+            .field("warnings")?
+            .array()?
+            .iter()
+            .map(read_warning)
+            .collect::<Result<Vec<ParseError>, JsonError>>()?;
+
+        let comments = reader
+            .field("comments")?
+            .array()?
+            .iter()
+            .map(read_comment)
+            .collect::<Result<Vec<Comment>, JsonError>>()?;
+
+        let block = read_block(&reader.field("root")?)?;
+
+        Ok(Document::new(filepath, block, warnings)
+            .with_errors(errors)
+            .with_comments(comments))
+    }
+
+    /// JSONの文字列から復元する。
+    pub fn from_json(json: &str) -> Result<Document, JsonError> {
+        let value = serde_json::from_str::<Value>(json)
+            .map_err(|e| JsonError::new("", format!("The JSON is malformed. ({})", e)))?;
+        Document::from_json_value(&value)
+    }
+}
+
+fn write_warning(warning: &ParseError) -> Value {
+    json!({
+        "filepath": warning.file_position.filepath.to_string_lossy(),
+        "position": warning.file_position.position.as_ref().map(write_position),
+        "included_from": write_include_chain(warning.file_position.include_chain()),
+        "parser_name": warning.parser_name,
+        "message": warning.message,
+        "span": warning.span.as_deref().map(write_span),
+    })
+}
+
+fn write_comment(comment: &Comment) -> Value {
+    json!({
+        "kind": match comment.kind() {
+            CommentKind::Line => "Line",
+            CommentKind::Block => "Block",
+        },
+        "text": comment.text(),
+        "source_position": write_span(comment.span()),
+    })
+}
+
+fn write_position(position: &Position) -> Value {
+    json!({
+        "line": position.line_number,
+        "column": position.column_number,
+        "byte_offset": position.byte_offset,
+        "utf16_column": position.utf16_column_number,
+        "utf8_column": position.utf8_column_number,
+    })
+}
+
+fn write_include_chain<'a>(chain: impl Iterator<Item = &'a FilePosition>) -> Value {
+    chain
+        .map(|file_position| {
+            json!({
+                "filepath": file_position.filepath.to_string_lossy(),
+                "position": file_position.position.as_ref().map(write_position),
+            })
+        })
+        .collect::<Vec<Value>>()
+        .into()
+}
+
+fn write_span(span: &Span) -> Value {
+    json!({
+        "start": write_position(&span.start),
+        "end": write_position(&span.end),
+        "file": span.file.as_ref().map(|file| json!({
+            "filepath": file.filepath.to_string_lossy(),
+            "included_from": write_include_chain(
+                std::iter::once(&file.included_from).chain(file.included_from.include_chain())
+            ),
+        })),
+    })
+}
+
+fn write_block(block: &Block) -> Value {
+    json!({
+        "type": "Block",
+        "contents": block.contents().iter().map(write_block_node).collect::<Vec<Value>>(),
+        "source_position": write_span(block.span()),
+    })
+}
+
+fn write_block_node(node: &BlockNode) -> Value {
+    match node {
+        BlockNode::Paragraph(paragraph) => json!({
+            "type": "Paragraph",
+            "contents": write_inline_contents(paragraph.contents()),
+            "source_position": write_span(paragraph.span()),
+        }),
+        BlockNode::BlockTag(block_tag) => json!({
+            "type": "BlockTag",
+            "name": block_tag.name().name(),
+            "abbreviation": block_tag.name().abbreviation(),
+            "name_position": write_span(block_tag.name().span()),
+            "attributes": write_attributes(block_tag.attributes()),
+            "header": block_tag.header().map(|header| json!({
+                "type": "BlockTagHeader",
+                "contents": write_inline_contents(header.contents()),
+                "source_position": write_span(header.span()),
+            })),
+            "contents": block_tag.contents().map(write_block),
+            "source_position": write_span(block_tag.span()),
+        }),
+        BlockNode::Block(block) => write_block(block),
+        BlockNode::BlankLine(span) => json!({
+            "type": "BlankLine",
+            "source_position": write_span(span),
+        }),
+        BlockNode::Error(span) => json!({
+            "type": "Error",
+            "source_position": write_span(span),
+        }),
+    }
+}
+
+fn write_inline_contents(contents: &InlineContents) -> Value {
+    Value::Array(contents.iter().map(write_inline_node).collect())
+}
+
+fn write_inline_node(node: &InlineNode) -> Value {
+    match node {
+        InlineNode::Text(text) => json!({
+            "type": "Text",
+            "value": text.value(),
+            "source_position": write_span(text.span()),
+        }),
+        InlineNode::InlineTag(inline_tag) => json!({
+            "type": "InlineTag",
+            "name": inline_tag.name().name(),
+            "abbreviation": inline_tag.name().abbreviation(),
+            "name_position": write_span(inline_tag.name().span()),
+            "attributes": write_attributes(inline_tag.attributes()),
+            "contents": write_inline_contents(inline_tag.contents()),
+            "source_position": write_span(inline_tag.span()),
+        }),
+    }
+}
+
+fn write_attributes(attributes: &Attributes) -> Value {
+    Value::Array(
+        attributes
+            .items()
+            .iter()
+            .map(|item| match item {
+                AttributeItem::Named(attribute) => json!({
+                    "name": attribute.name(),
+                    "name_position": write_span(attribute.name_span()),
+                    "value": attribute.value().value(),
+                    "source_position": write_span(attribute.value().span()),
+                }),
+                AttributeItem::Nameless(value) => json!({
+                    "name": null,
+                    "name_position": null,
+                    "value": value.value(),
+                    "source_position": write_span(value.span()),
+                }),
+            })
+            .collect(),
+    )
+}
+
+/// 読み込み中の値とその位置。
+struct Reader<'a> {
+    value: &'a Value,
+    pointer: String,
+}
+
+impl<'a> Reader<'a> {
+    fn new(value: &'a Value) -> Reader<'a> {
+        Reader {
+            value,
+            pointer: String::new(),
+        }
+    }
+
+    fn error(&self, message: String) -> JsonError {
+        JsonError::new(&self.pointer, message)
+    }
+
+    fn child(&self, value: &'a Value, key: &str) -> Reader<'a> {
+        Reader {
+            value,
+            pointer: format!(
+                "{}/{}",
+                self.pointer,
+                key.replace('~', "~0").replace('/', "~1")
+            ),
+        }
+    }
+
+    /// フィールドを取得する。なければエラー。
+    fn field(&self, name: &str) -> Result<Reader<'a>, JsonError> {
+        match self.value {
+            Value::Object(map) => match map.get(name) {
+                Some(value) => Ok(self.child(value, name)),
+                None => Err(self.error(format!("There is no field '{}'.", name))),
+            },
+            _ => Err(self.error("An object is required.".to_owned())),
+        }
+    }
+
+    /// nullでなければフィールドを取得する。
+    fn optional_field(&self, name: &str) -> Result<Option<Reader<'a>>, JsonError> {
+        let field = self.field(name)?;
+        if field.value.is_null() {
+            Ok(None)
+        } else {
+            Ok(Some(field))
+        }
+    }
+
+    fn string(&self) -> Result<&'a str, JsonError> {
+        self.value
+            .as_str()
+            .ok_or_else(|| self.error("A string is required.".to_owned()))
+    }
+
+    fn number(&self) -> Result<u64, JsonError> {
+        self.value
+            .as_u64()
+            .ok_or_else(|| self.error("A non-negative integer is required.".to_owned()))
+    }
+
+    fn boolean(&self) -> Result<bool, JsonError> {
+        self.value
+            .as_bool()
+            .ok_or_else(|| self.error("A boolean is required.".to_owned()))
+    }
+
+    fn array(&self) -> Result<Vec<Reader<'a>>, JsonError> {
+        match self.value {
+            Value::Array(values) => Ok(values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| self.child(value, &i.to_string()))
+                .collect()),
+            _ => Err(self.error("An array is required.".to_owned())),
+        }
+    }
+
+    /// 要素の種類を取得して、期待するものでなければエラー。
+    fn node_type(&self, expected: &[&str]) -> Result<&'a str, JsonError> {
+        let field = self.field("type")?;
+        let node_type = field.string()?;
+        if expected.contains(&node_type) {
+            Ok(node_type)
+        } else {
+            Err(field.error(format!(
+                "The type '{}' is not allowed here. ({})",
+                node_type,
+                expected.join(", ")
+            )))
+        }
+    }
+}
+
+fn read_warning(reader: &Reader) -> Result<ParseError, JsonError> {
+    let position = match reader.optional_field("position")? {
+        Some(position) => Some(read_position(&position)?),
+        None => None,
+    };
+    let included_from = read_include_chain(&reader.field("included_from")?)?;
+    let parser_name = match reader.optional_field("parser_name")? {
+        Some(parser_name) => Some(parser_name.string()?.to_owned()),
+        None => None,
+    };
+
+    let mut warning = ParseError::new(
+        FilePosition {
+            filepath: PathBuf::from(reader.field("filepath")?.string()?),
+            position,
+            included_from,
+        },
+        parser_name,
+        reader.field("message")?.string()?.to_owned(),
+    );
+    if let Some(span) = reader.optional_field("span")? {
+        warning.span = Some(Box::new(read_span(&span)?));
+    }
+    Ok(warning)
+}
+
+fn read_comment(reader: &Reader) -> Result<Comment, JsonError> {
+    let kind = reader.field("kind")?;
+    let kind = match kind.string()? {
+        "Line" => CommentKind::Line,
+        "Block" => CommentKind::Block,
+        other => return Err(kind.error(format!("The comment kind {} is unknown.", other))),
+    };
+
+    Ok(Comment::new(
+        kind,
+        reader.field("text")?.string()?.to_owned(),
+        read_source_position(reader)?,
+    ))
+}
+
+fn read_position(reader: &Reader) -> Result<Position, JsonError> {
+    let byte_offset = match reader.optional_field("byte_offset")? {
+        Some(byte_offset) => Some(byte_offset.number()? as usize),
+        None => None,
+    };
+    Ok(Position {
+        line_number: reader.field("line")?.number()?,
+        column_number: reader.field("column")?.number()?,
+        byte_offset,
+        utf16_column_number: reader.field("utf16_column")?.number()?,
+        utf8_column_number: reader.field("utf8_column")?.number()?,
+    })
+}
+
+fn read_include_chain(reader: &Reader) -> Result<Option<Box<FilePosition>>, JsonError> {
+    // 配列は内側のインクルードから並ぶので、外側から繋げる
+    let mut included_from = None;
+    for reader in reader.array()?.iter().rev() {
+        included_from = Some(Box::new(FilePosition {
+            filepath: PathBuf::from(reader.field("filepath")?.string()?),
+            position: match reader.optional_field("position")? {
+                Some(position) => Some(read_position(&position)?),
+                None => None,
+            },
+            included_from,
+        }));
+    }
+    Ok(included_from)
+}
+
+fn read_span(reader: &Reader) -> Result<Span, JsonError> {
+    let mut span = Span::new(
+        read_position(&reader.field("start")?)?,
+        read_position(&reader.field("end")?)?,
+    );
+    if let Some(file) = reader.optional_field("file")? {
+        let included_from = file.field("included_from")?;
+        span.file = Some(Rc::new(SourceFile {
+            filepath: PathBuf::from(file.field("filepath")?.string()?),
+            included_from: match read_include_chain(&included_from)? {
+                Some(included_from) => *included_from,
+                None => {
+                    return Err(included_from.error("An include position is required.".to_owned()))
+                }
+            },
+        }));
+    }
+    Ok(span)
+}
+
+fn read_source_position(reader: &Reader) -> Result<Span, JsonError> {
+    read_span(&reader.field("source_position")?)
+}
+
+fn read_block(reader: &Reader) -> Result<Block, JsonError> {
+    reader.node_type(&["Block"])?;
+
+    let contents = reader
+        .field("contents")?
+        .array()?
+        .iter()
+        .map(read_block_node)
+        .collect::<Result<BlockContents, JsonError>>()?;
+
+    Ok(Block::new(contents, read_source_position(reader)?))
+}
+
+fn read_block_node(reader: &Reader) -> Result<BlockNode, JsonError> {
+    let node = match reader.node_type(&["Block", "Paragraph", "BlankLine", "Error", "BlockTag"])? {
+        "Block" => BlockNode::Block(read_block(reader)?),
+        "Paragraph" => BlockNode::Paragraph(Paragraph::new(
+            read_inline_contents(&reader.field("contents")?)?,
+            read_source_position(reader)?,
+        )),
+        "BlankLine" => BlockNode::BlankLine(read_source_position(reader)?),
+        "Error" => BlockNode::Error(read_source_position(reader)?),
+        _ => {
+            let attributes = read_attributes(&reader.field("attributes")?)?;
+
+            let header = match reader.optional_field("header")? {
+                Some(header) => {
+                    header.node_type(&["BlockTagHeader"])?;
+                    Some(BlockTagHeader::new(
+                        read_inline_contents(&header.field("contents")?)?,
+                        read_source_position(&header)?,
+                    ))
+                }
+                None => None,
+            };
+
+            let contents = match reader.optional_field("contents")? {
+                Some(contents) => Some(read_block(&contents)?),
+                None => None,
+            };
+
+            BlockNode::BlockTag(Box::new(BlockTag::new(
+                read_tag_name(reader)?,
+                attributes,
+                header,
+                contents,
+                read_source_position(reader)?,
+            )))
+        }
+    };
+
+    Ok(node)
+}
+
+fn read_inline_contents(reader: &Reader) -> Result<InlineContents, JsonError> {
+    reader
+        .array()?
+        .iter()
+        .map(read_inline_node)
+        .collect::<Result<InlineContents, JsonError>>()
+}
+
+fn read_inline_node(reader: &Reader) -> Result<InlineNode, JsonError> {
+    let node = match reader.node_type(&["Text", "InlineTag"])? {
+        "Text" => InlineNode::Text(Text::new(
+            reader.field("value")?.string()?.to_owned(),
+            read_source_position(reader)?,
+        )),
+        _ => {
+            let attributes = read_attributes(&reader.field("attributes")?)?;
+
+            InlineNode::InlineTag(InlineTag::new(
+                read_tag_name(reader)?,
+                attributes,
+                read_inline_contents(&reader.field("contents")?)?,
+                read_source_position(reader)?,
+            ))
+        }
+    };
+
+    Ok(node)
+}
+
+fn read_tag_name(reader: &Reader) -> Result<TagName, JsonError> {
+    Ok(TagName::new(
+        reader.field("name")?.string()?.to_owned(),
+        reader.field("abbreviation")?.boolean()?,
+        read_span(&reader.field("name_position")?)?,
+    ))
+}
+
+fn read_attributes(reader: &Reader) -> Result<Attributes, JsonError> {
+    let mut attributes = Attributes::new();
+
+    for attribute in reader.array()? {
+        let value = AttributeValue::new(
+            attribute.field("value")?.string()?.to_owned(),
+            read_source_position(&attribute)?,
+        );
+
+        match attribute.optional_field("name")? {
+            Some(name) => {
+                let name_span = read_span(&attribute.field("name_position")?)?;
+                let name = name.string()?.to_owned();
+                attributes.push(AttributeItem::Named(Attribute::new(name, name_span, value)));
+            }
+            None => attributes.push(AttributeItem::Nameless(value)),
+        }
+    }
+
+    Ok(attributes)
+}
+
+#[cfg(test)]
+mod test_to_json {
+    use crate::build::step3::ParseOptions;
+    use crate::document::parse_document;
+    use crate::document::parse_document_with_options;
+    use crate::document::Document;
+    use indoc::indoc;
+    use serde_json::json;
+
+    /// スキーマに沿った形式で出力される
+    #[test]
+    fn test_schema() {
+        let document = parse_document("a:b[a=1 x]{t}\n".as_bytes()).unwrap();
+
+        // ASCIIだけの1行なので、バイトオフセットとほかの桁は桁から決まる
+        let position = |line: u64, column: u64| {
+            json!({
+                "line": line,
+                "column": column,
+                "byte_offset": if line == 1 { column - 1 } else { 14 },
+                "utf16_column": column,
+                "utf8_column": column,
+            })
+        };
+        let span = |l1, c1, l2, c2| json!({"start": position(l1, c1), "end": position(l2, c2), "file": null});
+
+        assert_eq!(
+            document.to_json_value(),
+            json!({
+                "schema": "oreno-document",
+                "version": 1,
+                "filepath": "",
+                "errors": [],
+                "warnings": [],
+                "comments": [],
+                "root": {
+                    "type": "Block",
+                    "contents": [{
+                        "type": "Paragraph",
+                        "contents": [
+                            {
+                                "type": "Text",
+                                "value": "a",
+                                "source_position": span(1, 1, 1, 2),
+                            },
+                            {
+                                "type": "InlineTag",
+                                "name": "b",
+                                "abbreviation": false,
+                                "name_position": span(1, 2, 1, 4),
+                                "attributes": [
+                                    {
+                                        "name": "a",
+                                        "name_position": span(1, 5, 1, 6),
+                                        "value": "1",
+                                        "source_position": span(1, 7, 1, 8),
+                                    },
+                                    {
+                                        "name": null,
+                                        "name_position": null,
+                                        "value": "x",
+                                        "source_position": span(1, 9, 1, 10),
+                                    },
+                                ],
+                                "contents": [{
+                                    "type": "Text",
+                                    "value": "t",
+                                    "source_position": span(1, 12, 1, 13),
+                                }],
+                                "source_position": span(1, 2, 1, 14),
+                            },
+                            {
+                                "type": "Text",
+                                "value": "\n",
+                                "source_position": span(1, 14, 2, 1),
+                            },
+                        ],
+                        "source_position": span(1, 1, 2, 1),
+                    }],
+                    "source_position": span(1, 1, 2, 1),
+                },
+            })
+        );
+    }
+
+    /// 制御文字やバックスラッシュもエスケープされる
+    #[test]
+    fn test_escape() {
+        let document = parse_document("a\\\\b\t\"c\"".as_bytes()).unwrap();
+        let json = document.to_json();
+
+        assert!(json.contains(r#""value":"a\\b\t\"c\"""#));
+        assert_eq!(Document::from_json(&json).unwrap(), document);
+    }
+
+    /// 出力したJSONから同じドキュメントを復元できる
+    #[test]
+    fn test_round_trip() {
+        let document = parse_document(
+            indoc! {r#"
+                abc:b{xyz} :link[https://example.com "a b"]{link} :; TODO
+
+                :; block
+                    comment
+                :section[id=s1] header :i{x}
+                    :code-block[rust]
+                        fn main() {}
+
+                    text
+            "#}
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let restored = Document::from_json(&document.to_json()).unwrap();
+
+        assert_eq!(restored, document);
+    }
+
+    /// 位置はバイトオフセットとUTF-16とUTF-8の桁も出力して復元する
+    #[test]
+    fn test_position_fields() {
+        let document = parse_document("\u{feff}あ😀:b{x}".as_bytes()).unwrap();
+        let value = document.to_json_value();
+
+        assert_eq!(
+            value["root"]["contents"][0]["contents"][1]["source_position"]["start"],
+            json!({
+                "line": 1,
+                "column": 3,
+                "byte_offset": 10,
+                "utf16_column": 4,
+                "utf8_column": 8,
+            })
+        );
+        assert_eq!(Document::from_json_value(&value).unwrap(), document);
+
+        // 構築したドキュメントの位置はバイトオフセットがわからない
+        let mut value = value;
+        value["root"]["source_position"]["start"]["byte_offset"] = json!(null);
+        let restored = Document::from_json_value(&value).unwrap();
+        assert_eq!(restored.block().span().start.byte_offset, None);
+    }
+
+    /// 属性は書かれた順に出力され、繰り返された属性も復元できる
+    #[test]
+    fn test_attribute_order() {
+        let document = parse_document_with_options(
+            ":p[z=1 x class=a class=b]{t}".as_bytes(),
+            &ParseOptions::new().allow_repeated_attribute("p", "class"),
+        )
+        .unwrap();
+        let value = document.to_json_value();
+
+        let attributes = value["root"]["contents"][0]["contents"][0]["attributes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|attribute| (attribute["name"].clone(), attribute["value"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            attributes,
+            vec![
+                (json!("z"), json!("1")),
+                (json!(null), json!("x")),
+                (json!("class"), json!("a")),
+                (json!("class"), json!("b")),
+            ]
+        );
+        assert_eq!(Document::from_json_value(&value).unwrap(), document);
+    }
+
+    /// 警告も出力される
+    #[test]
+    fn test_warnings() {
+        let document = parse_document(":tag;".as_bytes()).unwrap();
+        let value = document.to_json_value();
+
+        assert_eq!(
+            value["warnings"][0],
+            json!({
+                "filepath": "",
+                "position": {"line": 1, "column": 5, "byte_offset": 4, "utf16_column": 5, "utf8_column": 5},
+                "included_from": [],
+                "parser_name": "block tag",
+                "message": "There is an illegal character. ';'",
+                "span": null,
+            })
+        );
+        assert_eq!(
+            Document::from_json_value(&value).unwrap().warnings(),
+            document.warnings()
+        );
+
+        // 範囲のある警告も復元できる
+        let mut value = value;
+        value["warnings"][0]["span"] = value["root"]["source_position"].clone();
+        let restored = Document::from_json_value(&value).unwrap();
+        assert_eq!(
+            restored.warnings()[0].span.as_deref(),
+            Some(document.block().span())
+        );
+        assert_eq!(restored.to_json_value(), value);
+    }
+
+    /// 回復したエラーとエラーの要素も出力される
+    #[test]
+    fn test_errors() {
+        let document = parse_document_with_options(
+            std::path::PathBuf::from("resources/test/include/absent_include.oreno"),
+            &ParseOptions::new()
+                .resolve_includes(true)
+                .recover_errors(true),
+        )
+        .unwrap();
+        let value = document.to_json_value();
+
+        assert_eq!(
+            value["errors"][0]["filepath"],
+            json!("resources/test/include/absent.oreno")
+        );
+        assert_eq!(
+            value["root"]["contents"][2],
+            json!({
+                "type": "Error",
+                "source_position": {
+                    "start": {"line": 3, "column": 1, "byte_offset": 6, "utf16_column": 1, "utf8_column": 1},
+                    "end": {"line": 3, "column": 23, "byte_offset": 28, "utf16_column": 23, "utf8_column": 23},
+                    "file": null,
+                },
+            })
+        );
+        assert_eq!(Document::from_json_value(&value).unwrap(), document);
+    }
+
+    /// インクルードしたファイルの警告はインクルードしたタグの位置も出力される
+    #[test]
+    fn test_included_warnings() {
+        let document = parse_document_with_options(
+            std::path::PathBuf::from("resources/test/include/book.oreno"),
+            &ParseOptions::new().resolve_includes(true),
+        )
+        .unwrap();
+        let value = document.to_json_value();
+
+        assert_eq!(
+            value["warnings"][0]["included_from"],
+            json!([{
+                "filepath": "resources/test/include/book.oreno",
+                "position": {"line": 4, "column": 5, "byte_offset": 32, "utf16_column": 5, "utf8_column": 5},
+            }])
+        );
+        assert_eq!(
+            Document::from_json_value(&value).unwrap().warnings(),
+            document.warnings()
+        );
+
+        // インクルードした要素の範囲には書かれていたファイルが付く
+        assert_eq!(
+            value["root"]["contents"][2]["source_position"]["file"],
+            json!({
+                "filepath": "resources/test/include/chapters/part.oreno",
+                "included_from": [
+                    {
+                        "filepath": "resources/test/include/chapters/chapter2.oreno",
+                        "position": {"line": 3, "column": 1, "byte_offset": 9, "utf16_column": 1, "utf8_column": 1},
+                    },
+                    {
+                        "filepath": "resources/test/include/book.oreno",
+                        "position": {"line": 6, "column": 1, "byte_offset": 58, "utf16_column": 1, "utf8_column": 1},
+                    },
+                ],
+            })
+        );
+        assert_eq!(Document::from_json_value(&value).unwrap(), document);
+    }
+}
+
+#[cfg(test)]
+mod test_from_json {
+    use crate::document::parse_document;
+    use crate::document::Document;
+    use crate::json::JsonError;
+
+    fn json() -> serde_json::Value {
+        parse_document(":b{t}".as_bytes()).unwrap().to_json_value()
+    }
+
+    /// バージョンが違えばエラー
+    #[test]
+    fn test_version() {
+        let mut value = json();
+        value["version"] = 2.into();
+
+        assert_eq!(
+            Document::from_json_value(&value).err().unwrap(),
+            JsonError {
+                pointer: "/version".to_owned(),
+                message: "The version 2 is not supported.".to_owned(),
+            }
+        );
+    }
+
+    /// スキーマが違えばエラー
+    #[test]
+    fn test_schema() {
+        let mut value = json();
+        value["schema"] = "other".into();
+
+        let error = Document::from_json_value(&value).err().unwrap();
+        assert_eq!(&error.pointer, "/schema");
+    }
+
+    /// 不正な箇所をJSON Pointerで示す
+    #[test]
+    fn test_pointer() {
+        let mut value = json();
+        value["root"]["contents"][0]["contents"][0]["type"] = "Paragraph".into();
+
+        let error = Document::from_json_value(&value).err().unwrap();
+        assert_eq!(&error.pointer, "/root/contents/0/contents/0/type");
+        assert_eq!(
+            &error.to_string(),
+            "/root/contents/0/contents/0/type: The type 'Paragraph' is not allowed here. (Text, InlineTag)"
+        );
+
+        let mut value = json();
+        value["root"]["contents"][0]["contents"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("name");
+
+        let error = Document::from_json_value(&value).err().unwrap();
+        assert_eq!(&error.pointer, "/root/contents/0/contents/0");
+        assert_eq!(&error.message, "There is no field 'name'.");
+    }
+
+    /// JSONとして不正ならエラー
+    #[test]
+    fn test_malformed() {
+        let error = Document::from_json("{").err().unwrap();
+        assert!(error.pointer.is_empty());
+        assert!(error.message.starts_with("The JSON is malformed."));
+    }
+}
@@ -0,0 +1,59 @@
+pub mod build;
+mod builder;
+mod cst;
+mod document;
+mod format;
+mod json;
+mod query;
+mod schema;
+mod template;
+
+pub use build::step1::convert_column;
+pub use build::step1::ColumnUnit;
+pub use build::step1::Encoding;
+pub use build::step2::TabPolicy;
+pub use build::step3::comment::Comment;
+pub use build::step3::comment::CommentKind;
+pub use build::step3::symbol::NameSyntax;
+pub use build::step3::tag::AbbreviationError;
+pub use build::step3::tag::Abbreviations;
+pub use build::step3::ParseOptions;
+pub use builder::BlockBuilder;
+pub use builder::BlockTagBuilder;
+pub use builder::InlineBuilder;
+pub use builder::InlineTagBuilder;
+pub use cst::build_syntax_tree;
+pub use cst::build_syntax_tree_with_options;
+pub use cst::parse_syntax_tree;
+pub use cst::parse_syntax_tree_with_options;
+pub use cst::NodeKind;
+pub use cst::SyntaxElement;
+pub use cst::SyntaxNode;
+pub use cst::SyntaxToken;
+pub use cst::TokenKind;
+pub use document::parse_document;
+pub use document::parse_document_with_options;
+pub use document::parse_reader;
+pub use document::Diagnostics;
+pub use document::Document;
+pub use document::Source;
+pub use format::emit_source;
+pub use format::format_document;
+pub use format::AttributeOrder;
+pub use format::FormatError;
+pub use format::FormatOptions;
+pub use format::TagSpelling;
+pub use json::JsonError;
+pub use json::JSON_SCHEMA_NAME;
+pub use json::JSON_SCHEMA_VERSION;
+pub use query::select;
+pub use query::Selector;
+pub use query::SelectorError;
+pub use query::TagRef;
+pub use schema::validate;
+pub use schema::Schema;
+pub use schema::TagSchema;
+pub use schema::TagUsage;
+pub use template::expand_templates;
+pub use template::Template;
+pub use template::Templates;
@@ -1,35 +1,266 @@
-pub mod build;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
-trait MyTrait {
-    fn a();
+fn main() -> ExitCode {
+    let mut args = env::args().skip(1).peekable();
 
-    #[cfg(test)]
-    fn b();
-}
+    // fmtサブコマンドならファイルを整形する
+    if args.peek().map(String::as_str) == Some("fmt") {
+        args.next();
+        return format_files(args.collect());
+    }
+
+    let mut exit_code = ExitCode::SUCCESS;
 
-struct MyStruct {}
+    // --jsonが指定されたらパースしたドキュメントをJSONで出力する
+    // --validateが指定されたらタグが標準の定義に従っているか検証して警告する
+    // --expand-templatesが指定されたらテンプレートを展開する
+    // --templates=ファイルが指定されたらファイルで定義したテンプレートも使って展開する
+    let (options, filepaths): (Vec<String>, Vec<String>) =
+        args.partition(|arg| arg.starts_with("--"));
+    let json = options.iter().any(|option| option == "--json");
+    let validate = options.iter().any(|option| option == "--validate");
+    let template_files = options
+        .iter()
+        .filter_map(|option| option.strip_prefix("--templates="))
+        .collect::<Vec<_>>();
+    let expand_templates =
+        !template_files.is_empty() || options.iter().any(|option| option == "--expand-templates");
 
-impl MyTrait for MyStruct {
-    fn a() {
-        println!("a");
+    let parse_options = match parse_options(&options) {
+        Ok(parse_options) => parse_options,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut templates = oreno::Templates::new();
+    for filepath in template_files {
+        match oreno::parse_document_with_options(PathBuf::from(filepath), &parse_options) {
+            Ok(document) => {
+                for warning in document
+                    .warnings()
+                    .iter()
+                    .chain(&templates.add_definitions(&document))
+                {
+                    eprintln!("{}", warning);
+                }
+            }
+            Err(diagnostics) => {
+                eprintln!("{}", diagnostics);
+                return ExitCode::FAILURE;
+            }
+        }
     }
 
-    #[cfg(test)]
-    fn b() {
-        println!("b");
+    for filepath in filepaths {
+        // "-"なら標準入力から読み込む
+        let result = if filepath == "-" {
+            oreno::parse_reader(PathBuf::from("<stdin>"), io::stdin(), &parse_options)
+        } else {
+            oreno::parse_document_with_options(PathBuf::from(filepath), &parse_options)
+        };
+
+        match result {
+            Ok(mut document) => {
+                // 回復したエラーがあれば、ドキュメントは出力しても失敗にする
+                for error in document.errors() {
+                    eprintln!("{}", error);
+                    exit_code = ExitCode::FAILURE;
+                }
+                for warning in document.warnings() {
+                    eprintln!("{}", warning);
+                }
+                if expand_templates {
+                    for warning in document.expand_templates(&templates) {
+                        eprintln!("{}", warning);
+                    }
+                }
+                if validate {
+                    for warning in document.validate(parse_options.get_schema()) {
+                        eprintln!("{}", warning);
+                    }
+                }
+                if json {
+                    println!("{}", document.to_json());
+                }
+            }
+            Err(diagnostics) => {
+                eprintln!("{}", diagnostics);
+                exit_code = ExitCode::FAILURE;
+            }
+        }
     }
+
+    exit_code
 }
 
-fn main() {
-    MyStruct::a();
+/// パースのオプションを読み込む。
+///
+/// --encoding=ラベル   BOMがないファイルのエンコーディング
+/// --indent-width=桁数 1段のインデントの桁数
+/// --tabs=方針         インデントのタブの扱い。expand:桁数、indent、text、rejectのいずれか。既定はtext
+/// --strict-indent     幅の倍数でないインデントと、2段以上深くなるインデントを警告する
+/// --names=書式        タグ名と属性名に使える文字。ascii、unicode、namespacedのいずれか
+/// --abbreviation=記号=タグ名 省略記法を追加する。タグ名を空にすると記号を取り除く
+/// --no-abbreviations  標準の省略記法を使わない
+/// --include           includeタグで指定したファイルを読み込んで置き換える
+/// --recover-errors    エラーがあってもパースを続けて、すべてのエラーを出力する
+fn parse_options(options: &[String]) -> Result<oreno::ParseOptions, String> {
+    let mut parse_options = oreno::ParseOptions::new();
+    let mut abbreviations = oreno::Abbreviations::new();
+
+    for option in options {
+        if let Some(label) = option.strip_prefix("--encoding=") {
+            let encoding = oreno::Encoding::for_label(label)
+                .ok_or_else(|| format!("The encoding is not supported. ({})", label))?;
+            parse_options = parse_options.encoding(encoding);
+        } else if let Some(width) = option.strip_prefix("--indent-width=") {
+            let width = width
+                .parse::<u64>()
+                .ok()
+                .filter(|width| *width > 0)
+                .ok_or_else(|| format!("The indent width is invalid. ({})", width))?;
+            parse_options = parse_options.indent_width(width);
+        } else if let Some(policy) = option.strip_prefix("--tabs=") {
+            let tab_policy = match policy.split_once(':') {
+                Some(("expand", width)) => width
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|width| *width > 0)
+                    .map(oreno::TabPolicy::Expand),
+                None if policy == "indent" => Some(oreno::TabPolicy::Indent),
+                None if policy == "text" => Some(oreno::TabPolicy::Text),
+                None if policy == "reject" => Some(oreno::TabPolicy::Reject),
+                _ => None,
+            }
+            .ok_or_else(|| format!("The tab policy is invalid. ({})", policy))?;
+            parse_options = parse_options.tab_policy(tab_policy);
+        } else if option == "--strict-indent" {
+            parse_options = parse_options.strict_indent(true);
+        } else if let Some(syntax) = option.strip_prefix("--names=") {
+            let name_syntax = match syntax {
+                "ascii" => oreno::NameSyntax::Ascii,
+                "unicode" => oreno::NameSyntax::Unicode,
+                "namespaced" => oreno::NameSyntax::Namespaced,
+                _ => return Err(format!("The name syntax is invalid. ({})", syntax)),
+            };
+            parse_options = parse_options.name_syntax(name_syntax);
+        } else if let Some(abbreviation) = option.strip_prefix("--abbreviation=") {
+            let mut chars = abbreviation.chars();
+            let (symbol, tag_name) = chars
+                .next()
+                .zip(chars.as_str().strip_prefix('='))
+                .ok_or_else(|| format!("The abbreviation is invalid. ({})", abbreviation))?;
+            if tag_name.is_empty() {
+                abbreviations.remove(symbol);
+            } else {
+                abbreviations
+                    .insert(symbol, tag_name)
+                    .map_err(|error| error.to_string())?;
+            }
+        } else if option == "--no-abbreviations" {
+            abbreviations = oreno::Abbreviations::empty();
+        } else if option == "--include" {
+            parse_options = parse_options.resolve_includes(true);
+        } else if option == "--recover-errors" {
+            parse_options = parse_options.recover_errors(true);
+        }
+    }
+
+    Ok(parse_options.abbreviations(abbreviations))
 }
 
-#[cfg(test)]
-mod test {
-    use super::MyStruct;
-    use super::MyTrait;
-    #[test]
-    fn test() {
-        MyStruct::b();
+/// ファイルを整形して書き換える。
+/// --checkが指定されたら書き換えずに、整形で変わるファイルがあれば失敗にする。
+fn format_files(args: Vec<String>) -> ExitCode {
+    let mut exit_code = ExitCode::SUCCESS;
+
+    let (options, filepaths): (Vec<String>, Vec<String>) =
+        args.into_iter().partition(|arg| arg.starts_with("--"));
+    let check = options.iter().any(|option| option == "--check");
+    let parse_options = match parse_options(&options) {
+        Ok(parse_options) => parse_options,
+        Err(message) => {
+            eprintln!("{}", message);
+            return ExitCode::FAILURE;
+        }
+    };
+    let format_options = oreno::FormatOptions {
+        attribute_order: if options.iter().any(|option| option == "--sort-attributes") {
+            oreno::AttributeOrder::Sorted
+        } else {
+            oreno::AttributeOrder::Source
+        },
+        tag_spelling: if options
+            .iter()
+            .any(|option| option == "--keep-abbreviations")
+        {
+            oreno::TagSpelling::Preserve
+        } else {
+            oreno::TagSpelling::Full
+        },
+        // 整形したファイルにインクルードしたファイルの内容を書き込まない
+        // エラーを読み飛ばしたファイルは整形できないので回復しない
+        parse_options: parse_options.resolve_includes(false).recover_errors(false),
+    };
+
+    for filepath in filepaths {
+        let filepath = PathBuf::from(filepath);
+
+        let source = match fs::read(&filepath) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}: {}", filepath.display(), e);
+                exit_code = ExitCode::FAILURE;
+                continue;
+            }
+        };
+
+        // UTF-8以外のファイルは書き換えるとエンコーディングが変わるので整形しない
+        if std::str::from_utf8(&source).is_err() {
+            eprintln!("{}: The file is not encoded in UTF-8.", filepath.display());
+            exit_code = ExitCode::FAILURE;
+            continue;
+        }
+
+        let document = match oreno::parse_document_with_options(
+            filepath.clone(),
+            &format_options.parse_options,
+        ) {
+            Ok(document) => document,
+            Err(diagnostics) => {
+                eprintln!("{}", diagnostics);
+                exit_code = ExitCode::FAILURE;
+                continue;
+            }
+        };
+
+        let formatted = match oreno::format_document(&document, &format_options) {
+            Ok(formatted) => formatted,
+            Err(e) => {
+                eprintln!("{}: {}", filepath.display(), e);
+                exit_code = ExitCode::FAILURE;
+                continue;
+            }
+        };
+
+        if formatted.as_bytes() == source.as_slice() {
+            continue;
+        }
+
+        if check {
+            println!("{}", filepath.display());
+            exit_code = ExitCode::FAILURE;
+        } else if let Err(e) = fs::write(&filepath, formatted) {
+            eprintln!("{}: {}", filepath.display(), e);
+            exit_code = ExitCode::FAILURE;
+        }
     }
+
+    exit_code
 }
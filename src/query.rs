@@ -0,0 +1,768 @@
+//! CSSに似たセレクターでツリーからタグを探す。
+//!
+//! ```text
+//! セレクター
+//!     複合セレクターを結合子でつなげたもの。","で区切って複数書ける。
+//!
+//! 結合子
+//!     空白  子孫
+//!     >     子
+//!
+//! 複合セレクター
+//!     タグ名か"*"に続けて、属性セレクターと:nth-child()をいくつでも書ける。
+//!     タグ名を省略すると"*"と同じ。
+//!
+//! 属性セレクター
+//!     [name]        名前付きの属性がある
+//!     [name=value]  値が等しい
+//!     [name^=value] 値がvalueで始まる
+//!     [name$=value] 値がvalueで終わる
+//!     [name*=value] 値がvalueを含む
+//!     同じ名前の属性が複数あれば、いずれかの値で判定する。
+//!     名前を省略すると、いずれかの無名属性の値で判定する。
+//!     値は引用符で囲める。引用符の中の引用符は2つ重ねる。
+//!
+//! :nth-child(an+b)
+//!     兄弟の中で何番目か。1から数える。odd、evenも書ける。
+//! ```
+//!
+//! 要素はタグだけで、段落、ブロック、ヘッダーは親子関係に含めない。
+//! ヘッダーのインラインタグの親はそのブロックタグになる。
+
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::build::step1::Span;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::symbol::NameSyntax;
+use crate::build::step3::tag::TagName;
+use crate::build::step3::visit::walk_block;
+use crate::build::step3::visit::Ancestor;
+use crate::build::step3::visit::Visitor;
+use crate::document::Document;
+
+/// セレクターに一致したタグ
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TagRef<'a> {
+    BlockTag(&'a BlockTag),
+    InlineTag(&'a InlineTag),
+}
+
+impl<'a> TagRef<'a> {
+    pub fn name(&self) -> &'a TagName {
+        match self {
+            TagRef::BlockTag(block_tag) => block_tag.name(),
+            TagRef::InlineTag(inline_tag) => inline_tag.name(),
+        }
+    }
+
+    pub fn attributes(&self) -> &'a Attributes {
+        match self {
+            TagRef::BlockTag(block_tag) => block_tag.attributes(),
+            TagRef::InlineTag(inline_tag) => inline_tag.attributes(),
+        }
+    }
+
+    pub fn span(&self) -> &'a Span {
+        match self {
+            TagRef::BlockTag(block_tag) => block_tag.span(),
+            TagRef::InlineTag(inline_tag) => inline_tag.span(),
+        }
+    }
+
+    pub fn as_block_tag(&self) -> Option<&'a BlockTag> {
+        match self {
+            TagRef::BlockTag(block_tag) => Some(block_tag),
+            _ => None,
+        }
+    }
+
+    pub fn as_inline_tag(&self) -> Option<&'a InlineTag> {
+        match self {
+            TagRef::InlineTag(inline_tag) => Some(inline_tag),
+            _ => None,
+        }
+    }
+}
+
+/// セレクターが不正な時のエラー
+#[derive(Debug, PartialEq)]
+pub struct SelectorError {
+    /// セレクターの何文字目か。0から数える。
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at {})", self.message, self.position)
+    }
+}
+
+impl Error for SelectorError {}
+
+/// パースしたセレクター
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selector {
+    /// ","で区切られたセレクター
+    complexes: Vec<ComplexSelector>,
+}
+
+/// 複合セレクターを結合子でつなげたもの。
+/// 最初の複合セレクターには結合子がない。
+#[derive(Clone, Debug, PartialEq)]
+struct ComplexSelector {
+    compounds: Vec<(Combinator, CompoundSelector)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Combinator {
+    Descendant,
+    Child,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct CompoundSelector {
+    /// Noneなら全てのタグ
+    name: Option<String>,
+    attributes: Vec<AttributeSelector>,
+    nth_child: Vec<(i64, i64)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct AttributeSelector {
+    /// Noneなら無名属性
+    name: Option<String>,
+    condition: Option<(Operator, String)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Equals,
+    StartsWith,
+    EndsWith,
+    Contains,
+}
+
+impl Selector {
+    pub fn parse(source: &str) -> Result<Selector, SelectorError> {
+        SelectorParser {
+            chars: source.chars().collect(),
+            index: 0,
+        }
+        .parse()
+    }
+}
+
+impl FromStr for Selector {
+    type Err = SelectorError;
+
+    fn from_str(source: &str) -> Result<Selector, SelectorError> {
+        Selector::parse(source)
+    }
+}
+
+struct SelectorParser {
+    chars: Vec<char>,
+    index: usize,
+}
+
+impl SelectorParser {
+    fn error<T>(&self, message: &str) -> Result<T, SelectorError> {
+        Err(SelectorError {
+            position: self.index,
+            message: message.to_owned(),
+        })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.index).copied()
+    }
+
+    /// 空白を読み飛ばす。読み飛ばしたらtrueを返す。
+    fn skip_whitespace(&mut self) -> bool {
+        let start = self.index;
+        while let Some(' ' | '\t' | '\n') = self.peek() {
+            self.index += 1;
+        }
+        self.index > start
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), SelectorError> {
+        if self.peek() == Some(c) {
+            self.index += 1;
+            Ok(())
+        } else {
+            self.error(&format!("'{}' is required.", c))
+        }
+    }
+
+    fn parse(mut self) -> Result<Selector, SelectorError> {
+        let mut complexes = vec![];
+
+        loop {
+            self.skip_whitespace();
+            complexes.push(self.parse_complex()?);
+            match self.peek() {
+                Some(',') => self.index += 1,
+                None => break,
+                Some(c) => return self.error(&format!("There is an illegal character. '{}'", c)),
+            }
+        }
+
+        Ok(Selector { complexes })
+    }
+
+    fn parse_complex(&mut self) -> Result<ComplexSelector, SelectorError> {
+        let mut compounds = vec![(Combinator::Descendant, self.parse_compound()?)];
+
+        loop {
+            let whitespace = self.skip_whitespace();
+            let combinator = match self.peek() {
+                Some('>') => {
+                    self.index += 1;
+                    self.skip_whitespace();
+                    Combinator::Child
+                }
+                Some(',') | None => break,
+                _ if whitespace => Combinator::Descendant,
+                Some(c) => return self.error(&format!("There is an illegal character. '{}'", c)),
+            };
+            compounds.push((combinator, self.parse_compound()?));
+        }
+
+        Ok(ComplexSelector { compounds })
+    }
+
+    fn parse_compound(&mut self) -> Result<CompoundSelector, SelectorError> {
+        let start = self.index;
+        let mut compound = CompoundSelector::default();
+
+        if self.peek() == Some('*') {
+            self.index += 1;
+        } else if let Some(name) = self.parse_symbol() {
+            compound.name = Some(name);
+        }
+
+        loop {
+            match self.peek() {
+                Some('[') => {
+                    self.index += 1;
+                    compound.attributes.push(self.parse_attribute()?);
+                }
+                Some(':') => {
+                    self.index += 1;
+                    compound.nth_child.push(self.parse_nth_child()?);
+                }
+                _ => break,
+            }
+        }
+
+        if self.index == start {
+            return self.error("A selector is required.");
+        }
+
+        Ok(compound)
+    }
+
+    fn parse_symbol(&mut self) -> Option<String> {
+        let mut symbol = String::new();
+        while let Some(c) = self.peek() {
+            // どの書式で書いた名前も選べるように一番広い書式で判定する
+            if NameSyntax::Namespaced.is_name_char(c, symbol.is_empty()) {
+                symbol.push(c);
+                self.index += 1;
+            } else {
+                break;
+            }
+        }
+
+        (!symbol.is_empty()).then_some(symbol)
+    }
+
+    fn parse_attribute(&mut self) -> Result<AttributeSelector, SelectorError> {
+        self.skip_whitespace();
+        let name = self.parse_symbol();
+        self.skip_whitespace();
+
+        let operator = match self.peek() {
+            Some('=') => Some(Operator::Equals),
+            Some('^') => Some(Operator::StartsWith),
+            Some('$') => Some(Operator::EndsWith),
+            Some('*') => Some(Operator::Contains),
+            _ => None,
+        };
+
+        let condition = match operator {
+            Some(operator) => {
+                self.index += 1;
+                if operator != Operator::Equals {
+                    self.expect('=')?;
+                }
+                self.skip_whitespace();
+                Some((operator, self.parse_value()?))
+            }
+            None if name.is_some() => None,
+            None => return self.error("An attribute name or value is required."),
+        };
+
+        self.skip_whitespace();
+        self.expect(']')?;
+
+        Ok(AttributeSelector { name, condition })
+    }
+
+    fn parse_value(&mut self) -> Result<String, SelectorError> {
+        let mut value = String::new();
+
+        if self.peek() == Some('"') {
+            self.index += 1;
+            loop {
+                match self.peek() {
+                    Some('"') => {
+                        self.index += 1;
+                        if self.peek() == Some('"') {
+                            value.push('"');
+                            self.index += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    Some(c) => {
+                        value.push(c);
+                        self.index += 1;
+                    }
+                    None => return self.error("'\"' is required."),
+                }
+            }
+        } else {
+            while let Some(c) = self.peek() {
+                if matches!(c, ']' | ' ' | '\t' | '\n' | '"') {
+                    break;
+                }
+                value.push(c);
+                self.index += 1;
+            }
+            if value.is_empty() {
+                return self.error("An attribute value is required.");
+            }
+        }
+
+        Ok(value)
+    }
+
+    /// :nth-child(an+b)をパースして(a, b)を返す。
+    fn parse_nth_child(&mut self) -> Result<(i64, i64), SelectorError> {
+        if self.parse_symbol().as_deref() != Some("nth-child") {
+            return self.error("Only :nth-child() is supported.");
+        }
+        self.expect('(')?;
+        self.skip_whitespace();
+
+        let start = self.index;
+        let mut expression = String::new();
+        while let Some(c) = self.peek() {
+            if c == ')' {
+                break;
+            }
+            if !matches!(c, ' ' | '\t' | '\n') {
+                expression.push(c);
+            }
+            self.index += 1;
+        }
+
+        let nth = match parse_an_plus_b(&expression) {
+            Some(nth) => nth,
+            None => {
+                self.index = start;
+                return self.error("The argument of :nth-child() is invalid.");
+            }
+        };
+        self.expect(')')?;
+
+        Ok(nth)
+    }
+}
+
+/// an+bの形式を(a, b)にする。
+fn parse_an_plus_b(expression: &str) -> Option<(i64, i64)> {
+    match expression {
+        "odd" => return Some((2, 1)),
+        "even" => return Some((2, 0)),
+        _ => {}
+    }
+
+    match expression.split_once('n') {
+        Some((a, b)) => {
+            let a = match a {
+                "" | "+" => 1,
+                "-" => -1,
+                a => a.parse().ok()?,
+            };
+            let b = match b {
+                "" => 0,
+                b if b.starts_with(['+', '-']) => b.trim_start_matches('+').parse().ok()?,
+                _ => return None,
+            };
+            Some((a, b))
+        }
+        None => Some((0, expression.parse().ok()?)),
+    }
+}
+
+/// 親子関係を含めたタグ
+struct Element<'a> {
+    tag: TagRef<'a>,
+    parent: Option<usize>,
+    /// 兄弟の中で何番目か。1から数える。
+    position: i64,
+}
+
+/// ツリーのタグを出現順に集める。
+#[derive(Default)]
+struct ElementCollector<'a> {
+    elements: Vec<Element<'a>>,
+    /// 囲んでいるタグの位置と、そのタグの子の数
+    stack: Vec<(usize, i64)>,
+    root_children: i64,
+}
+
+impl<'a> ElementCollector<'a> {
+    fn enter(&mut self, tag: TagRef<'a>) {
+        let (parent, position) = match self.stack.last_mut() {
+            Some((parent, children)) => {
+                *children += 1;
+                (Some(*parent), *children)
+            }
+            None => {
+                self.root_children += 1;
+                (None, self.root_children)
+            }
+        };
+        self.stack.push((self.elements.len(), 0));
+        self.elements.push(Element {
+            tag,
+            parent,
+            position,
+        });
+    }
+}
+
+impl<'a> Visitor<'a> for ElementCollector<'a> {
+    fn enter_block_tag(&mut self, block_tag: &'a BlockTag, _ancestors: &[Ancestor<'a>]) {
+        self.enter(TagRef::BlockTag(block_tag));
+    }
+
+    fn leave_block_tag(&mut self, _block_tag: &'a BlockTag, _ancestors: &[Ancestor<'a>]) {
+        self.stack.pop();
+    }
+
+    fn enter_inline_tag(&mut self, inline_tag: &'a InlineTag, _ancestors: &[Ancestor<'a>]) {
+        self.enter(TagRef::InlineTag(inline_tag));
+    }
+
+    fn leave_inline_tag(&mut self, _inline_tag: &'a InlineTag, _ancestors: &[Ancestor<'a>]) {
+        self.stack.pop();
+    }
+}
+
+impl CompoundSelector {
+    fn matches(&self, element: &Element) -> bool {
+        if let Some(name) = &self.name {
+            if element.tag.name().name() != name {
+                return false;
+            }
+        }
+
+        self.attributes
+            .iter()
+            .all(|attribute| attribute.matches(element.tag))
+            && self.nth_child.iter().all(|(a, b)| {
+                let n = element.position - b;
+                if *a == 0 {
+                    n == 0
+                } else {
+                    n % a == 0 && n / a >= 0
+                }
+            })
+    }
+}
+
+impl AttributeSelector {
+    fn matches(&self, tag: TagRef) -> bool {
+        let test = |value: &str| match &self.condition {
+            None => true,
+            Some((Operator::Equals, expected)) => value == expected,
+            Some((Operator::StartsWith, expected)) => value.starts_with(expected.as_str()),
+            Some((Operator::EndsWith, expected)) => value.ends_with(expected.as_str()),
+            Some((Operator::Contains, expected)) => value.contains(expected.as_str()),
+        };
+
+        match &self.name {
+            Some(name) => tag
+                .attributes()
+                .get_all(name)
+                .any(|attribute| test(attribute.value().value())),
+            None => tag.attributes().nameless().any(|value| test(value.value())),
+        }
+    }
+}
+
+impl ComplexSelector {
+    fn matches(&self, elements: &[Element], index: usize) -> bool {
+        self.matches_from(elements, index, self.compounds.len() - 1)
+    }
+
+    /// index番目の要素がcompound番目までの複合セレクターに一致するか。
+    fn matches_from(&self, elements: &[Element], index: usize, compound: usize) -> bool {
+        let (combinator, selector) = &self.compounds[compound];
+        if !selector.matches(&elements[index]) {
+            return false;
+        }
+        if compound == 0 {
+            return true;
+        }
+
+        let mut parent = elements[index].parent;
+        match combinator {
+            Combinator::Child => {
+                parent.is_some_and(|parent| self.matches_from(elements, parent, compound - 1))
+            }
+            Combinator::Descendant => {
+                while let Some(ancestor) = parent {
+                    if self.matches_from(elements, ancestor, compound - 1) {
+                        return true;
+                    }
+                    parent = elements[ancestor].parent;
+                }
+                false
+            }
+        }
+    }
+}
+
+/// セレクターに一致するタグを出現順に返す。
+pub fn select<'a>(block: &'a Block, selector: &Selector) -> Vec<TagRef<'a>> {
+    let mut collector = ElementCollector::default();
+    walk_block(&mut collector, block);
+    let elements = collector.elements;
+
+    (0..elements.len())
+        .filter(|index| {
+            selector
+                .complexes
+                .iter()
+                .any(|complex| complex.matches(&elements, *index))
+        })
+        .map(|index| elements[index].tag)
+        .collect()
+}
+
+impl Document {
+    /// セレクターに一致するタグを出現順に返す。
+    pub fn select(&self, selector: &str) -> Result<Vec<TagRef<'_>>, SelectorError> {
+        Ok(select(self.block(), &Selector::parse(selector)?))
+    }
+
+    /// セレクターに一致する最初のタグを返す。
+    pub fn select_first(&self, selector: &str) -> Result<Option<TagRef<'_>>, SelectorError> {
+        Ok(self.select(selector)?.into_iter().next())
+    }
+}
+
+#[cfg(test)]
+mod test_parse_selector {
+    use super::Selector;
+    use super::SelectorError;
+
+    #[test]
+    fn test_valid() {
+        for source in [
+            "table",
+            "*",
+            "table column",
+            "table > column",
+            "table>column, section",
+            "link[href^=http]",
+            "link[href^=\"http\"]",
+            "code[=rust]",
+            "a[x][y=\"a \"\"b\"\"\"]",
+            "column:nth-child(2n+1)",
+            ":nth-child(odd)",
+            "[x]",
+        ] {
+            assert!(Selector::parse(source).is_ok(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            Selector::parse("table >"),
+            Err(SelectorError {
+                position: 7,
+                message: "A selector is required.".to_owned()
+            })
+        );
+        assert_eq!(
+            Selector::parse("a[x").unwrap_err().message,
+            "']' is required."
+        );
+        assert_eq!(
+            Selector::parse("a[]").unwrap_err().message,
+            "An attribute name or value is required."
+        );
+        assert_eq!(
+            Selector::parse("a:first-child").unwrap_err().message,
+            "Only :nth-child() is supported."
+        );
+        assert_eq!(
+            Selector::parse("a:nth-child(x)"),
+            Err(SelectorError {
+                position: 12,
+                message: "The argument of :nth-child() is invalid.".to_owned()
+            })
+        );
+        assert_eq!(
+            Selector::parse("a;").unwrap_err().message,
+            "There is an illegal character. ';'"
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_select {
+    use crate::build::step1::Position;
+    use crate::build::step1::Span;
+    use crate::build::step3::ParseOptions;
+    use crate::document::parse_document;
+    use crate::document::parse_document_with_options;
+    use crate::document::Document;
+    use indoc::indoc;
+    use std::error::Error;
+
+    fn document() -> Document {
+        parse_document(
+            indoc! {r#"
+                :section[id=s1] First :b{header}
+                    :table
+                        :column[width=10] a
+                        :column b
+                        :p
+                            :column c
+                    :link[href=https://example.com]{x} :link[href=ftp://example.com]{y}
+                :section Second
+                    :code-block[rust]
+                        :column
+            "#}
+            .as_bytes(),
+        )
+        .unwrap()
+    }
+
+    fn names(document: &Document, selector: &str) -> Vec<String> {
+        document
+            .select(selector)
+            .unwrap()
+            .iter()
+            .map(|tag| {
+                let mut name = tag.name().name().to_owned();
+                if let Some(header) = tag.as_block_tag().and_then(|tag| tag.header()) {
+                    name.push_str(&format!("({})", header.contents().len()));
+                }
+                name
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_descendant() {
+        let document = document();
+        assert_eq!(
+            names(&document, "table column"),
+            vec!["column(1)", "column(1)", "column(1)"]
+        );
+        assert_eq!(names(&document, "section b"), vec!["b"]);
+    }
+
+    #[test]
+    fn test_child() {
+        let document = document();
+        assert_eq!(
+            names(&document, "table > column"),
+            vec!["column(1)", "column(1)"]
+        );
+        assert_eq!(names(&document, "section > column"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_attribute() {
+        let document = document();
+        assert_eq!(names(&document, "[id=s1]"), vec!["section(2)"]);
+        assert_eq!(names(&document, "column[width]"), vec!["column(1)"]);
+        assert_eq!(names(&document, "link[href^=http]").len(), 1);
+        assert_eq!(names(&document, "link[href$=\".com\"]").len(), 2);
+        assert_eq!(names(&document, "link[href*=tp:]").len(), 1);
+        assert_eq!(names(&document, "[=rust]"), vec!["code-block"]);
+        assert_eq!(names(&document, "[^=ru]"), vec!["code-block"]);
+    }
+
+    /// 繰り返された属性はどれかが一致すればよい
+    #[test]
+    fn test_repeated_attribute() {
+        let document = parse_document_with_options(
+            ":p[class=a class=b]{x} :p[class=c]{y}".as_bytes(),
+            &ParseOptions::new().allow_repeated_attribute("p", "class"),
+        )
+        .unwrap();
+        assert_eq!(names(&document, "p[class=b]"), vec!["p"]);
+        assert_eq!(names(&document, "p[class]").len(), 2);
+    }
+
+    #[test]
+    fn test_nth_child() {
+        let document = document();
+        assert_eq!(names(&document, "section:nth-child(2)"), vec!["section(1)"]);
+        // ヘッダーのタグも子に含まれる
+        assert_eq!(names(&document, "section > :nth-child(2)"), vec!["table"]);
+        assert_eq!(
+            names(&document, "table > :nth-child(odd)"),
+            vec!["column(1)", "p"]
+        );
+        assert_eq!(names(&document, "table > :nth-child(-n+2)").len(), 2);
+    }
+
+    #[test]
+    fn test_list() {
+        let document = document();
+        // 出現順で、重複しない
+        assert_eq!(
+            names(&document, "table, section, [id]"),
+            vec!["section(2)", "table", "section(1)"]
+        );
+    }
+
+    #[test]
+    fn test_first() -> Result<(), Box<dyn Error>> {
+        let document = document();
+
+        let section = document.select_first("section")?.unwrap();
+        assert_eq!(
+            section.span(),
+            &Span::new(
+                Position::with_byte_offset(1, 1, 0),
+                Position::with_byte_offset(8, 1, 195)
+            )
+        );
+        let header = section.as_block_tag().unwrap().header().unwrap();
+        assert_eq!(header.contents()[0].as_text().unwrap().value(), "First ");
+
+        assert!(document.select_first("nothing")?.is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,548 @@
+//! タグの定義と、定義に従っているかの検証。
+//!
+//! ```text
+//! タグの定義
+//!     タグ名
+//!     インラインタグとブロックタグのどちらに使えるか
+//!     内容をパースするか
+//!     ブロックタグにヘッダーと内容を書けるか
+//!     書ける属性と必ず書く属性、無名属性の数
+//!     内容に直接書けるタグ
+//! ```
+//!
+//! パーサーは内容をパースするかだけを定義から判断する。
+//! それ以外は検証で警告にする。
+
+use std::sync::LazyLock;
+
+use crate::build::step1::Position;
+use crate::build::step2::FilePosition;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::tag::TagName;
+use crate::build::step3::visit::walk_block;
+use crate::build::step3::visit::Ancestor;
+use crate::build::step3::visit::Visitor;
+use crate::build::step3::ParseError;
+use crate::document::Document;
+
+/// タグをどこに書けるか
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TagUsage {
+    Inline,
+    Block,
+    Both,
+}
+
+impl TagUsage {
+    fn allows_inline(self) -> bool {
+        matches!(self, TagUsage::Inline | TagUsage::Both)
+    }
+
+    fn allows_block(self) -> bool {
+        matches!(self, TagUsage::Block | TagUsage::Both)
+    }
+}
+
+/// タグの定義
+#[derive(Clone, Debug, PartialEq)]
+pub struct TagSchema {
+    name: String,
+    usage: TagUsage,
+    raw: bool,
+    header: bool,
+    block_contents: bool,
+    /// 書ける属性の名前。Noneならどの属性も書ける
+    attributes: Option<Vec<String>>,
+    /// 必ず書く属性の名前
+    required_attributes: Vec<String>,
+    /// 無名属性の最小数と最大数
+    nameless: (usize, Option<usize>),
+    /// 内容に直接書けるタグの名前。Noneならどのタグも書ける
+    children: Option<Vec<String>>,
+}
+
+impl TagSchema {
+    /// 内容をパースし、ヘッダーと内容、どの属性とタグも書けるタグ
+    pub fn new<T: Into<String>>(name: T, usage: TagUsage) -> TagSchema {
+        TagSchema {
+            name: name.into(),
+            usage,
+            raw: false,
+            header: true,
+            block_contents: true,
+            attributes: None,
+            required_attributes: Vec::new(),
+            nameless: (0, None),
+            children: None,
+        }
+    }
+
+    /// 内容をパースしないようにする。
+    pub fn raw(mut self, raw: bool) -> TagSchema {
+        self.raw = raw;
+        self
+    }
+
+    /// ブロックタグにヘッダーを書けるか指定する。
+    pub fn header(mut self, allowed: bool) -> TagSchema {
+        self.header = allowed;
+        self
+    }
+
+    /// ブロックタグに内容を書けるか指定する。
+    pub fn block_contents(mut self, allowed: bool) -> TagSchema {
+        self.block_contents = allowed;
+        self
+    }
+
+    /// 書ける属性を追加する。
+    /// 追加すると追加した属性以外は書けなくなる。
+    pub fn attribute<T: Into<String>>(mut self, name: T) -> TagSchema {
+        self.attributes
+            .get_or_insert_with(Vec::new)
+            .push(name.into());
+        self
+    }
+
+    /// 必ず書く属性を追加する。必ず書く属性は書ける属性でもある。
+    pub fn required_attribute<T: Into<String>>(mut self, name: T) -> TagSchema {
+        self.required_attributes.push(name.into());
+        self
+    }
+
+    /// 無名属性の最小数と最大数を指定する。最大数がNoneなら上限はない。
+    pub fn nameless(mut self, min: usize, max: Option<usize>) -> TagSchema {
+        self.nameless = (min, max);
+        self
+    }
+
+    /// 内容に直接書けるタグを指定する。空なら内容にタグを書けない。
+    pub fn children<I, T>(mut self, names: I) -> TagSchema
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        self.children = Some(names.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn usage(&self) -> TagUsage {
+        self.usage
+    }
+
+    pub fn is_raw(&self) -> bool {
+        self.raw
+    }
+
+    fn allows_attribute(&self, name: &str) -> bool {
+        match &self.attributes {
+            Some(attributes) => attributes
+                .iter()
+                .chain(&self.required_attributes)
+                .any(|attribute| attribute == name),
+            None => true,
+        }
+    }
+
+    fn allows_child(&self, name: &str) -> bool {
+        match &self.children {
+            Some(children) => children.iter().any(|child| child == name),
+            None => true,
+        }
+    }
+}
+
+/// タグの定義の表
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Schema {
+    tags: Vec<TagSchema>,
+}
+
+/// オプションが指定されなかった時の定義
+pub(crate) static STANDARD_SCHEMA: LazyLock<Schema> = LazyLock::new(Schema::standard);
+
+impl Schema {
+    /// 空の表
+    pub fn new() -> Schema {
+        Schema { tags: Vec::new() }
+    }
+
+    /// 標準のタグの表。省略記法のタグと、内容をパースしないタグがある。
+    pub fn standard() -> Schema {
+        Schema::new()
+            .tag(TagSchema::new("b", TagUsage::Inline))
+            .tag(TagSchema::new("i", TagUsage::Inline))
+            .tag(TagSchema::new("u", TagUsage::Inline))
+            .tag(TagSchema::new("del", TagUsage::Inline))
+            .tag(TagSchema::new("q", TagUsage::Inline))
+            .tag(TagSchema::new("code", TagUsage::Inline).raw(true))
+            .tag(TagSchema::new("raw", TagUsage::Inline))
+            .tag(TagSchema::new("image", TagUsage::Inline))
+            .tag(TagSchema::new("sequence", TagUsage::Both))
+            .tag(TagSchema::new("section", TagUsage::Both))
+            .tag(TagSchema::new("link", TagUsage::Inline))
+            .tag(TagSchema::new("apply-template", TagUsage::Both))
+            .tag(
+                TagSchema::new("code-block", TagUsage::Block)
+                    .raw(true)
+                    .nameless(0, Some(1)),
+            )
+            .tag(TagSchema::new("raw-html", TagUsage::Both).raw(true))
+            .tag(
+                TagSchema::new("template", TagUsage::Block)
+                    .header(false)
+                    .nameless(1, None),
+            )
+            .tag(
+                TagSchema::new("param", TagUsage::Both)
+                    .block_contents(false)
+                    .nameless(1, Some(1)),
+            )
+            .tag(
+                TagSchema::new("contents", TagUsage::Both)
+                    .block_contents(false)
+                    .nameless(0, Some(0)),
+            )
+            .tag(
+                TagSchema::new("include", TagUsage::Block)
+                    .header(false)
+                    .block_contents(false)
+                    .attribute("lines")
+                    .attribute("section")
+                    .nameless(1, Some(1)),
+            )
+    }
+
+    /// タグの定義を追加する。同じ名前の定義があれば置き換える。
+    pub fn tag(mut self, tag: TagSchema) -> Schema {
+        match self.tags.iter_mut().find(|t| t.name == tag.name) {
+            Some(t) => *t = tag,
+            None => self.tags.push(tag),
+        }
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&TagSchema> {
+        self.tags.iter().find(|tag| tag.name == name)
+    }
+
+    pub fn tags(&self) -> &[TagSchema] {
+        &self.tags
+    }
+
+    /// ブロックタグの内容をパースしないか。
+    pub fn is_raw_block_tag(&self, name: &str) -> bool {
+        self.get(name)
+            .is_some_and(|tag| tag.raw && tag.usage.allows_block())
+    }
+
+    /// インラインタグの内容をパースしないか。
+    pub fn is_raw_inline_tag(&self, name: &str) -> bool {
+        self.get(name)
+            .is_some_and(|tag| tag.raw && tag.usage.allows_inline())
+    }
+}
+
+/// ドキュメントのタグが定義に従っているか検証して、従っていなければ警告を返す。
+/// 警告はタグの出現順に並ぶ。
+pub fn validate(document: &Document, schema: &Schema) -> Vec<ParseError> {
+    let mut validator = Validator {
+        document,
+        schema,
+        warnings: vec![],
+    };
+    walk_block(&mut validator, document.block());
+    validator.warnings
+}
+
+impl Document {
+    /// タグが定義に従っているか検証する。
+    pub fn validate(&self, schema: &Schema) -> Vec<ParseError> {
+        validate(self, schema)
+    }
+}
+
+struct Validator<'a> {
+    document: &'a Document,
+    schema: &'a Schema,
+    warnings: Vec<ParseError>,
+}
+
+impl Validator<'_> {
+    fn warn(&mut self, position: &Position, message: String) {
+        self.warnings.push(ParseError::new(
+            FilePosition {
+                filepath: self.document.filepath().to_path_buf(),
+                position: Some(position.clone()),
+                included_from: None,
+            },
+            None,
+            message,
+        ));
+    }
+
+    /// インラインタグとブロックタグに共通の検証をする。
+    /// タグが定義されていればその定義を返す。
+    fn tag<'s>(
+        &mut self,
+        schema: &'s Schema,
+        name: &TagName,
+        attributes: &Attributes,
+        ancestors: &[Ancestor],
+    ) -> Option<&'s TagSchema> {
+        let position = &name.span().start;
+
+        // 親が定義されていて、内容に書けるタグが決まっていれば従う
+        if let Some(parent) = ancestors.last() {
+            if let Some(parent_schema) = schema.get(parent.name().name()) {
+                if !parent_schema.allows_child(name.name()) {
+                    self.warn(
+                        position,
+                        format!(
+                            "The tag is not allowed in {:?}. ({:?})",
+                            parent_schema.name,
+                            name.name()
+                        ),
+                    );
+                }
+            }
+        }
+
+        let Some(tag) = schema.get(name.name()) else {
+            self.warn(
+                position,
+                format!("The tag is not defined. ({:?})", name.name()),
+            );
+            return None;
+        };
+
+        for attribute in attributes.named() {
+            if !tag.allows_attribute(attribute.name()) {
+                self.warn(
+                    &attribute.name_span().start,
+                    format!("The attribute is not allowed. ({:?})", attribute.name()),
+                );
+            }
+        }
+        for required in &tag.required_attributes {
+            if !attributes.contains(required) {
+                self.warn(
+                    position,
+                    format!("The attribute is required. ({:?})", required),
+                );
+            }
+        }
+
+        let (min, max) = tag.nameless;
+        let nameless = attributes.nameless().collect::<Vec<_>>();
+        if nameless.len() < min {
+            self.warn(
+                position,
+                format!("At least {} nameless values are required.", min),
+            );
+        }
+        if let Some(max) = max {
+            if let Some(value) = nameless.get(max) {
+                self.warn(
+                    &value.span().start,
+                    format!("At most {} nameless values are allowed.", max),
+                );
+            }
+        }
+
+        Some(tag)
+    }
+}
+
+impl<'a> Visitor<'a> for Validator<'a> {
+    fn enter_block_tag(&mut self, block_tag: &'a BlockTag, ancestors: &[Ancestor<'a>]) {
+        let name = block_tag.name();
+        let Some(tag) = self.tag(self.schema, name, block_tag.attributes(), ancestors) else {
+            return;
+        };
+        let position = &name.span().start;
+
+        if !tag.usage.allows_block() {
+            self.warn(
+                position,
+                format!("The tag cannot be a block tag. ({:?})", tag.name),
+            );
+        }
+        if block_tag.header().is_some() && !tag.header {
+            self.warn(
+                position,
+                format!("The tag cannot have a header. ({:?})", tag.name),
+            );
+        }
+        if block_tag.contents().is_some() && !tag.block_contents {
+            self.warn(
+                position,
+                format!("The tag cannot have block contents. ({:?})", tag.name),
+            );
+        }
+    }
+
+    fn enter_inline_tag(&mut self, inline_tag: &'a InlineTag, ancestors: &[Ancestor<'a>]) {
+        let name = inline_tag.name();
+        let Some(tag) = self.tag(self.schema, name, inline_tag.attributes(), ancestors) else {
+            return;
+        };
+
+        if !tag.usage.allows_inline() {
+            self.warn(
+                &name.span().start,
+                format!("The tag cannot be an inline tag. ({:?})", tag.name),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_schema {
+    use super::Schema;
+    use super::TagSchema;
+    use super::TagUsage;
+
+    #[test]
+    fn test_raw() {
+        let schema = Schema::standard();
+        assert!(schema.is_raw_block_tag("code-block"));
+        assert!(!schema.is_raw_inline_tag("code-block"));
+        assert!(schema.is_raw_inline_tag("code"));
+        assert!(!schema.is_raw_block_tag("code"));
+        assert!(schema.is_raw_block_tag("raw-html"));
+        assert!(schema.is_raw_inline_tag("raw-html"));
+        assert!(!schema.is_raw_inline_tag("unknown"));
+    }
+
+    #[test]
+    fn test_replace() {
+        let schema = Schema::standard().tag(TagSchema::new("code", TagUsage::Both));
+        assert!(!schema.is_raw_inline_tag("code"));
+        assert_eq!(schema.tags().len(), Schema::standard().tags().len());
+    }
+}
+
+#[cfg(test)]
+mod test_validate {
+    use super::Schema;
+    use super::TagSchema;
+    use super::TagUsage;
+    use crate::build::step3::ParseOptions;
+    use crate::document::parse_document;
+    use crate::document::parse_document_with_options;
+    use indoc::indoc;
+
+    fn validate(source: &str, schema: &Schema) -> Vec<String> {
+        parse_document(source.as_bytes())
+            .unwrap()
+            .validate(schema)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_valid() {
+        let source = indoc! {"
+            :code-block[rust]
+                fn main() {}
+            a :b{b} :code{c}
+        "};
+        assert!(validate(source, &Schema::standard()).is_empty());
+    }
+
+    #[test]
+    fn test_unknown_tag() {
+        assert_eq!(
+            validate("a :x{b}\n", &Schema::standard()),
+            vec![":1:3: The tag is not defined. (\"x\")"]
+        );
+    }
+
+    #[test]
+    fn test_usage() {
+        let source = indoc! {"
+            :b
+                a
+            :code-block{x}
+        "};
+        assert_eq!(
+            validate(source, &Schema::standard()),
+            vec![
+                ":1:1: The tag cannot be a block tag. (\"b\")",
+                ":3:1: The tag cannot be an inline tag. (\"code-block\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_block_tag() {
+        let schema = Schema::new()
+            .tag(TagSchema::new("hr", TagUsage::Block).block_contents(false))
+            .tag(TagSchema::new("list", TagUsage::Block).header(false));
+        let source = indoc! {"
+            :hr
+                a
+            :list b
+        "};
+        assert_eq!(
+            validate(source, &schema),
+            vec![
+                ":1:1: The tag cannot have block contents. (\"hr\")",
+                ":3:1: The tag cannot have a header. (\"list\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_attributes() {
+        let schema = Schema::new().tag(
+            TagSchema::new("link", TagUsage::Inline)
+                .required_attribute("href")
+                .attribute("title")
+                .nameless(1, Some(1)),
+        );
+        assert_eq!(
+            validate(":link[href=a title=b]{c} :link[x=y a b]{c}\n", &schema),
+            vec![
+                ":1:1: At least 1 nameless values are required.",
+                ":1:32: The attribute is not allowed. (\"x\")",
+                ":1:26: The attribute is required. (\"href\")",
+                ":1:38: At most 1 nameless values are allowed.",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_children() {
+        let schema = Schema::new()
+            .tag(TagSchema::new("list", TagUsage::Block).children(["item"]))
+            .tag(TagSchema::new("item", TagUsage::Both))
+            .tag(TagSchema::new("b", TagUsage::Inline));
+        let source = indoc! {"
+            :list
+                :item a :b{b}
+                :b{c}
+        "};
+        assert_eq!(
+            validate(source, &schema),
+            vec![":3:5: The tag is not allowed in \"list\". (\"b\")"]
+        );
+    }
+
+    /// 定義に従って内容をパースしない
+    #[test]
+    fn test_parse_raw() {
+        let options = ParseOptions::new()
+            .schema(Schema::new().tag(TagSchema::new("math", TagUsage::Inline).raw(true)));
+        let document = parse_document_with_options("a :math{:x}\n".as_bytes(), &options).unwrap();
+        assert!(document.validate(options.get_schema()).is_empty());
+    }
+}
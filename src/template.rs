@@ -0,0 +1,1070 @@
+//! テンプレートの定義と展開。
+//!
+//! ```text
+//! 定義
+//!     :template[名前 引数 引数=既定値]
+//!         本体
+//!     最初の無名属性がテンプレートの名前になる。
+//!     残りの無名属性は既定値のない引数、名前付き属性は既定値のある引数で、書いた順に並ぶ。
+//!     本体の:param[引数]は引数の値に、:contentsは呼び出しの内容に置き換える。
+//!
+//! 呼び出し
+//!     :apply-template[名前 値 引数=値]{内容}
+//!     :apply-template[名前 値 引数=値] ヘッダー
+//!         内容
+//!     タグに続ける場合は省略記法の@[名前 値 引数=値]{内容}も使える。
+//!     無名属性の値は定義の順に引数に、名前付き属性の値は同じ名前の引数に割り当てる。
+//!     インラインの:contentsはインラインタグの内容かブロックタグのヘッダー、
+//!     ブロックの:contentsはブロックタグの内容になる。
+//!     インラインタグで呼び出すテンプレートの本体は1つの段落にする。
+//! ```
+//!
+//! 本体の中の呼び出しはテンプレートを定義した位置で、呼び出しの内容の中の呼び出しは呼び出した位置で展開する。
+//! 展開した本体の範囲は、テンプレートのファイルを呼び出した位置に持ち込んだ範囲にする。
+//! 展開中のテンプレートを本体の中で呼び出すのは誤りで、呼び出しの回数にも上限がある。
+
+use std::path::Path;
+
+use crate::build::step1::Position;
+use crate::build::step1::Span;
+use crate::build::step2::include_span;
+use crate::build::step2::FilePosition;
+use crate::build::step3::attribute::AttributeItem;
+use crate::build::step3::attribute::Attributes;
+use crate::build::step3::block::Block;
+use crate::build::step3::block_tag::BlockTag;
+use crate::build::step3::inline_tag::InlineTag;
+use crate::build::step3::paragraph::Paragraph;
+use crate::build::step3::visit::map_inline_spans;
+use crate::build::step3::visit::map_spans;
+use crate::build::step3::BlockNode;
+use crate::build::step3::InlineContents;
+use crate::build::step3::InlineNode;
+use crate::build::step3::ParseError;
+use crate::build::step3::Text;
+use crate::document::Document;
+
+/// テンプレートを定義するタグ
+const TEMPLATE_TAG_NAME: &str = "template";
+/// テンプレートを呼び出すタグ
+const APPLY_TAG_NAME: &str = "apply-template";
+/// 本体で引数の値に置き換えるタグ
+const PARAM_TAG_NAME: &str = "param";
+/// 本体で呼び出しの内容に置き換えるタグ
+const CONTENTS_TAG_NAME: &str = "contents";
+
+/// 展開できる呼び出しの深さ。本体の中の呼び出しは1段深くなる
+const MAX_DEPTH: usize = 32;
+/// 1つのドキュメントで展開できる呼び出しの数
+const MAX_EXPANSIONS: usize = 10000;
+
+/// テンプレートの引数
+#[derive(Clone, Debug, PartialEq)]
+struct Parameter {
+    name: String,
+    default: Option<String>,
+}
+
+/// テンプレート
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    name: String,
+    parameters: Vec<Parameter>,
+    body: Block,
+    /// 定義したタグの位置
+    file_position: FilePosition,
+}
+
+impl Template {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 引数の名前。定義の順に並ぶ。
+    pub fn parameters(&self) -> impl Iterator<Item = &str> {
+        self.parameters
+            .iter()
+            .map(|parameter| parameter.name.as_str())
+    }
+
+    pub fn body(&self) -> &Block {
+        &self.body
+    }
+
+    pub fn file_position(&self) -> &FilePosition {
+        &self.file_position
+    }
+}
+
+/// テンプレートの表
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Templates {
+    templates: Vec<Template>,
+}
+
+impl Templates {
+    /// 空の表
+    pub fn new() -> Templates {
+        Templates {
+            templates: Vec::new(),
+        }
+    }
+
+    /// ドキュメントで定義したテンプレートを追加する。同じ名前のテンプレートがあれば置き換える。
+    /// 定義の誤りは警告にして返す。
+    pub fn add_definitions(&mut self, document: &Document) -> Vec<ParseError> {
+        let mut collector = Collector {
+            filepath: document.filepath(),
+            templates: vec![],
+            warnings: vec![],
+        };
+        collector.block(document.block());
+
+        for template in collector.templates {
+            match self
+                .templates
+                .iter_mut()
+                .find(|defined| defined.name == template.name)
+            {
+                Some(defined) => *defined = template,
+                None => self.templates.push(template),
+            }
+        }
+        collector.warnings
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Template> {
+        self.templates.iter().find(|template| template.name == name)
+    }
+
+    /// 定義の順に返す。
+    pub fn templates(&self) -> &[Template] {
+        &self.templates
+    }
+}
+
+/// ドキュメントのテンプレートの呼び出しを展開する。
+/// ドキュメントで定義したテンプレートは取り除いて、ライブラリの同じ名前のテンプレートより優先する。
+/// 展開できない呼び出しはそのまま残して警告を返す。
+pub fn expand_templates(document: &mut Document, library: &Templates) -> Vec<ParseError> {
+    let mut templates = library.clone();
+    let mut warnings = templates.add_definitions(document);
+    remove_definitions(document.block_mut());
+
+    let filepath = document.filepath().to_path_buf();
+    let mut expander = Expander {
+        templates: &templates,
+        stack: vec![],
+        expansions: 0,
+        warnings: vec![],
+    };
+    expander.block(document.block_mut(), &filepath, 0);
+
+    warnings.append(&mut expander.warnings);
+    warnings
+}
+
+impl Document {
+    /// テンプレートの呼び出しを展開する。
+    pub fn expand_templates(&mut self, library: &Templates) -> Vec<ParseError> {
+        expand_templates(self, library)
+    }
+}
+
+fn file_position(filepath: &Path, position: &Position) -> FilePosition {
+    FilePosition {
+        filepath: filepath.to_path_buf(),
+        position: Some(position.clone()),
+        included_from: None,
+    }
+}
+
+/// 範囲の始まりの位置。別のファイルから持ち込んだ範囲はそのファイルの位置にする。
+fn span_position(filepath: &Path, span: &Span) -> FilePosition {
+    match &span.file {
+        Some(file) => file.file_position(span),
+        None => file_position(filepath, &span.start),
+    }
+}
+
+/// テンプレートの定義を集める。
+struct Collector<'a> {
+    filepath: &'a Path,
+    templates: Vec<Template>,
+    warnings: Vec<ParseError>,
+}
+
+impl Collector<'_> {
+    fn warn(&mut self, position: &Position, message: String) {
+        self.warnings.push(ParseError::new(
+            file_position(self.filepath, position),
+            None,
+            message,
+        ));
+    }
+
+    fn block(&mut self, block: &Block) {
+        for node in block.contents() {
+            match node {
+                BlockNode::BlockTag(block_tag) if block_tag.name().name() == TEMPLATE_TAG_NAME => {
+                    self.template(block_tag);
+                }
+                BlockNode::BlockTag(block_tag) => {
+                    if let Some(contents) = block_tag.contents() {
+                        self.block(contents);
+                    }
+                }
+                BlockNode::Block(block) => self.block(block),
+                _ => {}
+            }
+        }
+    }
+
+    fn template(&mut self, block_tag: &BlockTag) {
+        let position = &block_tag.name().span().start;
+        let mut items = block_tag.attributes().items().iter();
+        let name = match items.next() {
+            Some(AttributeItem::Nameless(name)) => name.value().to_owned(),
+            _ => {
+                self.warn(position, "The template name is required.".to_owned());
+                return;
+            }
+        };
+        if self.templates.iter().any(|template| template.name == name) {
+            self.warn(
+                position,
+                format!("The template is already defined. ({:?})", name),
+            );
+            return;
+        }
+
+        let mut parameters: Vec<Parameter> = vec![];
+        for item in items {
+            let parameter = match item {
+                AttributeItem::Named(attribute) => Parameter {
+                    name: attribute.name().to_owned(),
+                    default: Some(attribute.value().value().to_owned()),
+                },
+                AttributeItem::Nameless(value) => Parameter {
+                    name: value.value().to_owned(),
+                    default: None,
+                },
+            };
+            if parameters
+                .iter()
+                .any(|defined| defined.name == parameter.name)
+            {
+                self.warn(
+                    &item.span().start,
+                    format!("The parameter is already defined. ({:?})", parameter.name),
+                );
+                continue;
+            }
+            parameters.push(parameter);
+        }
+
+        let body = block_tag.contents().cloned().unwrap_or_else(|| {
+            let end = block_tag.span().end.clone();
+            Block::new(vec![], Span::new(end.clone(), end))
+        });
+
+        self.templates.push(Template {
+            name,
+            parameters,
+            body,
+            file_position: file_position(self.filepath, &block_tag.span().start),
+        });
+    }
+}
+
+/// テンプレートの定義を取り除く。
+fn remove_definitions(block: &mut Block) {
+    block.contents_mut().retain_mut(|node| match node {
+        BlockNode::BlockTag(block_tag) => {
+            if block_tag.name().name() == TEMPLATE_TAG_NAME {
+                return false;
+            }
+            if let Some(contents) = block_tag.contents_mut() {
+                remove_definitions(contents);
+            }
+            true
+        }
+        BlockNode::Block(block) => {
+            remove_definitions(block);
+            true
+        }
+        _ => true,
+    });
+}
+
+/// 呼び出しの引数と内容
+struct Call<'c> {
+    template: &'c Template,
+    /// 引数の名前と値。定義の順に並ぶ
+    arguments: Vec<(String, String)>,
+    /// インラインタグの内容かブロックタグのヘッダー
+    inline: &'c [InlineNode],
+    /// ブロックタグの内容
+    block: &'c [BlockNode],
+    /// 呼び出したタグの位置
+    file_position: FilePosition,
+}
+
+/// テンプレートの呼び出しを展開する。
+struct Expander<'a> {
+    templates: &'a Templates,
+    /// 本体を展開しているテンプレート。外側から並ぶ
+    stack: Vec<&'a Template>,
+    /// 展開した呼び出しの数
+    expansions: usize,
+    warnings: Vec<ParseError>,
+}
+
+impl<'a> Expander<'a> {
+    fn warn(&mut self, file_position: FilePosition, message: String) {
+        self.warnings
+            .push(ParseError::new(file_position, None, message));
+    }
+
+    /// 呼び出しの誤りを、呼び出した位置とテンプレートを定義した位置で警告する。
+    fn warn_call(&mut self, call_site: &FilePosition, template: &Template, message: &str) {
+        self.warn(
+            call_site.clone(),
+            format!(
+                "{} (template {:?} at {})",
+                message, template.name, template.file_position
+            ),
+        );
+    }
+
+    fn block(&mut self, block: &mut Block, filepath: &Path, depth: usize) {
+        let contents = std::mem::take(block.contents_mut());
+        let mut expanded = Vec::with_capacity(contents.len());
+
+        for node in contents {
+            match node {
+                BlockNode::BlockTag(mut block_tag) if block_tag.name().name() == APPLY_TAG_NAME => {
+                    match self.block_call(&mut block_tag, filepath, depth) {
+                        Some(nodes) => expanded.extend(nodes),
+                        None => expanded.push(BlockNode::BlockTag(block_tag)),
+                    }
+                }
+                BlockNode::BlockTag(mut block_tag) => {
+                    if let Some(header) = block_tag.header_mut() {
+                        self.inline(header.contents_mut(), filepath, depth);
+                    }
+                    if let Some(contents) = block_tag.contents_mut() {
+                        self.block(contents, filepath, depth);
+                    }
+                    expanded.push(BlockNode::BlockTag(block_tag));
+                }
+                BlockNode::Block(mut block) => {
+                    self.block(&mut block, filepath, depth);
+                    expanded.push(BlockNode::Block(block));
+                }
+                BlockNode::Paragraph(mut paragraph) => {
+                    self.inline(paragraph.contents_mut(), filepath, depth);
+                    expanded.push(BlockNode::Paragraph(paragraph));
+                }
+                node => expanded.push(node),
+            }
+        }
+
+        *block.contents_mut() = expanded;
+    }
+
+    fn inline(&mut self, contents: &mut InlineContents, filepath: &Path, depth: usize) {
+        let nodes = std::mem::take(contents);
+        let mut expanded = Vec::with_capacity(nodes.len());
+
+        for node in nodes {
+            match node {
+                InlineNode::InlineTag(mut inline_tag)
+                    if inline_tag.name().name() == APPLY_TAG_NAME =>
+                {
+                    match self.inline_call(&mut inline_tag, filepath, depth) {
+                        Some(nodes) => expanded.extend(nodes),
+                        None => expanded.push(InlineNode::InlineTag(inline_tag)),
+                    }
+                }
+                InlineNode::InlineTag(mut inline_tag) => {
+                    self.inline(inline_tag.contents_mut(), filepath, depth);
+                    expanded.push(InlineNode::InlineTag(inline_tag));
+                }
+                node => expanded.push(node),
+            }
+        }
+
+        *contents = expanded;
+    }
+
+    /// ブロックタグの呼び出しを展開する。展開できなければNone。
+    fn block_call(
+        &mut self,
+        block_tag: &mut BlockTag,
+        filepath: &Path,
+        depth: usize,
+    ) -> Option<Vec<BlockNode>> {
+        let call_site = span_position(filepath, block_tag.span());
+        let template = self.template(block_tag.attributes(), &call_site, depth)?;
+        let arguments = self.bind(template, block_tag.attributes(), &call_site);
+
+        if let Some(header) = block_tag.header_mut() {
+            self.inline(header.contents_mut(), filepath, depth);
+        }
+        if let Some(contents) = block_tag.contents_mut() {
+            self.block(contents, filepath, depth);
+        }
+
+        let mut body = template.body.clone();
+        self.stack.push(template);
+        self.block(&mut body, &template.file_position.filepath, depth + 1);
+        self.stack.pop();
+        // 引数と内容は呼び出した位置のものなので、置き換える前に範囲を書き換える
+        map_spans(
+            body.contents_mut(),
+            &mut include_span(template.file_position.filepath.clone(), call_site.clone()),
+        );
+
+        let call = Call {
+            template,
+            arguments,
+            inline: block_tag
+                .header()
+                .map_or(&[][..], |header| header.contents()),
+            block: block_tag
+                .contents()
+                .map_or(&[][..], |contents| contents.contents()),
+            file_position: call_site,
+        };
+        let nodes = self.substitute_block(std::mem::take(body.contents_mut()), &call);
+        Some(nodes)
+    }
+
+    /// インラインタグの呼び出しを展開する。展開できなければNone。
+    fn inline_call(
+        &mut self,
+        inline_tag: &mut InlineTag,
+        filepath: &Path,
+        depth: usize,
+    ) -> Option<Vec<InlineNode>> {
+        let call_site = span_position(filepath, inline_tag.span());
+        let template = self.template(inline_tag.attributes(), &call_site, depth)?;
+
+        // 本体は1つの段落でなければ段落の中に入れられない
+        let mut paragraphs = template
+            .body
+            .contents()
+            .iter()
+            .filter(|node| !node.is_blank_line());
+        let mut body = match (paragraphs.next(), paragraphs.next()) {
+            (Some(BlockNode::Paragraph(paragraph)), None) => paragraph.contents().clone(),
+            (None, None) => vec![],
+            _ => {
+                self.warn_call(
+                    &call_site,
+                    template,
+                    "The template cannot be expanded in a paragraph.",
+                );
+                return None;
+            }
+        };
+        // 段落の最後の改行は入れない
+        if let Some(InlineNode::Text(text)) = body.last_mut() {
+            if text.value().ends_with('\n') {
+                text.value_mut().pop();
+                if text.value().is_empty() {
+                    body.pop();
+                }
+            }
+        }
+
+        let arguments = self.bind(template, inline_tag.attributes(), &call_site);
+        self.inline(inline_tag.contents_mut(), filepath, depth);
+        self.stack.push(template);
+        self.inline(&mut body, &template.file_position.filepath, depth + 1);
+        self.stack.pop();
+        map_inline_spans(
+            &mut body,
+            &mut include_span(template.file_position.filepath.clone(), call_site.clone()),
+        );
+
+        let call = Call {
+            template,
+            arguments,
+            inline: inline_tag.contents(),
+            block: &[],
+            file_position: call_site,
+        };
+        Some(self.substitute_inline(body, &call))
+    }
+
+    /// 呼び出すテンプレートを探す。
+    fn template(
+        &mut self,
+        attributes: &Attributes,
+        call_site: &FilePosition,
+        depth: usize,
+    ) -> Option<&'a Template> {
+        let name = match attributes.nameless().next() {
+            Some(name) => name.value(),
+            None => {
+                self.warn(
+                    call_site.clone(),
+                    "The template name is required.".to_owned(),
+                );
+                return None;
+            }
+        };
+        let templates = self.templates;
+        let template = match templates.get(name) {
+            Some(template) => template,
+            None => {
+                self.warn(
+                    call_site.clone(),
+                    format!("The template is not defined. ({:?})", name),
+                );
+                return None;
+            }
+        };
+        if let Some(i) = self
+            .stack
+            .iter()
+            .position(|called| called.name == template.name)
+        {
+            let names: Vec<String> = self.stack[i..]
+                .iter()
+                .chain(Some(&template))
+                .map(|called| format!("{:?}", called.name))
+                .collect();
+            self.warn_call(
+                call_site,
+                template,
+                &format!(
+                    "The template is called recursively. ({})",
+                    names.join(" -> ")
+                ),
+            );
+            return None;
+        }
+        if depth >= MAX_DEPTH {
+            self.warn_call(call_site, template, "The template expansion is too deep.");
+            return None;
+        }
+        // 上限を超えたら1度だけ警告して、残りの呼び出しは展開しない
+        if self.expansions >= MAX_EXPANSIONS {
+            if self.expansions == MAX_EXPANSIONS {
+                self.warn_call(
+                    call_site,
+                    template,
+                    &format!("The template is called more than {} times.", MAX_EXPANSIONS),
+                );
+                self.expansions += 1;
+            }
+            return None;
+        }
+        self.expansions += 1;
+        Some(template)
+    }
+
+    /// 呼び出しの属性を引数に割り当てる。
+    fn bind(
+        &mut self,
+        template: &Template,
+        attributes: &Attributes,
+        call_site: &FilePosition,
+    ) -> Vec<(String, String)> {
+        let mut values: Vec<Option<String>> = vec![None; template.parameters.len()];
+
+        // 最初の無名属性はテンプレートの名前
+        for (i, value) in attributes.nameless().skip(1).enumerate() {
+            if i >= values.len() {
+                self.warn_call(call_site, template, "There are too many values.");
+                break;
+            }
+            values[i] = Some(value.value().to_owned());
+        }
+
+        for attribute in attributes.named() {
+            match template
+                .parameters
+                .iter()
+                .position(|parameter| parameter.name == attribute.name())
+            {
+                Some(i) => {
+                    if values[i].is_some() {
+                        self.warn_call(
+                            call_site,
+                            template,
+                            &format!("The parameter is given twice. ({:?})", attribute.name()),
+                        );
+                    }
+                    values[i] = Some(attribute.value().value().to_owned());
+                }
+                None => self.warn_call(
+                    call_site,
+                    template,
+                    &format!("The parameter is not defined. ({:?})", attribute.name()),
+                ),
+            }
+        }
+
+        template
+            .parameters
+            .iter()
+            .zip(values)
+            .map(|(parameter, value)| {
+                let value = value.or_else(|| parameter.default.clone());
+                if value.is_none() {
+                    self.warn_call(
+                        call_site,
+                        template,
+                        &format!("The parameter is required. ({:?})", parameter.name),
+                    );
+                }
+                (parameter.name.clone(), value.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    /// 本体の:paramタグの引数の値。
+    fn argument(&mut self, attributes: &Attributes, position: &Position, call: &Call) -> String {
+        let name = attributes.nameless().next().map_or("", |name| name.value());
+        match call
+            .arguments
+            .iter()
+            .find(|(parameter, _)| parameter == name)
+        {
+            Some((_, value)) => value.clone(),
+            None => {
+                // 本体の誤りは、本体の位置と呼び出した位置で警告する
+                self.warn(
+                    file_position(&call.template.file_position.filepath, position),
+                    format!(
+                        "The parameter is not defined. ({:?}) (expanded at {})",
+                        name, call.file_position
+                    ),
+                );
+                String::new()
+            }
+        }
+    }
+
+    /// 本体のブロックの要素の:paramと:contentsを置き換える。
+    fn substitute_block(&mut self, nodes: Vec<BlockNode>, call: &Call) -> Vec<BlockNode> {
+        let mut substituted = Vec::with_capacity(nodes.len());
+
+        for node in nodes {
+            match node {
+                BlockNode::BlockTag(block_tag) if block_tag.name().name() == PARAM_TAG_NAME => {
+                    let span = block_tag.span();
+                    let value = self.argument(block_tag.attributes(), &span.start, call);
+                    if !value.is_empty() {
+                        let text = Text::new(format!("{}\n", value), span.clone());
+                        substituted.push(BlockNode::Paragraph(Paragraph::new(
+                            vec![InlineNode::Text(text)],
+                            span.clone(),
+                        )));
+                    }
+                }
+                BlockNode::BlockTag(block_tag) if block_tag.name().name() == CONTENTS_TAG_NAME => {
+                    substituted.extend(call.block.iter().cloned());
+                }
+                BlockNode::BlockTag(mut block_tag) => {
+                    if let Some(header) = block_tag.header_mut() {
+                        let contents = std::mem::take(header.contents_mut());
+                        *header.contents_mut() = self.substitute_inline(contents, call);
+                    }
+                    if let Some(block) = block_tag.contents_mut() {
+                        let contents = std::mem::take(block.contents_mut());
+                        *block.contents_mut() = self.substitute_block(contents, call);
+                    }
+                    substituted.push(BlockNode::BlockTag(block_tag));
+                }
+                BlockNode::Block(mut block) => {
+                    let contents = std::mem::take(block.contents_mut());
+                    *block.contents_mut() = self.substitute_block(contents, call);
+                    substituted.push(BlockNode::Block(block));
+                }
+                BlockNode::Paragraph(mut paragraph) => {
+                    let contents = std::mem::take(paragraph.contents_mut());
+                    *paragraph.contents_mut() = self.substitute_inline(contents, call);
+                    substituted.push(BlockNode::Paragraph(paragraph));
+                }
+                node => substituted.push(node),
+            }
+        }
+
+        substituted
+    }
+
+    /// 本体のインラインの要素の:paramと:contentsを置き換える。
+    fn substitute_inline(&mut self, nodes: InlineContents, call: &Call) -> InlineContents {
+        let mut substituted = Vec::with_capacity(nodes.len());
+
+        for node in nodes {
+            match node {
+                InlineNode::InlineTag(inline_tag) if inline_tag.name().name() == PARAM_TAG_NAME => {
+                    let span = inline_tag.span();
+                    let value = self.argument(inline_tag.attributes(), &span.start, call);
+                    if !value.is_empty() {
+                        substituted.push(InlineNode::Text(Text::new(value, span.clone())));
+                    }
+                }
+                InlineNode::InlineTag(inline_tag)
+                    if inline_tag.name().name() == CONTENTS_TAG_NAME =>
+                {
+                    substituted.extend(call.inline.iter().cloned());
+                }
+                InlineNode::InlineTag(mut inline_tag) => {
+                    let contents = std::mem::take(inline_tag.contents_mut());
+                    *inline_tag.contents_mut() = self.substitute_inline(contents, call);
+                    substituted.push(InlineNode::InlineTag(inline_tag));
+                }
+                node => substituted.push(node),
+            }
+        }
+
+        substituted
+    }
+}
+
+#[cfg(test)]
+mod test_expand_templates {
+    use std::path::PathBuf;
+
+    use super::Templates;
+    use crate::build::step1::Span;
+    use crate::build::step3::test_utils::assert_model;
+    use crate::build::step3::BlockNode;
+    use crate::document::parse_document;
+    use crate::document::Document;
+    use crate::document::Source;
+    use indoc::indoc;
+
+    fn parse(filepath: &str, source: &str) -> Document {
+        parse_document(Source::Binary(
+            PathBuf::from(filepath),
+            source.as_bytes().to_vec(),
+        ))
+        .unwrap()
+    }
+
+    fn messages(warnings: &[crate::build::step3::ParseError]) -> Vec<String> {
+        warnings.iter().map(|warning| warning.to_string()).collect()
+    }
+
+    /// ブロックタグの呼び出しを、引数と内容を入れた本体に置き換える
+    #[test]
+    fn test_block_call() {
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[note title level=info]
+                    :section[class=note]
+                        Note - :b{:param[title]{}} (:param[level]{})
+
+                        :contents
+
+                :apply-template[note Warning level=high]
+                    Body text
+            "},
+        );
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert!(warnings.is_empty());
+        assert_model(
+            document.block(),
+            r#"{"b":[
+                "<bl>",
+                {"bt":"section","a":{"class":"note"},"c":{"b":[
+                    {"p":["Note - ",{"it":"b","c":["Warning"]}," (","high",")\n"]},
+                    "<bl>",
+                    {"p":["Body text\n"]}
+                ]}}
+            ]}"#,
+        );
+    }
+
+    /// インラインタグの呼び出しは本体の段落の内容に置き換える
+    #[test]
+    fn test_inline_call() {
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[em]
+                    very :b{:contents{}}
+
+                It is :i@[em]{good}.
+            "},
+        );
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert!(warnings.is_empty());
+        assert_model(
+            document.block(),
+            r#"{"b":["<bl>",{"p":["It is ",{"it":"i","c":["very ",{"it":"b","c":["good"]}]},".\n"]}]}"#,
+        );
+    }
+
+    /// ライブラリのテンプレートを使える。ドキュメントの定義が優先する
+    #[test]
+    fn test_library() {
+        let library = parse(
+            "library.oreno",
+            indoc! {"
+                :template[greet name]
+                    Hello, :param[name]{}.
+                :template[bye]
+                    Bye.
+            "},
+        );
+        let mut templates = Templates::new();
+        assert!(templates.add_definitions(&library).is_empty());
+        assert_eq!(
+            templates
+                .get("greet")
+                .unwrap()
+                .parameters()
+                .collect::<Vec<_>>(),
+            vec!["name"]
+        );
+
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[bye]
+                    See you.
+
+                :apply-template[greet World]
+
+                :apply-template[bye]
+            "},
+        );
+        let warnings = document.expand_templates(&templates);
+
+        assert!(warnings.is_empty());
+        assert_model(
+            document.block(),
+            r#"{"b":["<bl>",{"p":["Hello, ","World",".\n"]},"<bl>",{"p":["See you.\n"]}]}"#,
+        );
+    }
+
+    /// 呼び出しの誤りは呼び出した位置とテンプレートの位置を警告する
+    #[test]
+    fn test_call_errors() {
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[pair first second=2]
+                    Values - :param[first] :param[second] :param[third]
+
+                :apply-template[pair third=3]
+
+                :apply-template[unknown]
+            "},
+        );
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert_eq!(
+            messages(&warnings),
+            vec![
+                "book.oreno:4:1: The parameter is not defined. (\"third\") \
+                 (template \"pair\" at book.oreno:1:1)",
+                "book.oreno:4:1: The parameter is required. (\"first\") \
+                 (template \"pair\" at book.oreno:1:1)",
+                "book.oreno:2:43: The parameter is not defined. (\"third\") \
+                 (expanded at book.oreno:4:1)",
+                "book.oreno:6:1: The template is not defined. (\"unknown\")",
+            ]
+        );
+        assert_model(
+            document.block(),
+            r#"{"b":["<bl>",{"p":["Values - "," ","2"," ","\n"]},"<bl>",{"bt":"apply-template","v":["unknown"]}]}"#,
+        );
+    }
+
+    /// 展開中のテンプレートの呼び出しは、呼び出した位置とテンプレートの位置を警告する
+    #[test]
+    fn test_recursion() {
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[loop]
+                    :apply-template[loop]
+
+                :apply-template[loop]
+            "},
+        );
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert_eq!(
+            messages(&warnings),
+            vec![
+                "book.oreno:2:5: The template is called recursively. (\"loop\" -> \"loop\") \
+                 (template \"loop\" at book.oreno:1:1)"
+            ]
+        );
+        assert_model(
+            document.block(),
+            r#"{"b":["<bl>",{"bt":"apply-template","v":["loop"]}]}"#,
+        );
+    }
+
+    /// 本体で2回呼び出しても展開は増えない
+    #[test]
+    fn test_recursion_twice() {
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[x]
+                    a :apply-template[x]{} :apply-template[x]{}
+
+                :apply-template[x]
+            "},
+        );
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert_eq!(
+            messages(&warnings),
+            vec![
+                "book.oreno:2:7: The template is called recursively. (\"x\" -> \"x\") \
+                 (template \"x\" at book.oreno:1:1)",
+                "book.oreno:2:28: The template is called recursively. (\"x\" -> \"x\") \
+                 (template \"x\" at book.oreno:1:1)",
+            ]
+        );
+    }
+
+    /// 別のテンプレートを通した再帰は呼び出しの順を警告する
+    #[test]
+    fn test_indirect_recursion() {
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[a]
+                    :apply-template[b]
+                :template[b]
+                    :apply-template[a]
+
+                :apply-template[a]
+            "},
+        );
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert_eq!(
+            messages(&warnings),
+            vec![
+                "book.oreno:4:5: The template is called recursively. (\"a\" -> \"b\" -> \"a\") \
+                 (template \"a\" at book.oreno:1:1)"
+            ]
+        );
+    }
+
+    /// 呼び出しの数が上限を超えたら1度だけ警告して展開をやめる
+    #[test]
+    fn test_expansion_limit() {
+        let mut source = String::new();
+        for i in 0..5 {
+            source.push_str(&format!(":template[w{}]\n", i));
+            for _ in 0..10 {
+                source.push_str(&format!("    :apply-template[w{}]\n", i + 1));
+            }
+        }
+        source.push_str(":template[w5]\n    x\n\n:apply-template[w0]\n");
+        let mut document = parse("book.oreno", &source);
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0]
+            .to_string()
+            .contains("The template is called more than 10000 times."));
+    }
+
+    /// 展開した本体の範囲はテンプレートのファイルを呼び出した位置に持ち込んだ範囲になる
+    #[test]
+    fn test_expanded_spans() {
+        let library = parse(
+            "library.oreno",
+            indoc! {"
+                :template[greet name]
+                    Hello, :param[name]{}.
+
+                    :contents
+            "},
+        );
+        let mut templates = Templates::new();
+        templates.add_definitions(&library);
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :section
+                    :apply-template[greet World]
+                        Body
+            "},
+        );
+
+        let warnings = document.expand_templates(&templates);
+
+        assert!(warnings.is_empty());
+        let locate = |span: &Span| {
+            let file_position = document.file_position(span);
+            std::iter::once(&file_position)
+                .chain(file_position.include_chain())
+                .map(|file_position| file_position.to_string())
+                .collect::<Vec<_>>()
+        };
+        let section = match &document.block().contents()[0] {
+            BlockNode::BlockTag(section) => section.contents().unwrap().contents(),
+            _ => unreachable!(),
+        };
+        let paragraph = match &section[0] {
+            BlockNode::Paragraph(paragraph) => paragraph,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            locate(paragraph.span()),
+            vec!["library.oreno:2:5", "book.oreno:2:5"]
+        );
+        // 引数の値は本体の:paramの位置
+        assert_eq!(
+            locate(paragraph.contents()[1].span()),
+            vec!["library.oreno:2:12", "book.oreno:2:5"]
+        );
+        // 呼び出しの内容は呼び出した位置のまま
+        assert_eq!(locate(section[2].span()), vec!["book.oreno:3:9"]);
+    }
+
+    /// 本体が段落でなければインラインタグでは呼び出せない
+    #[test]
+    fn test_block_template_in_paragraph() {
+        let mut document = parse(
+            "book.oreno",
+            indoc! {"
+                :template[two]
+                    a
+
+                    b
+
+                x :apply-template[two]{} y
+            "},
+        );
+
+        let warnings = document.expand_templates(&Templates::new());
+
+        assert_eq!(
+            messages(&warnings),
+            vec![
+                "book.oreno:6:3: The template cannot be expanded in a paragraph. \
+                 (template \"two\" at book.oreno:1:1)"
+            ]
+        );
+    }
+}